@@ -0,0 +1,59 @@
+//! Benchmarks of loading configuration: merging big nb.toml files with the built-in defaults,
+//! and applying `NB_*` environment variables on top.
+
+use std::fmt::Write;
+use criterion::{criterion_group, criterion_main, Criterion};
+use nb::config::file::File;
+use nb::config::toml_merge::merge_files;
+
+/// A configuration file with `count` aliases, step policies and branch rules, numbered from `offset`,
+/// so that files with overlapping numbers override each other's values.
+fn big_config(offset: usize, count: usize) -> String {
+    let mut config = format!("team = \"team{offset}\"\n\n[alias]\n");
+    for n in offset..offset + count {
+        writeln!(config, "ship{n} = \"deploy --env prod --cluster prod-gcp-{n}\"").unwrap();
+    }
+    for n in offset..offset + count {
+        writeln!(config, "\n[steps.step{n}]\nretries = {}\nretry_delay_ms = 500\ntimeout_seconds = 600", n % 5).unwrap();
+    }
+    for n in offset..offset + count {
+        writeln!(config, "\n[branch.\"^release-{n}$\"]\noutput = \"deploy\"\ndeploy.profiles = [\"default\", \"dev-gcp\"]").unwrap();
+    }
+    config
+}
+
+fn config_merge(c: &mut Criterion) {
+    let directory = tempfile::tempdir().unwrap();
+    let filenames: Vec<String> = (0..3)
+        .map(|n| {
+            let path = directory.path().join(format!("nb{n}.toml"));
+            std::fs::write(&path, big_config(n * 250, 500)).unwrap();
+            path.to_str().unwrap().to_string()
+        })
+        .collect();
+
+    c.bench_function("merge big config files with the defaults", |b| {
+        b.iter(|| File::default_with_user_config_files(&filenames).unwrap())
+    });
+
+    let contents: Vec<String> = (0..3).map(|n| big_config(n * 250, 500)).collect();
+    let contents: Vec<&str> = contents.iter().map(String::as_str).collect();
+    c.bench_function("merge big TOML documents", |b| {
+        b.iter(|| merge_files(&contents).unwrap())
+    });
+
+    let vars: Vec<(String, String)> = (0..50)
+        .map(|n| (format!("NB_ALIAS__SHIP{n}"), format!("deploy --env dev --cluster dev-gcp-{n}")))
+        .chain([("NB_TEAM".to_string(), "myteam".to_string())])
+        .collect();
+    c.bench_function("apply environment overrides to a big config", |b| {
+        b.iter_batched(
+            || File::default_with_user_config_files(&filenames).unwrap(),
+            |file| file.with_env_overrides(vars.iter().cloned()).unwrap(),
+            criterion::BatchSize::LargeInput,
+        )
+    });
+}
+
+criterion_group!(benches, config_merge);
+criterion_main!(benches);
@@ -0,0 +1,70 @@
+//! Benchmarks of what nb does on startup in a source directory: detecting its SDK and finding its nais.yaml,
+//! in a tree shaped like a monorepo, with many modules and a large dependency directory.
+
+use std::path::Path;
+use criterion::{criterion_group, criterion_main, Criterion};
+use nb::nais_yaml::{detect_nais_yaml, detect_nais_yaml_candidates, Search};
+use nb::vfs::Os;
+
+const APPLICATION: &str = "apiVersion: nais.io/v1alpha1\nkind: Application\nmetadata:\n  name: myapp\nspec:\n  image: \"{{ image }}\"\n";
+
+/// A Go monorepo with `targets` commands, `modules` packages of `files` files each,
+/// a `node_modules` directory as big as the packages, and manifests for two environments.
+fn monorepo(targets: usize, modules: usize, files: usize) -> tempfile::TempDir {
+    let directory = tempfile::tempdir().unwrap();
+    let write = |path: &str, contents: &str| {
+        let path = directory.path().join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    };
+    write("go.mod", "module example.com/monorepo\n\ngo 1.22\n");
+    for target in 0..targets {
+        write(&format!("cmd/target{target}/main.go"), "package main\n\nfunc main() {}\n");
+    }
+    for module in 0..modules {
+        for file in 0..files {
+            write(&format!("internal/module{module}/file{file}.go"), "package module\n");
+            write(&format!("node_modules/package{module}/file{file}.js"), "module.exports = {};\n");
+        }
+    }
+    for environment in ["dev", "prod"] {
+        write(&format!(".nais/{environment}/app.yaml"), APPLICATION);
+        write(&format!(".nais/{environment}/vars.yaml"), "ingress: https://myapp.example\n");
+        for topic in 0..20 {
+            write(&format!(".nais/{environment}/topic{topic}.yaml"), "kind: Topic\nmetadata:\n  name: mytopic\n");
+        }
+    }
+    write("nais.yaml", APPLICATION);
+    directory
+}
+
+fn sdk_detection(c: &mut Criterion) {
+    let settings = nb::config::file::File::default().sdk.expect("default SDK settings");
+    let tree = monorepo(200, 200, 25);
+    let path = tree.path().to_str().unwrap();
+    c.bench_function("detect SDK in a monorepo", |b| {
+        b.iter(|| nb::sdk::detect(path, &settings).unwrap().expect("an SDK is detected"))
+    });
+}
+
+fn nais_yaml_scanning(c: &mut Criterion) {
+    let tree = monorepo(20, 200, 25);
+    let path = tree.path().to_str().unwrap();
+    assert!(Path::new(path).join(".nais/dev/app.yaml").exists());
+
+    c.bench_function("detect nais.yaml in well-known places", |b| {
+        b.iter(|| detect_nais_yaml(&Os, path, &Search::default()).unwrap())
+    });
+
+    let paths = vec!["deploy/*.yaml".to_string(), ".nais/dev/**/*.yaml".to_string(), ".nais/**/*.y*ml".to_string()];
+    let search = Search { paths: Some(paths), ..Default::default() };
+    c.bench_function("detect nais.yaml with glob paths", |b| {
+        b.iter(|| detect_nais_yaml(&Os, path, &search).unwrap())
+    });
+    c.bench_function("list nais.yaml candidates with glob paths", |b| {
+        b.iter(|| detect_nais_yaml_candidates(&Os, path, &search).unwrap())
+    });
+}
+
+criterion_group!(benches, sdk_detection, nais_yaml_scanning);
+criterion_main!(benches);
@@ -0,0 +1,114 @@
+//! User-defined command aliases from the `[alias]` section of nb.toml, such as
+//! `ship = "deploy --env prod"`, expanded in the command line before it is parsed.
+//!
+//! Built-in commands always take precedence, and aliases are not expanded recursively.
+
+use std::collections::BTreeMap;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("alias `{alias}`: {err}")]
+    Parse {
+        alias: String,
+        err: shell_words::ParseError,
+    },
+}
+
+/// Replace an alias in place of the subcommand in `args`, which start with the program name.
+///
+/// The subcommand is the first positional argument, or the second if the first is the source directory.
+/// `aliases` is called with the source directory and the `--config` file, if any,
+/// and only if the command line does not name a built-in command.
+pub fn expand<F>(command: &clap::Command, args: Vec<String>, aliases: F) -> Result<Vec<String>, Error>
+where
+    F: FnOnce(&str, Option<&str>) -> BTreeMap<String, String>,
+{
+    let builtin = |name: &str| command.find_subcommand(name).is_some();
+    let positionals = positionals(command, &args);
+    let (source_directory, index) = match positionals.as_slice() {
+        [first, ..] if builtin(&args[*first]) => return Ok(args),
+        [_, second, ..] if builtin(&args[*second]) => return Ok(args),
+        [first, second, ..] => (args[*first].as_str(), *second),
+        [first] => (".", *first),
+        [] => return Ok(args),
+    };
+
+    let aliases = aliases(source_directory, option_value(command, &args, "config").as_deref());
+    let Some(expansion) = aliases.get(&args[index]) else {
+        return Ok(args);
+    };
+    let expansion = shell_words::split(expansion).map_err(|err| Error::Parse { alias: args[index].clone(), err })?;
+    let mut expanded = args[..index].to_vec();
+    expanded.extend(expansion);
+    expanded.extend_from_slice(&args[index + 1..]);
+    Ok(expanded)
+}
+
+/// Indices of the positional arguments before any subcommand arguments, skipping options and their values.
+fn positionals(command: &clap::Command, args: &[String]) -> Vec<usize> {
+    let mut positionals = Vec::new();
+    let mut index = 1;
+    while index < args.len() && positionals.len() < 2 {
+        let arg = &args[index];
+        if arg == "--" {
+            break;
+        }
+        if let Some(long) = arg.strip_prefix("--") {
+            if !long.contains('=') && takes_value(command, long) {
+                index += 1;
+            }
+        } else if !arg.starts_with('-') || arg == "-" {
+            positionals.push(index);
+        }
+        index += 1;
+    }
+    positionals
+}
+
+fn takes_value(command: &clap::Command, long: &str) -> bool {
+    command.get_arguments().any(|arg| arg.get_long() == Some(long) && arg.get_action().takes_values())
+}
+
+/// Value of a long option given before the subcommand, as `--name value` or `--name=value`.
+fn option_value(command: &clap::Command, args: &[String], name: &str) -> Option<String> {
+    let flag = format!("--{name}");
+    let mut iter = args.iter().skip(1);
+    while let Some(arg) = iter.next() {
+        if let Some(value) = arg.strip_prefix(&format!("{flag}=")) {
+            return Some(value.to_string());
+        }
+        if *arg == flag && takes_value(command, name) {
+            return iter.next().cloned();
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use clap::{Arg, Command};
+
+    #[test]
+    pub fn expand_aliases() {
+        let command = Command::new("nb")
+            .arg(Arg::new("source_directory").default_value("."))
+            .arg(Arg::new("env").long("env").global(true))
+            .arg(Arg::new("config").long("config"))
+            .subcommand(Command::new("build"))
+            .subcommand(Command::new("deploy"));
+        let args = |line: &str| line.split(' ').map(str::to_string).collect::<Vec<_>>();
+        let aliases = |source_directory: &str, config: Option<&str>| {
+            assert_eq!((source_directory, config), ("apps/foo", Some("nb.toml")));
+            BTreeMap::from([("ship".to_string(), "deploy --env 'prod gcp'".to_string())])
+        };
+
+        let expanded = expand(&command, args("nb --config nb.toml apps/foo ship --dry-run"), aliases).unwrap();
+        assert_eq!(expanded, ["nb", "--config", "nb.toml", "apps/foo", "deploy", "--env", "prod gcp", "--dry-run"]);
+        let unchanged = expand(&command, args("nb --env prod build"), |_, _| panic!("aliases are not needed")).unwrap();
+        assert_eq!(unchanged, args("nb --env prod build"));
+        let unknown = expand(&command, args("nb unknown"), |_, _| BTreeMap::new()).unwrap();
+        assert_eq!(unknown, args("nb unknown"));
+    }
+}
@@ -0,0 +1,252 @@
+//! Storage for the intermediate outputs of the pipeline, such as the generated Dockerfile, the rendered
+//! manifests and the build summary, so that they can be inspected after a run, or used by a later CI job.
+//!
+//! A store is a local directory or a Google Cloud Storage bucket, given as `gs://<bucket>[/<path>]`.
+//! The artifacts of a run are kept under `<app>/<image tag>`, which later jobs know from `--docker-image-name`.
+
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::path::PathBuf;
+use std::time::Duration;
+use log::debug;
+use thiserror::Error;
+use tokio::sync::Mutex;
+use crate::auth;
+
+const STORAGE_URL: &str = "https://storage.googleapis.com";
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("{path}: {err}")]
+    IO {
+        path: String,
+        err: std::io::Error,
+    },
+
+    #[error("`{0}` is not a bucket like gs://<bucket>[/<path>]")]
+    InvalidBucket(String),
+
+    #[error("auth: {0}")]
+    Auth(#[from] auth::Error),
+
+    #[error("reqwest: {0}")]
+    Reqwest(#[from] reqwest::Error),
+
+    #[error("Cloud Storage API returned {status}: {body}")]
+    Api {
+        status: u16,
+        body: String,
+    },
+}
+
+/// Outputs of the pipeline that are kept in a store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Artifact {
+    Dockerfile,
+    /// Software bill of materials of the image, in SPDX JSON.
+    Sbom,
+    /// The rendered resources that were deployed.
+    Manifests,
+    /// The image, digest and timings of the run, as in `artifacts.json`.
+    Summary,
+}
+
+impl Artifact {
+    pub fn file_name(self) -> &'static str {
+        match self {
+            Artifact::Dockerfile => "Dockerfile",
+            Artifact::Sbom => "sbom.spdx.json",
+            Artifact::Manifests => "manifests.yaml",
+            Artifact::Summary => "summary.json",
+        }
+    }
+}
+
+/// Where the artifacts of a run are kept.
+pub trait ArtifactStore {
+    /// Store `contents`, replacing an artifact of the same kind.
+    fn put(&self, artifact: Artifact, contents: &[u8]) -> impl Future<Output = Result<(), Error>>;
+
+    /// Contents of the artifact, if it has been stored.
+    fn get(&self, artifact: Artifact) -> impl Future<Output = Result<Option<Vec<u8>>, Error>>;
+}
+
+/// Artifacts in a local directory, e.g. one that is cached between CI jobs.
+pub struct LocalStore {
+    directory: PathBuf,
+}
+
+impl LocalStore {
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        LocalStore { directory: directory.into() }
+    }
+}
+
+impl ArtifactStore for LocalStore {
+    async fn put(&self, artifact: Artifact, contents: &[u8]) -> Result<(), Error> {
+        let path = self.directory.join(artifact.file_name());
+        let err = |err| Error::IO { path: path.to_string_lossy().to_string(), err };
+        std::fs::create_dir_all(&self.directory).map_err(err)?;
+        // Write to a temporary file first, so that readers never see a partially written artifact.
+        let tmp_path = self.directory.join(format!(".{}.tmp", artifact.file_name()));
+        std::fs::write(&tmp_path, contents).map_err(err)?;
+        std::fs::rename(&tmp_path, &path).map_err(err)
+    }
+
+    async fn get(&self, artifact: Artifact) -> Result<Option<Vec<u8>>, Error> {
+        let path = self.directory.join(artifact.file_name());
+        match std::fs::read(&path) {
+            Ok(contents) => Ok(Some(contents)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(Error::IO { path: path.to_string_lossy().to_string(), err }),
+        }
+    }
+}
+
+/// Artifacts in a Cloud Storage bucket, accessed with Google credentials from the environment.
+pub struct GcsStore {
+    bucket: String,
+    prefix: String,
+    credentials: Mutex<Option<auth::RefreshingCredentials>>,
+    http: auth::HttpSettings,
+}
+
+impl GcsStore {
+    pub fn new(bucket: &str, prefix: &str, http: auth::HttpSettings) -> Self {
+        GcsStore {
+            bucket: bucket.to_string(),
+            prefix: prefix.trim_matches('/').to_string(),
+            credentials: Mutex::new(None),
+            http,
+        }
+    }
+
+    fn object(&self, artifact: Artifact) -> String {
+        match self.prefix.as_str() {
+            "" => artifact.file_name().to_string(),
+            prefix => format!("{prefix}/{}", artifact.file_name()),
+        }
+    }
+
+    /// Access token for the bucket, acquired on first use.
+    async fn token(&self) -> Result<String, Error> {
+        let mut credentials = self.credentials.lock().await;
+        if credentials.is_none() {
+            *credentials = Some(auth::RefreshingCredentials::acquire(auth::Provider::google(), self.http.clone(), crate::shutdown::token()).await?);
+        }
+        let credentials = credentials.as_mut().expect("credentials are acquired");
+        Ok(credentials.get().await?.password.clone())
+    }
+
+    fn client() -> Result<reqwest::Client, Error> {
+        Ok(reqwest::Client::builder().timeout(Duration::from_secs(60)).build()?)
+    }
+}
+
+impl ArtifactStore for GcsStore {
+    async fn put(&self, artifact: Artifact, contents: &[u8]) -> Result<(), Error> {
+        let object = self.object(artifact);
+        debug!("Uploading gs://{}/{object}", self.bucket);
+        let resp = Self::client()?
+            .post(format!("{STORAGE_URL}/upload/storage/v1/b/{}/o", self.bucket))
+            .query(&[("uploadType", "media"), ("name", &object)])
+            .bearer_auth(self.token().await?)
+            .body(contents.to_vec())
+            .send()
+            .await?;
+        match resp.status().as_u16() {
+            200 => Ok(()),
+            status => Err(Error::Api { status, body: resp.text().await? }),
+        }
+    }
+
+    async fn get(&self, artifact: Artifact) -> Result<Option<Vec<u8>>, Error> {
+        let mut url = reqwest::Url::parse(&format!("{STORAGE_URL}/storage/v1/b/{}/o", self.bucket)).expect("storage URL is valid");
+        // The object name is a single path segment, with its slashes escaped.
+        url.path_segments_mut().expect("storage URL has a path").push(&self.object(artifact));
+        let resp = Self::client()?
+            .get(url)
+            .query(&[("alt", "media")])
+            .bearer_auth(self.token().await?)
+            .send()
+            .await?;
+        match resp.status().as_u16() {
+            200 => Ok(Some(resp.bytes().await?.to_vec())),
+            404 => Ok(None),
+            status => Err(Error::Api { status, body: resp.text().await? }),
+        }
+    }
+}
+
+/// The store configured with `--artifact-store` or `[artifacts] store`.
+pub enum Store {
+    Local(LocalStore),
+    Gcs(Box<GcsStore>),
+}
+
+impl Store {
+    /// Open the store at `location`, keeping artifacts under `key`, e.g. `myapp/20240101.120000.abcdef`.
+    pub fn open(location: &str, key: &str, http: auth::HttpSettings) -> Result<Self, Error> {
+        match location.strip_prefix("gs://") {
+            Some(path) => {
+                let (bucket, prefix) = path.split_once('/').unwrap_or((path, ""));
+                if bucket.is_empty() {
+                    return Err(Error::InvalidBucket(location.to_string()));
+                }
+                let prefix = [prefix.trim_matches('/'), key].iter().filter(|part| !part.is_empty()).copied().collect::<Vec<_>>().join("/");
+                Ok(Store::Gcs(Box::new(GcsStore::new(bucket, &prefix, http))))
+            }
+            None => Ok(Store::Local(LocalStore::new(PathBuf::from(location).join(key)))),
+        }
+    }
+}
+
+impl Display for Store {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Store::Local(store) => write!(f, "{}", store.directory.display()),
+            Store::Gcs(store) => write!(f, "gs://{}/{}", store.bucket, store.prefix),
+        }
+    }
+}
+
+impl ArtifactStore for Store {
+    async fn put(&self, artifact: Artifact, contents: &[u8]) -> Result<(), Error> {
+        match self {
+            Store::Local(store) => store.put(artifact, contents).await,
+            Store::Gcs(store) => store.put(artifact, contents).await,
+        }
+    }
+
+    async fn get(&self, artifact: Artifact) -> Result<Option<Vec<u8>>, Error> {
+        match self {
+            Store::Local(store) => store.get(artifact).await,
+            Store::Gcs(store) => store.get(artifact).await,
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[tokio::test]
+    pub async fn local_store() {
+        let directory = tempfile::tempdir().unwrap();
+        let store = Store::open(&directory.path().to_string_lossy(), "myapp/1.0.0", Default::default()).unwrap();
+        assert_eq!(store.get(Artifact::Dockerfile).await.unwrap(), None);
+        store.put(Artifact::Dockerfile, b"FROM scratch\n").await.unwrap();
+        store.put(Artifact::Dockerfile, b"FROM alpine\n").await.unwrap();
+        assert_eq!(store.get(Artifact::Dockerfile).await.unwrap().as_deref(), Some(&b"FROM alpine\n"[..]));
+        assert!(directory.path().join("myapp/1.0.0/Dockerfile").is_file());
+    }
+
+    #[test]
+    pub fn bucket_locations() {
+        let location = |location: &str| Store::open(location, "myapp/1.0.0", Default::default()).map(|store| store.to_string());
+        assert_eq!(location("gs://builds").unwrap(), "gs://builds/myapp/1.0.0");
+        assert_eq!(location("gs://builds/nb/").unwrap(), "gs://builds/nb/myapp/1.0.0");
+        assert!(location("gs:///nb").is_err());
+        assert_eq!(location("build/artifacts").unwrap(), "build/artifacts/myapp/1.0.0");
+    }
+}
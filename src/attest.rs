@@ -0,0 +1,93 @@
+//! Software bills of materials and signatures of images, made by the sbom and sign steps of the pipeline
+//! when `[release] sbom` and `[release] sign` are set.
+//!
+//! The SBOM of the built image is generated with `syft`, in SPDX JSON. The pushed image is signed with
+//! `cosign`, by digest, and the SBOM is attached to it as an attestation, which `nb verify` checks.
+//! Without a `signing_key`, cosign signs keyless, with the OIDC identity of the CI job.
+
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use thiserror::Error;
+use tokio::process::Command;
+use tokio_util::sync::CancellationToken;
+
+pub const SBOM_FILE_NAME: &str = "sbom.spdx.json";
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("run {program}: {err}")]
+    Execute {
+        program: &'static str,
+        err: std::io::Error,
+    },
+
+    #[error("{path}: {err}")]
+    IO {
+        path: String,
+        err: std::io::Error,
+    },
+
+    #[error("SBOM not generated, syft exited with {0}")]
+    Sbom(std::process::ExitStatus),
+
+    #[error("image not signed, cosign exited with {0}")]
+    Sign(std::process::ExitStatus),
+}
+
+/// Path of the SBOM in the directory of the artifacts file.
+pub fn sbom_path(artifacts_file: &str) -> PathBuf {
+    Path::new(artifacts_file).with_file_name(SBOM_FILE_NAME)
+}
+
+/// Write the SBOM of `image`, from the local Docker daemon, to `path` in SPDX JSON.
+pub async fn sbom(image: &str, path: &Path, cancel: &CancellationToken) -> Result<(), Error> {
+    if let Some(directory) = path.parent() {
+        std::fs::create_dir_all(directory).map_err(|err| Error::IO { path: directory.to_string_lossy().to_string(), err })?;
+    }
+    let status = crate::process::status(Command::new("syft")
+        .arg("scan")
+        .arg(format!("docker:{image}"))
+        .arg("--output")
+        .arg(format!("spdx-json={}", path.display()))
+        .stdout(crate::report::child_stdout())
+        .stderr(Stdio::inherit()), cancel).await
+        .map_err(|err| Error::Execute { program: "syft", err })?;
+    match status.success() {
+        true => Ok(()),
+        false => Err(Error::Sbom(status)),
+    }
+}
+
+/// Sign `reference`, an image by digest, with the private key `key`, or keyless.
+pub async fn sign(reference: &str, key: Option<&str>, cancel: &CancellationToken) -> Result<(), Error> {
+    cosign(&["sign"], key, reference, cancel).await
+}
+
+/// Attach the SBOM at `sbom` to `reference`, an image by digest, as an attestation signed like [sign].
+pub async fn attest_sbom(reference: &str, sbom: &Path, key: Option<&str>, cancel: &CancellationToken) -> Result<(), Error> {
+    let predicate = sbom.to_string_lossy();
+    cosign(&["attest", "--type", "spdxjson", "--predicate", &predicate], key, reference, cancel).await
+}
+
+/// Where cosign keeps the attestations of the image `repository@digest`: the tag `sha256-<hex>.att` in the repository.
+pub fn attestation_reference(repository: &str, digest: &str) -> String {
+    format!("{repository}:{}.att", digest.replace(':', "-"))
+}
+
+async fn cosign(subcommand: &[&str], key: Option<&str>, reference: &str, cancel: &CancellationToken) -> Result<(), Error> {
+    let mut command = Command::new("cosign");
+    // Keyless signing asks for confirmation of uploading to the transparency log, unless told yes.
+    command.args(subcommand).arg("--yes");
+    if let Some(key) = key {
+        command.arg("--key").arg(key);
+    }
+    let status = crate::process::status(command
+        .arg(reference)
+        .stdout(crate::report::child_stdout())
+        .stderr(Stdio::inherit()), cancel).await
+        .map_err(|err| Error::Execute { program: "cosign", err })?;
+    match status.success() {
+        true => Ok(()),
+        false => Err(Error::Sign(status)),
+    }
+}
@@ -0,0 +1,293 @@
+//! Minimal client for the AWS APIs needed to push images to Elastic Container Registry.
+//!
+//! Requests are signed with [Signature Version 4](https://docs.aws.amazon.com/IAM/latest/UserGuide/reference_sigv-create-signed-request.html),
+//! so no AWS SDK or external credential helper is required.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use log::debug;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("AWS credentials not found; set AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY, or AWS_ROLE_ARN together with a web identity token")]
+    MissingCredentials,
+
+    #[error("reqwest: {0}")]
+    Reqwest(#[from] reqwest::Error),
+
+    #[error("{service} returned {status}: {body}")]
+    Api {
+        service: &'static str,
+        status: u16,
+        body: String,
+    },
+
+    #[error("invalid ECR authorization token: {0}")]
+    InvalidAuthorizationToken(String),
+}
+
+/// Username that ECR authorization tokens are issued for.
+pub const ECR_USERNAME: &str = "AWS";
+
+/// AWS access key, optionally with a session token for temporary credentials.
+#[derive(Clone)]
+pub struct Credentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub session_token: Option<String>,
+}
+
+impl Credentials {
+    /// Static credentials from `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and `AWS_SESSION_TOKEN`.
+    pub fn from_environment() -> Option<Self> {
+        let var = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+        Some(Self {
+            access_key_id: var("AWS_ACCESS_KEY_ID")?,
+            secret_access_key: var("AWS_SECRET_ACCESS_KEY")?,
+            session_token: var("AWS_SESSION_TOKEN"),
+        })
+    }
+}
+
+/// Extract the region from an ECR registry host, e.g. `123456789012.dkr.ecr.eu-north-1.amazonaws.com`.
+pub fn ecr_region(registry: &str) -> Option<&str> {
+    let host = registry.split('/').next()?;
+    let (_, rest) = host.split_once(".dkr.ecr.")?;
+    let region = rest.strip_suffix(".amazonaws.com")?;
+    (!region.is_empty() && !region.contains('.')).then_some(region)
+}
+
+/// Exchange a web identity token (e.g. a Kubernetes service account token from IRSA,
+/// or a GitHub Actions OIDC token) for temporary credentials for `role_arn`.
+pub async fn assume_role_with_web_identity(region: &str, role_arn: &str, web_identity_token: &str) -> Result<Credentials, Error> {
+    debug!("Assuming AWS role {role_arn} with web identity");
+
+    #[derive(Deserialize)]
+    #[serde(rename_all = "PascalCase")]
+    struct Response {
+        assume_role_with_web_identity_response: ResponseBody,
+    }
+    #[derive(Deserialize)]
+    #[serde(rename_all = "PascalCase")]
+    struct ResponseBody {
+        assume_role_with_web_identity_result: RoleResult,
+    }
+    #[derive(Deserialize)]
+    #[serde(rename_all = "PascalCase")]
+    struct RoleResult {
+        credentials: StsCredentials,
+    }
+    #[derive(Deserialize)]
+    #[serde(rename_all = "PascalCase")]
+    struct StsCredentials {
+        access_key_id: String,
+        secret_access_key: String,
+        session_token: String,
+    }
+
+    // AssumeRoleWithWebIdentity is authenticated by the token itself, and must not be signed.
+    let request = http_client()?
+        .post(format!("https://sts.{region}.amazonaws.com/"))
+        .header("Accept", "application/json")
+        .form(&[
+            ("Action", "AssumeRoleWithWebIdentity"),
+            ("Version", "2011-06-15"),
+            ("RoleArn", role_arn),
+            ("RoleSessionName", "nais-build"),
+            ("WebIdentityToken", web_identity_token),
+        ]);
+    let response: Response = send_json("sts", request).await?;
+    let credentials = response.assume_role_with_web_identity_response.assume_role_with_web_identity_result.credentials;
+    Ok(Credentials {
+        access_key_id: credentials.access_key_id,
+        secret_access_key: credentials.secret_access_key,
+        session_token: Some(credentials.session_token),
+    })
+}
+
+/// Password for `docker login` as [ECR_USERNAME].
+pub struct AuthorizationToken {
+    pub password: String,
+    /// Expiry time in seconds since the Unix epoch.
+    pub expires_at: Option<u64>,
+}
+
+/// Call ECR `GetAuthorizationToken`.
+pub async fn ecr_authorization_token(region: &str, credentials: &Credentials) -> Result<AuthorizationToken, Error> {
+    debug!("Requesting ECR authorization token in {region}");
+
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct Response {
+        authorization_data: Vec<AuthorizationData>,
+    }
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct AuthorizationData {
+        authorization_token: String,
+        /// Seconds since the Unix epoch, with a fractional part.
+        expires_at: Option<f64>,
+    }
+
+    let host = format!("api.ecr.{region}.amazonaws.com");
+    let body = "{}";
+    let headers = BTreeMap::from([
+        ("content-type".to_string(), "application/x-amz-json-1.1".to_string()),
+        ("x-amz-target".to_string(), "AmazonEC2ContainerRegistry_V20150921.GetAuthorizationToken".to_string()),
+    ]);
+    let signed = sign(credentials, region, "ecr", "POST", &host, "/", headers, body, chrono::Utc::now());
+
+    let mut request = http_client()?.post(format!("https://{host}/")).body(body);
+    for (name, value) in &signed {
+        request = request.header(name, value);
+    }
+    let response: Response = send_json("ecr", request).await?;
+    let data = response.authorization_data.into_iter().next()
+        .ok_or_else(|| Error::InvalidAuthorizationToken("no authorization data in response".into()))?;
+    Ok(AuthorizationToken {
+        password: decode_authorization_token(&data.authorization_token)?,
+        expires_at: data.expires_at.map(|expires_at| expires_at as u64),
+    })
+}
+
+/// ECR authorization tokens are base64-encoded `AWS:<password>`.
+fn decode_authorization_token(token: &str) -> Result<String, Error> {
+    let decoded = base64::engine::general_purpose::STANDARD.decode(token)
+        .map_err(|err| Error::InvalidAuthorizationToken(err.to_string()))?;
+    let decoded = String::from_utf8(decoded).map_err(|err| Error::InvalidAuthorizationToken(err.to_string()))?;
+    match decoded.split_once(':') {
+        Some((ECR_USERNAME, password)) => Ok(password.to_string()),
+        _ => Err(Error::InvalidAuthorizationToken("expected `AWS:<password>`".into())),
+    }
+}
+
+/// Sign a request with SigV4, and return all headers that must be sent with it.
+///
+/// `headers` must have lowercase names. The `host`, `x-amz-date`, `x-amz-security-token`
+/// and `authorization` headers are added.
+#[allow(clippy::too_many_arguments)]
+fn sign(
+    credentials: &Credentials,
+    region: &str,
+    service: &str,
+    method: &str,
+    host: &str,
+    path: &str,
+    mut headers: BTreeMap<String, String>,
+    body: &str,
+    time: chrono::DateTime<chrono::Utc>,
+) -> BTreeMap<String, String> {
+    let timestamp = time.format("%Y%m%dT%H%M%SZ").to_string();
+    let date = &timestamp[..8];
+    headers.insert("host".into(), host.into());
+    headers.insert("x-amz-date".into(), timestamp.clone());
+    if let Some(session_token) = &credentials.session_token {
+        headers.insert("x-amz-security-token".into(), session_token.clone());
+    }
+
+    let canonical_headers: String = headers.iter().map(|(name, value)| format!("{name}:{}\n", value.trim())).collect();
+    let signed_headers = headers.keys().map(String::as_str).collect::<Vec<_>>().join(";");
+    let canonical_request = format!("{method}\n{path}\n\n{canonical_headers}\n{signed_headers}\n{}", hex_sha256(body.as_bytes()));
+
+    let scope = format!("{date}/{region}/{service}/aws4_request");
+    let string_to_sign = format!("AWS4-HMAC-SHA256\n{timestamp}\n{scope}\n{}", hex_sha256(canonical_request.as_bytes()));
+
+    let key = signing_key(&credentials.secret_access_key, date, region, service);
+    let signature = hex(&hmac(&key, string_to_sign.as_bytes()));
+    headers.insert(
+        "authorization".into(),
+        format!("AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={signature}", credentials.access_key_id),
+    );
+    headers
+}
+
+fn signing_key(secret_access_key: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    [date, region, service, "aws4_request"]
+        .iter()
+        .fold(format!("AWS4{secret_access_key}").into_bytes(), |key, part| hmac(&key, part.as_bytes()))
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex_sha256(data: &[u8]) -> String {
+    hex(&Sha256::digest(data))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn http_client() -> Result<reqwest::Client, Error> {
+    Ok(reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()?)
+}
+
+async fn send_json<T: serde::de::DeserializeOwned>(service: &'static str, request: reqwest::RequestBuilder) -> Result<T, Error> {
+    let response = request.send().await?;
+    let status = response.status().as_u16();
+    let bytes = response.bytes().await?;
+    serde_json::from_slice(&bytes).map_err(|_| Error::Api {
+        service,
+        status,
+        body: String::from_utf8_lossy(&bytes).to_string(),
+    })
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    /// Example from the AWS Signature Version 4 documentation.
+    #[test]
+    pub fn sign_request() {
+        let credentials = Credentials {
+            access_key_id: "AKIDEXAMPLE".into(),
+            secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".into(),
+            session_token: None,
+        };
+        assert_eq!(
+            hex(&signing_key(&credentials.secret_access_key, "20150830", "us-east-1", "iam")),
+            "c4afb1cc5771d871763a393e44b703571b55cc28424d1a5e86da6ed3c154a4b9",
+        );
+
+        let time = chrono::Utc.with_ymd_and_hms(2015, 8, 30, 12, 36, 0).unwrap();
+        let headers = BTreeMap::from([("content-type".to_string(), "application/x-www-form-urlencoded; charset=utf-8".to_string())]);
+        let signed = sign(&credentials, "us-east-1", "iam", "GET", "iam.amazonaws.com", "/", headers.clone(), "", time);
+        assert_eq!(signed["x-amz-date"], "20150830T123600Z");
+        assert!(signed["authorization"].starts_with(
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/iam/aws4_request, SignedHeaders=content-type;host;x-amz-date, Signature="
+        ));
+
+        let temporary = Credentials { session_token: Some("session".into()), ..credentials };
+        let signed = sign(&temporary, "us-east-1", "iam", "GET", "iam.amazonaws.com", "/", headers, "", time);
+        assert_eq!(signed["x-amz-security-token"], "session");
+        assert!(signed["authorization"].contains("SignedHeaders=content-type;host;x-amz-date;x-amz-security-token,"));
+    }
+
+    #[test]
+    pub fn parse_ecr_registry() {
+        assert_eq!(ecr_region("123456789012.dkr.ecr.eu-north-1.amazonaws.com"), Some("eu-north-1"));
+        assert_eq!(ecr_region("123456789012.dkr.ecr.eu-north-1.amazonaws.com/team/app"), Some("eu-north-1"));
+        assert_eq!(ecr_region("europe-north1-docker.pkg.dev/nais-management-233d"), None);
+        assert_eq!(ecr_region("ghcr.io/nais"), None);
+    }
+
+    #[test]
+    pub fn decode_ecr_token() {
+        let token = base64::engine::general_purpose::STANDARD.encode("AWS:secret:with:colons");
+        assert_eq!(decode_authorization_token(&token).unwrap(), "secret:with:colons");
+        let token = base64::engine::general_purpose::STANDARD.encode("oauth2accesstoken:secret");
+        assert!(decode_authorization_token(&token).is_err());
+    }
+}
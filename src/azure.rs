@@ -0,0 +1,105 @@
+//! Minimal client for the Azure APIs needed to push images to Azure Container Registry.
+//!
+//! An Azure AD access token is acquired with a federated credential or a managed identity,
+//! and exchanged for an ACR refresh token, which `docker login` accepts as a password.
+
+use std::time::Duration;
+use log::debug;
+use serde::Deserialize;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("reqwest: {0}")]
+    Reqwest(#[from] reqwest::Error),
+
+    #[error("{service} returned {status}: {body}")]
+    Api {
+        service: &'static str,
+        status: u16,
+        body: String,
+    },
+}
+
+/// Username that ACR expects together with a refresh token.
+pub const ACR_USERNAME: &str = "00000000-0000-0000-0000-000000000000";
+
+/// Audience of federated tokens exchanged with Azure AD.
+pub const FEDERATED_TOKEN_AUDIENCE: &str = "api://AzureADTokenExchange";
+
+/// Azure AD tokens for Azure Resource Manager are accepted by every registry's token exchange.
+const RESOURCE: &str = "https://management.azure.com/";
+
+/// Address of the Azure Instance Metadata Service, which issues managed identity tokens.
+const IMDS_TOKEN_URL: &str = "http://169.254.169.254/metadata/identity/oauth2/token";
+
+#[derive(Deserialize)]
+struct AccessTokenResponse {
+    access_token: String,
+}
+
+/// Exchange a federated token (e.g. from AKS workload identity or GitHub Actions)
+/// for an Azure AD access token for the application `client_id`.
+pub async fn federated_token(tenant_id: &str, client_id: &str, assertion: &str) -> Result<String, Error> {
+    debug!("Exchanging federated token for an Azure AD token");
+    let request = http_client()?
+        .post(format!("https://login.microsoftonline.com/{tenant_id}/oauth2/v2.0/token"))
+        .form(&[
+            ("grant_type", "client_credentials"),
+            ("client_id", client_id),
+            ("client_assertion_type", "urn:ietf:params:oauth:client-assertion-type:jwt-bearer"),
+            ("client_assertion", assertion),
+            ("scope", &format!("{RESOURCE}.default")),
+        ]);
+    let response: AccessTokenResponse = send_json("Azure AD", request).await?;
+    Ok(response.access_token)
+}
+
+/// Request an Azure AD access token for the managed identity of the current VM or container.
+/// `client_id` selects a user-assigned identity.
+pub async fn managed_identity_token(client_id: Option<&str>) -> Result<String, Error> {
+    debug!("Requesting Azure AD token from the instance metadata service");
+    let mut query = vec![("api-version", "2018-02-01"), ("resource", RESOURCE)];
+    query.extend(client_id.map(|client_id| ("client_id", client_id)));
+    let request = http_client()?
+        .get(IMDS_TOKEN_URL)
+        .header("Metadata", "true")
+        .query(&query);
+    let response: AccessTokenResponse = send_json("Azure instance metadata service", request).await?;
+    Ok(response.access_token)
+}
+
+/// Exchange an Azure AD access token for an ACR refresh token, used as the password for [ACR_USERNAME].
+pub async fn acr_refresh_token(registry_host: &str, tenant_id: Option<&str>, access_token: &str) -> Result<String, Error> {
+    debug!("Exchanging Azure AD token for an ACR refresh token for {registry_host}");
+
+    #[derive(Deserialize)]
+    struct Response {
+        refresh_token: String,
+    }
+
+    let mut form = vec![("grant_type", "access_token"), ("service", registry_host), ("access_token", access_token)];
+    form.extend(tenant_id.map(|tenant_id| ("tenant", tenant_id)));
+    let request = http_client()?
+        .post(format!("https://{registry_host}/oauth2/exchange"))
+        .form(&form);
+    let response: Response = send_json("ACR", request).await?;
+    Ok(response.refresh_token)
+}
+
+fn http_client() -> Result<reqwest::Client, Error> {
+    Ok(reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()?)
+}
+
+async fn send_json<T: serde::de::DeserializeOwned>(service: &'static str, request: reqwest::RequestBuilder) -> Result<T, Error> {
+    let response = request.send().await?;
+    let status = response.status().as_u16();
+    let bytes = response.bytes().await?;
+    serde_json::from_slice(&bytes).map_err(|_| Error::Api {
+        service,
+        status,
+        body: String::from_utf8_lossy(&bytes).to_string(),
+    })
+}
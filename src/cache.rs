@@ -0,0 +1,82 @@
+//! Per-user cache for downloaded and derived data, such as schemas and access tokens.
+//!
+//! Files are stored in `$XDG_CACHE_HOME/nb`, falling back to `$HOME/.cache/nb`.
+//! The directory is only accessible by the current user.
+
+use std::path::PathBuf;
+use std::time::Duration;
+use log::debug;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("neither XDG_CACHE_HOME nor HOME is set")]
+    NoCacheDirectory,
+
+    #[error("write {path}: {err}")]
+    Write {
+        err: std::io::Error,
+        path: String,
+    },
+}
+
+/// Return the cache directory, without creating it.
+pub fn dir() -> Result<PathBuf, Error> {
+    std::env::var("XDG_CACHE_HOME")
+        .ok()
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var("HOME").ok().map(|home| PathBuf::from(home).join(".cache")))
+        .map(|dir| dir.join("nb"))
+        .ok_or(Error::NoCacheDirectory)
+}
+
+/// Read a cached file, if it exists and is younger than `max_age`.
+/// Any errors are treated as a cache miss.
+pub fn read(name: &str, max_age: Option<Duration>) -> Option<String> {
+    let path = dir().ok()?.join(name);
+    let modified = std::fs::metadata(&path).ok()?.modified().ok()?;
+    let age = modified.elapsed().unwrap_or_default();
+    if max_age.is_some_and(|max_age| age > max_age) {
+        debug!("Cache entry {} is stale", path.display());
+        return None;
+    }
+    std::fs::read_to_string(&path).ok()
+}
+
+/// Paths of all cache entries. A missing cache directory has no entries.
+pub fn entries() -> Result<Vec<PathBuf>, std::io::Error> {
+    let Ok(dir) = dir() else {
+        return Ok(Vec::new());
+    };
+    match std::fs::read_dir(dir) {
+        Ok(entries) => entries.map(|entry| Ok(entry?.path())).collect(),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(err) => Err(err),
+    }
+}
+
+/// Write a file to the cache, replacing any existing entry.
+pub fn write(name: &str, contents: &str) -> Result<(), Error> {
+    let dir = dir()?;
+    let path = dir.join(name);
+    let err = |err| Error::Write { err, path: path.to_string_lossy().to_string() };
+
+    let mut builder = std::fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    builder.create(&dir).map_err(err)?;
+
+    // Write to a temporary file first, so that readers never see a partially written entry.
+    let tmp_path = dir.join(format!(".{name}.tmp"));
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    std::io::Write::write_all(&mut options.open(&tmp_path).map_err(err)?, contents.as_bytes()).map_err(err)?;
+    std::fs::rename(&tmp_path, &path).map_err(err)?;
+
+    debug!("Wrote cache entry {}", path.display());
+    Ok(())
+}
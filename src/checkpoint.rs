@@ -0,0 +1,114 @@
+//! State of a pipeline that has not finished, so that a failed build, release or deploy can be
+//! re-run with `--resume`, skipping the steps that finished and reusing the image they produced.
+//!
+//! The checkpoint is kept next to `artifacts.json` while the pipeline runs, and removed when it succeeds.
+//! It is only resumed for the same application and commit.
+
+use std::path::{Path, PathBuf};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use crate::pipeline::Node;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("checkpoint: {0}")]
+    IOError(#[from] std::io::Error),
+
+    #[error("checkpoint: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+pub const FILE_NAME: &str = "checkpoint.json";
+
+/// Path of the checkpoint in the directory of the artifacts file.
+pub fn path(artifacts_file: &str) -> PathBuf {
+    Path::new(artifacts_file).with_file_name(FILE_NAME)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Checkpoint {
+    pub app: String,
+    /// Commit of the source directory, if it is in a Git repository.
+    pub commit: Option<String>,
+    pub tag: String,
+    pub image: String,
+    pub digest: Option<String>,
+    /// Steps that finished, whose results are kept: the image in the local Docker daemon, or in the registry.
+    pub completed: Vec<Node>,
+}
+
+impl Checkpoint {
+    pub fn new(app: &str, commit: Option<String>, tag: &str, image: &str) -> Self {
+        Checkpoint { app: app.to_string(), commit, tag: tag.to_string(), image: image.to_string(), digest: None, completed: Vec::new() }
+    }
+
+    /// Whether a run of `app` at `commit` can resume from this checkpoint.
+    pub fn resumes(&self, app: &str, commit: Option<&str>) -> bool {
+        self.app == app && commit.is_some() && self.commit.as_deref() == commit
+    }
+
+    /// Record that `node` finished. Only steps with results that outlive the run are kept.
+    pub fn complete(&mut self, node: Node) {
+        if matches!(node, Node::Build | Node::Release) && !self.completed.contains(&node) {
+            self.completed.push(node);
+        }
+    }
+}
+
+/// Read the checkpoint, if there is one.
+pub fn read(path: &Path) -> Result<Option<Checkpoint>, Error> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => Ok(Some(serde_json::from_str(&contents)?)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Replace the checkpoint. The file is renamed into place, so that an interrupted run cannot leave a partial checkpoint.
+pub fn write(path: &Path, checkpoint: &Checkpoint) -> Result<(), Error> {
+    if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent)?;
+    }
+    let tmp_path = path.with_extension("json.tmp");
+    std::fs::write(&tmp_path, serde_json::to_string_pretty(checkpoint)? + "\n")?;
+    std::fs::rename(&tmp_path, path)?;
+    debug!("Checkpoint written to {}", path.display());
+    Ok(())
+}
+
+/// Remove the checkpoint of a pipeline that succeeded.
+pub fn remove(path: &Path) -> Result<(), Error> {
+    match std::fs::remove_file(path) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    pub fn resume_checkpoint() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = path(&directory.path().join("build/artifacts.json").to_string_lossy());
+        assert_eq!(read(&path).unwrap(), None);
+
+        let mut checkpoint = Checkpoint::new("myapp", Some("abc".to_string()), "1", "ghcr.io/navikt/myapp:1");
+        checkpoint.complete(Node::Login);
+        checkpoint.complete(Node::Build);
+        checkpoint.complete(Node::Build);
+        assert_eq!(checkpoint.completed, [Node::Build]);
+        write(&path, &checkpoint).unwrap();
+        let read = read(&path).unwrap().unwrap();
+        assert_eq!(read, checkpoint);
+        assert!(read.resumes("myapp", Some("abc")));
+        assert!(!read.resumes("myapp", Some("def")));
+        assert!(!read.resumes("other", Some("abc")));
+
+        remove(&path).unwrap();
+        remove(&path).unwrap();
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,254 @@
+//! Generation of CI workflows that run nb, from the branch rules and deploy profiles in `nb.toml`.
+
+use std::collections::BTreeMap;
+use serde::Serialize;
+use serde_yaml::Mapping;
+use thiserror::Error;
+use crate::config::runtime::{BranchOutput, BranchRule, DeployProfile, ReleaseType};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("branch pattern `{0}` cannot be expressed as a GitHub Actions condition; use `.*`, `^name$`, `^(a|b)$` or `^prefix.*`")]
+    UnsupportedBranchPattern(String),
+
+    #[error("branch rule `{rule}` refers to unknown deploy profile `{profile}`")]
+    UnknownProfile {
+        rule: String,
+        profile: String,
+    },
+
+    #[error("serialize workflow: {0}")]
+    Serialize(#[from] serde_yaml::Error),
+}
+
+/// Command that installs nb in a workflow job.
+const INSTALL_COMMAND: &str = "cargo install --locked --git https://github.com/nais/build";
+
+pub struct Params<'a> {
+    /// Source directory relative to the repository root.
+    pub source_directory: &'a str,
+    pub release_type: ReleaseType,
+    pub branches: &'a BTreeMap<String, BranchRule>,
+    pub profiles: &'a BTreeMap<String, DeployProfile>,
+}
+
+#[derive(Serialize)]
+struct Workflow {
+    name: &'static str,
+    on: Mapping,
+    permissions: BTreeMap<&'static str, &'static str>,
+    jobs: Mapping,
+}
+
+#[derive(Serialize, Default)]
+#[serde(rename_all = "kebab-case")]
+struct Job {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    needs: Vec<String>,
+    #[serde(rename = "if", skip_serializing_if = "Option::is_none")]
+    condition: Option<String>,
+    runs_on: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    environment: Option<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    permissions: BTreeMap<&'static str, &'static str>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    outputs: BTreeMap<&'static str, &'static str>,
+    steps: Vec<Step>,
+}
+
+#[derive(Serialize, Default)]
+struct Step {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uses: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    run: Option<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    env: BTreeMap<&'static str, &'static str>,
+}
+
+/// Generate a GitHub Actions workflow that builds every branch and pull request, and releases
+/// and deploys pushed branches as their branch rules say. If several rules match a branch,
+/// the one that goes furthest applies.
+pub fn github_workflow(params: &Params) -> Result<String, Error> {
+    let nb = match params.source_directory {
+        "" | "." => "nb".to_string(),
+        directory => format!("nb {directory}"),
+    };
+    let setup = || vec![
+        Step { uses: Some("actions/checkout@v4"), ..Step::default() },
+        Step { name: Some("Install nb".into()), run: Some(INSTALL_COMMAND.into()), ..Step::default() },
+    ];
+
+    let mut jobs = Mapping::new();
+    let mut build_steps = setup();
+    build_steps.push(Step { name: Some("Build".into()), run: Some(format!("{nb} build")), ..Step::default() });
+    jobs.insert("build".into(), serde_yaml::to_value(Job { runs_on: "ubuntu-latest", steps: build_steps, ..Job::default() })?);
+
+    // Branch conditions of the rules that release, and of the rules that deploy to each cluster.
+    let mut release_conditions = Vec::new();
+    let mut clusters: Vec<(String, Vec<Option<String>>, Vec<String>)> = Vec::new();
+    for (pattern, rule) in params.branches {
+        if rule.output < BranchOutput::Release {
+            continue;
+        }
+        let condition = branch_condition(pattern)?;
+        release_conditions.push(condition.clone());
+        if rule.output < BranchOutput::Deploy {
+            continue;
+        }
+        let mut previous: Option<String> = None;
+        for profile in &rule.deploy.profiles {
+            let profile = params.profiles.get(profile).ok_or_else(|| Error::UnknownProfile {
+                rule: pattern.clone(),
+                profile: profile.clone(),
+            })?;
+            for cluster in &profile.clusters {
+                let index = match clusters.iter().position(|(name, _, _)| name == cluster) {
+                    Some(index) => index,
+                    None => {
+                        clusters.push((cluster.clone(), Vec::new(), Vec::new()));
+                        clusters.len() - 1
+                    }
+                };
+                let (_, conditions, needs) = &mut clusters[index];
+                conditions.push(condition.clone());
+                if let Some(previous) = previous.filter(|_| !rule.deploy.parallel) {
+                    if !needs.contains(&previous) {
+                        needs.push(previous);
+                    }
+                }
+                previous = Some(cluster.clone());
+            }
+        }
+    }
+
+    if !release_conditions.is_empty() {
+        let mut permissions = BTreeMap::from([("contents", "write"), ("id-token", "write")]);
+        if params.release_type == ReleaseType::GHCR {
+            permissions.insert("packages", "write");
+        }
+        let mut steps = setup();
+        steps.push(Step { id: Some("nb"), name: Some("Release".into()), run: Some(format!("{nb} release")), ..Step::default() });
+        jobs.insert("release".into(), serde_yaml::to_value(Job {
+            needs: vec!["build".into()],
+            condition: Some(push_condition(&release_conditions)),
+            runs_on: "ubuntu-latest",
+            permissions,
+            outputs: BTreeMap::from([("tag", "${{ steps.nb.outputs.tag }}"), ("image", "${{ steps.nb.outputs.image }}")]),
+            steps,
+            ..Job::default()
+        })?);
+    }
+
+    for (cluster, conditions, needs) in clusters {
+        let mut steps = setup();
+        steps.push(Step {
+            name: Some(format!("Deploy to {cluster}")),
+            run: Some(format!("{nb} --docker-image-name ${{{{ needs.release.outputs.tag }}}} deploy --cluster {cluster}")),
+            env: BTreeMap::from([("NAIS_DEPLOY_APIKEY", "${{ secrets.NAIS_DEPLOY_APIKEY }}")]),
+            ..Step::default()
+        });
+        jobs.insert(format!("deploy-{cluster}").into(), serde_yaml::to_value(Job {
+            needs: std::iter::once("release".to_string()).chain(needs.iter().map(|cluster| format!("deploy-{cluster}"))).collect(),
+            condition: Some(push_condition(&conditions)),
+            runs_on: "ubuntu-latest",
+            environment: Some(cluster.clone()),
+            steps,
+            ..Job::default()
+        })?);
+    }
+
+    let workflow = Workflow {
+        name: "nb",
+        on: Mapping::from_iter([("push".into(), Mapping::new().into()), ("pull_request".into(), Mapping::new().into())]),
+        permissions: BTreeMap::from([("contents", "read")]),
+        jobs,
+    };
+    Ok(format!("# Generated by `nb ci generate github`.\n{}", serde_yaml::to_string(&workflow)?))
+}
+
+/// Condition for a job that runs on pushes to branches matching any of the conditions, where `None` matches all branches.
+fn push_condition(conditions: &[Option<String>]) -> String {
+    const PUSH: &str = "github.event_name == 'push'";
+    if conditions.iter().any(Option::is_none) {
+        return PUSH.to_string();
+    }
+    let branches: Vec<&str> = conditions.iter().flatten().map(String::as_str).collect();
+    match branches.as_slice() {
+        [branch] => format!("{PUSH} && ({branch})"),
+        branches => format!("{PUSH} && ({})", branches.iter().map(|branch| format!("({branch})")).collect::<Vec<_>>().join(" || ")),
+    }
+}
+
+/// Translate a branch rule's regular expression into a GitHub Actions expression on `github.ref_name`,
+/// or `None` if it matches every branch. Only simple patterns can be expressed.
+fn branch_condition(pattern: &str) -> Result<Option<String>, Error> {
+    let unsupported = || Error::UnsupportedBranchPattern(pattern.to_string());
+    if matches!(pattern, "" | ".*" | "^.*$" | "^.*") {
+        return Ok(None);
+    }
+    let body = pattern.strip_prefix('^').ok_or_else(unsupported)?;
+    let (body, anchored) = match body.strip_suffix('$') {
+        Some(body) => (body, true),
+        None => (body, false),
+    };
+    let wildcard = [".*", ".+", "(.*)", "(.+)"].iter().find_map(|suffix| body.strip_suffix(suffix));
+    if let Some(prefix) = wildcard.or((!anchored).then_some(body)) {
+        let prefix = literal(prefix).ok_or_else(unsupported)?;
+        return Ok(Some(format!("startsWith(github.ref_name, '{prefix}')")));
+    }
+    let names = match body.strip_prefix('(').and_then(|body| body.strip_suffix(')')) {
+        Some(alternatives) => alternatives.split('|').map(literal).collect::<Option<Vec<_>>>(),
+        None => literal(body).map(|name| vec![name]),
+    }.ok_or_else(unsupported)?;
+    Ok(Some(names.iter().map(|name| format!("github.ref_name == '{name}'")).collect::<Vec<_>>().join(" || ")))
+}
+
+/// The text matched by a regular expression without special characters, except escaped dots.
+fn literal(pattern: &str) -> Option<String> {
+    let text = pattern.replace("\\.", ".");
+    let plain = pattern.replace("\\.", "");
+    (!text.is_empty() && plain.chars().all(|c| c.is_ascii_alphanumeric() || "-_/".contains(c))).then_some(text)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::config::file::File;
+
+    #[test]
+    pub fn translate_branch_patterns() {
+        assert_eq!(branch_condition(".*").unwrap(), None);
+        assert_eq!(branch_condition("^main$").unwrap(), Some("github.ref_name == 'main'".into()));
+        assert_eq!(branch_condition("^(main|master)$").unwrap(), Some("github.ref_name == 'main' || github.ref_name == 'master'".into()));
+        assert_eq!(branch_condition("^feature-preview-(.+)$").unwrap(), Some("startsWith(github.ref_name, 'feature-preview-')".into()));
+        assert_eq!(branch_condition("^release/v1\\.").unwrap(), Some("startsWith(github.ref_name, 'release/v1.')".into()));
+        assert!(branch_condition("main").is_err());
+        assert!(branch_condition("^(feat|fix)/.*$").is_err());
+    }
+
+    #[test]
+    pub fn generate_workflow() {
+        let cfg = File::default();
+        let workflow = github_workflow(&Params {
+            source_directory: "apps/myapp",
+            release_type: ReleaseType::GAR,
+            branches: &cfg.branch,
+            profiles: &cfg.deploy.unwrap().nais.unwrap().profiles,
+        }).unwrap();
+        let workflow: serde_yaml::Value = serde_yaml::from_str(&workflow).unwrap();
+        let jobs = &workflow["jobs"];
+        assert_eq!(jobs["build"]["steps"][2]["run"], "nb apps/myapp build");
+        assert_eq!(jobs["release"]["if"], "github.event_name == 'push' && ((github.ref_name == 'main' || github.ref_name == 'master') || (startsWith(github.ref_name, 'feature-preview-')))");
+        assert_eq!(jobs["deploy-prod-gcp"]["if"], "github.event_name == 'push' && (github.ref_name == 'main' || github.ref_name == 'master')");
+        assert_eq!(jobs["release"]["permissions"]["id-token"], "write");
+        assert_eq!(jobs["deploy-dev-gcp"]["needs"], serde_yaml::to_value(["release"]).unwrap());
+        assert_eq!(jobs["deploy-prod-gcp"]["needs"], serde_yaml::to_value(["release", "deploy-dev-gcp"]).unwrap());
+        assert_eq!(jobs["deploy-prod-gcp"]["environment"], "prod-gcp");
+    }
+}
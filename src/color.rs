@@ -0,0 +1,44 @@
+//! Whether output is colored, decided once at startup for logs, progress, prompts and printed results.
+//!
+//! Colors are written with [console::style], which follows the decision for standard output,
+//! and `style(..).for_stderr()` for standard error.
+
+use std::io::IsTerminal;
+use clap::ValueEnum;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, ValueEnum)]
+pub enum ColorChoice {
+    /// Color output to terminals, unless `NO_COLOR` is set.
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+/// Decide whether standard output and standard error are colored. With `auto`, `plain` turns colors off,
+/// as does a reserved standard output in JSON mode. When standard error is not colored,
+/// child processes such as `docker build` are asked not to color their output either.
+pub fn init(choice: ColorChoice, plain: bool) {
+    let no_color = std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+    let dumb = std::env::var("TERM").is_ok_and(|term| term == "dumb");
+    let auto = |terminal: bool| terminal && !no_color && !dumb && !plain;
+    let (stdout, stderr) = match choice {
+        ColorChoice::Always => (true, true),
+        ColorChoice::Never => (false, false),
+        ColorChoice::Auto => (
+            auto(std::io::stdout().is_terminal()) && !crate::report::stdout_reserved(),
+            auto(std::io::stderr().is_terminal()),
+        ),
+    };
+    console::set_colors_enabled(stdout);
+    console::set_colors_enabled_stderr(stderr);
+    if !stderr && !no_color {
+        // Set at startup, before any child processes are started.
+        std::env::set_var("NO_COLOR", "1");
+    }
+}
+
+/// Whether logs and progress on standard error are colored.
+pub fn stderr() -> bool {
+    console::colors_enabled_stderr()
+}
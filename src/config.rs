@@ -1,79 +1,265 @@
 pub mod toml_merge {
     // from https://github.com/mrnerdhair/toml-merge/blob/c44eee7c7fa52e98b34c20c88d19979adcafcf1b/src/main.rs
-    fn merge(merged: &mut toml::Value, value: &toml::Value) {
-        match value {
-            toml::Value::String(_) |
-            toml::Value::Integer(_) |
-            toml::Value::Float(_) |
-            toml::Value::Boolean(_) |
-            toml::Value::Datetime(_) => *merged = value.clone(),
-            toml::Value::Array(x) => {
-                match merged {
-                    toml::Value::Array(merged) => {
-                        for (k, v) in x.iter().enumerate() {
-                            match merged.get_mut(k) {
-                                Some(x) => merge(x, v),
-                                None => {
-                                    let _ = merged.insert(k.clone(), v.clone());
-                                }
-                            }
-                        }
+    // Values are moved rather than cloned, since configurations can be big.
+    fn merge(merged: &mut toml::Value, value: toml::Value) {
+        match (merged, value) {
+            (toml::Value::Array(merged), toml::Value::Array(x)) => {
+                for (k, v) in x.into_iter().enumerate() {
+                    match merged.get_mut(k) {
+                        Some(x) => merge(x, v),
+                        None => merged.insert(k, v),
                     }
-                    _ => *merged = value.clone(),
                 }
             }
-            toml::Value::Table(x) => {
-                match merged {
-                    toml::Value::Table(merged) => {
-                        for (k, v) in x.iter() {
-                            match merged.get_mut(k) {
-                                Some(x) => merge(x, v),
-                                None => {
-                                    let _ = merged.insert(k.clone(), v.clone());
-                                }
-                            }
-                        }
-                    }
-                    _ => *merged = value.clone(),
+            (toml::Value::Table(merged), toml::Value::Table(x)) => merge_table(merged, x),
+            (merged, value) => *merged = value,
+        }
+    }
+
+    fn merge_table(merged: &mut toml::value::Table, table: toml::value::Table) {
+        for (k, v) in table {
+            match merged.get_mut(&k) {
+                Some(x) => merge(x, v),
+                None => {
+                    let _ = merged.insert(k, v);
                 }
             }
         }
     }
 
+    /// Merge TOML tables into one. Later tables take precedence.
+    pub fn merge_tables(tables: impl IntoIterator<Item = toml::value::Table>) -> toml::value::Table {
+        let mut merged = toml::value::Table::new();
+        for table in tables {
+            merge_table(&mut merged, table);
+        }
+        merged
+    }
+
     /// Merge one or more TOML files into one.
     /// Returns a String with the merged TOMLs.
     pub fn merge_files(file_contents: &[&str]) -> Result<String, toml::de::Error> {
-        let mut merged: toml::Value = toml::Value::Table(toml::value::Table::new());
-        for toml_data in file_contents.iter() {
-            let value: toml::value::Table = toml::from_str(toml_data)?;
-            merge(&mut merged, &toml::Value::Table(value));
+        let tables = file_contents.iter()
+            .map(|toml_data| toml::from_str::<toml::value::Table>(toml_data))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(toml::to_string_pretty(&merge_tables(tables)).unwrap())
+    }
+}
+
+/// Configuration overrides from environment variables.
+///
+/// A variable named `NB_<KEY>` overrides the configuration value at `<KEY>`,
+/// where nested tables are separated by a double underscore. Keys are lowercased.
+///
+/// Example: `NB_TEAM=myteam` or `NB_RELEASE__GAR__REGISTRY=europe-north1-docker.pkg.dev/myproject`.
+///
+/// Values are strings, unless the value they override is a boolean, integer or float, e.g.
+/// `NB_BUILD__DOCKER__SKIP_UNCHANGED=false`; see [typed]. So `NB_TEAM=1234` is the team `"1234"`.
+///
+/// Variables with the `NB_STEP_` prefix are not overrides: they are given to shell steps, see [STEP_PREFIX].
+pub mod env {
+    const PREFIX: &str = "NB_";
+    const SEPARATOR: &str = "__";
+    /// Prefix of the variables nb gives to shell steps, such as `NB_STEP_IMAGE`.
+    pub const STEP_PREFIX: &str = "NB_STEP_";
+
+    /// Build a TOML table from all variables with the `NB_` prefix.
+    pub fn overrides(vars: impl Iterator<Item = (String, String)>) -> toml::value::Table {
+        let mut table = toml::value::Table::new();
+        for (key, value) in vars {
+            if key.starts_with(STEP_PREFIX) {
+                continue;
+            }
+            let Some(key) = key.strip_prefix(PREFIX) else {
+                continue;
+            };
+            let path: Vec<String> = key
+                .split(SEPARATOR)
+                .map(|part| part.to_lowercase())
+                .collect();
+            if path.iter().any(|part| part.is_empty()) {
+                continue;
+            }
+            insert(&mut table, &path, value);
+        }
+        table
+    }
+
+    fn insert(table: &mut toml::value::Table, path: &[String], value: String) {
+        let (key, rest) = path.split_first().unwrap();
+        if rest.is_empty() {
+            table.insert(key.clone(), toml::Value::String(value));
+            return;
+        }
+        let entry = table
+            .entry(key.clone())
+            .or_insert_with(|| toml::Value::Table(toml::value::Table::new()));
+        if !entry.is_table() {
+            *entry = toml::Value::Table(toml::value::Table::new());
+        }
+        insert(entry.as_table_mut().unwrap(), rest, value);
+    }
+
+    /// Give the string values of `overrides` the type of the values they override in `config`: booleans,
+    /// integers and floats are parsed, if they can be. Values that override nothing, since the key has
+    /// no default, stay strings, unless `parse_unset`.
+    pub fn typed(overrides: &toml::value::Table, config: &toml::value::Table, parse_unset: bool) -> toml::value::Table {
+        overrides.iter()
+            .map(|(key, value)| {
+                let value = match (value, config.get(key)) {
+                    (toml::Value::Table(overrides), Some(toml::Value::Table(config))) => toml::Value::Table(typed(overrides, config, parse_unset)),
+                    (toml::Value::Table(overrides), _) => toml::Value::Table(typed(overrides, &toml::value::Table::new(), parse_unset)),
+                    (toml::Value::String(value), Some(toml::Value::Boolean(_) | toml::Value::Integer(_) | toml::Value::Float(_))) => scalar(value),
+                    (toml::Value::String(value), None) if parse_unset => scalar(value),
+                    (value, _) => value.clone(),
+                };
+                (key.clone(), value)
+            })
+            .collect()
+    }
+
+    /// Parse a boolean, integer or float, or keep the value as a string.
+    fn scalar(value: &str) -> toml::Value {
+        let parsed = toml::from_str::<toml::value::Table>(&format!("value = {value}"))
+            .ok()
+            .filter(|table| table.len() == 1)
+            .and_then(|mut table| table.remove("value"));
+        match parsed {
+            Some(parsed @ (toml::Value::Boolean(_) | toml::Value::Integer(_) | toml::Value::Float(_))) => parsed,
+            _ => toml::Value::String(value.to_string()),
+        }
+    }
+
+    #[cfg(test)]
+    #[test]
+    fn test_overrides() {
+        let vars = vec![
+            ("NB_TEAM".to_string(), "myteam".to_string()),
+            ("NB_RELEASE__GAR__REGISTRY".to_string(), "example.com/foo".to_string()),
+            ("NB___BROKEN".to_string(), "ignored".to_string()),
+            ("NB_STEP_TEAM".to_string(), "ignored".to_string()),
+            ("HOME".to_string(), "/root".to_string()),
+        ];
+        let table = overrides(vars.into_iter());
+        assert_eq!(toml::to_string(&table).unwrap(), r#"team = "myteam"
+
+[release.gar]
+registry = "example.com/foo"
+"#);
+    }
+
+    #[cfg(test)]
+    #[test]
+    fn test_scalar_overrides() {
+        let vars = vec![
+            ("NB_BUILD__DOCKER__SKIP_UNCHANGED".to_string(), "false".to_string()),
+            ("NB_LIMITS__MAX_PARALLEL_BUILDS".to_string(), "2".to_string()),
+            ("NB_RATIO".to_string(), "0.5".to_string()),
+            ("NB_TEAM".to_string(), "1234".to_string()),
+            ("NB_DATE".to_string(), "2024-01-01".to_string()),
+        ];
+        let config: toml::value::Table = toml::from_str("team = \"myteam\"\nratio = 1.0\n[build.docker]\nskip_unchanged = true\n").unwrap();
+        let table = typed(&overrides(vars.into_iter()), &config, false);
+        assert_eq!(toml::to_string(&table).unwrap(), r#"date = "2024-01-01"
+ratio = 0.5
+team = "1234"
+
+[build.docker]
+skip_unchanged = false
+
+[limits]
+max_parallel_builds = "2"
+"#);
+        let table = typed(&table, &config, true);
+        assert_eq!(table["limits"]["max_parallel_builds"], toml::Value::Integer(2));
+        assert_eq!(table["date"], toml::Value::String("2024-01-01".into()));
+    }
+}
+
+/// Hide secret values from configuration that is about to be displayed.
+pub mod redact {
+    const SECRET_KEY_PARTS: [&str; 6] = ["apikey", "password", "secret", "token", "credentials", "webhook"];
+    pub const REDACTED: &str = "<redacted>";
+
+    /// Whether a key or environment variable name looks like it holds a secret.
+    pub fn is_secret(key: &str) -> bool {
+        let key = key.to_lowercase();
+        SECRET_KEY_PARTS.iter().any(|part| key.contains(part))
+    }
+
+    /// Replace the values of any keys that look like they contain secrets.
+    pub fn redact(value: &mut toml::Value) {
+        match value {
+            toml::Value::Table(table) => {
+                for (key, value) in table.iter_mut() {
+                    if is_secret(key) {
+                        *value = toml::Value::String(REDACTED.into());
+                    } else {
+                        redact(value);
+                    }
+                }
+            }
+            toml::Value::Array(array) => array.iter_mut().for_each(redact),
+            _ => {}
         }
-        Ok(toml::to_string_pretty(&merged).unwrap())
     }
 }
 
 pub mod runtime {
+    use std::collections::BTreeMap;
     use serde::{Deserialize, Serialize};
     use serde_inline_default::serde_inline_default;
     use thiserror::Error;
     use crate::docker;
     use crate::nais_yaml::NaisYaml;
 
-    #[derive(Serialize, Deserialize, Debug)]
+    /// What to do with branches matching a `[branch."<regex>"]` rule.
+    #[derive(Serialize, Deserialize, Debug, Clone)]
     pub struct BranchRule {
-        output: String,
-        deploy: BranchDeployRule,
+        pub output: BranchOutput,
+        #[serde(default)]
+        pub deploy: BranchDeployRule,
+    }
+
+    /// How far a branch goes through the pipeline. Each step implies the ones before it.
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+    #[serde(rename_all = "lowercase")]
+    pub enum BranchOutput {
+        Build,
+        Release,
+        Deploy,
     }
 
     #[serde_inline_default]
-    #[derive(Serialize, Deserialize, Debug)]
+    #[derive(Serialize, Deserialize, Debug, Clone, Default)]
     pub struct BranchDeployRule {
-        pub environments: Vec<String>,
-        //pub app_name_prefix: String,
+        /// Deploy profiles in `[deploy.nais.profiles]`, deployed in order.
+        #[serde(default)]
+        pub profiles: Vec<String>,
+        pub prefix: Option<String>,
+        /// Deploy to all clusters at once, instead of one after another.
         #[serde_inline_default(false)]
         pub parallel: bool,
     }
 
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct Deploy {
+        pub nais: Option<DeployNais>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct DeployNais {
+        #[serde(default)]
+        pub profiles: BTreeMap<String, DeployProfile>,
+    }
+
+    /// A named set of clusters to deploy to.
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct DeployProfile {
+        #[serde(default)]
+        pub clusters: Vec<String>,
+    }
+
     #[derive(Serialize, Deserialize, Debug, Clone)]
     pub struct Sdk {
         pub go: SdkGolang,
@@ -107,17 +293,63 @@ pub mod runtime {
         pub runtime_docker_image: String,
     }
 
-    #[derive(Serialize, Deserialize, Debug)]
+    #[derive(Serialize, Deserialize, Debug, Clone)]
     pub struct Build {
-        typ: String,
-        sdk: String,
-        docker: Docker,
+        #[serde(rename = "type")]
+        pub typ: String,
+        pub sdk: String,
+        pub docker: Docker,
+        /// Paths outside the source directory that the build depends on, relative to the
+        /// repository root. Changes to these paths trigger a build with `--changed-since`.
+        #[serde(default)]
+        pub dependencies: Vec<String>,
+        pub remote: Option<Remote>,
     }
 
-    #[derive(Serialize, Deserialize, Debug)]
+    /// Builds on a shared BuildKit instance, see [crate::docker::remote].
+    #[derive(Serialize, Deserialize, Debug, Clone, Default)]
+    pub struct Remote {
+        /// buildx driver: `remote` or `kubernetes`. Blank to build with the local Docker daemon.
+        #[serde(default)]
+        pub driver: String,
+        /// Address of the BuildKit instance for the `remote` driver, e.g. `tcp://buildkit.example.com:1234`.
+        #[serde(default)]
+        pub endpoint: String,
+        /// Options for the driver, e.g. `cacert=/certs/ca.pem` or `namespace=builds`.
+        #[serde(default)]
+        pub driver_opts: Vec<String>,
+    }
+
+    impl Remote {
+        /// The builder to build with, or `None` to build with the local Docker daemon.
+        pub fn builder(&self) -> Result<Option<crate::docker::remote::Builder>, super::runtime::Error> {
+            use crate::docker::remote::{Builder, Driver};
+            let driver = match self.driver.as_str() {
+                "" => return Ok(None),
+                "remote" if self.endpoint.is_empty() => return Err(super::runtime::Error::RemoteEndpointMissing),
+                "remote" => Driver::Remote { endpoint: self.endpoint.clone() },
+                "kubernetes" => Driver::Kubernetes,
+                driver => return Err(super::runtime::Error::UnknownBuildDriver(driver.to_string())),
+            };
+            Ok(Some(Builder { driver, driver_opts: self.driver_opts.clone() }))
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
     pub struct Docker {
-        image_name: String,
-        image_tag: String,
+        pub image_name: String,
+        /// Template for Docker image tags, see [crate::docker::tag::generate].
+        pub tag_format: String,
+        /// Which files from the source directory are sent to the Docker builder.
+        #[serde(default)]
+        pub context: BuildContext,
+        /// Glob patterns, relative to the source directory, for untracked files such as
+        /// build outputs that are added to a `git` build context.
+        #[serde(default)]
+        pub context_include: Vec<String>,
+        /// Skip the build when an image built from the same inputs is in the registry, see [crate::inputs].
+        #[serde(default)]
+        pub skip_unchanged: bool,
         /*
         //auto_generate: bool,
         //output_files: Vec<String>,
@@ -126,6 +358,349 @@ pub mod runtime {
          */
     }
 
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+    #[serde(rename_all = "lowercase")]
+    pub enum BuildContext {
+        /// The source directory, minus the outputs of native builds and files excluded by `.dockerignore`.
+        #[default]
+        Directory,
+        /// Only files tracked by Git, so that ignored files and secrets never reach the builder.
+        Git,
+    }
+
+    /// Opt-in export of traces, see [crate::telemetry].
+    #[derive(Serialize, Deserialize, Debug, Clone, Default)]
+    pub struct Telemetry {
+        /// Base URL of an OTLP/HTTP collector, e.g. `https://collector.example.com:4318`. Blank to disable.
+        #[serde(default)]
+        pub endpoint: String,
+    }
+
+    /// Copy of the logs for debugging, see [crate::log_file].
+    #[serde_inline_default]
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct Log {
+        /// Path of the log file, relative to the working directory. Blank to disable.
+        #[serde(default)]
+        pub file: String,
+        /// Directory of a log file for the output of each step, relative to the working directory. Blank to disable.
+        #[serde(default)]
+        pub step_directory: String,
+        /// Prefix the output of child processes with their step and application when progress is not drawn.
+        #[serde_inline_default(true)]
+        pub prefix_output: bool,
+    }
+
+    /// Storage of intermediate outputs, see [crate::artifacts].
+    #[derive(Serialize, Deserialize, Debug, Clone, Default)]
+    pub struct Artifacts {
+        /// Local directory, or Cloud Storage bucket as `gs://<bucket>[/<path>]`. Blank to disable.
+        #[serde(default)]
+        pub store: String,
+    }
+
+    /// Notifications of pipeline outcomes, see [crate::notify].
+    #[derive(Serialize, Deserialize, Debug, Clone, Default)]
+    pub struct Notify {
+        /// Slack incoming webhook URL. Blank to disable.
+        #[serde(default)]
+        pub slack_webhook: String,
+    }
+
+    /// What an image must have to pass `nb verify`, see [crate::verify].
+    #[serde_inline_default]
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct Verify {
+        /// Require a cosign signature.
+        #[serde_inline_default(true)]
+        pub signature: bool,
+        /// Require a SLSA provenance attestation.
+        #[serde_inline_default(true)]
+        pub provenance: bool,
+        /// Require an SBOM attestation, in SPDX or CycloneDX format.
+        #[serde(default)]
+        pub sbom: bool,
+        /// Public key to verify with, as a path or KMS URI. Blank for keyless verification with the certificate identity.
+        #[serde(default)]
+        pub key: String,
+        /// Regular expression that the identity of the signing certificate must match, for keyless verification.
+        #[serde(default)]
+        pub certificate_identity_regexp: String,
+        /// Issuer of the OIDC token the signing certificate was issued for, for keyless verification.
+        #[serde(default)]
+        pub certificate_oidc_issuer: String,
+    }
+
+    impl Default for Verify {
+        fn default() -> Self {
+            Verify {
+                signature: true,
+                provenance: true,
+                sbom: false,
+                key: String::new(),
+                certificate_identity_regexp: String::new(),
+                certificate_oidc_issuer: String::new(),
+            }
+        }
+    }
+
+    /// Which licenses dependencies may have, checked by `nb licenses`, see [crate::licenses].
+    #[derive(Serialize, Deserialize, Debug, Clone, Default)]
+    pub struct Licenses {
+        /// SPDX identifiers of licenses that no dependency may have, e.g. `GPL-3.0-only`.
+        /// If unset, [Licenses::DEFAULT_DENY]. Unlike the defaults of `default.toml`, a list replaces it as a whole.
+        pub deny: Option<Vec<String>>,
+        /// Fail if the license of a dependency cannot be resolved.
+        #[serde(default)]
+        pub fail_on_unknown: bool,
+    }
+
+    impl Licenses {
+        /// Licenses denied unless `deny` is set: those that would require the source of the application to be published.
+        pub const DEFAULT_DENY: [&'static str; 7] = ["AGPL-3.0-only", "AGPL-3.0-or-later", "GPL-2.0-only", "GPL-2.0-or-later", "GPL-3.0-only", "GPL-3.0-or-later", "SSPL-1.0"];
+
+        /// The denied licenses, `deny` or else [Licenses::DEFAULT_DENY].
+        pub fn denied(&self) -> Vec<String> {
+            self.deny.clone().unwrap_or_else(|| Self::DEFAULT_DENY.map(String::from).to_vec())
+        }
+    }
+
+    /// A user-defined pipeline, with shell steps among the built-in steps, see [crate::pipeline::arrange].
+    #[derive(Serialize, Deserialize, Debug, Clone, Default)]
+    pub struct Pipeline {
+        /// Names of built-in steps and shell steps, in the order they run. Empty for the built-in pipeline.
+        #[serde(default)]
+        pub steps: Vec<String>,
+        #[serde(default)]
+        pub shell: BTreeMap<String, ShellStep>,
+        #[serde(default)]
+        pub sandbox: Sandbox,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct ShellStep {
+        /// Command line, run with `sh -c` in the source directory.
+        pub run: String,
+        /// Directory to run in, relative to the source directory, which it must be inside of.
+        pub directory: Option<String>,
+    }
+
+    /// The environment shell steps run in, see [crate::pipeline::Sandbox].
+    #[derive(Serialize, Deserialize, Debug, Clone, Default)]
+    pub struct Sandbox {
+        /// Environment variables passed to shell steps. `PREFIX_*` passes every variable with the prefix.
+        /// If unset, [Sandbox::DEFAULT_ENV]. Unlike the defaults of `default.toml`, a list replaces it as a whole.
+        pub env: Option<Vec<String>>,
+        /// Image to run shell steps in, with only the source directory mounted. Blank to run them on the host.
+        #[serde(default)]
+        pub image: String,
+    }
+
+    impl Sandbox {
+        /// Variables passed to shell steps unless `env` is set.
+        pub const DEFAULT_ENV: [&'static str; 8] = ["PATH", "HOME", "USER", "LANG", "LC_ALL", "TERM", "TMPDIR", "CI"];
+
+        pub fn sandbox(&self) -> crate::pipeline::Sandbox {
+            crate::pipeline::Sandbox {
+                env: self.env.clone().unwrap_or_else(|| Self::DEFAULT_ENV.map(String::from).to_vec()),
+                image: Some(self.image.clone()).filter(|image| !image.is_empty()),
+            }
+        }
+    }
+
+    impl Pipeline {
+        /// The steps of the pipeline, with built-in steps in dependency order and every shell step used once.
+        pub fn steps(&self) -> Result<Vec<crate::pipeline::Step>, Error> {
+            use crate::pipeline::{Node, Shell, Step};
+            if let Some(name) = self.shell.keys().find(|name| Node::from_name(name).is_some()) {
+                return Err(Error::ShellStepName(name.clone()));
+            }
+            if let Some(name) = self.shell.keys().find(|name| !self.steps.contains(name)) {
+                return Err(Error::ShellStepNotInPipeline(name.clone()));
+            }
+            let mut steps: Vec<Step> = Vec::new();
+            for name in &self.steps {
+                let step = match (Node::from_name(name), self.shell.get(name)) {
+                    (Some(node), _) => Step::Builtin(node),
+                    (None, Some(shell)) => Step::Shell(Shell { name: name.clone(), run: shell.run.clone(), directory: shell.directory.clone() }),
+                    (None, None) => return Err(Error::UnknownPipelineStep(name.clone())),
+                };
+                let previous = steps.iter().filter_map(Step::node).max();
+                if steps.iter().any(|other| other.name() == name) || step.node().is_some_and(|node| previous > Some(node)) {
+                    return Err(Error::PipelineStepOrder(name.clone()));
+                }
+                steps.push(step);
+            }
+            Ok(steps)
+        }
+    }
+
+    /// Steps that may run at once on the machine, across nb processes, see [crate::limits]. 0 for no limit.
+    #[derive(Serialize, Deserialize, Debug, Clone, Default)]
+    pub struct Limits {
+        /// Steps of any kind.
+        #[serde(default)]
+        pub max_parallel_steps: u32,
+        #[serde(default)]
+        pub max_parallel_builds: u32,
+        #[serde(default)]
+        pub max_parallel_releases: u32,
+        #[serde(default)]
+        pub max_parallel_deploys: u32,
+    }
+
+    impl Limits {
+        /// The limits of `step`, the global limit first.
+        pub fn of(&self, step: &crate::pipeline::Step) -> Vec<crate::limits::Limit> {
+            use crate::pipeline::Node;
+            let per_step = match step.node() {
+                Some(Node::Build) => Some(("build", self.max_parallel_builds)),
+                Some(Node::Release) => Some(("release", self.max_parallel_releases)),
+                Some(Node::Deploy) => Some(("deploy", self.max_parallel_deploys)),
+                _ => None,
+            };
+            std::iter::once(("step", self.max_parallel_steps))
+                .chain(per_step)
+                .filter(|(_, max)| *max > 0)
+                .map(|(name, max)| crate::limits::Limit { name, max })
+                .collect()
+        }
+    }
+
+    /// Retries and timeout of a pipeline step, see [crate::pipeline::Policy].
+    #[derive(Serialize, Deserialize, Debug, Clone, Default)]
+    pub struct StepPolicy {
+        /// Number of times to run the step again after it failed.
+        pub retries: Option<u32>,
+        /// Delay before the first retry, in milliseconds; doubled for each subsequent retry.
+        pub retry_delay_ms: Option<u64>,
+        /// How long each attempt may run, in seconds, before its processes are terminated.
+        pub timeout_seconds: Option<u64>,
+    }
+
+    impl StepPolicy {
+        pub fn policy(&self) -> crate::pipeline::Policy {
+            let default = crate::pipeline::Policy::default();
+            crate::pipeline::Policy {
+                retries: self.retries.unwrap_or(default.retries),
+                retry_delay: self.retry_delay_ms.map(std::time::Duration::from_millis).unwrap_or(default.retry_delay),
+                timeout: self.timeout_seconds.map(std::time::Duration::from_secs).or(default.timeout),
+            }
+        }
+    }
+
+    /// Registry authentication settings.
+    #[derive(Serialize, Deserialize, Debug, Clone, Default)]
+    pub struct Auth {
+        /// Google service account to impersonate when pushing to GAR, or `team`
+        /// for the team's GAR service account in the registry's project.
+        pub impersonate: Option<String>,
+        /// Google Security Token Service endpoint for workload identity federation.
+        pub sts_endpoint: Option<String>,
+        /// Timeout of each token request, in seconds.
+        pub timeout_seconds: Option<u64>,
+        /// Number of retries of token requests that fail with a server error or a network failure.
+        pub retries: Option<u32>,
+        /// Delay before the first retry, in milliseconds; doubled for each subsequent retry.
+        pub retry_delay_ms: Option<u64>,
+    }
+
+    impl Auth {
+        pub fn http_settings(&self) -> crate::auth::HttpSettings {
+            let default = crate::auth::HttpSettings::default();
+            crate::auth::HttpSettings {
+                sts_endpoint: self.sts_endpoint.clone().unwrap_or(default.sts_endpoint),
+                timeout: self.timeout_seconds.map(std::time::Duration::from_secs).unwrap_or(default.timeout),
+                retries: self.retries.unwrap_or(default.retries),
+                retry_delay: self.retry_delay_ms.map(std::time::Duration::from_millis).unwrap_or(default.retry_delay),
+            }
+        }
+    }
+
+    /// How to find and render nais.yaml files.
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct NaisYamlSettings {
+        /// Glob patterns for manifest files, relative to the source directory,
+        /// e.g. `[".nais/**/*.y*ml", "deploy/*.yaml"]`. If unset, well-known file names are used.
+        pub paths: Option<Vec<String>>,
+
+        /// Render resources from an external source instead of detecting nais.yaml,
+        /// e.g. `kustomize:.nais/overlays/dev` or `helm:charts/myapp --values values-dev.yaml`.
+        pub resources: Option<String>,
+
+        /// Base manifest, relative to the source directory. If set, the detected nais.yaml
+        /// is applied to the base as a JSON merge patch, so that it only needs to contain
+        /// the differences for its environment.
+        pub base: Option<String>,
+
+        /// Deploy a full image reference hardcoded in `spec.image` as it is,
+        /// skipping build and release, instead of refusing to deploy.
+        #[serde(default)]
+        pub allow_pinned_image: bool,
+
+        /// File name patterns for each deploy environment, e.g. `dev = ["dev*.yaml"]`.
+        /// Environments that are not set here have the patterns of [NaisYamlSettings::DEFAULT_ENVIRONMENTS], if any.
+        #[serde(default)]
+        pub environments: BTreeMap<String, Vec<String>>,
+
+        /// Template variables available when rendering nais.yaml.
+        #[serde(default)]
+        pub vars: toml::Table,
+
+        /// Where to download the NAIS Application schema from, either as
+        /// a CustomResourceDefinition or as a plain JSON schema.
+        pub schema_url: Option<String>,
+    }
+
+    impl NaisYamlSettings {
+        /// File name patterns of the environments that `environments` does not set. Unlike the defaults of
+        /// `default.toml`, patterns that are set replace these as a whole.
+        pub const DEFAULT_ENVIRONMENTS: [(&'static str, [&'static str; 2]); 2] = [
+            ("dev", ["dev*.yaml", "dev*.yml"]),
+            ("prod", ["prod*.yaml", "prod*.yml"]),
+        ];
+
+        /// Return the file name patterns for nais.yaml files belonging to an environment.
+        pub fn patterns_for(&self, environment: &str) -> Result<Vec<String>, Error> {
+            if let Some(patterns) = self.environments.get(environment) {
+                return Ok(patterns.clone());
+            }
+            Self::DEFAULT_ENVIRONMENTS.iter()
+                .find(|(name, _)| *name == environment)
+                .map(|(_, patterns)| patterns.map(String::from).to_vec())
+                .ok_or_else(|| {
+                    let defaults = Self::DEFAULT_ENVIRONMENTS.iter().map(|(name, _)| name.to_string());
+                    let available: std::collections::BTreeSet<String> = self.environments.keys().cloned().chain(defaults).collect();
+                    Error::UnknownEnvironment {
+                        environment: environment.to_string(),
+                        available: available.into_iter().collect::<Vec<_>>().join(", "),
+                    }
+                })
+        }
+    }
+
+    /// How seriously to treat a `nb lint` rule.
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+    #[serde(rename_all = "lowercase")]
+    pub enum Severity {
+        Off,
+        Warning,
+        Error,
+    }
+
+    /// What to do when releasing or deploying from a working tree with uncommitted changes.
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+    #[serde(rename_all = "lowercase")]
+    pub enum DirtyPolicy {
+        /// Abort the release or deploy.
+        Fail,
+        /// Log the uncommitted changes, and continue.
+        Warn,
+        /// Continue silently.
+        #[default]
+        Allow,
+    }
+
     #[derive(Serialize, Deserialize, Debug, Clone)]
     pub struct ReleaseParams {
         pub registry: String,
@@ -140,11 +715,38 @@ pub mod runtime {
         #[serde(rename = "ghcr")]
         /// GitHub Container Registry
         GHCR,
+
+        #[serde(rename = "acr")]
+        /// Azure Container Registry
+        ACR,
     }
 
+    #[derive(Debug, Clone)]
     pub struct Release {
         pub typ: ReleaseType,
         pub params: ReleaseParams,
+        /// Create a GitHub release when releasing a commit tagged with a version.
+        pub github_release: bool,
+        pub dirty_policy: DirtyPolicy,
+        /// Require a valid signature on HEAD, or on the version tag pointing at it.
+        pub require_signed: bool,
+        /// Fingerprints of keys allowed to sign releases. If empty, any valid signature is accepted.
+        pub allowed_signing_keys: Vec<String>,
+        /// Name of the Git tag created by `nb release --git-tag`, with `{app}` and `{tag}` placeholders.
+        pub git_tag_format: String,
+        pub push_git_tag: bool,
+        /// Resource name of the Google service account to impersonate,
+        /// i.e. `projects/<project>/serviceAccounts/<email>`.
+        pub impersonate: Option<String>,
+        pub http: crate::auth::HttpSettings,
+        /// Create the GAR repository before pushing, if it does not exist.
+        pub create_repository: bool,
+        /// Generate an SBOM of the image before pushing it, see [crate::attest].
+        pub sbom: bool,
+        /// Sign the pushed image, and attest its SBOM, with cosign.
+        pub sign: bool,
+        /// Private key to sign with, as given to `cosign --key`. Keyless signing if unset.
+        pub signing_key: Option<String>,
     }
 
     impl Release {
@@ -152,37 +754,272 @@ pub mod runtime {
             match self.typ {
                 ReleaseType::GAR => Box::new(docker::name::GoogleArtifactRegistry(config)),
                 ReleaseType::GHCR => Box::new(docker::name::GitHubContainerRegistry(config)),
+                ReleaseType::ACR => Box::new(docker::name::AzureContainerRegistry(config)),
+            }
+        }
+
+        /// Check that the registry has the format expected by the release type.
+        pub fn validate(&self) -> Result<(), Error> {
+            let registry = &self.params.registry;
+            self.typ.validate_registry(registry).map_err(|reason| Error::InvalidRegistry {
+                registry: registry.clone(),
+                expected: self.typ.registry_format(),
+                reason,
+            })
+        }
+    }
+
+    impl ReleaseType {
+        /// Format of the registry expected by the release type.
+        pub fn registry_format(&self) -> &'static str {
+            match self {
+                ReleaseType::GAR => "<region>-docker.pkg.dev/<project>[/<repository>]",
+                ReleaseType::GHCR => "ghcr.io/<organization>",
+                ReleaseType::ACR => "<name>.azurecr.io[/<repository>]",
             }
         }
+
+        /// Check that a registry has the format expected by the release type, or say why not.
+        pub fn validate_registry(&self, registry: &str) -> Result<(), String> {
+            match self {
+                ReleaseType::GAR => validate_gar_registry(registry),
+                ReleaseType::GHCR => validate_ghcr_registry(registry),
+                ReleaseType::ACR => validate_acr_registry(registry),
+            }
+        }
+    }
+
+    /// Google Artifact Registry: `<region>-docker.pkg.dev/<project>[/<repository>]`.
+    fn validate_gar_registry(registry: &str) -> Result<(), String> {
+        const HOST_SUFFIX: &str = "-docker.pkg.dev";
+
+        let mut parts = registry.split('/');
+        let host = parts.next().unwrap_or_default();
+        let region = host
+            .strip_suffix(HOST_SUFFIX)
+            .ok_or(format!("host `{host}` does not end with `{HOST_SUFFIX}`"))?;
+        if region.is_empty() || !region.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-') {
+            return Err(format!("`{region}` is not a valid region"));
+        }
+
+        let project = parts.next().filter(|project| !project.is_empty()).ok_or("project is missing")?;
+        if !(6..=30).contains(&project.len())
+            || !project.starts_with(|c: char| c.is_ascii_lowercase())
+            || project.ends_with('-')
+            || !project.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        {
+            return Err(format!("`{project}` is not a valid Google Cloud project ID"));
+        }
+
+        parts.try_for_each(validate_path_component)
+    }
+
+    /// GitHub Container Registry: `ghcr.io/<organization>`.
+    fn validate_ghcr_registry(registry: &str) -> Result<(), String> {
+        const HOST: &str = "ghcr.io";
+
+        let (host, organization) = registry.split_once('/').ok_or("organization is missing")?;
+        if host != HOST {
+            return Err(format!("host `{host}` is not `{HOST}`"));
+        }
+        if organization.is_empty()
+            || organization.contains('/')
+            || organization.starts_with('-')
+            || !organization.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        {
+            return Err(format!("`{organization}` is not a valid lowercase GitHub organization name"));
+        }
+        Ok(())
     }
 
+    /// Azure Container Registry: `<name>.azurecr.io[/<repository>]`.
+    fn validate_acr_registry(registry: &str) -> Result<(), String> {
+        const HOST_SUFFIX: &str = ".azurecr.io";
+
+        let mut parts = registry.split('/');
+        let host = parts.next().unwrap_or_default();
+        let name = host
+            .strip_suffix(HOST_SUFFIX)
+            .ok_or(format!("host `{host}` does not end with `{HOST_SUFFIX}`"))?;
+        if !(5..=50).contains(&name.len()) || !name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit()) {
+            return Err(format!("`{name}` is not a valid lowercase registry name"));
+        }
+
+        parts.try_for_each(validate_path_component)
+    }
+
+    /// Docker image path components consist of lowercase alphanumerics, separated by `.`, `_`, `__` or `-`.
+    fn validate_path_component(component: &str) -> Result<(), String> {
+        let valid = !component.is_empty()
+            && component.starts_with(|c: char| c.is_ascii_lowercase() || c.is_ascii_digit())
+            && component.ends_with(|c: char| c.is_ascii_lowercase() || c.is_ascii_digit())
+            && component.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "._-".contains(c));
+        match valid {
+            true => Ok(()),
+            false => Err(format!("`{component}` is not a valid path component")),
+        }
+    }
+
+    #[cfg(test)]
+    #[test]
+    fn test_validate_registry() {
+        assert!(validate_gar_registry("europe-north1-docker.pkg.dev/nais-management-233d").is_ok());
+        assert!(validate_gar_registry("europe-north1-docker.pkg.dev/nais-management-233d/myrepo").is_ok());
+        assert!(validate_gar_registry("europe-north1-docker.pkg.dev").is_err());
+        assert!(validate_gar_registry("europe-north1-docker.pkg.dev/Project").is_err());
+        assert!(validate_gar_registry("europe-north1-docker.pkg.dev/nais-management-233d/").is_err());
+        assert!(validate_gar_registry("gcr.io/nais-management-233d").is_err());
+        assert!(validate_ghcr_registry("ghcr.io/navikt").is_ok());
+        assert!(validate_ghcr_registry("ghcr.io/NAVikt").is_err());
+        assert!(validate_ghcr_registry("ghcr.io").is_err());
+        assert!(validate_ghcr_registry("docker.io/navikt").is_err());
+        assert!(validate_acr_registry("navikt.azurecr.io").is_ok());
+        assert!(validate_acr_registry("navikt.azurecr.io/team").is_ok());
+        assert!(validate_acr_registry("nav.azurecr.io").is_err());
+        assert!(validate_acr_registry("nav-ikt.azurecr.io").is_err());
+        assert!(validate_acr_registry("navikt.azurecr.io/").is_err());
+    }
+
+    #[cfg(test)]
+    #[test]
+    fn test_empty_team_is_unset() {
+        let nais_yaml = || NaisYaml::parse("apiVersion: nais.io/v1alpha1\nkind: Application\nmetadata:\n  name: myapp\n  namespace: myteam\n").unwrap();
+        let mut cfg = super::file::File { team: Some(String::new()), ..super::file::File::default() };
+        assert_eq!(Config::new(&cfg, nais_yaml()).unwrap().team, "myteam");
+        cfg.team = Some("otherteam".into());
+        assert_eq!(Config::new(&cfg, nais_yaml()).unwrap().team, "otherteam");
+    }
+
+    #[derive(Debug, Clone)]
     pub struct Config {
         pub app: String,
         pub team: String,
         pub release: Release,
+        pub tag_format: String,
+        pub context: BuildContext,
+        pub context_include: Vec<String>,
+        pub skip_unchanged: bool,
+        /// Shared builder to build with instead of the local Docker daemon.
+        pub remote_builder: Option<crate::docker::remote::Builder>,
+        /// Retries and timeouts of the pipeline steps that have them, by step name.
+        pub step_policies: Vec<(String, crate::pipeline::Policy)>,
+        /// The user-defined pipeline, if any.
+        pub pipeline: Vec<crate::pipeline::Step>,
+        /// The environment its shell steps run in.
+        pub sandbox: crate::pipeline::Sandbox,
+        pub limits: Limits,
     }
 
     #[derive(Debug, Clone, Error)]
     pub enum Error {
         #[error("missing configuration")]
         MissingConfig,
+
+        #[error("team could not be detected; set `team` in nb.toml or `metadata.namespace` in nais.yaml")]
+        TeamNotDetected,
+
+        #[error("unknown environment `{environment}`; configured environments are: {available}")]
+        UnknownEnvironment {
+            environment: String,
+            available: String,
+        },
+
+        #[error("unknown `[build.remote] driver` `{0}`; expected `remote` or `kubernetes`")]
+        UnknownBuildDriver(String),
+
+        #[error("`[build.remote] endpoint` is required by the `remote` driver")]
+        RemoteEndpointMissing,
+
+        #[error("unknown step `[steps.{0}]`; steps are login, dockerfile, build, sbom, release, sign, deploy and the shell steps of `[pipeline]`")]
+        UnknownStep(String),
+
+        #[error("unknown step `{0}` in `[pipeline] steps`; add it as `[pipeline.shell.{0}]`")]
+        UnknownPipelineStep(String),
+
+        #[error("step `{0}` is out of order in `[pipeline] steps`; built-in steps run once, in the order login, dockerfile, build, sbom, release, sign, deploy")]
+        PipelineStepOrder(String),
+
+        #[error("shell step `[pipeline.shell.{0}]` has the name of a built-in step")]
+        ShellStepName(String),
+
+        #[error("shell step `[pipeline.shell.{0}]` is not in `[pipeline] steps`")]
+        ShellStepNotInPipeline(String),
+
+        #[error("service account impersonation is only supported for GAR releases")]
+        ImpersonationUnsupported,
+
+        #[error("release registry `{registry}` is invalid: {reason}; expected format `{expected}`")]
+        InvalidRegistry {
+            registry: String,
+            expected: &'static str,
+            reason: String,
+        },
     }
 
+    /// Value of `[auth] impersonate` that selects the team's GAR service account.
+    const TEAM_SERVICE_ACCOUNT: &str = "team";
+
     impl Config {
         /// Extract essential configuration from many sources, including build.toml and nais.yaml.
         pub fn new(
             cfg: &super::file::File,
             nais_yaml: NaisYaml,
         ) -> Result<Config, Error> {
+            let team = cfg.team.clone()
+                .filter(|team| !team.is_empty())
+                .or(nais_yaml.team.filter(|team| !team.is_empty()))
+                .or_else(crate::auth::team_from_environment)
+                .ok_or(Error::TeamNotDetected)?;
             let release = cfg.release.clone().ok_or(Error::MissingConfig)?;
-            let release_params = release.params_for_type();
+            let params = release.params_for_type();
+            let impersonate = match cfg.auth.as_ref().and_then(|auth| auth.impersonate.as_deref()) {
+                None | Some("") => None,
+                Some(_) if release.typ != ReleaseType::GAR => return Err(Error::ImpersonationUnsupported),
+                Some(TEAM_SERVICE_ACCOUNT) => {
+                    let project = params.registry.split('/').nth(1).unwrap_or_default();
+                    Some(crate::auth::gar_service_account_id(&team, project))
+                }
+                Some(email) => Some(format!("projects/-/serviceAccounts/{email}")),
+            };
+            let release = Release {
+                params,
+                typ: release.typ,
+                github_release: release.github_release,
+                dirty_policy: release.dirty_policy,
+                require_signed: release.require_signed,
+                allowed_signing_keys: release.allowed_signing_keys,
+                git_tag_format: release.git_tag_format,
+                push_git_tag: release.push_git_tag,
+                impersonate,
+                http: cfg.auth.clone().unwrap_or_default().http_settings(),
+                create_repository: release.create_repository,
+                sbom: release.sbom,
+                sign: release.sign,
+                signing_key: Some(release.signing_key).filter(|key| !key.is_empty()),
+            };
+            release.validate()?;
+            let build = cfg.build.clone().ok_or(Error::MissingConfig)?;
+            let pipeline = cfg.pipeline.clone().unwrap_or_default();
+            let mut step_policies = Vec::new();
+            for (name, policy) in &cfg.steps {
+                if crate::pipeline::Node::from_name(name).is_none() && !pipeline.shell.contains_key(name) {
+                    return Err(Error::UnknownStep(name.clone()));
+                }
+                step_policies.push((name.clone(), policy.policy()));
+            }
             Ok(Config {
                 app: nais_yaml.app,
-                team: cfg.team.clone().unwrap_or(nais_yaml.team),
-                release: Release {
-                    typ: release.typ,
-                    params: release_params,
-                },
+                team,
+                release,
+                tag_format: build.docker.tag_format,
+                context: build.docker.context,
+                context_include: build.docker.context_include,
+                skip_unchanged: build.docker.skip_unchanged,
+                remote_builder: build.remote.unwrap_or_default().builder()?,
+                step_policies,
+                pipeline: pipeline.steps()?,
+                sandbox: pipeline.sandbox.sandbox(),
+                limits: cfg.limits.clone().unwrap_or_default(),
             })
         }
     }
@@ -190,16 +1027,20 @@ pub mod runtime {
 }
 
 pub mod file {
-    /// Contains structures for parsing the nb.toml configuration file.
+    //! Contains structures for parsing the nb.toml configuration file.
 
     use serde::{Deserialize, Serialize};
     use thiserror::Error;
-    use crate::config::file::Error::{ParseConfig, ReadConfig, Serialization};
-    use crate::config::runtime::{ReleaseParams, ReleaseType, Sdk};
+    use crate::config::file::Error::{ParseConfig, ReadConfig};
+    use std::collections::BTreeMap;
+    use crate::config::runtime::{Auth, BranchRule, Build, Deploy, DirtyPolicy, NaisYamlSettings, ReleaseParams, ReleaseType, Sdk, Severity, Telemetry, Log, Artifacts, Notify, Pipeline, Limits, StepPolicy, Verify, Licenses};
 
     /// Built-in default configuration.
     pub const DEFAULT_CONFIG: &str = include_str!("../default.toml");
 
+    /// [DEFAULT_CONFIG], parsed once.
+    static DEFAULT_TABLE: std::sync::LazyLock<toml::Table> = std::sync::LazyLock::new(|| toml::from_str(DEFAULT_CONFIG).unwrap());
+
     #[derive(Debug, Error)]
     pub enum Error {
         #[error(r#"read "{filename}": {err}"#)]
@@ -215,7 +1056,7 @@ pub mod file {
         },
 
         #[error("{0}")]
-        Serialization(toml::de::Error),
+        Encode(#[from] toml::ser::Error),
     }
 
     /// A nb.toml file.
@@ -223,10 +1064,31 @@ pub mod file {
     pub struct File {
         pub description: Option<String>,
         pub team: Option<String>,
-        //#[serde(default = "HashMap::new")]
-        //pub branch: HashMap<String, BranchRule>,
+        /// Branch rules, by regular expression matching the branch name.
+        #[serde(default)]
+        pub branch: BTreeMap<String, BranchRule>,
         pub sdk: Option<Sdk>,
+        pub build: Option<Build>,
         pub release: Option<Release>,
+        pub naisyaml: Option<NaisYamlSettings>,
+        /// Severity of `nb lint` rules, by rule name, overriding the built-in defaults.
+        pub lint: Option<BTreeMap<String, Severity>>,
+        pub auth: Option<Auth>,
+        pub deploy: Option<Deploy>,
+        pub telemetry: Option<Telemetry>,
+        pub log: Option<Log>,
+        pub notify: Option<Notify>,
+        pub pipeline: Option<Pipeline>,
+        pub limits: Option<Limits>,
+        pub artifacts: Option<Artifacts>,
+        pub verify: Option<Verify>,
+        pub licenses: Option<Licenses>,
+        /// Retries and timeouts of pipeline steps, by step name.
+        #[serde(default)]
+        pub steps: BTreeMap<String, StepPolicy>,
+        /// Command aliases, e.g. `ship = "deploy --env prod"`.
+        #[serde(default)]
+        pub alias: BTreeMap<String, String>,
     }
 
     impl Default for File {
@@ -238,24 +1100,59 @@ pub mod file {
     }
 
     impl File {
-        pub fn default_with_user_config_file(filename: &str) -> Result<Self, Error> {
-            let config_string = std::fs::read_to_string(filename)
-                .map_err(|err| { ReadConfig { err, filename: filename.to_string() } })?;
+        /// Merge the built-in defaults with one or more user configuration files.
+        /// Files later in the list take precedence.
+        pub fn default_with_user_config_files(filenames: &[String]) -> Result<Self, Error> {
+            let Some(last_filename) = filenames.last() else {
+                return Ok(File::default());
+            };
+
+            // Each file is parsed once, and the merged tables are deserialized without printing them first.
+            let mut tables = vec![DEFAULT_TABLE.clone()];
+            for filename in filenames {
+                let config_string = std::fs::read_to_string(filename)
+                    .map_err(|err| { ReadConfig { err, filename: filename.to_string() } })?;
+
+                // Only check the syntax here; user files are allowed to be partial,
+                // and the structure is validated after merging with the defaults.
+                let table = toml::from_str::<toml::Table>(&config_string)
+                    .map_err(|err| ParseConfig { err, filename: filename.to_string() })?;
 
-            if let Err(err) = toml::from_str::<File>(&config_string) {
-                return Err(ParseConfig { err, filename: filename.to_string() });
+                tables.push(table);
             }
 
-            let merged_config_string = super::toml_merge::merge_files(&[
-                DEFAULT_CONFIG,
-                &config_string,
-            ])
-                .map_err(Serialization)?;
+            toml::Value::Table(super::toml_merge::merge_tables(tables))
+                .try_into::<File>()
+                .map_err(|err| ParseConfig { err, filename: last_filename.to_string() })
+        }
+
+        /// Apply overrides from `NB_*` environment variables on top of this configuration.
+        /// See [super::env] for the naming scheme.
+        pub fn with_env_overrides(self, vars: impl Iterator<Item = (String, String)>) -> Result<Self, Error> {
+            let overrides = super::env::overrides(vars);
+            if overrides.is_empty() {
+                return Ok(self);
+            }
 
-            Ok(
-                toml::from_str::<File>(&merged_config_string)
-                    .map_err(|err| ParseConfig { err, filename: filename.to_string() })?
-            )
+            let toml::Value::Table(table) = toml::Value::try_from(&self)? else {
+                unreachable!("a configuration file is a table");
+            };
+
+            // Values of keys without a default are kept as strings, and only parsed if they do not fit as such.
+            let merge = |parse_unset| {
+                let overrides = super::env::typed(&overrides, &table, parse_unset);
+                toml::Value::Table(super::toml_merge::merge_tables([table.clone(), overrides])).try_into::<File>()
+            };
+            merge(false)
+                .or_else(|err| merge(true).map_err(|_| err))
+                .map_err(|err| ParseConfig { err, filename: "environment".to_string() })
+        }
+
+        /// Return the configuration as a TOML value, with secrets masked out.
+        pub fn redacted(&self) -> Result<toml::Value, Error> {
+            let mut value = toml::Value::try_from(self)?;
+            super::redact::redact(&mut value);
+            Ok(value)
         }
     }
 
@@ -265,6 +1162,31 @@ pub mod file {
         pub typ: ReleaseType,
         ghcr: ReleaseParams,
         gar: ReleaseParams,
+        acr: ReleaseParams,
+        #[serde(default)]
+        pub github_release: bool,
+        #[serde(default)]
+        pub dirty_policy: DirtyPolicy,
+        #[serde(default)]
+        pub require_signed: bool,
+        #[serde(default)]
+        pub allowed_signing_keys: Vec<String>,
+        #[serde(default = "default_git_tag_format")]
+        pub git_tag_format: String,
+        #[serde(default)]
+        pub push_git_tag: bool,
+        #[serde(default)]
+        pub create_repository: bool,
+        #[serde(default)]
+        pub sbom: bool,
+        #[serde(default)]
+        pub sign: bool,
+        #[serde(default)]
+        pub signing_key: String,
+    }
+
+    fn default_git_tag_format() -> String {
+        "{app}/{tag}".to_string()
     }
 
     impl Release {
@@ -272,6 +1194,15 @@ pub mod file {
             match self.typ {
                 ReleaseType::GAR => self.gar.clone(),
                 ReleaseType::GHCR => self.ghcr.clone(),
+                ReleaseType::ACR => self.acr.clone(),
+            }
+        }
+
+        pub fn params_for_type_mut(&mut self) -> &mut ReleaseParams {
+            match self.typ {
+                ReleaseType::GAR => &mut self.gar,
+                ReleaseType::GHCR => &mut self.ghcr,
+                ReleaseType::ACR => &mut self.acr,
             }
         }
     }
@@ -287,7 +1218,94 @@ pub mod file {
             let release = cfg.release.unwrap();
             assert_eq!(cfg.description, Some("Default configuration file".into()));
             assert_eq!(release.typ, GAR);
-            assert!(release.gar.registry.len() > 0);
+            assert!(!release.gar.registry.is_empty());
+            assert_eq!(cfg.branch["^(main|master)$"].output, crate::config::runtime::BranchOutput::Deploy);
+            assert_eq!(cfg.deploy.unwrap().nais.unwrap().profiles["default"].clusters, vec!["dev-gcp", "prod-gcp"]);
+        }
+
+        #[test]
+        pub fn workspace_configuration_layers() {
+            let dir = tempfile::tempdir().unwrap();
+            let root = dir.path().join("nb.toml");
+            let app = dir.path().join("app.toml");
+            std::fs::write(&root, "team = \"root\"\ndescription = \"shared\"\n").unwrap();
+            std::fs::write(&app, "team = \"app\"\n").unwrap();
+
+            let filenames = [root, app].map(|path| path.to_string_lossy().to_string());
+            let cfg = File::default_with_user_config_files(&filenames).unwrap();
+            assert_eq!(cfg.team, Some("app".into()));
+            assert_eq!(cfg.description, Some("shared".into()));
+            assert!(cfg.sdk.is_some());
+        }
+
+        #[test]
+        pub fn sandbox_environment_replaces_default() {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("nb.toml");
+            let sandbox_env = |contents: &str| {
+                std::fs::write(&path, contents).unwrap();
+                let cfg = File::default_with_user_config_files(&[path.to_string_lossy().to_string()]).unwrap();
+                cfg.pipeline.unwrap().sandbox.sandbox().env
+            };
+            assert_eq!(sandbox_env(""), crate::config::runtime::Sandbox::DEFAULT_ENV);
+            assert_eq!(sandbox_env("[pipeline.sandbox]\nenv = [\"PATH\"]\n"), ["PATH"]);
+            assert!(sandbox_env("[pipeline.sandbox]\nenv = []\n").is_empty());
+        }
+
+        #[test]
+        pub fn denied_licenses_replace_default() {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("nb.toml");
+            let denied = |contents: &str| {
+                std::fs::write(&path, contents).unwrap();
+                let cfg = File::default_with_user_config_files(&[path.to_string_lossy().to_string()]).unwrap();
+                cfg.licenses.unwrap().denied()
+            };
+            assert_eq!(denied(""), crate::config::runtime::Licenses::DEFAULT_DENY);
+            assert_eq!(denied("[licenses]\ndeny = [\"GPL-3.0-only\"]\n"), ["GPL-3.0-only"]);
+        }
+
+        #[test]
+        pub fn environment_patterns_replace_default() {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("nb.toml");
+            std::fs::write(&path, "[naisyaml.environments]\ndev = [\"staging.yaml\"]\ntest = [\"test.yaml\"]\n").unwrap();
+            let cfg = File::default_with_user_config_files(&[path.to_string_lossy().to_string()]).unwrap();
+            let settings = cfg.naisyaml.unwrap();
+            assert_eq!(settings.patterns_for("dev").unwrap(), ["staging.yaml"]);
+            assert_eq!(settings.patterns_for("prod").unwrap(), ["prod*.yaml", "prod*.yml"]);
+            assert_eq!(settings.patterns_for("test").unwrap(), ["test.yaml"]);
+            assert_eq!(settings.patterns_for("qa").unwrap_err().to_string(), "unknown environment `qa`; configured environments are: dev, prod, test");
+        }
+
+        #[test]
+        pub fn environment_overrides_configuration() {
+            let vars = vec![
+                ("NB_TEAM".to_string(), "myteam".to_string()),
+                ("NB_RELEASE__GAR__REGISTRY".to_string(), "example.com/foo".to_string()),
+            ];
+            let cfg = File::default().with_env_overrides(vars.into_iter()).unwrap();
+            assert_eq!(cfg.team, Some("myteam".into()));
+            assert_eq!(cfg.release.unwrap().gar.registry, "example.com/foo");
+        }
+
+        #[test]
+        pub fn environment_overrides_are_typed() {
+            let vars = vec![
+                ("NB_BUILD__DOCKER__SKIP_UNCHANGED".to_string(), "true".to_string()),
+                ("NB_LIMITS__MAX_PARALLEL_BUILDS".to_string(), "3".to_string()),
+            ];
+            let cfg = File::default().with_env_overrides(vars.into_iter()).unwrap();
+            assert!(cfg.build.unwrap().docker.skip_unchanged);
+            assert_eq!(cfg.limits.unwrap().max_parallel_builds, 3);
+
+            let vars = vec![
+                ("NB_TEAM".to_string(), "1234".to_string()),
+                ("NB_RELEASE__GIT_TAG_FORMAT".to_string(), "2024".to_string()),
+            ];
+            let cfg = File::default().with_env_overrides(vars.into_iter()).unwrap();
+            assert_eq!(cfg.team.as_deref(), Some("1234"));
+            assert_eq!(cfg.release.unwrap().git_tag_format, "2024");
         }
     }
 }
@@ -1,5 +1,7 @@
-use std::process::{ExitStatus, Stdio};
+use std::collections::BTreeMap;
+use std::process::ExitStatus;
 use thiserror::Error;
+use crate::git::RepositoryMetadata;
 
 /// All field names corresponds with deploy client names
 #[derive(Default, Debug, Clone)]
@@ -26,10 +28,83 @@ pub enum Error {
     IOError(#[from] std::io::Error),
 }
 
+/// Version of nb that built and deployed the application.
+pub const ANNOTATION_CLIENT_VERSION: &str = "build.nais.io/client-version";
+
+/// Digest of the deployed image, for linking running pods to their SLSA provenance.
+pub const ANNOTATION_IMAGE_DIGEST: &str = "nais.io/salsa-image-digest";
+
+/// Repository the deployed application was built from.
+pub const ANNOTATION_SOURCE_REPO: &str = "nais.io/source-repo";
+
+/// Full SHA of the commit the deployed application was built from.
+pub const ANNOTATION_GIT_COMMIT: &str = "nais.io/git-commit";
+
+/// Branch the deployed application was built from.
+pub const ANNOTATION_GIT_BRANCH: &str = "nais.io/git-branch";
+
+/// Committer time of the commit the deployed application was built from.
+pub const ANNOTATION_GIT_COMMIT_TIME: &str = "nais.io/git-commit-time";
+
+/// Author of the commit the deployed application was built from.
+pub const ANNOTATION_GIT_AUTHOR: &str = "nais.io/git-author";
+
+/// Submodules and their commits, on the form `path=sha,path=sha`.
+pub const ANNOTATION_GIT_SUBMODULES: &str = "nais.io/git-submodules";
+
+/// Identifier of the CI run that built and deployed the application.
+pub const ANNOTATION_BUILD_ID: &str = "nais.io/build-id";
+
+/// Release notes generated from the commits since the previous release.
+pub const ANNOTATION_RELEASE_NOTES: &str = "nais.io/release-notes";
+
+/// Return an identifier for the current CI run, if running in CI.
+pub fn build_id() -> Option<String> {
+    let var = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+    match (var("GITHUB_RUN_ID"), var("GITHUB_RUN_ATTEMPT")) {
+        (Some(id), Some(attempt)) => Some(format!("{id}-{attempt}")),
+        (Some(id), None) => Some(id),
+        _ => var("BUILD_ID").or_else(|| var("BUILD_NUMBER")),
+    }
+}
+
+/// Annotations that link a deployed workload back to its source and build.
+pub fn provenance_annotations(git_meta: Option<&RepositoryMetadata>, image_digest: Option<String>, release_notes: Option<String>) -> BTreeMap<String, String> {
+    let mut annotations = BTreeMap::from([(ANNOTATION_CLIENT_VERSION.to_string(), crate::version::CLIENT_VERSION.to_string())]);
+    if let Some(digest) = image_digest {
+        annotations.insert(ANNOTATION_IMAGE_DIGEST.to_string(), digest);
+    }
+    if let Some(git_meta) = git_meta {
+        annotations.insert(ANNOTATION_SOURCE_REPO.to_string(), git_meta.web_url());
+        annotations.insert(ANNOTATION_GIT_COMMIT.to_string(), git_meta.sha.clone());
+        annotations.insert(ANNOTATION_GIT_BRANCH.to_string(), git_meta.branch.clone());
+        annotations.insert(ANNOTATION_GIT_COMMIT_TIME.to_string(), git_meta.commit_time.clone());
+        annotations.insert(ANNOTATION_GIT_AUTHOR.to_string(), git_meta.author.clone());
+        if !git_meta.submodules.is_empty() {
+            annotations.insert(ANNOTATION_GIT_SUBMODULES.to_string(), crate::git::format_submodules(&git_meta.submodules));
+        }
+    }
+    if let Some(build_id) = build_id() {
+        annotations.insert(ANNOTATION_BUILD_ID.to_string(), build_id);
+    }
+    if let Some(release_notes) = release_notes {
+        annotations.insert(ANNOTATION_RELEASE_NOTES.to_string(), release_notes);
+    }
+    annotations
+}
+
+/// Keychain account holding a team's deploy API key, as stored by `nb auth set-deploy-key`.
+pub fn keychain_account(team: &str) -> String {
+    format!("deploy-apikey:{team}")
+}
+
 impl Config {
-    pub fn try_new_from_env() -> Option<Self> {
+    /// The API key is read from `NAIS_DEPLOY_APIKEY`, or else from the team's entry in the OS keychain.
+    pub fn try_new_from_env(team: &str) -> Option<Self> {
+        let apikey = std::env::var("NAIS_DEPLOY_APIKEY").ok()
+            .or_else(|| crate::keychain::load(&keychain_account(team)))?;
         Some(Config {
-            apikey: std::env::var("NAIS_DEPLOY_APIKEY").ok()?,
+            apikey,
             deploy_server: std::env::var("NAIS_DEPLOY_SERVER").ok()?,
             wait: true,
             ..Default::default()
@@ -37,8 +112,9 @@ impl Config {
     }
 }
 
-pub fn deploy(cfg: Config) -> Result<(), Error> {
-    let mut process = std::process::Command::new("deploy");
+/// Run the deploy client, which is stopped if `cancel` is cancelled before the deployment is done.
+pub async fn deploy(cfg: Config, cancel: &tokio_util::sync::CancellationToken) -> Result<(), Error> {
+    let mut process = tokio::process::Command::new("deploy");
 
     for resource_file in cfg.resource {
         process.arg("--resource").arg(resource_file);
@@ -55,17 +131,14 @@ pub fn deploy(cfg: Config) -> Result<(), Error> {
         .arg("--ref").arg(cfg.git_ref)
         .arg("--repository").arg(cfg.repository)
         .arg("--vars").arg(cfg.vars)
-        .arg("--wait").arg(cfg.wait.to_string())
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .status()
-        .map(|exit_status| {
-            if exit_status.success() {
-                Ok(())
-            } else {
-                Err(Error::Deploy(exit_status))
-            }
-        })?
+        .arg("--wait").arg(cfg.wait.to_string());
+    let step = crate::progress::step("deploy");
+    let exit_status = crate::process::run(&step, &mut process, cancel).await?;
+    if !exit_status.success() {
+        return Err(Error::Deploy(exit_status));
+    }
+    step.finish();
+    Ok(())
 }
 // Unused configuration options
 
@@ -86,3 +159,30 @@ pub fn deploy(cfg: Config) -> Result<(), Error> {
 // TelemetryInput            string
 // Timeout                   time.Duration
 // TracingDashboardURL       string
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[tokio::test]
+    pub async fn deploy_client_arguments() {
+        let cfg = Config {
+            apikey: "0123456789abcdef".into(),
+            cluster: "dev-gcp".into(),
+            deploy_server: "deploy.example.com:443".into(),
+            owner: "navikt".into(),
+            git_ref: "main".into(),
+            repository: "myapp".into(),
+            resource: vec!["nais.yaml".into()],
+            vars: "vars.yaml".into(),
+            wait: true,
+            ..Default::default()
+        };
+        let mock = std::sync::Arc::new(crate::process::Mock::default().respond("deploy", 1, ""));
+        let cancel = tokio_util::sync::CancellationToken::new();
+        let deployed = crate::process::scope(mock.clone(), deploy(cfg, &cancel)).await;
+        assert!(matches!(deployed, Err(Error::Deploy(_))));
+        assert_eq!(mock.invocations()[0].command_line(), "deploy --resource nais.yaml --apikey 0123456789abcdef --cluster dev-gcp \
+            --deploy-server deploy.example.com:443 --owner navikt --ref main --repository myapp --vars vars.yaml --wait true");
+    }
+}
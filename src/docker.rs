@@ -1,8 +1,15 @@
+use std::collections::BTreeMap;
 use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::process::{ExitStatus, Stdio};
-use log::{debug, error};
+use tokio::process::Command;
+use tokio_util::sync::CancellationToken;
+use log::{debug, error, warn};
+use serde::Deserialize;
 use thiserror::Error;
-use crate::docker::Error::IOError;
+use crate::git::RepositoryMetadata;
+use crate::progress;
+use crate::workspace::Workspace;
 use crate::sdk;
 use crate::sdk::SDK;
 
@@ -23,8 +30,106 @@ pub enum Error {
     #[error("docker push failed with exit code {0}")]
     Push(ExitStatus),
 
+    #[error("docker image inspect failed with exit code {0}")]
+    Inspect(ExitStatus),
+
+    #[error("docker image ls failed with exit code {0}")]
+    List(ExitStatus),
+
+    #[error("docker image rm failed with exit code {0}")]
+    Remove(ExitStatus),
+
+    #[error("docker pull failed with exit code {0}")]
+    Pull(ExitStatus),
+
+    #[error("docker tag failed with exit code {0}")]
+    Tag(ExitStatus),
+
+    #[error("docker buildx create failed with exit code {0}")]
+    CreateBuilder(ExitStatus),
+
     #[error(transparent)]
     IOError(#[from] std::io::Error),
+
+    #[error("assemble build context: {0}")]
+    Git(#[from] crate::git::Error),
+
+    #[error("invalid build context include pattern: {0}")]
+    Pattern(#[from] glob::PatternError),
+}
+
+/// Return the registry digest of a pushed image, e.g. `sha256:abcdef...`,
+/// or `None` if the image has not been pushed to the registry in its name.
+pub async fn image_digest(image_name: &str, cancel: &CancellationToken) -> Result<Option<String>, Error> {
+    let output = crate::process::output(Command::new("docker")
+        .arg("image")
+        .arg("inspect")
+        .arg("--format")
+        .arg("{{range .RepoDigests}}{{println .}}{{end}}")
+        .arg(image_name)
+        .stderr(Stdio::inherit()), cancel).await?;
+    if !output.status.success() {
+        return Err(Error::Inspect(output.status));
+    }
+    Ok(repo_digest(&String::from_utf8_lossy(&output.stdout), image_name))
+}
+
+/// Find the digest of an image in the output of `docker image inspect`,
+/// which lists digests on the form `<repository>@<digest>`.
+fn repo_digest(repo_digests: &str, image_name: &str) -> Option<String> {
+    // The repository is everything before the tag; registries may contain a port number.
+    let repository = match image_name.rsplit_once(':') {
+        Some((repository, tag)) if !tag.contains('/') => repository,
+        _ => image_name,
+    };
+    repo_digests
+        .lines()
+        .filter_map(|line| line.trim().split_once('@'))
+        .find(|(name, _)| *name == repository)
+        .map(|(_, digest)| digest.to_string())
+}
+
+#[cfg(test)]
+#[test]
+fn test_credential_helper() {
+    let config: ClientConfig = serde_json::from_str(r#"{
+        "auths": {},
+        "credHelpers": {"europe-north1-docker.pkg.dev": "gcloud"},
+        "credsStore": "osxkeychain"
+    }"#).unwrap();
+    assert_eq!(config.helper("europe-north1-docker.pkg.dev"), Some("gcloud"));
+    assert_eq!(config.helper("ghcr.io"), Some("osxkeychain"));
+    assert_eq!(ClientConfig::default().helper("ghcr.io"), None);
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_login_and_push() {
+    let mock = std::sync::Arc::new(crate::process::Mock::default().respond("docker push", 1, ""));
+    let credentials = crate::auth::Credentials { username: "oauth2accesstoken".into(), password: "hunter22".into(), expires_at: None };
+    let cancel = CancellationToken::new();
+    let pushed = crate::process::scope(mock.clone(), async {
+        let session = Session::new("europe-north1-docker.pkg.dev/project/team", &credentials, &cancel).await.unwrap();
+        session.push("europe-north1-docker.pkg.dev/project/team/app:1", &cancel).await
+    }).await;
+    assert!(matches!(pushed, Err(Error::Push(_))));
+
+    let invocations = mock.invocations();
+    let command_lines: Vec<String> = invocations.iter().map(|invocation| invocation.command_line()).collect();
+    assert_eq!(command_lines, [
+        "docker login europe-north1-docker.pkg.dev --username oauth2accesstoken --password-stdin",
+        "docker push europe-north1-docker.pkg.dev/project/team/app:1",
+        "docker logout europe-north1-docker.pkg.dev",
+    ]);
+    assert_eq!(invocations[0].input.as_deref(), Some(b"hunter22".as_slice()), "the password is not on the command line");
+}
+
+#[cfg(test)]
+#[test]
+fn test_repo_digest() {
+    let output = "ghcr.io/navikt/other@sha256:111\nghcr.io/navikt/myapp@sha256:222\n";
+    assert_eq!(repo_digest(output, "ghcr.io/navikt/myapp:1.0"), Some("sha256:222".into()));
+    assert_eq!(repo_digest(output, "localhost:5000/myapp:1.0"), None);
 }
 
 /// Specifies how to format Docker image names.
@@ -40,6 +145,7 @@ pub mod name {
 
     pub struct GoogleArtifactRegistry(pub Config);
     pub struct GitHubContainerRegistry(pub Config);
+    pub struct AzureContainerRegistry(pub Config);
 
     impl Display for GoogleArtifactRegistry {
         fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -47,7 +153,7 @@ pub mod name {
             let team = &self.0.team;
             let app = &self.0.app;
             let tag = &self.0.tag;
-            write!(f, "{}", format!("{registry}/{team}/{app}:{tag}"))
+            write!(f, "{registry}/{team}/{app}:{tag}")
         }
     }
 
@@ -56,7 +162,17 @@ pub mod name {
             let registry = &self.0.registry;
             let app = &self.0.app;
             let tag = &self.0.tag;
-            write!(f, "{}", format!("{registry}/{app}:{tag}"))
+            write!(f, "{registry}/{app}:{tag}")
+        }
+    }
+
+    impl Display for AzureContainerRegistry {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            let registry = &self.0.registry;
+            let team = &self.0.team;
+            let app = &self.0.app;
+            let tag = &self.0.tag;
+            write!(f, "{registry}/{team}/{app}:{tag}")
         }
     }
 
@@ -82,61 +198,702 @@ pub mod name {
         pub fn ghcr_release() {
             assert_eq!(GitHubContainerRegistry(configuration()).to_string(), "path/to/registry/myapplication:1-foo".to_string());
         }
+
+        #[test]
+        pub fn acr_release() {
+            assert_eq!(AzureContainerRegistry(configuration()).to_string(), "path/to/registry/mynamespace/myapplication:1-foo".to_string());
+        }
+    }
+}
+
+/// Builds on a shared BuildKit instance through a buildx builder, instead of the local Docker daemon,
+/// so that heavyweight builds do not run on laptops and CI runners. Configured in `[build.remote]`.
+pub mod remote {
+    use std::process::{Command, Stdio};
+    use log::{debug, info};
+    use tokio_util::sync::CancellationToken;
+    use super::Error;
+
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum Driver {
+        /// The buildx `remote` driver, connecting to a running BuildKit instance, e.g. `tcp://buildkit.example.com:1234`.
+        Remote { endpoint: String },
+        /// The buildx `kubernetes` driver, running BuildKit pods in the current Kubernetes context.
+        Kubernetes,
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Builder {
+        pub driver: Driver,
+        /// Options for the driver, e.g. `cacert=/certs/ca.pem` or `namespace=builds`.
+        pub driver_opts: Vec<String>,
+    }
+
+    impl Builder {
+        /// Name of the buildx builder, which is the same for the same settings, so that it is created once.
+        pub fn name(&self) -> String {
+            format!("nb-{}", &sha256::digest(format!("{:?}", self))[..12])
+        }
+
+        /// The `docker buildx create` invocation of [ensure].
+        pub fn create_command(&self) -> Command {
+            let mut command = Command::new("docker");
+            command.args(["buildx", "create", "--name", &self.name(), "--driver"]);
+            match &self.driver {
+                Driver::Remote { .. } => command.arg("remote"),
+                Driver::Kubernetes => command.arg("kubernetes"),
+            };
+            for opt in &self.driver_opts {
+                command.arg("--driver-opt").arg(opt);
+            }
+            if let Driver::Remote { endpoint } = &self.driver {
+                command.arg(endpoint);
+            }
+            command
+        }
+    }
+
+    /// Create the buildx builder unless it exists, and return its name.
+    pub async fn ensure(builder: &Builder, cancel: &CancellationToken) -> Result<String, Error> {
+        let name = builder.name();
+        let exists = crate::process::status(tokio::process::Command::new("docker")
+            .args(["buildx", "inspect", &name])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null()), cancel).await?
+            .success();
+        if exists {
+            debug!("Using buildx builder {name}");
+            return Ok(name);
+        }
+        info!("Creating buildx builder {name} for {:?}", builder.driver);
+        let status = crate::process::status(tokio::process::Command::from(builder.create_command()).stdout(Stdio::null()), cancel).await?;
+        if !status.success() {
+            return Err(Error::CreateBuilder(status));
+        }
+        Ok(name)
+    }
+
+    #[cfg(test)]
+    pub mod tests {
+        use super::*;
+
+        #[test]
+        pub fn create_builder() {
+            let builder = Builder {
+                driver: Driver::Remote { endpoint: "tcp://buildkit:1234".to_string() },
+                driver_opts: vec!["cacert=/certs/ca.pem".to_string()],
+            };
+            let command = crate::process::command_line(&builder.create_command());
+            assert_eq!(command, format!("docker buildx create --name {} --driver remote --driver-opt 'cacert=/certs/ca.pem' tcp://buildkit:1234", builder.name()));
+            let kubernetes = Builder { driver: Driver::Kubernetes, driver_opts: vec![] };
+            assert_ne!(kubernetes.name(), builder.name());
+        }
     }
 }
 
 /// Specifies how to format Docker image tags.
 pub mod tag {
-    pub type Error = crate::git::Error;
+    use thiserror::Error;
+
+    #[derive(Debug, Error)]
+    pub enum Error {
+        #[error(transparent)]
+        Git(#[from] crate::git::Error),
 
-    /// Generate a Docker tag with the current timestamp and the currently checked out Git short SHA sum.
+        #[error("unknown placeholder {{{0}}} in tag format")]
+        UnknownPlaceholder(String),
+
+        #[error("placeholder {{{0}}} is not available in this environment")]
+        Unavailable(String),
+
+        #[error("unterminated placeholder in tag format '{0}'")]
+        Unterminated(String),
+    }
+
+    /// Environment variables holding a CI build number, in order of preference.
+    const BUILD_NUMBER_VARIABLES: [&str; 2] = ["GITHUB_RUN_NUMBER", "BUILD_NUMBER"];
+
+    /// Generate a Docker tag from a format string such as `{date}.{time}.{short_sha}`.
     ///
-    /// Git-related values will be generated by the currently installed `git` executable.
-    /// Returns an error if `git` is not installed, or if short sha parsing failed.
+    /// Supported placeholders:
+    /// * `{date}` - current date, e.g. `20241008`
+    /// * `{time}` - current time, e.g. `152558`
+    /// * `{sha}` - full Git SHA sum of the checked out commit
+    /// * `{short_sha}` - Git short SHA sum, suffixed with `-dirty` if the working tree is dirty
+    /// * `{branch}` - currently checked out Git branch, or the branch being built in CI if HEAD is detached
+    /// * `{build_number}` - CI build number, read from `GITHUB_RUN_NUMBER` or `BUILD_NUMBER`
+    /// * `{version}` - version from the latest `v*` Git tag, e.g. `1.4.2`, or `1.4.2-3-gabcdef` if HEAD
+    ///   is three commits past the tag, see [crate::git::version]
     ///
-    /// If working tree is dirty, tag will be suffixed with `-dirty`.
+    /// Git-related values are read from the repository containing the source directory,
+    /// and are only computed if they are used in the format string.
+    /// Characters that are not valid in a Docker tag are replaced with `-`.
     ///
     /// Example output: `20241008.152558.abcdef` or `20241008.152558.abcdef-dirty`
-    pub fn generate(filesystem_path: &str) -> Result<String, Error> {
+    pub fn generate(filesystem_path: &str, format: &str) -> Result<String, Error> {
         let now = chrono::Local::now();
-        let datetime = now.format("%Y%m%d.%H%M%S").to_string();
-        let git_short_sha = crate::git::short_sha(filesystem_path)?;
-        Ok(format!("{datetime}.{git_short_sha}"))
+        let tag = render(format, |placeholder| {
+            Ok(match placeholder {
+                "date" => now.format("%Y%m%d").to_string(),
+                "time" => now.format("%H%M%S").to_string(),
+                "sha" => crate::git::sha(filesystem_path)?,
+                "short_sha" => crate::git::short_sha(filesystem_path)?,
+                "branch" => Some(crate::git::branch(filesystem_path)?)
+                    .filter(|branch| branch != crate::git::DETACHED_HEAD)
+                    .ok_or(Error::Unavailable(placeholder.to_string()))?,
+                "version" => crate::git::version(filesystem_path)?
+                    .ok_or(Error::Unavailable(placeholder.to_string()))?
+                    .to_string(),
+                "build_number" => BUILD_NUMBER_VARIABLES
+                    .iter()
+                    .find_map(|name| std::env::var(name).ok())
+                    .ok_or(Error::Unavailable(placeholder.to_string()))?,
+                _ => return Err(Error::UnknownPlaceholder(placeholder.to_string())),
+            })
+        })?;
+        Ok(sanitize(&tag))
+    }
+
+    /// Replace all `{placeholder}` occurrences in `format` with values returned by `value_of`.
+    fn render<F>(format: &str, mut value_of: F) -> Result<String, Error>
+    where
+        F: FnMut(&str) -> Result<String, Error>,
+    {
+        let mut output = String::new();
+        let mut rest = format;
+        while let Some(start) = rest.find('{') {
+            output.push_str(&rest[..start]);
+            let end = rest[start..]
+                .find('}')
+                .ok_or(Error::Unterminated(format.to_string()))?;
+            output.push_str(&value_of(&rest[start + 1..start + end])?);
+            rest = &rest[start + end + 1..];
+        }
+        output.push_str(rest);
+        Ok(output)
+    }
+
+    /// Docker tags may contain at most 128 characters out of `[A-Za-z0-9_.-]`,
+    /// and must not start with a period or a dash.
+    fn sanitize(tag: &str) -> String {
+        const MAX_LENGTH: usize = 128;
+        tag.chars()
+            .map(|c| if c.is_ascii_alphanumeric() || "_.-".contains(c) { c } else { '-' })
+            .skip_while(|c| *c == '.' || *c == '-')
+            .take(MAX_LENGTH)
+            .collect()
+    }
+
+    #[cfg(test)]
+    pub mod tests {
+        use super::*;
+
+        #[test]
+        pub fn render_placeholders() {
+            let tag = render("{branch}-{build_number}.{short_sha}", |placeholder| {
+                Ok(match placeholder {
+                    "branch" => "feature/foo".to_string(),
+                    "build_number" => "42".to_string(),
+                    "short_sha" => "abcdef".to_string(),
+                    _ => return Err(Error::UnknownPlaceholder(placeholder.to_string())),
+                })
+            }).unwrap();
+            assert_eq!(sanitize(&tag), "feature-foo-42.abcdef");
+        }
+
+        #[test]
+        pub fn render_errors() {
+            let value_of = |placeholder: &str| Err(Error::UnknownPlaceholder(placeholder.to_string()));
+            assert!(matches!(render("{nope}", value_of), Err(Error::UnknownPlaceholder(p)) if p == "nope"));
+            assert!(matches!(render("{date", value_of), Err(Error::Unterminated(_))));
+        }
+    }
+}
+
+/// OCI image labels describing the source code an image is built from.
+///
+/// Standard `org.opencontainers.image.*` labels are used where they exist.
+pub fn labels(git_meta: &RepositoryMetadata) -> BTreeMap<String, String> {
+    let mut labels = BTreeMap::from([
+        ("org.opencontainers.image.source".to_string(), git_meta.web_url()),
+        ("org.opencontainers.image.revision".to_string(), git_meta.sha.clone()),
+        ("org.opencontainers.image.created".to_string(), chrono::Utc::now().to_rfc3339()),
+        ("io.nais.build.git.remote-url".to_string(), git_meta.remote_url.clone()),
+        ("io.nais.build.git.branch".to_string(), git_meta.branch.clone()),
+        ("io.nais.build.git.commit-time".to_string(), git_meta.commit_time.clone()),
+        ("io.nais.build.git.author".to_string(), git_meta.author.clone()),
+    ]);
+    if !git_meta.submodules.is_empty() {
+        labels.insert("io.nais.build.git.submodules".to_string(), crate::git::format_submodules(&git_meta.submodules));
+    }
+    labels
+}
+
+/// [labels] of the Git repository in the source directory. Images can be built outside a Git repository,
+/// but then without source labels.
+pub fn source_labels(source_directory: &str) -> BTreeMap<String, String> {
+    match crate::git::metadata(source_directory) {
+        Ok(git_meta) => labels(&git_meta),
+        Err(err) => {
+            warn!("Git metadata not available, image will not be labelled: {err}");
+            BTreeMap::new()
+        }
+    }
+}
+
+/// The build context, assembled in-process as a tar archive and streamed to `docker build -`,
+/// so that only the files the build needs are sent to the builder, instead of Docker reading the
+/// whole source directory, with `node_modules`, `target` and the like, and filtering it afterwards.
+pub mod context {
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+    use log::debug;
+    use crate::config::runtime::BuildContext;
+    use super::Error;
+
+    /// Path of the generated Dockerfile in the archive, given to `docker build --file`.
+    pub const DOCKERFILE: &str = ".nb/Dockerfile";
+
+    /// The rules of a `.dockerignore` file: paths matching a pattern are excluded, along with
+    /// everything below them, unless a later pattern starting with `!` matches them again.
+    #[derive(Debug, Default)]
+    pub struct Dockerignore {
+        /// Each pattern, and whether it excludes rather than re-includes.
+        rules: Vec<(glob::Pattern, bool)>,
+    }
+
+    impl Dockerignore {
+        pub fn parse(contents: &str) -> Result<Self, Error> {
+            let mut rules = Vec::new();
+            for line in contents.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+                let (pattern, exclude) = match line.strip_prefix('!') {
+                    Some(pattern) => (pattern.trim(), false),
+                    None => (line, true),
+                };
+                // Patterns are relative to the root of the context, with or without a leading or trailing slash.
+                let pattern = pattern.trim_start_matches("./").trim_matches('/');
+                if !pattern.is_empty() {
+                    rules.push((glob::Pattern::new(pattern)?, exclude));
+                }
+            }
+            Ok(Dockerignore { rules })
+        }
+
+        /// The `.dockerignore` file of `source_directory`, if any.
+        pub fn read(source_directory: &Path) -> Result<Self, Error> {
+            match std::fs::read_to_string(source_directory.join(".dockerignore")) {
+                Ok(contents) => Self::parse(&contents),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+                Err(err) => Err(err.into()),
+            }
+        }
+
+        /// Whether `path`, relative to the root of the context, is excluded.
+        pub fn excludes(&self, path: &Path) -> bool {
+            let options = glob::MatchOptions { require_literal_separator: true, ..Default::default() };
+            let mut excluded = false;
+            for (pattern, exclude) in &self.rules {
+                if path.ancestors().filter(|path| !path.as_os_str().is_empty()).any(|path| pattern.matches_path_with(path, options)) {
+                    excluded = *exclude;
+                }
+            }
+            excluded
+        }
+    }
+
+    /// The files of the build context, relative to `source_directory`, minus those excluded by `.dockerignore`.
+    /// A `git` context has the files tracked by Git, and untracked files matching the `include` glob patterns.
+    /// A `directory` context has every file, except the top-level `outputs` of the SDK's native builds.
+    pub fn files(source_directory: &str, context: BuildContext, include: &[String], outputs: &[&str]) -> Result<Vec<PathBuf>, Error> {
+        let source = Path::new(source_directory);
+        let mut files = match context {
+            BuildContext::Git => git_files(source_directory, include)?,
+            BuildContext::Directory => crate::inputs::directory_files(source, outputs)?,
+        };
+        let dockerignore = Dockerignore::read(source)?;
+        files.retain(|file| !dockerignore.excludes(file));
+        files.sort();
+        files.dedup();
+        Ok(files)
+    }
+
+    fn git_files(source_directory: &str, include: &[String]) -> Result<Vec<PathBuf>, Error> {
+        let source = Path::new(source_directory);
+        let mut files = crate::git::tracked_files(source_directory)?;
+        for pattern in include {
+            let pattern = source.join(pattern).to_string_lossy().to_string();
+            for path in glob::glob(&pattern)?.filter_map(Result::ok).filter(|path| path.is_file()) {
+                files.extend(path.strip_prefix(source).ok().map(Path::to_path_buf));
+            }
+        }
+        Ok(files)
+    }
+
+    /// Write a tar archive of `files` in `source_directory`, and of `dockerfile` as [DOCKERFILE], to `writer`.
+    /// Symbolic links are archived as links, the way Docker does.
+    pub fn write(writer: impl Write, source_directory: &Path, files: &[PathBuf], dockerfile: &Path) -> Result<(), Error> {
+        let mut archive = tar::Builder::new(writer);
+        archive.follow_symlinks(false);
+        for file in files {
+            archive.append_path_with_name(source_directory.join(file), file)?;
+        }
+        archive.append_path_with_name(dockerfile, DOCKERFILE)?;
+        archive.into_inner()?.flush()?;
+        debug!("Sent {} files in the build context", files.len());
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub mod tests {
+        use super::*;
+
+        #[test]
+        pub fn dockerignore() {
+            let dockerignore = Dockerignore::parse("# comment\n/node_modules\n*.md\n!README.md\n**/*.log\ntarget/\n").unwrap();
+            let excluded = |path: &str| dockerignore.excludes(Path::new(path));
+            assert!(excluded("node_modules/left-pad/index.js"));
+            assert!(!excluded("web/node_modules/left-pad/index.js"));
+            assert!(excluded("CHANGELOG.md"));
+            assert!(!excluded("README.md"));
+            assert!(!excluded("docs/guide.md"), "* does not match across directories");
+            assert!(excluded("app.log"));
+            assert!(excluded("logs/2024/app.log"));
+            assert!(excluded("target/release/app"));
+            assert!(!excluded("src/main.rs"));
+            assert!(!Dockerignore::default().excludes(Path::new("node_modules/x")));
+        }
+
+        #[test]
+        pub fn git_context() {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().to_str().unwrap();
+            let git = |args: &[&str]| assert!(std::process::Command::new("git").args(args).current_dir(path).status().unwrap().success());
+            git(&["init", "--quiet"]);
+            std::fs::write(dir.path().join("main.go"), "").unwrap();
+            std::fs::write(dir.path().join("README.md"), "").unwrap();
+            std::fs::write(dir.path().join(".dockerignore"), "*.md\n").unwrap();
+            std::fs::write(dir.path().join(".env"), "SECRET=1").unwrap();
+            std::fs::create_dir(dir.path().join("build")).unwrap();
+            std::fs::write(dir.path().join("build/app.jar"), "").unwrap();
+            git(&["add", "main.go", "README.md", ".dockerignore"]);
+
+            let files = files(path, BuildContext::Git, &["build/*.jar".to_string()], &[]).unwrap();
+            assert_eq!(files, [".dockerignore", "build/app.jar", "main.go"].map(PathBuf::from));
+
+            let dockerfile = dir.path().join("Dockerfile");
+            std::fs::write(&dockerfile, "FROM scratch\n").unwrap();
+            let mut archive = Vec::new();
+            write(&mut archive, dir.path(), &files, &dockerfile).unwrap();
+            let mut archive = tar::Archive::new(archive.as_slice());
+            let entries: Vec<String> = archive.entries().unwrap().map(|entry| entry.unwrap().path().unwrap().to_string_lossy().to_string()).collect();
+            assert_eq!(entries, [".dockerignore", "build/app.jar", "main.go", DOCKERFILE]);
+        }
+
+        #[test]
+        pub fn directory_context() {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().to_str().unwrap();
+            for file in ["go.mod", "cmd/app/main.go", "build/app", "vendor/x/x.go", ".nb/dockerfiles/app.Dockerfile"] {
+                std::fs::create_dir_all(dir.path().join(file).parent().unwrap()).unwrap();
+                std::fs::write(dir.path().join(file), "").unwrap();
+            }
+            std::fs::write(dir.path().join(".dockerignore"), "vendor\n").unwrap();
+            let files = files(path, BuildContext::Directory, &[], &["build"]).unwrap();
+            assert_eq!(files, [".dockerignore", "cmd/app/main.go", "go.mod"].map(PathBuf::from));
+        }
     }
 }
 
-/// Build a Docker image and tag it using the provided tag.
-pub fn build(docker_file_builder: &Box<dyn SDK>, tag: &str) -> Result<(), Error> {
-    let mut file = tempfile::NamedTempFile::new()?;
+/// Write the Dockerfile generated by the SDK to the workspace of the source directory, named after
+/// the application so that it can be inspected after the build, and return its path.
+pub fn write_dockerfile(docker_file_builder: &dyn SDK, app: &str) -> Result<PathBuf, Error> {
+    let path = Workspace::new(docker_file_builder.filesystem_path()).create_dockerfile(app)?;
+    let mut file = tempfile::NamedTempFile::new_in(path.parent().expect("Dockerfiles are in a directory"))?;
     file.write_all(docker_file_builder.dockerfile().map_err(Error::Generate)?.as_bytes())?;
+    // Replace atomically, in case another build of the same application is running.
+    file.persist(&path).map_err(|err| err.error)?;
+    Ok(path)
+}
+
+/// Label set on every image built by nb from a Git repository, see [labels].
+const BUILT_BY_NB_LABEL: &str = "io.nais.build.git.branch";
 
-    std::process::Command::new("docker")
-        .arg("build")
+/// IDs of images built by nb that are no longer tagged, because a later build took over the tag.
+pub async fn dangling_images(cancel: &CancellationToken) -> Result<Vec<String>, Error> {
+    let output = crate::process::output(Command::new("docker")
+        .args(["image", "ls", "--quiet", "--no-trunc", "--filter", "dangling=true", "--filter"])
+        .arg(format!("label={BUILT_BY_NB_LABEL}"))
+        .stderr(Stdio::inherit()), cancel).await?;
+    if !output.status.success() {
+        return Err(Error::List(output.status));
+    }
+    let mut ids: Vec<String> = String::from_utf8_lossy(&output.stdout).lines().map(str::to_string).collect();
+    ids.dedup();
+    Ok(ids)
+}
+
+/// Whether the image exists in its registry, using the credentials of `docker login`.
+pub async fn exists_in_registry(image_name: &str, cancel: &CancellationToken) -> Result<bool, Error> {
+    let status = crate::process::status(Command::new("docker")
+        .args(["manifest", "inspect", image_name])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null()), cancel).await?;
+    Ok(status.success())
+}
+
+/// Digest of the manifest of an image in its registry, e.g. `sha256:abcdef...`, without pulling it.
+/// `None` if the image or the registry cannot be reached.
+pub async fn manifest_digest(image_name: &str, cancel: &CancellationToken) -> Result<Option<String>, Error> {
+    if let Some((_, digest)) = image_name.split_once('@') {
+        return Ok(Some(digest.to_string()));
+    }
+    let output = crate::process::output(Command::new("docker")
+        .args(["buildx", "imagetools", "inspect", "--format", "{{.Manifest.Digest}}", image_name])
+        .stderr(Stdio::null()), cancel).await?;
+    let digest = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    Ok(Some(digest).filter(|digest| output.status.success() && digest.starts_with("sha256:")))
+}
+
+pub async fn pull(image_name: &str, cancel: &CancellationToken) -> Result<(), Error> {
+    let step = progress::step("pull");
+    let exit_status = crate::process::run(&step, Command::new("docker").arg("pull").arg(image_name), cancel).await?;
+    if !exit_status.success() {
+        return Err(Error::Pull(exit_status));
+    }
+    step.finish();
+    Ok(())
+}
+
+/// Give the image `source` another name.
+pub async fn tag(source: &str, target: &str, cancel: &CancellationToken) -> Result<(), Error> {
+    let status = crate::process::status(Command::new("docker").args(["tag", source, target]), cancel).await?;
+    if !status.success() {
+        return Err(Error::Tag(status));
+    }
+    Ok(())
+}
+
+/// Build `target` from `source` with `labels`, e.g. to label an image that was built from the same inputs
+/// at another commit with the metadata of this one. Only the configuration of the image changes.
+pub async fn relabel(source: &str, target: &str, labels: &BTreeMap<String, String>, cancel: &CancellationToken) -> Result<(), Error> {
+    let mut command = Command::new("docker");
+    command.args(["build", "--quiet"]);
+    for (key, value) in labels {
+        command.arg("--label").arg(format!("{key}={value}"));
+    }
+    command.args(["--tag", target, "-"]).stdout(Stdio::null());
+    // A build without context reads the Dockerfile from standard input.
+    let status = crate::process::status_with_input(&mut command, format!("FROM {source}\n").as_bytes(), cancel).await?;
+    if !status.success() {
+        return Err(Error::Build(status));
+    }
+    Ok(())
+}
+
+pub async fn remove_images(ids: &[String], cancel: &CancellationToken) -> Result<(), Error> {
+    let status = crate::process::status(Command::new("docker")
+        .args(["image", "rm"])
+        .args(ids)
+        .stdout(Stdio::null()), cancel).await?;
+    if !status.success() {
+        return Err(Error::Remove(status));
+    }
+    Ok(())
+}
+
+/// Build the image from a Dockerfile, streaming the build context of `files` in `source_directory` to the builder:
+/// the local Docker daemon, or the buildx builder named `builder`, from which the image is loaded into the local daemon.
+pub async fn build(dockerfile: &Path, tag: &str, labels: &BTreeMap<String, String>, source_directory: &Path, files: &[PathBuf], builder: Option<&str>, cancel: &CancellationToken) -> Result<BTreeMap<String, f64>, Error> {
+    let mut process = Command::from(build_command(tag, labels, builder));
+    let (reader, writer) = std::io::pipe()?;
+    process.stdin(reader);
+    let (dockerfile, source_directory, files) = (dockerfile.to_path_buf(), source_directory.to_path_buf(), files.to_vec());
+    let sender = tokio::task::spawn_blocking(move || context::write(writer, &source_directory, &files, &dockerfile));
+    let step = progress::step("build");
+    let mut stages = StageTimings::default();
+    let exit_status = crate::process::run_observed(&step, &mut process, Some(&mut |line| stages.line(line)), cancel).await;
+    // Close our end of the pipe, so that the archive is not written forever if docker exits without reading it.
+    drop(process);
+    let sent = sender.await.expect("build context is written");
+    let exit_status = exit_status?;
+    if !exit_status.success() {
+        return Err(Error::Build(exit_status));
+    }
+    sent?;
+    step.finish();
+    Ok(stages.stages)
+}
+
+/// Seconds spent in each stage of a build, read from BuildKit's plain progress output, where
+/// each build step is a numbered vertex like `#5 [builder 2/4] RUN go build`, finished by `#5 DONE 3.2s`.
+/// Cached steps take no time. The legacy builder does not report stages.
+#[derive(Default)]
+struct StageTimings {
+    /// Stage of each vertex.
+    vertices: BTreeMap<String, String>,
+    stages: BTreeMap<String, f64>,
+}
+
+impl StageTimings {
+    fn line(&mut self, line: &str) {
+        let Some((vertex, rest)) = line.strip_prefix('#').and_then(|line| line.split_once(' ')) else {
+            return;
+        };
+        if let Some(name) = rest.strip_prefix('[').and_then(|rest| rest.split_once(']')).map(|(name, _)| name) {
+            // `builder 2/4` is step 2 of the `builder` stage; `internal` is BuildKit's own work.
+            let stage = name.split_once(' ').map_or(name, |(stage, _)| stage);
+            self.vertices.insert(vertex.to_string(), stage.to_string());
+        } else if let Some(seconds) = rest.strip_prefix("DONE ").and_then(|done| done.strip_suffix('s')).and_then(|done| done.parse::<f64>().ok()) {
+            if let Some(stage) = self.vertices.get(vertex) {
+                *self.stages.entry(stage.clone()).or_default() += seconds;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_stage_timings() {
+    let output = "#1 [internal] load build definition from Dockerfile\n#1 DONE 0.1s\n\
+        #5 [builder 2/4] RUN go build\n#5 0.512 go: downloading\n#6 [builder 1/4] WORKDIR /src\n#6 CACHED\n\
+        #5 DONE 3.2s\n#7 [stage-1 1/2] COPY --from=builder /src/app /app\n#7 DONE 0.25s\n\
+        #8 [builder 3/4] RUN go test\n#8 DONE 1.0s\n#9 exporting to image\n#9 DONE 0.5s\n";
+    let mut timings = StageTimings::default();
+    output.lines().for_each(|line| timings.line(line));
+    assert_eq!(timings.stages, BTreeMap::from([
+        ("builder".to_string(), 4.2),
+        ("internal".to_string(), 0.1),
+        ("stage-1".to_string(), 0.25),
+    ]));
+}
+
+/// The `docker build` invocation of [build], reading the build context from standard input.
+pub fn build_command(tag: &str, labels: &BTreeMap<String, String>, builder: Option<&str>) -> std::process::Command {
+    let mut process = std::process::Command::new("docker");
+    match builder {
+        Some(builder) => process.args(["buildx", "build", "--builder", builder, "--load"]),
+        None => process.arg("build"),
+    };
+    for (key, value) in labels {
+        process.arg("--label").arg(format!("{key}={value}"));
+    }
+    process
         .arg("--file")
-        .arg(file.path())
+        .arg(context::DOCKERFILE)
         .arg("--tag")
         .arg(tag)
-        .arg(docker_file_builder.filesystem_path())
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .status()
-        .map(|exit_status| {
-            if exit_status.success() {
-                Ok(())
-            } else {
-                Err(Error::Build(exit_status))
-            }
-        })?
+        .arg("-");
+    process
+}
+
+/// Run the SDK's tests in its builder image, with the source directory mounted as the working directory.
+/// Returns the exit status of the tests.
+pub async fn test(sdk: &dyn SDK, source_directory: &Path, cancel: &CancellationToken) -> Result<ExitStatus, Error> {
+    let source_directory = std::fs::canonicalize(source_directory)?;
+    let command = sdk.test_command();
+    Ok(crate::process::status(Command::new("docker")
+        .arg("run")
+        .arg("--rm")
+        .arg("--volume")
+        .arg(format!("{}:/src", source_directory.display()))
+        .arg("--workdir")
+        .arg("/src")
+        .arg(sdk.builder_docker_image())
+        .args(command)
+        .stdout(crate::report::child_stdout())
+        .stderr(Stdio::inherit()), cancel).await?)
+}
+
+/// Start a container from `image_name` in the foreground, removing it when it exits.
+pub fn run(image_name: &str, args: &[String]) -> Result<tokio::process::Child, Error> {
+    Ok(crate::process::spawn(Command::new("docker")
+        .arg("run")
+        .arg("--rm")
+        .args(args)
+        .arg(image_name)
+        .stdout(crate::report::child_stdout())
+        .stderr(Stdio::inherit()))?)
+}
+
+/// Docker client configuration in `$DOCKER_CONFIG/config.json`, or `~/.docker/config.json`.
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct ClientConfig {
+    #[serde(default)]
+    cred_helpers: BTreeMap<String, String>,
+    creds_store: Option<String>,
+}
+
+impl ClientConfig {
+    fn load() -> Self {
+        let directory = std::env::var("DOCKER_CONFIG").ok()
+            .or_else(|| std::env::var("HOME").ok().map(|home| format!("{home}/.docker")));
+        let Some(directory) = directory else {
+            return Self::default();
+        };
+        std::fs::read_to_string(Path::new(&directory).join("config.json")).ok()
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default()
+    }
+
+    /// Name of the credential helper for a registry host: `docker-credential-<name>`.
+    fn helper(&self, host: &str) -> Option<&str> {
+        self.cred_helpers.get(host).or(self.creds_store.as_ref()).map(String::as_str)
+    }
+}
+
+/// Credentials as returned by `docker-credential-<helper> get`.
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct HelperCredentials {
+    username: String,
+    secret: String,
+}
+
+/// Look up credentials for a registry in the credential helper configured for Docker,
+/// such as `docker-credential-gcloud` or `docker-credential-osxkeychain`.
+///
+/// Returns `None` if no helper is configured, or the helper has no credentials for the registry.
+pub fn helper_credentials(registry: &str) -> Option<crate::auth::Credentials> {
+    let host = registry_host(registry);
+    let helper = format!("docker-credential-{}", ClientConfig::load().helper(host)?);
+    debug!("Asking {helper} for credentials for {host}");
+    let output = crate::process::output_blocking(std::process::Command::new(&helper).arg("get"), Some(host.as_bytes()));
+    match output {
+        Ok(output) if output.status.success() => {
+            let credentials: HelperCredentials = serde_json::from_slice(&output.stdout).ok()?;
+            Some(crate::auth::Credentials { username: credentials.username, password: credentials.secret, expires_at: None })
+                .filter(|credentials| !credentials.password.is_empty())
+        }
+        Ok(output) => {
+            debug!("{helper} has no credentials for {host}: {}", String::from_utf8_lossy(&output.stdout).trim());
+            None
+        }
+        Err(err) => {
+            debug!("execute {helper}: {err}");
+            None
+        }
+    }
+}
+
+/// Credentials are stored per host, so `ghcr.io/navikt` must be logged in to as `ghcr.io`.
+fn registry_host(registry: &str) -> &str {
+    registry.split('/').next().unwrap_or(registry)
 }
 
 /// Docker sessions are used for uploading artifacts to a Docker registry.
-/// Sessions are created by logging into a registry.
+/// Sessions are created by logging into a registry, or by reusing existing credentials.
 pub struct Session {
     registry: String,
+    /// Whether the session logged in, and must log out again.
+    logged_in: bool,
 }
 
 impl Drop for Session {
     fn drop(&mut self) {
+        if !self.logged_in {
+            return;
+        }
         if let Err(e) = self.logout() {
             error!("failed to logout from Docker: {e}")
         }
@@ -144,27 +901,27 @@ impl Drop for Session {
 }
 
 impl Session {
+    /// Use credentials that Docker already has for a registry, e.g. through a credential helper.
+    /// The credentials are left in place when the session ends.
+    pub fn existing(registry: &str) -> Self {
+        Session { registry: registry_host(registry).to_string(), logged_in: false }
+    }
+
     /// Log in to a Docker registry, retaining credentials in ~/.docker/config.json
     /// for the lifetime of the returned Session object.
-    ///
-    /// FIXME: credential helpers seem to obstruct usage of this token
-    pub fn new(registry: &str, token: &str) -> Result<Self, Error> {
+    pub async fn new(registry: &str, credentials: &crate::auth::Credentials, cancel: &CancellationToken) -> Result<Self, Error> {
+        let registry = registry_host(registry);
         debug!("Logging in to Docker registry {}", registry);
-        let mut child = std::process::Command::new("docker")
+        let status = crate::process::status_with_input(Command::new("docker")
             .arg("login")
             .arg(registry)
             .arg("--username")
-            .arg("oauth2accesstoken") // TODO: this only works for GAR
+            .arg(&credentials.username)
             .arg("--password-stdin")
-            .stdin(Stdio::piped())
-            .stdout(Stdio::inherit())
-            .stderr(Stdio::inherit())
-            .spawn().map_err(IOError)?;
-
-        child.stdin.as_mut().unwrap().write_all(token.as_bytes())?;
-        let status = child.wait_with_output()?.status;
+            .stdout(crate::report::child_stdout())
+            .stderr(Stdio::inherit()), credentials.password.as_bytes(), cancel).await?;
         if status.success() {
-            Ok(Session { registry: registry.to_string() })
+            Ok(Session { registry: registry.to_string(), logged_in: true })
         } else {
             Err(Error::Login(status))
         }
@@ -172,15 +929,14 @@ impl Session {
 
     /// Remove credentials for this registry by running `docker logout`.
     /// This function is called automatically when the `Session` object
-    /// goes out of scope.
+    /// goes out of scope, which is why it blocks.
     pub fn logout(&self) -> Result<(), Error> {
         debug!("Logging out of Docker registry {}", &self.registry);
-        std::process::Command::new("docker")
+        crate::process::status_blocking(std::process::Command::new("docker")
             .arg("logout")
             .arg(&self.registry)
-            .stdout(Stdio::inherit())
-            .stderr(Stdio::inherit())
-            .status()
+            .stdout(crate::report::child_stdout())
+            .stderr(Stdio::inherit()))
             .map(|exit_status| {
                 if exit_status.success() {
                     Ok(())
@@ -191,20 +947,14 @@ impl Session {
     }
 
     /// Push a Docker image to the registry.
-    pub fn push(&self, image_name: &str) -> Result<(), Error> {
+    pub async fn push(&self, image_name: &str, cancel: &CancellationToken) -> Result<(), Error> {
         debug!("Pushing image: {}", image_name);
-        std::process::Command::new("docker")
-            .arg("push")
-            .arg(image_name)
-            .stdout(Stdio::inherit())
-            .stderr(Stdio::inherit())
-            .status()
-            .map(|exit_status| {
-                if exit_status.success() {
-                    Ok(())
-                } else {
-                    Err(Error::Push(exit_status))
-                }
-            })?
+        let step = progress::step("push");
+        let exit_status = crate::process::run(&step, Command::new("docker").arg("push").arg(image_name), cancel).await?;
+        if !exit_status.success() {
+            return Err(Error::Push(exit_status));
+        }
+        step.finish();
+        Ok(())
     }
 }
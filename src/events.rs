@@ -0,0 +1,158 @@
+//! Lifecycle events of the pipeline, published on a [Bus] as the steps run.
+//!
+//! Reporting subscribes to the bus instead of being done by the steps: the JSON report records
+//! the timings of the steps, [crate::telemetry] records events on the trace of the command, and
+//! [crate::notify] posts the outcome to Slack. Subscribers get their own copy of each event,
+//! and one that falls behind misses the oldest events rather than holding up the pipeline.
+
+use std::future::Future;
+use std::time::Duration;
+use log::warn;
+use serde::Serialize;
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+
+/// Events kept for subscribers that have not received them yet.
+const CAPACITY: usize = 256;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// A pipeline of `steps` is about to run for the application.
+    Pipeline {
+        team: String,
+        app: String,
+        image: String,
+        steps: Vec<String>,
+    },
+    /// An attempt at a step started. Attempts after the first are retries.
+    StepStarted {
+        step: String,
+        attempt: u32,
+    },
+    StepFinished {
+        step: String,
+        #[serde(serialize_with = "seconds")]
+        elapsed: Duration,
+    },
+    /// An attempt at a step failed. The pipeline fails unless the step is `retrying`.
+    StepFailed {
+        step: String,
+        error: String,
+        retrying: bool,
+    },
+}
+
+fn seconds<S: serde::Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
+
+/// Publishes events to every subscriber.
+pub struct Bus {
+    sender: broadcast::Sender<Event>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Default for Bus {
+    fn default() -> Self {
+        Bus { sender: broadcast::channel(CAPACITY).0, tasks: Vec::new() }
+    }
+}
+
+impl Bus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Receive the events published from now on.
+    pub fn subscribe(&self) -> Receiver {
+        Receiver { receiver: self.sender.subscribe() }
+    }
+
+    /// Run `subscriber` on its own task until the bus is closed, see [Bus::close].
+    pub fn spawn<F: Future<Output = ()> + Send + 'static>(&mut self, subscriber: impl FnOnce(Receiver) -> F) {
+        let task = tokio::spawn(subscriber(self.subscribe()));
+        self.tasks.push(task);
+    }
+
+    /// Publish `event`. Events with no subscribers are dropped.
+    pub fn publish(&self, event: Event) {
+        let _ = self.sender.send(event);
+    }
+
+    /// Stop publishing, and wait up to `timeout` for spawned subscribers to handle the events they have.
+    pub async fn close(self, timeout: Duration) {
+        drop(self.sender);
+        let tasks = async {
+            for task in self.tasks {
+                let _ = task.await;
+            }
+        };
+        if tokio::time::timeout(timeout, tasks).await.is_err() {
+            warn!("Gave up waiting for event subscribers after {}s", timeout.as_secs());
+        }
+    }
+}
+
+/// Events for one subscriber, in the order they were published.
+#[derive(Debug)]
+pub struct Receiver {
+    receiver: broadcast::Receiver<Event>,
+}
+
+impl Receiver {
+    /// The next event, or `None` once the bus is closed.
+    pub async fn recv(&mut self) -> Option<Event> {
+        loop {
+            match self.receiver.recv().await {
+                Ok(event) => return Some(event),
+                Err(broadcast::error::RecvError::Lagged(missed)) => warn!("Event subscriber missed {missed} event(s)"),
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    /// The events that have been published, without waiting for more.
+    pub fn drain(&mut self) -> Vec<Event> {
+        let mut events = Vec::new();
+        loop {
+            match self.receiver.try_recv() {
+                Ok(event) => events.push(event),
+                Err(broadcast::error::TryRecvError::Lagged(missed)) => warn!("Event subscriber missed {missed} event(s)"),
+                Err(_) => return events,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[tokio::test]
+    pub async fn publish_to_subscribers() {
+        let mut bus = Bus::new();
+        let mut receiver = bus.subscribe();
+        let (sender, spawned) = tokio::sync::oneshot::channel();
+        bus.spawn(|mut receiver| async move {
+            let mut events = Vec::new();
+            while let Some(event) = receiver.recv().await {
+                events.push(event);
+            }
+            let _ = sender.send(events);
+        });
+
+        bus.publish(Event::StepStarted { step: "build".into(), attempt: 1 });
+        bus.publish(Event::StepFinished { step: "build".into(), elapsed: Duration::from_millis(1500) });
+        assert_eq!(receiver.drain().len(), 2);
+        assert_eq!(receiver.drain(), []);
+        bus.close(Duration::from_secs(5)).await;
+
+        let events = spawned.await.unwrap();
+        assert_eq!(events[0], Event::StepStarted { step: "build".into(), attempt: 1 });
+        assert_eq!(
+            serde_json::to_value(&events[1]).unwrap(),
+            serde_json::json!({"event": "step_finished", "step": "build", "elapsed": 1.5}),
+        );
+    }
+}
@@ -0,0 +1,164 @@
+//! Interaction with the Google Artifact Registry API, to check the target repository before pushing.
+
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+use log::{debug, info};
+use serde::Deserialize;
+use thiserror::Error;
+
+const API_URL: &str = "https://artifactregistry.googleapis.com/v1";
+
+/// Permission needed to push images, granted by `roles/artifactregistry.writer`.
+const UPLOAD_PERMISSION: &str = "artifactregistry.repositories.uploadArtifacts";
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("registry `{0}` is not a GAR registry")]
+    InvalidRegistry(String),
+
+    #[error("GAR repository {0} does not exist; create it, or set `[release] create_repository = true`")]
+    NotFound(Repository),
+
+    #[error("missing permission {UPLOAD_PERMISSION} on GAR repository {0}; grant roles/artifactregistry.writer")]
+    PermissionDenied(Repository),
+
+    #[error("creating GAR repository {0} did not complete in time")]
+    CreateTimeout(Repository),
+
+    #[error("reqwest: {0}")]
+    Reqwest(#[from] reqwest::Error),
+
+    #[error("Artifact Registry API returned {status}: {body}")]
+    Api {
+        status: u16,
+        body: String,
+    },
+}
+
+/// Docker repository in Google Artifact Registry.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Repository {
+    pub project: String,
+    pub location: String,
+    pub name: String,
+}
+
+impl Display for Repository {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "projects/{}/locations/{}/repositories/{}", self.project, self.location, self.name)
+    }
+}
+
+impl Repository {
+    /// The repository that images are pushed to, given a registry on the form
+    /// `<location>-docker.pkg.dev/<project>[/<repository>]`. Without a repository in the
+    /// registry, images are pushed to a repository named after the team.
+    pub fn from_registry(registry: &str, team: &str) -> Result<Self, Error> {
+        let mut parts = registry.split('/');
+        let location = parts.next()
+            .and_then(|host| host.strip_suffix("-docker.pkg.dev"))
+            .ok_or_else(|| Error::InvalidRegistry(registry.to_string()))?;
+        let project = parts.next().ok_or_else(|| Error::InvalidRegistry(registry.to_string()))?;
+        Ok(Self {
+            project: project.to_string(),
+            location: location.to_string(),
+            name: parts.next().unwrap_or(team).to_string(),
+        })
+    }
+}
+
+/// Check that a repository exists and that the caller may push to it, creating it first if `create` is set.
+pub async fn ensure_repository(repository: &Repository, access_token: &str, create: bool) -> Result<(), Error> {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()?;
+
+    debug!("Checking GAR repository {repository}");
+    let resp = client.get(format!("{API_URL}/{repository}")).bearer_auth(access_token).send().await?;
+    match resp.status().as_u16() {
+        200 => {}
+        404 if create => create_repository(&client, repository, access_token).await?,
+        404 => return Err(Error::NotFound(repository.clone())),
+        403 => return Err(Error::PermissionDenied(repository.clone())),
+        status => return Err(Error::Api { status, body: resp.text().await? }),
+    }
+
+    #[derive(Deserialize)]
+    struct Permissions {
+        #[serde(default)]
+        permissions: Vec<String>,
+    }
+
+    let resp = client.post(format!("{API_URL}/{repository}:testIamPermissions"))
+        .bearer_auth(access_token)
+        .json(&serde_json::json!({ "permissions": [UPLOAD_PERMISSION] }))
+        .send()
+        .await?;
+    let status = resp.status().as_u16();
+    if status != 200 {
+        return Err(Error::Api { status, body: resp.text().await? });
+    }
+    let granted: Permissions = resp.json().await?;
+    if !granted.permissions.iter().any(|permission| permission == UPLOAD_PERMISSION) {
+        return Err(Error::PermissionDenied(repository.clone()));
+    }
+    Ok(())
+}
+
+/// Create a Docker repository, and wait for the operation to complete.
+async fn create_repository(client: &reqwest::Client, repository: &Repository, access_token: &str) -> Result<(), Error> {
+    const POLL_INTERVAL: Duration = Duration::from_secs(1);
+    const POLL_ATTEMPTS: u32 = 30;
+
+    #[derive(Deserialize)]
+    struct Operation {
+        name: String,
+        #[serde(default)]
+        done: bool,
+        error: Option<serde_json::Value>,
+    }
+
+    info!("Creating GAR repository {repository}");
+    let parent = format!("projects/{}/locations/{}", repository.project, repository.location);
+    let resp = client.post(format!("{API_URL}/{parent}/repositories"))
+        .query(&[("repositoryId", &repository.name)])
+        .bearer_auth(access_token)
+        .json(&serde_json::json!({ "format": "DOCKER" }))
+        .send()
+        .await?;
+    let status = resp.status().as_u16();
+    match status {
+        200 => {}
+        // Created concurrently by another job.
+        409 => return Ok(()),
+        _ => return Err(Error::Api { status, body: resp.text().await? }),
+    }
+
+    let mut operation: Operation = resp.json().await?;
+    for _ in 0..POLL_ATTEMPTS {
+        if let Some(error) = operation.error {
+            return Err(Error::Api { status, body: error.to_string() });
+        }
+        if operation.done {
+            return Ok(());
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+        let resp = client.get(format!("{API_URL}/{}", operation.name)).bearer_auth(access_token).send().await?;
+        operation = resp.json().await?;
+    }
+    Err(Error::CreateTimeout(repository.clone()))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    pub fn repository_from_registry() {
+        let repository = Repository::from_registry("europe-north1-docker.pkg.dev/nais-management-233d", "myteam").unwrap();
+        assert_eq!(repository.to_string(), "projects/nais-management-233d/locations/europe-north1/repositories/myteam");
+        let repository = Repository::from_registry("europe-north1-docker.pkg.dev/nais-management-233d/shared", "myteam").unwrap();
+        assert_eq!(repository.name, "shared");
+        assert!(Repository::from_registry("ghcr.io/navikt", "myteam").is_err());
+    }
+}
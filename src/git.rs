@@ -1,3 +1,13 @@
+//! Read information about the Git repository containing the source code.
+//!
+//! Repositories are read in-process with libgit2, so that `git` does not need to be installed.
+//! If libgit2 fails, e.g. because of a repository format it does not support,
+//! the `git` executable is used as a fallback.
+//!
+//! Like `git` itself, the `GIT_DIR` and `GIT_WORK_TREE` environment variables take precedence
+//! over discovering the repository from the source directory.
+
+use log::{debug, info};
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -9,47 +19,730 @@ pub enum Error {
     ParseGitShortSha(#[from] std::string::FromUtf8Error),
 
     #[error("could not parse git remote '{0}'")]
-    ParseRemote(String)
+    ParseRemote(String),
+
+    #[error("git {command} exited with {status}: {stderr}")]
+    Command {
+        command: String,
+        status: std::process::ExitStatus,
+        stderr: String,
+    },
+
+    #[error("{0}")]
+    Library(#[from] git2::Error),
+
+    #[error("submodules are not checked out, and updating them failed: {0}; \
+        check out submodules before building, e.g. `submodules: recursive` with actions/checkout")]
+    Submodules(Box<Error>),
+
+    #[error("{0} does not have a valid signature: {1}")]
+    Unsigned(String, String),
+
+    #[error("repository is a shallow clone, and fetching the full history failed: {0}; \
+        clone with full history instead, e.g. `fetch-depth: 0` with actions/checkout")]
+    Shallow(Box<Error>),
 }
 
 /// Return the currently checked out Git short SHA sum.
 ///
-/// Git-related values will be generated by the currently installed `git` executable.
-/// Returns an error if `git` is not installed, or if short sha parsing failed.
+/// Returns an error if the source directory is not inside a Git repository.
 ///
-/// If working tree is dirty, tag will be suffixed with `-dirty`.
+/// If working tree is dirty, i.e. has uncommitted changes or untracked files,
+/// tag will be suffixed with `-dirty`.
 ///
 /// Example output: `abcdef` or `abcdef-dirty`
 pub fn short_sha(filesystem_path: &str) -> Result<String, Error> {
-    let git_tree_dirty = std::process::Command::new("git")
-        .arg("ls-files")
-        .arg("--exclude-standard")
-        .arg("--others")
-        .current_dir(filesystem_path)
-        .output()
-        .map(|output| output.stdout.len() > 0)
-        .map_err(Error::FailedExecute)?;
-
-    let git_short_sha = std::process::Command::new("git")
-        .arg("rev-parse")
-        .arg("--short").arg("HEAD")
-        .current_dir(filesystem_path)
-        .output()
-        .map(|output| String::from_utf8(output.stdout))
-        .map_err(Error::FailedExecute)?
-        .map_err(Error::ParseGitShortSha)
-        .map(|short_sha| short_sha.trim().to_string())
-        ?;
-
+    let (git_short_sha, git_tree_dirty) = with_fallback(filesystem_path, embedded::short_sha, cli::short_sha)?;
     Ok(match git_tree_dirty {
         true => format!("{git_short_sha}-dirty"),
-        false => format!("{git_short_sha}"),
+        false => git_short_sha,
+    })
+}
+
+/// Return the paths of files with uncommitted changes, including untracked files,
+/// relative to the repository root.
+pub fn dirty_files(filesystem_path: &str) -> Result<Vec<String>, Error> {
+    with_fallback(filesystem_path, embedded::dirty_files, cli::dirty_files)
+}
+
+/// Return the files tracked by Git in the source directory, relative to the source directory.
+///
+/// Files staged for commit are included, and files that have been deleted from the working tree are not.
+/// Submodules are replaced with the files tracked in them.
+pub fn tracked_files(filesystem_path: &str) -> Result<Vec<std::path::PathBuf>, Error> {
+    let files = with_fallback(
+        filesystem_path,
+        |repository| embedded::tracked_files(repository, filesystem_path),
+        cli::tracked_files,
+    )?;
+    let directory = std::path::Path::new(filesystem_path);
+    let mut tracked = Vec::new();
+    for path in files {
+        let full_path = directory.join(&path);
+        if full_path.is_dir() {
+            let submodule_files = tracked_files(&full_path.to_string_lossy())?;
+            tracked.extend(submodule_files.into_iter().map(|file| path.join(file)));
+        } else if full_path.exists() {
+            tracked.push(path);
+        }
+    }
+    Ok(tracked)
+}
+
+/// Return the submodules of the repository, with the commits recorded for them.
+pub fn submodules(filesystem_path: &str) -> Result<Vec<Submodule>, Error> {
+    with_fallback(filesystem_path, embedded::submodules, cli::submodules)
+}
+
+/// Make sure that all submodules are checked out at their recorded commits.
+///
+/// Uninitialized or outdated submodules are updated with `git submodule update --init --recursive`,
+/// which requires access to the submodule repositories.
+pub fn ensure_submodules(filesystem_path: &str) -> Result<(), Error> {
+    let outdated: Vec<_> = submodules(filesystem_path)?
+        .into_iter()
+        .filter(|submodule| !submodule.checked_out)
+        .map(|submodule| submodule.path)
+        .collect();
+    if outdated.is_empty() {
+        return Ok(());
+    }
+    info!("Updating submodules: {}", outdated.join(", "));
+    cli::update_submodules(filesystem_path).map_err(|err| Error::Submodules(Box::new(err)))
+}
+
+/// Return the full SHA sum of the currently checked out commit.
+pub fn sha(filesystem_path: &str) -> Result<String, Error> {
+    with_fallback(filesystem_path, embedded::sha, cli::sha)
+}
+
+/// Return the name of the currently checked out branch.
+///
+/// CI systems usually check out a detached HEAD, e.g. the merge commit of a pull request on
+/// GitHub Actions. In that case, the branch is read from the CI environment, see [branch_from_ci].
+/// If HEAD is detached and the branch is not known, this function returns `HEAD`.
+pub fn branch(filesystem_path: &str) -> Result<String, Error> {
+    let branch = with_fallback(filesystem_path, embedded::branch, cli::branch)?;
+    if branch != DETACHED_HEAD {
+        return Ok(branch);
+    }
+    let var = |name: &str| std::env::var(name).ok();
+    Ok(branch_from_ci(var).unwrap_or(branch))
+}
+
+/// Branch name returned by [branch] when it is not known.
+pub const DETACHED_HEAD: &str = "HEAD";
+
+/// Read the branch being built from CI environment variables.
+///
+/// For pull and merge requests, this is the source branch rather than the target branch.
+fn branch_from_ci<F: Fn(&str) -> Option<String>>(var: F) -> Option<String> {
+    let var = |name: &str| var(name).filter(|value| !value.is_empty());
+    let branch_ref = |name: &str| var(name).and_then(|value| value.strip_prefix("refs/heads/").map(str::to_string));
+    // GitHub Actions
+    var("GITHUB_HEAD_REF")
+        .or_else(|| branch_ref("GITHUB_REF"))
+        // GitLab CI
+        .or_else(|| var("CI_MERGE_REQUEST_SOURCE_BRANCH_NAME"))
+        .or_else(|| var("CI_COMMIT_BRANCH"))
+        // Azure Pipelines
+        .or_else(|| branch_ref("SYSTEM_PULLREQUEST_SOURCEBRANCH"))
+        .or_else(|| branch_ref("BUILD_SOURCEBRANCH"))
+        // Jenkins
+        .or_else(|| var("CHANGE_BRANCH"))
+        .or_else(|| var("BRANCH_NAME"))
+}
+
+/// Return metadata about a Git repository and its currently checked out commit.
+pub fn metadata(filesystem_path: &str) -> Result<RepositoryMetadata, Error> {
+    let origin = with_fallback(filesystem_path, embedded::origin, cli::origin)?;
+    let (owner, name) = parse_origin(&origin).ok_or_else(|| Error::ParseRemote(origin.clone()))?;
+    let commit = with_fallback(filesystem_path, embedded::commit, cli::commit)?;
+    let submodules = submodules(filesystem_path)?
+        .into_iter()
+        .map(|submodule| (submodule.path, submodule.sha))
+        .collect();
+    Ok(RepositoryMetadata {
+        owner,
+        name,
+        remote_url: origin,
+        branch: branch(filesystem_path)?,
+        sha: commit.sha,
+        commit_time: commit.time,
+        author: commit.author,
+        submodules,
+    })
+}
+
+/// Return the version derived from the most recent `v<major>.<minor>.<patch>` tag reachable from HEAD,
+/// or `None` if there is no such tag.
+///
+/// In a shallow clone, the tag may be missing from the fetched history.
+/// If no tag is found, the full history is fetched before trying again, see [deepen].
+pub fn version(filesystem_path: &str) -> Result<Option<Version>, Error> {
+    let mut description = with_fallback(filesystem_path, embedded::describe, cli::describe)?;
+    if description.is_none() && deepen(filesystem_path)? {
+        description = with_fallback(filesystem_path, embedded::describe, cli::describe)?;
+    }
+    Ok(description.as_deref().and_then(parse_description))
+}
+
+/// Return the subject lines of the commits made since the previous version tag, newest first.
+///
+/// A version tag pointing at HEAD is not considered previous, so that the commits
+/// belonging to a release are returned when HEAD is the release commit.
+/// If there are no version tags, all commits reachable from HEAD are returned.
+///
+/// A shallow clone is deepened first, so that history is not cut off before the previous tag.
+pub fn commits_since_release(filesystem_path: &str) -> Result<Vec<String>, Error> {
+    deepen(filesystem_path)?;
+    with_fallback(filesystem_path, embedded::commits_since_release, cli::commits_since_release)
+}
+
+/// Return the subject lines of the commits reachable from HEAD but not from `since`, newest first.
+///
+/// `since` can be any revision, e.g. a tag such as `v1.4.2`.
+pub fn commits_since(filesystem_path: &str, since: &str) -> Result<Vec<String>, Error> {
+    deepen(filesystem_path)?;
+    with_fallback(
+        filesystem_path,
+        |repository| embedded::commits_since(repository, since),
+        |filesystem_path| cli::commits_since(filesystem_path, since),
+    )
+}
+
+/// Verify the GPG or SSH signature of a commit, or of an annotated tag if `tag` is set,
+/// and return the fingerprint of the signing key.
+///
+/// Signatures are verified by the `git` executable, using the keys trusted by GnuPG
+/// and the SSH keys in `gpg.ssh.allowedSignersFile`.
+pub fn verify_signature(filesystem_path: &str, revision: &str, tag: bool) -> Result<String, Error> {
+    let command = if tag { "verify-tag" } else { "verify-commit" };
+    let output = crate::process::output_blocking(cli::command(filesystem_path).args([command, "--raw", revision]), None)?;
+    let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+    match parse_signing_key(&stderr) {
+        Some(key) if output.status.success() => Ok(key),
+        _ => Err(Error::Unsigned(revision.to_string(), stderr)),
+    }
+}
+
+/// Find the fingerprint of the signing key in the output of `git verify-commit --raw`.
+fn parse_signing_key(output: &str) -> Option<String> {
+    output.lines().find_map(|line| {
+        if let Some(status) = line.strip_prefix("[GNUPG:] VALIDSIG ") {
+            // GnuPG status line: VALIDSIG <fingerprint> <date> ...
+            return status.split_whitespace().next().map(str::to_string);
+        }
+        // SSH: Good "git" signature for <principal> with <type> key SHA256:<fingerprint>
+        let (_, key) = line.strip_prefix("Good \"git\" signature")?.rsplit_once(" key ")?;
+        Some(key.trim().to_string())
     })
 }
 
+/// Create an annotated tag pointing at HEAD.
+///
+/// The tagger is read from the Git configuration, falling back to a generic identity
+/// in environments such as CI where none is configured.
+pub fn create_tag(filesystem_path: &str, name: &str, message: &str) -> Result<(), Error> {
+    let repository = open(filesystem_path)?;
+    let head = repository.head()?.peel_to_commit()?;
+    let tagger = repository.signature().or_else(|_| git2::Signature::now("nais-build", "nb@nais.io"))?;
+    repository.tag(name, head.as_object(), &tagger, message, false)?;
+    Ok(())
+}
+
+/// Push a tag to the `origin` remote.
+pub fn push_tag(filesystem_path: &str, name: &str) -> Result<(), Error> {
+    cli::push_tag(filesystem_path, name)
+}
+
+/// Return the root directory of the working tree containing the source directory.
+pub fn root(filesystem_path: &str) -> Result<std::path::PathBuf, Error> {
+    with_fallback(filesystem_path, embedded::root, cli::root)
+}
+
+/// Return the files that have changed since the common ancestor of `since` and HEAD,
+/// including uncommitted changes and untracked files, relative to the repository root.
+///
+/// `since` can be any revision, e.g. `origin/main` or a commit SHA.
+///
+/// A shallow clone is deepened first, so that the common ancestor can be found.
+pub fn changed_files(filesystem_path: &str, since: &str) -> Result<Vec<std::path::PathBuf>, Error> {
+    deepen(filesystem_path)?;
+    with_fallback(
+        filesystem_path,
+        |repository| embedded::changed_files(repository, since),
+        |filesystem_path| cli::changed_files(filesystem_path, since),
+    )
+}
+
+/// Whether any file in the source directory, or in one of `dependencies`, relative to the repository root,
+/// has changed since the common ancestor of `since` and HEAD. See [changed_files].
+pub fn changed_since<P: AsRef<std::path::Path>>(filesystem_path: &str, dependencies: &[P], since: &str) -> Result<bool, Error> {
+    let root = std::fs::canonicalize(root(filesystem_path)?)?;
+    let source_directory = std::fs::canonicalize(filesystem_path)?;
+    let watched: Vec<_> = std::iter::once(source_directory).chain(dependencies.iter().map(|path| root.join(path))).collect();
+
+    let changed = changed_files(filesystem_path, since)?;
+    debug!("{} files changed since {since}", changed.len());
+    Ok(changed.iter().map(|path| root.join(path)).any(|path| watched.iter().any(|dir| path.starts_with(dir))))
+}
+
+/// Whether the repository is a shallow clone, i.e. only part of its history has been fetched.
+/// This is the default for most CI systems, including actions/checkout.
+pub fn is_shallow(filesystem_path: &str) -> Result<bool, Error> {
+    with_fallback(filesystem_path, |repository| Ok(repository.is_shallow()), cli::is_shallow)
+}
+
+/// Fetch the full history and all tags from `origin` if the repository is a shallow clone.
+///
+/// Returns whether the repository was deepened.
+pub fn deepen(filesystem_path: &str) -> Result<bool, Error> {
+    if !is_shallow(filesystem_path)? {
+        return Ok(false);
+    }
+    info!("Repository is a shallow clone; fetching full history from origin");
+    cli::deepen(filesystem_path).map_err(|err| Error::Shallow(Box::new(err)))?;
+    Ok(true)
+}
+
+/// Open the repository containing the source directory, honouring `GIT_DIR` and `GIT_WORK_TREE`.
+fn open(filesystem_path: &str) -> Result<git2::Repository, Error> {
+    if std::env::var_os("GIT_DIR").is_some() {
+        // Also reads GIT_WORK_TREE and the other variables supported by git.
+        return Ok(git2::Repository::open_from_env()?);
+    }
+    let repository = git2::Repository::discover(filesystem_path)?;
+    if let Some(work_tree) = std::env::var_os("GIT_WORK_TREE") {
+        repository.set_workdir(std::path::Path::new(&work_tree), false)?;
+    }
+    Ok(repository)
+}
+
+/// Read from the repository with libgit2, and retry with the `git` executable on failure.
+fn with_fallback<T, E, C>(filesystem_path: &str, embedded: E, cli: C) -> Result<T, Error>
+where
+    E: FnOnce(&git2::Repository) -> Result<T, Error>,
+    C: FnOnce(&str) -> Result<T, Error>,
+{
+    let result = open(filesystem_path).and_then(|repository| embedded(&repository));
+    match result {
+        Ok(value) => Ok(value),
+        Err(err) => {
+            debug!("Reading Git repository failed: {err}; falling back to the git executable");
+            cli(filesystem_path)
+        }
+    }
+}
+
+mod embedded {
+    use super::{Commit, Error, Submodule};
+    use std::path::{Path, PathBuf};
+    use chrono::TimeZone;
+    use git2::{DescribeFormatOptions, DescribeOptions, DiffOptions, ErrorClass, Repository, StatusOptions};
+
+    pub fn short_sha(repository: &Repository) -> Result<(String, bool), Error> {
+        let commit = repository.head()?.peel_to_commit()?;
+        let short_sha = commit.as_object().short_id()?.as_str().unwrap_or_default().to_string();
+        let dirty = !dirty_files(repository)?.is_empty();
+        Ok((short_sha, dirty))
+    }
+
+    pub fn dirty_files(repository: &Repository) -> Result<Vec<String>, Error> {
+        let mut options = StatusOptions::new();
+        options.include_untracked(true).include_ignored(false).recurse_untracked_dirs(true);
+        let statuses = repository.statuses(Some(&mut options))?;
+        Ok(statuses.iter().filter_map(|entry| entry.path().map(str::to_string)).collect())
+    }
+
+    pub fn sha(repository: &Repository) -> Result<String, Error> {
+        Ok(repository.head()?.peel_to_commit()?.id().to_string())
+    }
+
+    pub fn branch(repository: &Repository) -> Result<String, Error> {
+        if repository.head_detached()? {
+            return Ok(super::DETACHED_HEAD.to_string());
+        }
+        Ok(repository.head()?.shorthand().unwrap_or(super::DETACHED_HEAD).to_string())
+    }
+
+    pub fn origin(repository: &Repository) -> Result<String, Error> {
+        let remote = repository.find_remote("origin")?;
+        Ok(remote.url().unwrap_or_default().to_string())
+    }
+
+    pub fn commit(repository: &Repository) -> Result<Commit, Error> {
+        let commit = repository.head()?.peel_to_commit()?;
+        let time = commit.time();
+        let time = chrono::FixedOffset::east_opt(time.offset_minutes() * 60)
+            .and_then(|offset| offset.timestamp_opt(time.seconds(), 0).single())
+            .ok_or_else(|| git2::Error::from_str("commit time out of range"))?;
+        let author = commit.author();
+        Ok(Commit {
+            sha: commit.id().to_string(),
+            time: time.to_rfc3339(),
+            author: format!("{} <{}>", author.name().unwrap_or_default(), author.email().unwrap_or_default()),
+        })
+    }
+
+    pub fn describe(repository: &Repository) -> Result<Option<String>, Error> {
+        let mut options = DescribeOptions::new();
+        options.describe_tags().pattern(super::VERSION_TAG_PATTERN);
+        let description = match repository.describe(&options) {
+            Ok(description) => description,
+            Err(err) if err.class() == ErrorClass::Describe => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let mut format = DescribeFormatOptions::new();
+        format.always_use_long_format(true);
+        Ok(Some(description.format(Some(&format))?))
+    }
+
+    pub fn commits_since_release(repository: &Repository) -> Result<Vec<String>, Error> {
+        let head = repository.head()?.peel_to_commit()?.id();
+        let mut walk = repository.revwalk()?;
+        walk.push(head)?;
+        for tag in repository.tag_names(Some(super::VERSION_TAG_PATTERN))?.iter().flatten() {
+            let commit = repository.revparse_single(&format!("refs/tags/{tag}"))?.peel_to_commit()?.id();
+            if commit != head {
+                walk.hide(commit)?;
+            }
+        }
+        walk.map(|oid| Ok(repository.find_commit(oid?)?.summary().unwrap_or_default().to_string()))
+            .collect()
+    }
+
+    pub fn submodules(repository: &Repository) -> Result<Vec<Submodule>, Error> {
+        Ok(repository
+            .submodules()?
+            .iter()
+            .map(|submodule| {
+                let sha = submodule.index_id().or(submodule.head_id());
+                Submodule {
+                    path: submodule.path().to_string_lossy().to_string(),
+                    sha: sha.map(|oid| oid.to_string()).unwrap_or_default(),
+                    checked_out: sha.is_some() && submodule.workdir_id() == sha,
+                }
+            })
+            .collect())
+    }
+
+    pub fn tracked_files(repository: &Repository, filesystem_path: &str) -> Result<Vec<PathBuf>, Error> {
+        let root = std::fs::canonicalize(root(repository)?)?;
+        let directory = std::fs::canonicalize(filesystem_path)?;
+        let prefix = directory.strip_prefix(&root).unwrap_or(Path::new(""));
+        let index = repository.index()?;
+        Ok(index
+            .iter()
+            .filter_map(|entry| {
+                let path = PathBuf::from(String::from_utf8_lossy(&entry.path).as_ref());
+                path.strip_prefix(prefix).ok().map(Path::to_path_buf)
+            })
+            .collect())
+    }
+
+    pub fn commits_since(repository: &Repository, since: &str) -> Result<Vec<String>, Error> {
+        let since = repository.revparse_single(since)?.peel_to_commit()?.id();
+        let mut walk = repository.revwalk()?;
+        walk.push_head()?;
+        walk.hide(since)?;
+        walk.map(|oid| Ok(repository.find_commit(oid?)?.summary().unwrap_or_default().to_string()))
+            .collect()
+    }
+
+    pub fn root(repository: &Repository) -> Result<PathBuf, Error> {
+        repository
+            .workdir()
+            .map(Path::to_path_buf)
+            .ok_or_else(|| git2::Error::from_str("repository has no working tree").into())
+    }
+
+    pub fn changed_files(repository: &Repository, since: &str) -> Result<Vec<PathBuf>, Error> {
+        let since = repository.revparse_single(since)?.peel_to_commit()?.id();
+        let head = repository.head()?.peel_to_commit()?.id();
+        let base = repository.find_commit(repository.merge_base(since, head)?)?;
+
+        let mut options = DiffOptions::new();
+        options.include_untracked(true).recurse_untracked_dirs(true);
+        let diff = repository.diff_tree_to_workdir_with_index(Some(&base.tree()?), Some(&mut options))?;
+
+        let mut files: Vec<PathBuf> = diff
+            .deltas()
+            .flat_map(|delta| [delta.old_file().path(), delta.new_file().path()])
+            .flatten()
+            .map(Path::to_path_buf)
+            .collect();
+        files.sort();
+        files.dedup();
+        Ok(files)
+    }
+}
+
+mod cli {
+    use std::path::PathBuf;
+    use std::process::Command;
+    use super::{Commit, Error, Submodule};
+
+    pub fn short_sha(filesystem_path: &str) -> Result<(String, bool), Error> {
+        let dirty = !dirty_files(filesystem_path)?.is_empty();
+        let short_sha = output(filesystem_path, &["rev-parse", "--short", "HEAD"])?;
+        Ok((short_sha, dirty))
+    }
+
+    pub fn dirty_files(filesystem_path: &str) -> Result<Vec<String>, Error> {
+        // Entries are on the form `XY path`, separated by NUL so that paths are not quoted.
+        // The output is trimmed, which may remove the leading space of the first status code.
+        let status = output(filesystem_path, &["status", "--porcelain", "-z", "--untracked-files=all", "--no-renames"])?;
+        Ok(status
+            .split('\0')
+            .filter_map(|entry| entry.trim_start().split_once(' '))
+            .map(|(_, path)| path.trim_start().to_string())
+            .collect())
+    }
+
+    pub fn sha(filesystem_path: &str) -> Result<String, Error> {
+        output(filesystem_path, &["rev-parse", "HEAD"])
+    }
+
+    pub fn branch(filesystem_path: &str) -> Result<String, Error> {
+        output(filesystem_path, &["rev-parse", "--abbrev-ref", "HEAD"])
+    }
+
+    pub fn origin(filesystem_path: &str) -> Result<String, Error> {
+        output(filesystem_path, &["remote", "get-url", "origin"])
+    }
+
+    pub fn commit(filesystem_path: &str) -> Result<Commit, Error> {
+        let output = output(filesystem_path, &["log", "-1", "--format=%H%n%cI%n%an <%ae>"])?;
+        let mut lines = output.lines().map(str::to_string);
+        Ok(Commit {
+            sha: lines.next().unwrap_or_default(),
+            time: lines.next().unwrap_or_default(),
+            author: lines.next().unwrap_or_default(),
+        })
+    }
+
+    pub fn describe(filesystem_path: &str) -> Result<Option<String>, Error> {
+        match output(filesystem_path, &["describe", "--tags", "--long", "--match", super::VERSION_TAG_PATTERN]) {
+            Ok(description) => Ok(Some(description)),
+            Err(Error::Command { stderr, .. }) if stderr.contains("No names found") || stderr.contains("cannot describe") => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn commits_since_release(filesystem_path: &str) -> Result<Vec<String>, Error> {
+        let head = output(filesystem_path, &["rev-parse", "HEAD"])?;
+        let tags = output(filesystem_path, &["tag", "--list", super::VERSION_TAG_PATTERN, "--format=%(objectname) %(*objectname)"])?;
+        let mut args = vec!["log".to_string(), "--format=%s".to_string(), "HEAD".to_string(), "--not".to_string()];
+        for line in tags.lines() {
+            // Annotated tags are peeled to the commit they point at.
+            let commit = line.split_whitespace().last().unwrap_or_default();
+            if !commit.is_empty() && commit != head {
+                args.push(commit.to_string());
+            }
+        }
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        Ok(output(filesystem_path, &args)?.lines().map(str::to_string).collect())
+    }
+
+    pub fn is_shallow(filesystem_path: &str) -> Result<bool, Error> {
+        Ok(output(filesystem_path, &["rev-parse", "--is-shallow-repository"])? == "true")
+    }
+
+    pub fn deepen(filesystem_path: &str) -> Result<(), Error> {
+        output(filesystem_path, &["fetch", "--quiet", "--unshallow", "--tags", "origin"]).map(|_| ())
+    }
+
+    pub fn submodules(filesystem_path: &str) -> Result<Vec<Submodule>, Error> {
+        // Lines are on the form `<status><sha> <path>[ (<describe>)]`, where the status is
+        // a space if the submodule is checked out at the recorded commit.
+        let status = output(filesystem_path, &["submodule", "status"])?;
+        // `output` trims the leading space of the first line.
+        Ok(status
+            .lines()
+            .enumerate()
+            .filter_map(|(index, line)| {
+                let (checked_out, line) = match line.chars().next()? {
+                    '-' | '+' | 'U' => (false, &line[1..]),
+                    ' ' => (true, &line[1..]),
+                    _ if index == 0 => (true, line),
+                    _ => return None,
+                };
+                let mut fields = line.split_whitespace();
+                let sha = fields.next()?.to_string();
+                let path = fields.next()?.to_string();
+                Some(Submodule { path, sha, checked_out })
+            })
+            .collect())
+    }
+
+    pub fn push_tag(filesystem_path: &str, name: &str) -> Result<(), Error> {
+        output(filesystem_path, &["push", "--quiet", "origin", &format!("refs/tags/{name}")]).map(|_| ())
+    }
+
+    pub fn update_submodules(filesystem_path: &str) -> Result<(), Error> {
+        output(filesystem_path, &["submodule", "update", "--init", "--recursive"]).map(|_| ())
+    }
+
+    pub fn tracked_files(filesystem_path: &str) -> Result<Vec<PathBuf>, Error> {
+        // Paths are relative to the working directory, which is the source directory.
+        let files = output(filesystem_path, &["ls-files", "-z", "--cached"])?;
+        Ok(files.split('\0').filter(|path| !path.is_empty()).map(PathBuf::from).collect())
+    }
+
+    pub fn commits_since(filesystem_path: &str, since: &str) -> Result<Vec<String>, Error> {
+        let range = format!("{since}..HEAD");
+        Ok(output(filesystem_path, &["log", "--format=%s", &range, "--"])?.lines().map(str::to_string).collect())
+    }
+
+    pub fn root(filesystem_path: &str) -> Result<PathBuf, Error> {
+        output(filesystem_path, &["rev-parse", "--show-toplevel"]).map(PathBuf::from)
+    }
+
+    pub fn changed_files(filesystem_path: &str, since: &str) -> Result<Vec<PathBuf>, Error> {
+        let base = output(filesystem_path, &["merge-base", since, "HEAD"])?;
+        // Paths are relative to the repository root, regardless of the working directory.
+        let changed = output(filesystem_path, &["diff", "--name-only", "--no-renames", &base])?;
+        let untracked = output(filesystem_path, &["ls-files", "--others", "--exclude-standard", "--full-name"])?;
+
+        let mut files: Vec<PathBuf> = changed.lines().chain(untracked.lines()).map(PathBuf::from).collect();
+        files.sort();
+        files.dedup();
+        Ok(files)
+    }
+
+    /// Prepare a `git` command that runs in the source directory.
+    /// The C locale is used, so that output does not depend on the user's language settings.
+    ///
+    /// `GIT_DIR` and `GIT_WORK_TREE` are inherited, but relative paths are made absolute,
+    /// as they refer to the directory `nb` was started from, not the source directory.
+    pub fn command(filesystem_path: &str) -> Command {
+        let mut command = Command::new("git");
+        command.current_dir(filesystem_path).env("LC_ALL", "C");
+        for name in ["GIT_DIR", "GIT_WORK_TREE"] {
+            let Some(path) = std::env::var_os(name).map(PathBuf::from) else {
+                continue;
+            };
+            if let (false, Ok(current_dir)) = (path.is_absolute(), std::env::current_dir()) {
+                command.env(name, current_dir.join(path));
+            }
+        }
+        command
+    }
+
+    /// Run `git` with the specified arguments, and return its trimmed standard output.
+    fn output(filesystem_path: &str, args: &[&str]) -> Result<String, Error> {
+        let output = crate::process::output_blocking(command(filesystem_path).args(args), None).map_err(Error::FailedExecute)?;
+        if !output.status.success() {
+            return Err(Error::Command {
+                command: args.join(" "),
+                status: output.status,
+                stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            });
+        }
+        Ok(String::from_utf8(output.stdout)?.trim().to_string())
+    }
+}
+
+/// Tags that are considered for versioning.
+const VERSION_TAG_PATTERN: &str = "v[0-9]*";
+
+/// A semantic version derived from a Git tag and the number of commits since that tag.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Version {
+    /// The tag without its `v` prefix, e.g. `1.4.2`.
+    pub tag: String,
+    /// Number of commits between the tag and HEAD.
+    pub distance: usize,
+    /// Abbreviated SHA of HEAD.
+    pub short_sha: String,
+}
+
+impl Version {
+    /// Whether HEAD is exactly at the tag.
+    pub fn is_release(&self) -> bool {
+        self.distance == 0
+    }
+}
+
+impl std::fmt::Display for Version {
+    /// Example output: `1.4.2` at the tag, or `1.4.2-3-gabcdef` three commits later.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.distance {
+            0 => write!(f, "{}", self.tag),
+            distance => write!(f, "{}-{distance}-g{}", self.tag, self.short_sha),
+        }
+    }
+}
+
+/// Parse the long output of `git describe`, e.g. `v1.4.2-3-gabcdef`.
+fn parse_description(description: &str) -> Option<Version> {
+    let mut parts = description.trim().rsplitn(3, '-');
+    let short_sha = parts.next()?.strip_prefix('g')?;
+    let distance = parts.next()?.parse().ok()?;
+    let tag = parts.next()?.strip_prefix('v')?;
+
+    let core = tag.split(['-', '+']).next()?;
+    let numbers: Vec<_> = core.split('.').collect();
+    if numbers.len() != 3 || numbers.iter().any(|number| number.parse::<u64>().is_err()) {
+        return None;
+    }
+
+    Some(Version {
+        tag: tag.to_string(),
+        distance,
+        short_sha: short_sha.to_string(),
+    })
+}
+
+/// A submodule, and the commit recorded for it in the superproject.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Submodule {
+    /// Path relative to the repository root.
+    pub path: String,
+    pub sha: String,
+    /// Whether the submodule is checked out at the recorded commit.
+    pub checked_out: bool,
+}
+
+/// The currently checked out commit.
+#[derive(Debug, PartialEq)]
+struct Commit {
+    sha: String,
+    /// Committer time in RFC 3339 format.
+    time: String,
+    /// Author on the form `Name <email>`.
+    author: String,
+}
+
+#[derive(Debug, Clone)]
 pub struct RepositoryMetadata {
     pub owner: String,
     pub name: String,
+    /// URL of the `origin` remote, as configured.
+    pub remote_url: String,
+    /// Currently checked out branch, see [branch].
+    pub branch: String,
+    /// Full SHA of the currently checked out commit.
+    pub sha: String,
+    /// Committer time of the checked out commit in RFC 3339 format, e.g. `2024-10-08T15:25:58+02:00`.
+    pub commit_time: String,
+    /// Author of the checked out commit on the form `Name <email>`.
+    pub author: String,
+    /// Path and recorded commit SHA of each submodule.
+    pub submodules: Vec<(String, String)>,
+}
+
+/// Format submodules as a comma-separated list of `path=sha`, for use in labels and annotations.
+pub fn format_submodules(submodules: &[(String, String)]) -> String {
+    submodules.iter().map(|(path, sha)| format!("{path}={sha}")).collect::<Vec<_>>().join(",")
+}
+
+impl RepositoryMetadata {
+    /// Web address of the repository on GitHub.
+    pub fn web_url(&self) -> String {
+        format!("https://github.com/{}/{}", self.owner, self.name)
+    }
 }
 
 fn parse_origin(origin: &str) -> Option<(String, String)> {
@@ -57,36 +750,273 @@ fn parse_origin(origin: &str) -> Option<(String, String)> {
     // git@github.com:navikt/foo
     // optional: suffix .git
 
+    let origin = origin.trim();
     let owner_and_name = if origin.starts_with("git@") {
         origin.split(":").nth(1)?
     } else if origin.starts_with("https://") {
-        origin.split("://").nth(1)?.splitn(2, "/").nth(1)?
+        origin.split("://").nth(1)?.split_once("/")?.1
     } else {
         return None
     };
 
-    let parts: Vec<&str> = owner_and_name.splitn(2, "/")
-        .collect();
+    let (owner, name) = owner_and_name.split_once("/")?;
+    let name = name.strip_suffix(".git").unwrap_or(name);
 
-    Some((parts[0].to_string(), parts[1].to_string()))
+    Some((owner.to_string(), name.to_string()))
 }
 
-/// Return metadata about a Git repository.
-pub fn metadata(filesystem_path: &str) -> Result<RepositoryMetadata, Error> {
-    let origin_raw = std::process::Command::new("git")
-        .arg("remote")
-        .arg("get-url")
-        .arg("origin")
-        .current_dir(filesystem_path)
-        .output()
-        //.map(|output| output.stdout.len() > 0)
-        .map_err(Error::FailedExecute)?;
-
-    let origin_raw = String::from_utf8(origin_raw.stdout)?;
-    let (owner, name) = parse_origin(&origin_raw).ok_or(Error::ParseRemote(origin_raw))?;
-
-    Ok(RepositoryMetadata{
-        owner,
-        name,
-    })
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// Run git with `args` in `path`, as a committer that needs no configuration, and return its output.
+    pub fn git(path: &std::path::Path, args: &[&str]) -> String {
+        let output = std::process::Command::new("git")
+            .args(args)
+            .current_dir(path)
+            .env("GIT_AUTHOR_NAME", "nb")
+            .env("GIT_AUTHOR_EMAIL", "nb@example.com")
+            .env("GIT_COMMITTER_NAME", "nb")
+            .env("GIT_COMMITTER_EMAIL", "nb@example.com")
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "git {args:?}: {}", String::from_utf8_lossy(&output.stderr));
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    #[test]
+    pub fn branch_from_ci_environment() {
+        let environment = |vars: &'static [(&str, &str)]| {
+            move |name: &str| vars.iter().find(|(key, _)| *key == name).map(|(_, value)| value.to_string())
+        };
+        let pull_request = environment(&[("GITHUB_REF", "refs/pull/12/merge"), ("GITHUB_HEAD_REF", "feature")]);
+        assert_eq!(branch_from_ci(pull_request), Some("feature".into()));
+        let push = environment(&[("GITHUB_REF", "refs/heads/main"), ("GITHUB_HEAD_REF", "")]);
+        assert_eq!(branch_from_ci(push), Some("main".into()));
+        let tag = environment(&[("GITHUB_REF", "refs/tags/v1.0.0"), ("GITHUB_HEAD_REF", "")]);
+        assert_eq!(branch_from_ci(tag), None);
+        assert_eq!(branch_from_ci(environment(&[("CI_COMMIT_BRANCH", "main")])), Some("main".into()));
+    }
+
+    #[test]
+    pub fn deepen_shallow_clone() {
+        let origin = tempfile::tempdir().unwrap();
+        let clone = tempfile::tempdir().unwrap();
+        git(origin.path(), &["init", "--quiet"]);
+        git(origin.path(), &["commit", "--quiet", "--allow-empty", "--message", "initial"]);
+        git(origin.path(), &["tag", "v1.0.0"]);
+        git(origin.path(), &["commit", "--quiet", "--allow-empty", "--message", "second"]);
+        let url = format!("file://{}", origin.path().display());
+        git(clone.path(), &["clone", "--quiet", "--depth", "1", "--no-tags", &url, "."]);
+
+        let path = clone.path().to_str().unwrap();
+        assert!(is_shallow(path).unwrap());
+        assert_eq!(version(path).unwrap().unwrap().distance, 1);
+        assert!(!is_shallow(path).unwrap());
+        assert!(!deepen(path).unwrap());
+    }
+
+    #[test]
+    pub fn changes_in_watched_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let git = |args: &[&str]| git(dir.path(), args);
+        for directory in ["app", "lib", "other"] {
+            std::fs::create_dir(dir.path().join(directory)).unwrap();
+            std::fs::write(dir.path().join(directory).join("main.go"), "").unwrap();
+        }
+        git(&["init", "--quiet"]);
+        git(&["add", "."]);
+        git(&["commit", "--quiet", "--message", "initial"]);
+        git(&["tag", "base"]);
+
+        let app = dir.path().join("app");
+        let app = app.to_str().unwrap();
+        let no_dependencies: &[&str] = &[];
+        std::fs::write(dir.path().join("other/main.go"), "package other").unwrap();
+        assert!(!changed_since(app, no_dependencies, "base").unwrap());
+        std::fs::write(dir.path().join("lib/main.go"), "package lib").unwrap();
+        git(&["commit", "--quiet", "--all", "--message", "outside"]);
+        assert!(!changed_since(app, no_dependencies, "base").unwrap());
+        assert!(changed_since(app, &["lib"], "base").unwrap());
+        std::fs::write(dir.path().join("app/main.go"), "package main").unwrap();
+        assert!(changed_since(app, no_dependencies, "base").unwrap());
+    }
+
+    #[test]
+    pub fn create_and_push_tag() {
+        let dir = tempfile::tempdir().unwrap();
+        let origin = dir.path().join("origin");
+        let app = dir.path().join("app");
+        std::fs::create_dir(&origin).unwrap();
+        std::fs::create_dir(&app).unwrap();
+        git(&origin, &["init", "--quiet", "--bare"]);
+        git(&app, &["init", "--quiet"]);
+        git(&app, &["commit", "--quiet", "--allow-empty", "--message", "initial"]);
+        git(&app, &["remote", "add", "origin", origin.to_str().unwrap()]);
+
+        let path = app.to_str().unwrap();
+        create_tag(path, "myapp/1.0.0", "Release myapp:1.0.0").unwrap();
+        assert_eq!(git(&app, &["cat-file", "-t", "myapp/1.0.0"]), "tag\n");
+        assert_eq!(git(&app, &["tag", "--list", "--format=%(contents:subject)", "myapp/1.0.0"]), "Release myapp:1.0.0\n");
+        assert_eq!(git(&app, &["rev-parse", "myapp/1.0.0^{commit}"]), git(&app, &["rev-parse", "HEAD"]));
+        assert!(create_tag(path, "myapp/1.0.0", "Release myapp:1.0.0").is_err(), "existing tags are not moved");
+
+        push_tag(path, "myapp/1.0.0").unwrap();
+        assert_eq!(git(&origin, &["tag", "--list"]), "myapp/1.0.0\n");
+    }
+
+    #[test]
+    pub fn submodule_status() {
+        let dir = tempfile::tempdir().unwrap();
+        let library = dir.path().join("library");
+        let app = dir.path().join("app");
+        std::fs::create_dir(&library).unwrap();
+        std::fs::create_dir(&app).unwrap();
+        git(&library, &["init", "--quiet"]);
+        std::fs::write(library.join("lib.go"), "").unwrap();
+        git(&library, &["add", "lib.go"]);
+        git(&library, &["commit", "--quiet", "--message", "library"]);
+        git(&app, &["init", "--quiet"]);
+        git(&app, &["-c", "protocol.file.allow=always", "submodule", "--quiet", "add", &format!("file://{}", library.display()), "vendor/library"]);
+        git(&app, &["commit", "--quiet", "--message", "app"]);
+
+        let path = app.to_str().unwrap();
+        let repository = git2::Repository::discover(path).unwrap();
+        let submodules = embedded::submodules(&repository).unwrap();
+        assert_eq!(submodules.len(), 1);
+        assert_eq!(submodules[0].path, "vendor/library");
+        assert!(submodules[0].checked_out);
+        assert_eq!(cli::submodules(path).unwrap(), submodules);
+        assert!(tracked_files(path).unwrap().contains(&"vendor/library/lib.go".into()));
+
+        git(&app, &["submodule", "--quiet", "deinit", "--all"]);
+        assert!(!embedded::submodules(&repository).unwrap()[0].checked_out);
+        assert!(!cli::submodules(path).unwrap()[0].checked_out);
+    }
+
+    #[test]
+    pub fn parse_signature_output() {
+        let gpg = "[GNUPG:] NEWSIG\n[GNUPG:] GOODSIG 0123456789ABCDEF nb <nb@example.com>\n\
+            [GNUPG:] VALIDSIG 9F8E7D6C5B4A39281706F5E4D3C2B1A00123456789ABCDEF 2024-10-08 1728393958 0 4 0 22 10 00";
+        assert_eq!(parse_signing_key(gpg).unwrap(), "9F8E7D6C5B4A39281706F5E4D3C2B1A00123456789ABCDEF");
+        let ssh = "Good \"git\" signature for nb@example.com with ED25519 key SHA256:abcdefghijklmnop";
+        assert_eq!(parse_signing_key(ssh).unwrap(), "SHA256:abcdefghijklmnop");
+        assert_eq!(parse_signing_key("error: no signature found"), None);
+    }
+
+    #[test]
+    pub fn verify_signature_with_git() {
+        let ssh = "Good \"git\" signature for nb@example.com with ED25519 key SHA256:abcdefghijklmnop";
+        let mock = std::sync::Arc::new(crate::process::Mock::default()
+            .respond_with_stderr("git verify-tag", 0, "", ssh)
+            .respond_with_stderr("git verify-commit", 1, "", "error: no signature found"));
+        crate::process::sync_scope(mock.clone(), || {
+            assert_eq!(verify_signature("/src", "v1.0.0", true).unwrap(), "SHA256:abcdefghijklmnop");
+            assert!(matches!(verify_signature("/src", "HEAD", false), Err(Error::Unsigned(..))));
+        });
+        let invocations = mock.invocations();
+        assert_eq!(invocations[0].command_line(), "git verify-tag --raw v1.0.0");
+        assert_eq!(invocations[0].current_dir.as_deref(), Some(std::path::Path::new("/src")));
+        assert!(invocations[0].env.contains(&("LC_ALL".to_string(), Some("C".to_string()))));
+    }
+
+    #[test]
+    pub fn worktree_subdirectory() {
+        let dir = tempfile::tempdir().unwrap();
+        let main = dir.path().join("main");
+        let worktree = dir.path().join("worktree");
+        std::fs::create_dir(&main).unwrap();
+        git(&main, &["init", "--quiet"]);
+        std::fs::create_dir(main.join("app")).unwrap();
+        std::fs::write(main.join("app/main.go"), "").unwrap();
+        git(&main, &["add", "app/main.go"]);
+        git(&main, &["commit", "--quiet", "--message", "initial"]);
+        git(&main, &["worktree", "add", "--quiet", "-b", "feature", worktree.to_str().unwrap()]);
+
+        // In a worktree, `.git` is a file pointing to the Git directory of the main working tree.
+        let path = worktree.join("app");
+        let path = path.to_str().unwrap();
+        let repository = git2::Repository::discover(path).unwrap();
+        let worktree = std::fs::canonicalize(&worktree).unwrap();
+        assert_eq!(std::fs::canonicalize(embedded::root(&repository).unwrap()).unwrap(), worktree);
+        assert_eq!(std::fs::canonicalize(cli::root(path).unwrap()).unwrap(), worktree);
+        assert_eq!(embedded::branch(&repository).unwrap(), "feature");
+        assert_eq!(embedded::short_sha(&repository).unwrap(), cli::short_sha(path).unwrap());
+        assert_eq!(tracked_files(path).unwrap(), vec![std::path::PathBuf::from("main.go")]);
+    }
+
+    #[test]
+    pub fn parse_git_description() {
+        let version = parse_description("v1.4.2-0-gabcdef").unwrap();
+        assert!(version.is_release());
+        assert_eq!(version.to_string(), "1.4.2");
+
+        let version = parse_description("v1.4.2-rc.1-3-gabcdef").unwrap();
+        assert_eq!((version.tag.as_str(), version.distance), ("1.4.2-rc.1", 3));
+        assert_eq!(version.to_string(), "1.4.2-rc.1-3-gabcdef");
+
+        assert_eq!(parse_description("v1.4-0-gabcdef"), None);
+        assert_eq!(parse_description("vnext-0-gabcdef"), None);
+    }
+
+    #[test]
+    pub fn embedded_matches_cli() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        let git = |args: &[&str]| git(dir.path(), args);
+        git(&["init", "--quiet", "--initial-branch", "main"]);
+        git(&["remote", "add", "origin", "git@github.com:navikt/myapp.git"]);
+        std::fs::write(dir.path().join("README.md"), "").unwrap();
+        git(&["add", "README.md"]);
+        git(&["commit", "--quiet", "--message", "initial"]);
+
+        let repository = git2::Repository::discover(path).unwrap();
+        assert_eq!(embedded::short_sha(&repository).unwrap(), cli::short_sha(path).unwrap());
+        assert_eq!(embedded::sha(&repository).unwrap(), cli::sha(path).unwrap());
+        assert_eq!(embedded::branch(&repository).unwrap(), "main");
+        assert_eq!(embedded::origin(&repository).unwrap(), cli::origin(path).unwrap());
+        assert_eq!(embedded::commit(&repository).unwrap(), cli::commit(path).unwrap());
+
+        assert_eq!(embedded::describe(&repository).unwrap(), None);
+        assert_eq!(cli::describe(path).unwrap(), None);
+        git(&["tag", "v1.4.2"]);
+        assert_eq!(embedded::describe(&repository).unwrap(), cli::describe(path).unwrap());
+        assert_eq!(version(path).unwrap().unwrap().to_string(), "1.4.2");
+        assert_eq!(embedded::commits_since_release(&repository).unwrap(), vec!["initial"]);
+        git(&["commit", "--quiet", "--allow-empty", "--message", "feat: second"]);
+        git(&["tag", "--annotate", "--message", "release", "v1.5.0"]);
+        assert_eq!(embedded::commits_since_release(&repository).unwrap(), vec!["feat: second"]);
+        assert_eq!(cli::commits_since_release(path).unwrap(), vec!["feat: second"]);
+        assert_eq!(embedded::commits_since(&repository, "v1.4.2").unwrap(), vec!["feat: second"]);
+        assert_eq!(cli::commits_since(path, "v1.4.2").unwrap(), vec!["feat: second"]);
+        create_tag(path, "myapp/1.5.0", "Release myapp:1.5.0").unwrap();
+        assert!(repository.revparse_single("myapp/1.5.0").unwrap().as_tag().is_some());
+        assert!(create_tag(path, "myapp/1.5.0", "Release myapp:1.5.0").is_err());
+
+        std::fs::write(dir.path().join("untracked"), "").unwrap();
+        assert!(short_sha(path).unwrap().ends_with("-dirty"));
+        assert_eq!(embedded::dirty_files(&repository).unwrap(), vec!["untracked"]);
+        assert_eq!(cli::dirty_files(path).unwrap(), vec!["untracked"]);
+
+        git(&["checkout", "--quiet", "-b", "feature"]);
+        std::fs::create_dir(dir.path().join("app")).unwrap();
+        std::fs::write(dir.path().join("app/main.go"), "").unwrap();
+        git(&["add", "app/main.go"]);
+        git(&["commit", "--quiet", "--message", "feature"]);
+        let expected: Vec<std::path::PathBuf> = vec!["app/main.go".into(), "untracked".into()];
+        assert_eq!(embedded::changed_files(&repository, "main").unwrap(), expected);
+        assert_eq!(cli::changed_files(path, "main").unwrap(), expected);
+
+        let app = dir.path().join("app");
+        let app = app.to_str().unwrap();
+        let expected: Vec<std::path::PathBuf> = vec!["main.go".into()];
+        assert_eq!(embedded::tracked_files(&repository, app).unwrap(), expected);
+        assert_eq!(cli::tracked_files(app).unwrap(), expected);
+
+        let metadata = metadata(path).unwrap();
+        assert_eq!((metadata.owner.as_str(), metadata.name.as_str()), ("navikt", "myapp"));
+        assert_eq!(metadata.branch, "feature");
+        assert_eq!(metadata.author, "nb <nb@example.com>");
+        assert_eq!(metadata.web_url(), "https://github.com/navikt/myapp");
+    }
 }
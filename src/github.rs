@@ -0,0 +1,181 @@
+//! Interaction with the GitHub REST API and GitHub Actions.
+
+use std::time::Duration;
+use log::{debug, info};
+use serde::Serialize;
+use thiserror::Error;
+
+/// Used when `GITHUB_API_URL` is not set, i.e. outside of GitHub Actions.
+const DEFAULT_API_URL: &str = "https://api.github.com";
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("GITHUB_TOKEN must be set to create GitHub releases")]
+    MissingToken,
+
+    #[error("reqwest: {0}")]
+    Reqwest(#[from] reqwest::Error),
+
+    #[error("GitHub API returned {status}: {body}")]
+    Api {
+        status: u16,
+        body: String,
+    },
+}
+
+#[derive(Serialize)]
+struct CreateRelease<'a> {
+    tag_name: &'a str,
+    name: &'a str,
+    body: &'a str,
+}
+
+/// Create a GitHub release for an existing tag in the repository `owner/name`, described by `notes`.
+///
+/// Authenticates with `GITHUB_TOKEN`. A release that already exists for the tag is left untouched.
+pub async fn create_release(owner: &str, name: &str, tag: &str, notes: &str) -> Result<(), Error> {
+    let token = std::env::var("GITHUB_TOKEN").map_err(|_| Error::MissingToken)?;
+    let api_url = std::env::var("GITHUB_API_URL").unwrap_or(DEFAULT_API_URL.to_string());
+
+    debug!("Creating GitHub release {tag} in {owner}/{name}");
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .user_agent("nais-build")
+        .build()?;
+    let resp = client.post(format!("{api_url}/repos/{owner}/{name}/releases"))
+        .bearer_auth(token)
+        .header("Accept", "application/vnd.github+json")
+        .json(&CreateRelease { tag_name: tag, name: tag, body: notes })
+        .send()
+        .await?;
+
+    let status = resp.status().as_u16();
+    let body = resp.text().await?;
+    match status {
+        200..=299 => info!("Created GitHub release {tag}"),
+        // Validation fails with `already_exists` if a release has been created for this tag before.
+        422 if body.contains("already_exists") => info!("GitHub release {tag} already exists"),
+        _ => return Err(Error::Api { status, body }),
+    }
+    Ok(())
+}
+
+/// Whether we are running in a GitHub Actions workflow.
+pub fn is_actions() -> bool {
+    std::env::var("GITHUB_ACTIONS").is_ok_and(|value| value == "true")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AnnotationLevel {
+    Error,
+    Warning,
+}
+
+/// A message shown inline in the workflow run and pull request, optionally attached to a file.
+#[derive(Debug, Default)]
+pub struct Annotation<'a> {
+    pub file: Option<&'a str>,
+    pub title: Option<&'a str>,
+    pub message: &'a str,
+}
+
+impl Annotation<'_> {
+    /// Format as a workflow command, e.g. `::error file=.nais/nais.yaml,title=lint::message`.
+    pub fn command(&self, level: AnnotationLevel) -> String {
+        let level = match level {
+            AnnotationLevel::Error => "error",
+            AnnotationLevel::Warning => "warning",
+        };
+        let file = self.file.map(workspace_path);
+        let properties: Vec<String> = [("file", file.as_deref()), ("title", self.title)]
+            .into_iter()
+            .filter_map(|(key, value)| Some(format!("{key}={}", escape_property(value?))))
+            .collect();
+        match properties.is_empty() {
+            true => format!("::{level}::{}", escape_data(self.message)),
+            false => format!("::{level} {}::{}", properties.join(","), escape_data(self.message)),
+        }
+    }
+
+    /// Emit the annotation if running in GitHub Actions.
+    pub fn emit(&self, level: AnnotationLevel) {
+        if !is_actions() {
+            return;
+        }
+        // The runner reads workflow commands from both standard output and standard error.
+        match crate::report::stdout_reserved() {
+            true => eprintln!("{}", self.command(level)),
+            false => println!("{}", self.command(level)),
+        }
+    }
+}
+
+/// Set outputs of the current workflow step, for later steps to use as `steps.<id>.outputs.<name>`.
+/// Does nothing outside GitHub Actions.
+pub fn set_outputs(outputs: &[(&str, &str)]) -> std::io::Result<()> {
+    append_to_file("GITHUB_OUTPUT", &format_outputs(outputs))
+}
+
+/// Add Markdown to the summary shown on the workflow run page. Does nothing outside GitHub Actions.
+pub fn append_step_summary(markdown: &str) -> std::io::Result<()> {
+    append_to_file("GITHUB_STEP_SUMMARY", markdown)
+}
+
+/// Append to the file named by an environment variable, if set.
+fn append_to_file(variable: &str, text: &str) -> std::io::Result<()> {
+    use std::io::Write;
+    let Some(path) = std::env::var_os(variable) else {
+        return Ok(());
+    };
+    debug!("Writing to {variable}");
+    std::fs::OpenOptions::new().create(true).append(true).open(path)?.write_all(text.as_bytes())
+}
+
+/// Format outputs as `name=value` lines, or heredocs for values that span multiple lines.
+fn format_outputs(outputs: &[(&str, &str)]) -> String {
+    const DELIMITER: &str = "NB_OUTPUT_EOF";
+    outputs.iter()
+        .map(|(name, value)| match value.contains('\n') {
+            true => format!("{name}<<{DELIMITER}\n{value}\n{DELIMITER}\n"),
+            false => format!("{name}={value}\n"),
+        })
+        .collect()
+}
+
+/// Paths in annotations must be relative to the repository checkout.
+fn workspace_path(path: &str) -> String {
+    let workspace = std::env::var("GITHUB_WORKSPACE").unwrap_or_default();
+    std::path::Path::new(path)
+        .strip_prefix(&workspace)
+        .ok()
+        .filter(|_| !workspace.is_empty())
+        .map(|relative| relative.to_string_lossy().to_string())
+        .unwrap_or_else(|| path.trim_start_matches("./").to_string())
+}
+
+fn escape_data(value: &str) -> String {
+    value.replace('%', "%25").replace('\r', "%0D").replace('\n', "%0A")
+}
+
+fn escape_property(value: &str) -> String {
+    escape_data(value).replace(':', "%3A").replace(',', "%2C")
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    pub fn format_annotation() {
+        let annotation = Annotation { file: Some("./.nais/nais.yaml"), title: Some("lint: missing-readiness"), message: "no probe\n100%" };
+        assert_eq!(annotation.command(AnnotationLevel::Warning), "::warning file=.nais/nais.yaml,title=lint%3A missing-readiness::no probe%0A100%25");
+        let annotation = Annotation { message: "build failed", ..Annotation::default() };
+        assert_eq!(annotation.command(AnnotationLevel::Error), "::error::build failed");
+    }
+
+    #[test]
+    pub fn format_step_outputs() {
+        assert_eq!(format_outputs(&[("image", "ghcr.io/navikt/myapp:1"), ("tag", "1")]), "image=ghcr.io/navikt/myapp:1\ntag=1\n");
+        assert_eq!(format_outputs(&[("notes", "a\nb")]), "notes<<NB_OUTPUT_EOF\na\nb\nNB_OUTPUT_EOF\n");
+    }
+}
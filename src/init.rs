@@ -0,0 +1,138 @@
+//! Scaffolding of configuration files for new projects.
+
+use std::path::Path;
+use log::info;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("{0} already exists; use --force to overwrite")]
+    FileExists(String),
+
+    #[error("write {path}: {err}")]
+    Write {
+        err: std::io::Error,
+        path: String,
+    },
+}
+
+/// Parameters for the generated files.
+pub struct Params {
+    pub app: String,
+    pub team: String,
+    /// Name of the detected SDK, if any.
+    pub sdk: Option<&'static str>,
+}
+
+/// Path of the generated nais.yaml, relative to the source directory.
+pub const NAIS_YAML_PATH: &str = ".nais/nais.yaml";
+
+/// Path of the generated configuration file, relative to the source directory.
+pub const CONFIG_PATH: &str = "nb.toml";
+
+/// Write a starter nais.yaml and nb.toml into the source directory.
+/// Existing files are only overwritten if `force` is set.
+pub fn scaffold(source_directory: &str, params: &Params, force: bool) -> Result<(), Error> {
+    let files = [
+        (NAIS_YAML_PATH, nais_yaml(params)),
+        (CONFIG_PATH, config(params)),
+    ];
+
+    let root = Path::new(source_directory);
+    if !force {
+        if let Some((path, _)) = files.iter().find(|(path, _)| root.join(path).exists()) {
+            return Err(Error::FileExists(path.to_string()));
+        }
+    }
+
+    for (path, contents) in files {
+        let full_path = root.join(path);
+        let err = |err| Error::Write { err, path: path.to_string() };
+        if let Some(parent) = full_path.parent() {
+            std::fs::create_dir_all(parent).map_err(err)?;
+        }
+        std::fs::write(&full_path, contents).map_err(err)?;
+        info!("Wrote {}", full_path.display());
+    }
+
+    Ok(())
+}
+
+/// Port and probe paths commonly used by applications built with each SDK.
+fn port_and_probes(sdk: Option<&str>) -> (u16, &'static str, &'static str) {
+    match sdk {
+        Some("gradle") | Some("maven") => (8080, "/internal/isalive", "/internal/isready"),
+        _ => (8080, "/isalive", "/isready"),
+    }
+}
+
+fn nais_yaml(params: &Params) -> String {
+    let Params { app, team, sdk } = params;
+    let (port, liveness, readiness) = port_and_probes(*sdk);
+    format!(r#"apiVersion: nais.io/v1alpha1
+kind: Application
+metadata:
+  name: {app}
+  namespace: {team}
+  labels:
+    team: {team}
+spec:
+  # Set by NAIS build to the image that was built and released.
+  image: "{{{{ image }}}}"
+  port: {port}
+  liveness:
+    path: {liveness}
+  readiness:
+    path: {readiness}
+  replicas:
+    min: 2
+    max: 4
+  resources:
+    requests:
+      cpu: 50m
+      memory: 256Mi
+    limits:
+      memory: 512Mi
+"#)
+}
+
+fn config(params: &Params) -> String {
+    let sdk = match params.sdk {
+        Some(sdk) => format!("# Detected SDK: {sdk}\n"),
+        None => "# No SDK was detected for this project.\n".to_string(),
+    };
+    format!(r#"# NAIS build configuration.
+# Run `nb config show` to see all settings, including built-in defaults.
+{sdk}
+team = "{team}"
+"#, team = params.team)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    pub fn scaffold_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_str().unwrap();
+        let params = Params { app: "myapp".into(), team: "myteam".into(), sdk: Some("go") };
+
+        scaffold(root, &params, false).unwrap();
+        assert!(matches!(scaffold(root, &params, false), Err(Error::FileExists(_))));
+        scaffold(root, &params, true).unwrap();
+
+        let rendered = crate::template::render(
+            &std::fs::read_to_string(dir.path().join(NAIS_YAML_PATH)).unwrap(),
+            &crate::template::Vars::default(),
+        ).unwrap();
+        let nais_yaml = crate::nais_yaml::NaisYaml::parse(&rendered).unwrap();
+        assert_eq!(nais_yaml.app, "myapp");
+        assert_eq!(nais_yaml.team, Some("myteam".into()));
+
+        let cfg = crate::config::file::File::default_with_user_config_files(
+            &[dir.path().join(CONFIG_PATH).to_string_lossy().to_string()],
+        ).unwrap();
+        assert_eq!(cfg.team, Some("myteam".into()));
+    }
+}
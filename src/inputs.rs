@@ -0,0 +1,141 @@
+//! Content hash of the inputs of an image build, so that the build can be skipped when an image built
+//! from the same inputs is already in the registry.
+//!
+//! The inputs are the files of the build context, see [crate::docker::context], the generated Dockerfile, the digests of the images it
+//! builds from, and the build context settings. Outputs of native builds, such as Gradle's `build` directory,
+//! are not inputs of a `directory` context. Built images are also tagged `inputs-<hash>`, see [tag].
+
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use sha2::{Digest, Sha256};
+use crate::config::runtime::BuildContext;
+
+/// Inputs of a build other than the files of its context.
+pub struct Inputs<'a> {
+    pub dockerfile: &'a str,
+    /// Digests of the builder and runtime images, e.g. `sha256:abcdef...`.
+    pub base_image_digests: &'a [String],
+    pub context: BuildContext,
+    pub context_include: &'a [String],
+}
+
+/// Version of the hash, changed whenever the inputs or how they are hashed change.
+const VERSION: &str = "nb-inputs-v2";
+
+/// Hash of the inputs, with `files` relative to `source_directory`, as lowercase hex.
+pub fn hash(inputs: &Inputs, source_directory: &Path, files: &[PathBuf]) -> std::io::Result<String> {
+    let mut hasher = Sha256::new();
+    let mut field = |value: &[u8]| {
+        hasher.update((value.len() as u64).to_le_bytes());
+        hasher.update(value);
+    };
+    field(VERSION.as_bytes());
+    field(inputs.dockerfile.as_bytes());
+    for digest in inputs.base_image_digests {
+        field(digest.as_bytes());
+    }
+    field(format!("{:?}", inputs.context).as_bytes());
+    for pattern in inputs.context_include {
+        field(pattern.as_bytes());
+    }
+
+    let mut files = files.to_vec();
+    files.sort();
+    let mut buffer = vec![0; 64 * 1024];
+    for file in files {
+        // Paths are hashed with forward slashes, so that the hash is the same on every platform.
+        let name = file.components().map(|component| component.as_os_str().to_string_lossy()).collect::<Vec<_>>().join("/");
+        field(name.as_bytes());
+        let mut contents = Sha256::new();
+        let mut reader = std::fs::File::open(source_directory.join(&file))?;
+        loop {
+            match reader.read(&mut buffer)? {
+                0 => break,
+                read => contents.update(&buffer[..read]),
+            }
+        }
+        field(&contents.finalize());
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// The files of a `directory` build context, relative to `source_directory`, except the `.git` directory,
+/// nb's [workspace](crate::workspace), and the top-level `outputs` of the SDK's native builds.
+pub fn directory_files(source_directory: &Path, outputs: &[&str]) -> std::io::Result<Vec<PathBuf>> {
+    fn visit(source_directory: &Path, relative: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+        for entry in std::fs::read_dir(source_directory.join(relative))? {
+            let entry = entry?;
+            let path = relative.join(entry.file_name());
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                visit(source_directory, &path, files)?;
+            } else if file_type.is_file() || file_type.is_symlink() {
+                files.push(path);
+            }
+        }
+        Ok(())
+    }
+
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(source_directory)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if name == ".git" || name == crate::workspace::DIRECTORY || outputs.iter().any(|output| name == *output) {
+            continue;
+        }
+        let path = PathBuf::from(&name);
+        if entry.file_type()?.is_dir() {
+            visit(source_directory, &path, &mut files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+/// Name of the image built from inputs with `hash`, in the repository of `image`.
+pub fn tag(image: &str, hash: &str) -> String {
+    // The tag follows the last colon, unless that is part of the registry host, like `localhost:5000/app`.
+    let repository = match image.rsplit_once(':') {
+        Some((repository, tag)) if !tag.contains('/') => repository,
+        _ => image,
+    };
+    format!("{repository}:inputs-{hash}")
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    pub fn hash_inputs() {
+        let directory = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(directory.path().join("cmd/api")).unwrap();
+        std::fs::create_dir_all(directory.path().join("build")).unwrap();
+        std::fs::write(directory.path().join("go.mod"), "module example.com/app\n").unwrap();
+        std::fs::write(directory.path().join("cmd/api/main.go"), "package main\n").unwrap();
+        std::fs::write(directory.path().join("build/api"), "binary").unwrap();
+
+        let files = directory_files(directory.path(), &["build"]).unwrap();
+        let mut sorted = files.clone();
+        sorted.sort();
+        assert_eq!(sorted, [PathBuf::from("cmd/api/main.go"), PathBuf::from("go.mod")]);
+
+        let digests = ["sha256:1".to_string(), "sha256:2".to_string()];
+        let inputs = Inputs { dockerfile: "FROM golang", base_image_digests: &digests, context: BuildContext::Directory, context_include: &[] };
+        let hash = |inputs: &Inputs| super::hash(inputs, directory.path(), &files).unwrap();
+        let original = hash(&inputs);
+        assert_eq!(original.len(), 64);
+        assert_eq!(hash(&inputs), original);
+        assert_ne!(hash(&Inputs { dockerfile: "FROM golang:1.23", ..inputs }), original);
+        std::fs::write(directory.path().join("cmd/api/main.go"), "package main\n\nfunc main() {}\n").unwrap();
+        assert_ne!(hash(&inputs), original);
+    }
+
+    #[test]
+    pub fn input_tags() {
+        assert_eq!(tag("ghcr.io/navikt/app:1.0", "abc"), "ghcr.io/navikt/app:inputs-abc");
+        assert_eq!(tag("localhost:5000/app", "abc"), "localhost:5000/app:inputs-abc");
+        assert_eq!(tag("localhost:5000/app:1.0", "abc"), "localhost:5000/app:inputs-abc");
+    }
+}
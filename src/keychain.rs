@@ -0,0 +1,92 @@
+//! Secrets stored in the operating system's keychain.
+//!
+//! macOS uses the login keychain through `security`, and Linux uses the Secret Service
+//! (GNOME Keyring, KWallet) through `secret-tool`. Secrets are never passed as command line
+//! arguments, where other users could see them.
+
+use std::process::Command;
+use log::debug;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("no supported keychain on this platform")]
+    Unsupported,
+
+    #[error("execute {program}: {err}")]
+    Execute {
+        program: &'static str,
+        err: std::io::Error,
+    },
+
+    #[error("{program} exited with {status}: {stderr}")]
+    Failed {
+        program: &'static str,
+        status: std::process::ExitStatus,
+        stderr: String,
+    },
+}
+
+/// Keychain service name that all secrets are stored under.
+const SERVICE: &str = "nais-build";
+
+/// Store a secret for `account`, replacing any existing secret.
+pub fn store(account: &str, secret: &str) -> Result<(), Error> {
+    if cfg!(target_os = "macos") {
+        // `security -i` reads commands from standard input, keeping the secret out of the process list.
+        let command = format!("add-generic-password -U -s {} -a {} -w {}\n", quote(SERVICE), quote(account), quote(secret));
+        run("security", &["-i"], Some(&command)).map(|_| ())
+    } else if cfg!(target_os = "linux") {
+        let label = format!("--label=NAIS build: {account}");
+        run("secret-tool", &["store", &label, "service", SERVICE, "account", account], Some(secret)).map(|_| ())
+    } else {
+        Err(Error::Unsupported)
+    }
+}
+
+/// Look up the secret for `account`. Returns `None` if there is no such secret, or no keychain is available.
+pub fn load(account: &str) -> Option<String> {
+    let result = if cfg!(target_os = "macos") {
+        run("security", &["find-generic-password", "-s", SERVICE, "-a", account, "-w"], None)
+    } else if cfg!(target_os = "linux") {
+        run("secret-tool", &["lookup", "service", SERVICE, "account", account], None)
+    } else {
+        Err(Error::Unsupported)
+    };
+    match result {
+        Ok(secret) => Some(secret.trim_end_matches('\n').to_string()).filter(|secret| !secret.is_empty()),
+        Err(err) => {
+            debug!("No secret for {account} in keychain: {err}");
+            None
+        }
+    }
+}
+
+/// Quote a string for `security -i`, which splits commands like a shell.
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn run(program: &'static str, args: &[&str], stdin: Option<&str>) -> Result<String, Error> {
+    let output = crate::process::output_blocking(Command::new(program).args(args), stdin.map(str::as_bytes))
+        .map_err(|err| Error::Execute { program, err })?;
+    if !output.status.success() {
+        return Err(Error::Failed {
+            program,
+            status: output.status,
+            stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        });
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    pub fn quote_for_security() {
+        assert_eq!(quote("abc123"), r#""abc123""#);
+        assert_eq!(quote(r#"a"b\c"#), r#""a\"b\\c""#);
+    }
+}
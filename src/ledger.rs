@@ -0,0 +1,124 @@
+//! History of the artifacts produced from a source directory, with one JSON line per successful
+//! build, release or deploy, and per cancelled one that finished some of its steps.
+//! Kept next to `artifacts.json`, pruned by `nb clean`.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use log::debug;
+use thiserror::Error;
+use crate::report::{ArtifactManifest, DeploymentStatus};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("ledger: {0}")]
+    IOError(#[from] std::io::Error),
+
+    #[error("ledger entry: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+pub const FILE_NAME: &str = "ledger.jsonl";
+
+/// Path of the ledger in the directory of the artifacts file.
+pub fn path(artifacts_file: &str) -> PathBuf {
+    Path::new(artifacts_file).with_file_name(FILE_NAME)
+}
+
+/// Add an entry at the end of the ledger. The line is written at once, so that an interrupted run
+/// cannot leave a partial entry behind.
+pub fn append(path: &Path, manifest: &ArtifactManifest) -> Result<(), Error> {
+    let line = format!("{}\n", serde_json::to_string(manifest)?);
+    let mut file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(line.as_bytes())?;
+    Ok(())
+}
+
+/// Read all entries, oldest first. A missing ledger has no entries, and unreadable lines are skipped.
+pub fn read(path: &Path) -> Result<Vec<ArtifactManifest>, Error> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+    Ok(contents
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| serde_json::from_str(line).inspect_err(|err| debug!("{}: skipping entry: {err}", path.display())).ok())
+        .collect())
+}
+
+/// Replace the ledger with `entries`.
+pub fn write(path: &Path, entries: &[ArtifactManifest]) -> Result<(), Error> {
+    let directory = path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let mut file = tempfile::NamedTempFile::new_in(directory)?;
+    for entry in entries {
+        writeln!(file, "{}", serde_json::to_string(entry)?)?;
+    }
+    file.persist(path).map_err(|err| err.error)?;
+    Ok(())
+}
+
+/// What an entry records the state of: `build`, `release`, a deployment such as `deploy dev-gcp`,
+/// or what a cancelled command got done, such as `cancelled deploy`.
+pub fn kind(entry: &ArtifactManifest) -> String {
+    if entry.cancelled {
+        return format!("cancelled {}", entry.command);
+    }
+    match &entry.deployment {
+        Some(deployment) if deployment.status == DeploymentStatus::Deployed => format!("deploy {}", deployment.cluster),
+        Some(deployment) => format!("dry-run {}", deployment.cluster),
+        None => entry.command.clone(),
+    }
+}
+
+/// Indices of the entries that are superseded by a later entry of the same kind.
+pub fn stale(entries: &[ArtifactManifest]) -> Vec<usize> {
+    (0..entries.len())
+        .filter(|&index| entries[index + 1..].iter().any(|later| kind(later) == kind(&entries[index])))
+        .collect()
+}
+
+/// The latest entry of each kind, in the order they were recorded. Dry runs are left out,
+/// since they do not change what is deployed.
+pub fn latest(entries: &[ArtifactManifest]) -> Vec<&ArtifactManifest> {
+    let stale = stale(entries);
+    entries.iter().enumerate()
+        .filter(|(index, entry)| !stale.contains(index) && !kind(entry).starts_with("dry-run"))
+        .map(|(_, entry)| entry)
+        .collect()
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::report::Deployment;
+
+    #[test]
+    pub fn stale_entries() {
+        let entry = |command: &str, cluster: Option<&str>| ArtifactManifest {
+            command: command.into(),
+            deployment: cluster.map(|cluster| Deployment { cluster: cluster.into(), status: DeploymentStatus::Deployed }),
+            ..ArtifactManifest::default()
+        };
+        let entries = [
+            entry("build", None),
+            entry("deploy", Some("dev-gcp")),
+            entry("deploy", Some("prod-gcp")),
+            entry("build", None),
+            entry("deploy", Some("dev-gcp")),
+        ];
+        assert_eq!(kind(&entries[1]), "deploy dev-gcp");
+        assert_eq!(kind(&ArtifactManifest { cancelled: true, ..entry("deploy", None) }), "cancelled deploy");
+        assert_eq!(stale(&entries), vec![0, 1]);
+        assert_eq!(latest(&entries), vec![&entries[2], &entries[3], &entries[4]]);
+
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join(FILE_NAME);
+        assert!(read(&path).unwrap().is_empty());
+        append(&path, &entries[0]).unwrap();
+        append(&path, &entries[1]).unwrap();
+        assert_eq!(read(&path).unwrap(), entries[..2]);
+        write(&path, &entries[1..2]).unwrap();
+        assert_eq!(read(&path).unwrap(), entries[1..2]);
+    }
+}
@@ -1,136 +1,84 @@
-#![allow(dead_code)]
-
-mod config;
-mod docker;
-mod nais_yaml;
-mod oci;
-mod pipeline;
-mod sdk;
-mod deploy;
-mod auth;
-mod git;
-
-use std::fmt::{Display, Formatter};
-
-struct BuildInput {
-    nais_yaml: Option<String>,
-    source_directory: Option<String>,
-}
-
-enum DeployConfig {
-    Kubernetes(KubernetesDeployConfig),
-    CDN(CDNDeployConfig),
-    GithubBinaryRelease(GithubBinaryReleaseConfig),
-}
-
-/// nb = NAIS build
-/// ---------------
-/// nb config create
-/// nb build
-/// nb deploy
-
-const EXAMPLE: &str = r#"
-# Empty config will result in an auto-detected build.
-[[build]]
-sdk = rust
-sdk_version = nightly
-"#;
-
-struct SignatureAndAttestation;
-
-struct FileTree(String);
-
-struct Binary(String);
-
-enum BuildOutcome {
-    /// Build an application inside Docker and package it as an image.
-    /// SLSA signatures and attestation.
-    SignedAndAttestedDockerImage,
-
-    /// A directory that is an artifact of itself, for example a directory of static web files.
-    FileTree,
-
-    /// Executable file.
-    Binaries,
-}
-
-struct UploadArtifact {
-    docker_image: DockerImage,
-    sign_attest: SignatureAndAttestation,
-}
-
-struct DockerImage(String);
-
-struct PushDockerImageParams {
-    server: String,
-    credentials: String,
-    image_name: String,
-}
+//! NAIS build as a library: detect the SDK of a source directory, and build, release and deploy it
+//! the way `nb` does, without shelling out to it.
+//!
+//! The stable API is re-exported here: [Config] for the settings of an application, [detect_sdk],
+//! and the [Builder], [Releaser] and [Deployer] that do the work of the pipeline steps.
+//! The modules are public for the `nb` command itself, and may change between versions.
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+//! use nb::{config, detect_sdk, Builder, CancellationToken, Config, Releaser};
+//!
+//! let file = config::file::File::default_with_user_config_files(&["nb.toml".into()])?
+//!     .with_env_overrides(std::env::vars())?;
+//! let nais_yaml = nb::nais_yaml::NaisYaml::parse_file(".nais/nais.yaml")?;
+//! let cfg = Config::new(&file, nais_yaml)?;
+//! let sdk = detect_sdk(".", file.sdk.as_ref().ok_or("no SDK settings")?)?.ok_or("no SDK detected")?;
+//!
+//! let image = "europe-north1-docker.pkg.dev/my-project/my-team/my-app:1.0.0";
+//! let builder = Builder::new(sdk.as_ref(), &cfg);
+//! // Cancel the token to stop the build or push, and the processes and requests they are waiting for.
+//! let cancel = CancellationToken::new();
+//! let built = builder.build(&builder.write_dockerfile()?, image, &cancel).await?;
+//! let mut releaser = Releaser::new(&cfg);
+//! releaser.login(&cancel).await?;
+//! let digest = releaser.push(image, &built.inputs_image.into_iter().collect::<Vec<_>>(), &cancel).await?;
+//! # Ok(())
+//! # }
+//! ```
 
-
-struct NaisDeployInputParams {
-    artifact: UploadArtifact,
-    kubernetes_deploy_config: KubernetesDeployConfig,
-}
-
-enum BranchDeploySuffix {
-    /// Deploy application with its original name.
-    NoSuffix,
-
-    /// Deploy application with name 'myapplication-<branch>'
-    BranchNameSuffix,
-
-    /// Deploy application with name 'myapplication-<string>'
-    ManualSuffix(String),
-}
-
-struct BranchDeployOptions {
-    branch: String,
-    suffix: BranchDeploySuffix,
-}
-
-/// Unique per customer, do we want an enum or a String?
-struct Cluster(String);
-
-struct ClusterOptions {
-    /// Which Kubernetes cluster to deploy to.
-    cluster: Cluster,
-
-    /// Where to deploy the different branches.
-    /// e.g. 'main' deploys with suffix NoSuffix, and
-    /// 'q1' deploys with suffix BranchNameSuffix, and
-    /// '*' deploys with suffix ManualSuffix.
-    branch_deploys: BranchDeployOptions,
-
-    /// These environments are triggered _after_ the current environment has succeeded.
-    dependants: Vec<ClusterOptions>,
-}
-
-/// How do we deploy?
-
-struct DeployServer {
-    tenant: String,
-}
-
-impl Display for DeployServer {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        f.write_fmt(format_args!("https://deploy.{}.cloud.nais.io", self.tenant))
-    }
-}
-
-/// Stuff needed to create a NAIS deploy on Kubernetes
-struct KubernetesDeployConfig {
-    nais_yaml_template: String,
-    cluster: String,
-    namespace: String,
-    deploy_server: String, // https://deploy.<TENANT>.cloud.nais.io on gRPC
-}
-
-struct CDNDeployConfig {
-    team: String,
-    subdirectory: String,
-}
-
-struct GithubBinaryReleaseConfig {
-    repository: String,
-}
+#![allow(dead_code)]
+#![allow(clippy::upper_case_acronyms, clippy::enum_variant_names)]
+
+pub mod config;
+pub mod docker;
+pub mod nais_yaml;
+pub mod oci;
+pub mod pipeline;
+pub mod sdk;
+pub mod deploy;
+pub mod auth;
+pub mod git;
+pub mod template;
+pub mod cache;
+pub mod schema;
+pub mod init;
+pub mod prompt;
+pub mod resources;
+pub mod lint;
+pub mod github;
+pub mod release_notes;
+pub mod aws;
+pub mod azure;
+pub mod keychain;
+pub mod gar;
+pub mod watch;
+pub mod report;
+pub mod progress;
+pub mod ci;
+pub mod version;
+pub mod telemetry;
+pub mod color;
+pub mod ledger;
+pub mod man;
+pub mod alias;
+pub mod shutdown;
+pub mod log_file;
+pub mod artifacts;
+pub mod inputs;
+pub mod checkpoint;
+pub mod events;
+pub mod notify;
+pub mod limits;
+pub mod process;
+pub mod vfs;
+pub mod workspace;
+pub mod verify;
+pub mod licenses;
+pub mod steps;
+pub mod attest;
+
+pub use config::runtime::Config;
+pub use pipeline::{Builder, Deployer, Releaser};
+pub use sdk::{detect as detect_sdk, SDK};
+pub use tokio_util::sync::CancellationToken;
@@ -0,0 +1,529 @@
+//! Licenses of the dependencies of a project, checked by `nb licenses`.
+//!
+//! Dependencies are read from the lockfiles in the source directory: `go.sum`, `gradle.lockfile`,
+//! `Cargo.lock` and `package-lock.json`. Licenses recorded in a lockfile are used as they are; the others
+//! are looked up on [deps.dev](https://deps.dev), and cached for good, since a released version keeps its license.
+//! The report is written next to the artifacts file, where `artifacts.json` refers to it.
+
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use crate::cache;
+use crate::config::runtime::Licenses;
+
+pub const FILE_NAME: &str = "licenses.json";
+
+const DEPS_DEV: &str = "https://api.deps.dev/v3/systems";
+const CACHE_NAME: &str = "licenses.json";
+/// How many lookups run at once.
+const CONCURRENCY: usize = 16;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("no lockfile in {0}; expected one of go.sum, gradle.lockfile, Cargo.lock or package-lock.json")]
+    NoLockfile(String),
+
+    #[error("read {path}: {err}")]
+    Read {
+        err: std::io::Error,
+        path: String,
+    },
+
+    #[error("parse {path}: {message}")]
+    Parse {
+        path: String,
+        message: String,
+    },
+
+    #[error("write {path}: {err}")]
+    Write {
+        err: std::io::Error,
+        path: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Ecosystem {
+    Go,
+    Maven,
+    Cargo,
+    Npm,
+}
+
+impl Ecosystem {
+    /// Name of the package system in the deps.dev API.
+    fn system(&self) -> &'static str {
+        match self {
+            Ecosystem::Go => "GO",
+            Ecosystem::Maven => "MAVEN",
+            Ecosystem::Cargo => "CARGO",
+            Ecosystem::Npm => "NPM",
+        }
+    }
+}
+
+/// Lockfiles, and the ecosystem of the dependencies they list.
+const LOCKFILES: &[(&str, Ecosystem)] = &[
+    ("go.sum", Ecosystem::Go),
+    ("gradle.lockfile", Ecosystem::Maven),
+    ("Cargo.lock", Ecosystem::Cargo),
+    ("package-lock.json", Ecosystem::Npm),
+];
+
+/// The lockfile that a project of the SDK named `sdk` is expected to have, if the SDK has one.
+pub fn lockfile_of(sdk: &str) -> Option<&'static str> {
+    match sdk {
+        "go" => Some("go.sum"),
+        "gradle" => Some("gradle.lockfile"),
+        _ => None,
+    }
+}
+
+/// Path of the license report in the directory of the artifacts file.
+pub fn path(artifacts_file: &str) -> PathBuf {
+    Path::new(artifacts_file).with_file_name(FILE_NAME)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Dependency {
+    pub ecosystem: Ecosystem,
+    /// Name of the package, e.g. `github.com/spf13/cobra` or `org.slf4j:slf4j-api`.
+    pub name: String,
+    pub version: String,
+    /// SPDX license expression, e.g. `MIT OR Apache-2.0`. None if it could not be resolved.
+    pub license: Option<String>,
+    /// Whether the license is denied by the policy.
+    #[serde(default)]
+    pub denied: bool,
+}
+
+impl Dependency {
+    fn new(ecosystem: Ecosystem, name: &str, version: &str, license: Option<String>) -> Self {
+        Dependency { ecosystem, name: name.to_string(), version: version.to_string(), license, denied: false }
+    }
+
+    /// Key of the dependency in the cache of resolved licenses.
+    fn key(&self) -> String {
+        format!("{}/{}@{}", self.ecosystem.system(), self.name, self.version)
+    }
+}
+
+/// The dependencies of a project, and their licenses.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Scan {
+    /// Lockfiles the dependencies were read from, relative to the source directory.
+    pub lockfiles: Vec<String>,
+    pub dependencies: Vec<Dependency>,
+}
+
+impl Scan {
+    /// Read the dependencies from all lockfiles in `source_directory`.
+    pub fn read(source_directory: &Path) -> Result<Self, Error> {
+        let mut lockfiles = Vec::new();
+        let mut dependencies = Vec::new();
+        for (file_name, ecosystem) in LOCKFILES {
+            let path = source_directory.join(file_name);
+            let contents = match std::fs::read_to_string(&path) {
+                Ok(contents) => contents,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(Error::Read { err, path: path.display().to_string() }),
+            };
+            let parse_error = |message: String| Error::Parse { path: path.display().to_string(), message };
+            let found = match ecosystem {
+                Ecosystem::Go => parse_go_sum(&contents),
+                Ecosystem::Maven => parse_gradle_lockfile(&contents),
+                Ecosystem::Cargo => parse_cargo_lock(&contents).map_err(parse_error)?,
+                Ecosystem::Npm => parse_package_lock(&contents).map_err(parse_error)?,
+            };
+            debug!("{} dependencies in {file_name}", found.len());
+            lockfiles.push(file_name.to_string());
+            dependencies.extend(found);
+        }
+        if lockfiles.is_empty() {
+            return Err(Error::NoLockfile(source_directory.display().to_string()));
+        }
+        Ok(Scan { lockfiles, dependencies })
+    }
+
+    /// Look up the licenses that the lockfiles do not record. Dependencies whose license cannot be
+    /// looked up are left without one.
+    pub async fn resolve(&mut self) {
+        let mut cached: BTreeMap<String, String> = cache::read(CACHE_NAME, None)
+            .and_then(|contents| serde_json::from_str(&contents).ok())
+            .unwrap_or_default();
+
+        let client = match reqwest::Client::builder().timeout(Duration::from_secs(10)).build() {
+            Ok(client) => client,
+            Err(err) => {
+                warn!("Licenses not looked up: {err}");
+                return;
+            }
+        };
+        let permits = Arc::new(tokio::sync::Semaphore::new(CONCURRENCY));
+        let mut lookups = tokio::task::JoinSet::new();
+        for (index, dependency) in self.dependencies.iter_mut().enumerate().filter(|(_, dependency)| dependency.license.is_none()) {
+            if let Some(license) = cached.get(&dependency.key()) {
+                dependency.license = Some(license.clone());
+                continue;
+            }
+            let (client, permits, dependency) = (client.clone(), permits.clone(), dependency.clone());
+            lookups.spawn(async move {
+                let _permit = permits.acquire_owned().await;
+                (index, lookup(&client, &dependency).await)
+            });
+        }
+
+        let (mut looked_up, mut failures) = (0, 0);
+        while let Some(joined) = lookups.join_next().await {
+            let Ok((index, license)) = joined else {
+                continue;
+            };
+            let dependency = &mut self.dependencies[index];
+            match license {
+                Ok(Some(license)) => {
+                    cached.insert(dependency.key(), license.clone());
+                    dependency.license = Some(license);
+                    looked_up += 1;
+                }
+                Ok(None) => debug!("No license known for {}", dependency.key()),
+                Err(err) => {
+                    debug!("License of {} not looked up: {err}", dependency.key());
+                    failures += 1;
+                }
+            }
+        }
+        if failures > 0 {
+            warn!("Licenses of {failures} dependencies could not be looked up on deps.dev");
+        }
+        if looked_up > 0 {
+            if let Err(err) = cache::write(CACHE_NAME, &serde_json::to_string(&cached).unwrap_or_default()) {
+                warn!("Licenses could not be cached: {err}");
+            }
+        }
+    }
+
+    /// Mark the dependencies whose licenses are denied by `policy`.
+    pub fn check(&mut self, policy: &Licenses) {
+        let deny = policy.denied();
+        for dependency in &mut self.dependencies {
+            dependency.denied = dependency.license.as_deref().is_some_and(|license| is_denied(license, &deny));
+        }
+    }
+
+    pub fn denied(&self) -> impl Iterator<Item = &Dependency> {
+        self.dependencies.iter().filter(|dependency| dependency.denied)
+    }
+
+    pub fn unknown(&self) -> impl Iterator<Item = &Dependency> {
+        self.dependencies.iter().filter(|dependency| dependency.license.is_none())
+    }
+
+    /// Number of dependencies with each license, the unknown ones as `unknown`.
+    pub fn counts(&self) -> BTreeMap<&str, usize> {
+        let mut counts = BTreeMap::new();
+        for dependency in &self.dependencies {
+            *counts.entry(dependency.license.as_deref().unwrap_or("unknown")).or_default() += 1;
+        }
+        counts
+    }
+
+    /// Write the report as JSON to `path`.
+    pub fn write(&self, path: &Path) -> Result<(), Error> {
+        let write_error = |err| Error::Write { err, path: path.display().to_string() };
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).map_err(write_error)?;
+        }
+        let json = serde_json::to_string_pretty(self).expect("the report serializes");
+        std::fs::write(path, json + "\n").map_err(write_error)
+    }
+
+    /// A Markdown summary, for the GitHub Actions job summary.
+    pub fn markdown_summary(&self) -> String {
+        let mut summary = format!("### nb licenses\n\n{} dependencies in {}\n\n| License | Dependencies |\n|---|---|\n",
+            self.dependencies.len(), self.lockfiles.join(", "));
+        for (license, count) in self.counts() {
+            summary.push_str(&format!("| {license} | {count} |\n"));
+        }
+        let denied: Vec<String> = self.denied()
+            .map(|dependency| format!("| `{}` | {} | {} |\n", dependency.name, dependency.version, dependency.license.as_deref().unwrap_or_default()))
+            .collect();
+        if !denied.is_empty() {
+            summary.push_str("\n#### Denied licenses\n\n| Dependency | Version | License |\n|---|---|---|\n");
+            summary.push_str(&denied.concat());
+        }
+        summary
+    }
+}
+
+impl Display for Scan {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{} dependencies in {}", self.dependencies.len(), self.lockfiles.join(", "))?;
+        for (license, count) in self.counts() {
+            writeln!(f, "  {count:>5}  {license}")?;
+        }
+        for dependency in self.denied() {
+            let denied = console::style("denied").red().bold();
+            writeln!(f, "{denied}: {} {} ({})", dependency.name, dependency.version, dependency.license.as_deref().unwrap_or_default())?;
+        }
+        for dependency in self.unknown() {
+            let unknown = console::style("unknown").yellow().bold();
+            writeln!(f, "{unknown}: {} {}", dependency.name, dependency.version)?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether an SPDX license expression only allows licenses in `deny`.
+/// Of the alternatives of `OR`, any one that is not denied can be chosen; of the licenses joined
+/// with `AND`, all apply. `AND` binds tighter than `OR`, and parentheses group, as in
+/// `(MIT OR Apache-2.0) AND (GPL-3.0-only)` from [lookup].
+pub fn is_denied(expression: &str, deny: &[String]) -> bool {
+    let expression = expression.replace('(', " ( ").replace(')', " ) ");
+    denied_alternatives(&mut expression.split_whitespace().peekable(), deny)
+}
+
+type Tokens<'a> = std::iter::Peekable<std::str::SplitWhitespace<'a>>;
+
+/// `a OR b`, denied if every alternative is. Every operand is read, so that the tokens after it are in place.
+fn denied_alternatives(tokens: &mut Tokens, deny: &[String]) -> bool {
+    let mut denied = denied_conjunction(tokens, deny);
+    while tokens.next_if_eq(&"OR").is_some() {
+        denied &= denied_conjunction(tokens, deny);
+    }
+    denied
+}
+
+/// `a AND b`, denied if any of the licenses is.
+fn denied_conjunction(tokens: &mut Tokens, deny: &[String]) -> bool {
+    let mut denied = denied_term(tokens, deny);
+    while tokens.next_if_eq(&"AND").is_some() {
+        denied |= denied_term(tokens, deny);
+    }
+    denied
+}
+
+/// A license, or an expression in parentheses.
+fn denied_term(tokens: &mut Tokens, deny: &[String]) -> bool {
+    match tokens.next() {
+        Some("(") => {
+            let denied = denied_alternatives(tokens, deny);
+            tokens.next_if_eq(&")");
+            denied
+        }
+        Some(license) => {
+            // `GPL-2.0-only WITH Classpath-exception-2.0` is only denied if it is listed with the exception.
+            let license = match tokens.next_if_eq(&"WITH").and_then(|_| tokens.next()) {
+                Some(exception) => format!("{license} WITH {exception}"),
+                None => license.to_string(),
+            };
+            deny.contains(&license)
+        }
+        None => false,
+    }
+}
+
+/// Look up the license of `dependency` on deps.dev. None if the version is not known there, or has no license.
+async fn lookup(client: &reqwest::Client, dependency: &Dependency) -> Result<Option<String>, reqwest::Error> {
+    #[derive(Deserialize)]
+    struct Version {
+        #[serde(default)]
+        licenses: Vec<String>,
+    }
+
+    let mut url = reqwest::Url::parse(DEPS_DEV).expect("valid URL");
+    url.path_segments_mut().expect("a base URL")
+        .push(dependency.ecosystem.system())
+        .push("packages")
+        .push(&dependency.name)
+        .push("versions")
+        .push(&dependency.version);
+    let response = client.get(url).send().await?;
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    let version: Version = response.error_for_status()?.json().await?;
+    let licenses: Vec<String> = version.licenses.into_iter().filter(|license| license != "non-standard").collect();
+    Ok(match licenses.len() {
+        0 => None,
+        1 => Some(licenses.into_iter().next().unwrap()),
+        // Licenses that all apply, e.g. of different files.
+        _ => Some(licenses.iter().map(|license| format!("({license})")).collect::<Vec<_>>().join(" AND ")),
+    })
+}
+
+/// Modules in `go.sum`, leaving out those whose `go.mod` alone was needed.
+fn parse_go_sum(contents: &str) -> Vec<Dependency> {
+    let mut dependencies: Vec<Dependency> = contents.lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let (module, version) = (fields.next()?, fields.next()?);
+            match version.ends_with("/go.mod") {
+                true => None,
+                false => Some(Dependency::new(Ecosystem::Go, module, version, None)),
+            }
+        })
+        .collect();
+    dependencies.dedup_by(|a, b| a.name == b.name && a.version == b.version);
+    dependencies
+}
+
+/// Dependencies in a Gradle lockfile, like `org.slf4j:slf4j-api:2.0.16=compileClasspath,runtimeClasspath`,
+/// leaving out those only used by tests.
+fn parse_gradle_lockfile(contents: &str) -> Vec<Dependency> {
+    contents.lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| {
+            let (coordinates, configurations) = line.split_once('=')?;
+            if configurations.split(',').all(|configuration| configuration.starts_with("test")) {
+                return None;
+            }
+            let (name, version) = coordinates.rsplit_once(':')?;
+            Some(Dependency::new(Ecosystem::Maven, name, version, None))
+        })
+        .collect()
+}
+
+/// Packages in `Cargo.lock` from a registry, leaving out those of the workspace itself.
+fn parse_cargo_lock(contents: &str) -> Result<Vec<Dependency>, String> {
+    #[derive(Deserialize)]
+    struct Lockfile {
+        #[serde(default)]
+        package: Vec<Package>,
+    }
+    #[derive(Deserialize)]
+    struct Package {
+        name: String,
+        version: String,
+        source: Option<String>,
+    }
+
+    let lockfile: Lockfile = toml::from_str(contents).map_err(|err| err.message().to_string())?;
+    Ok(lockfile.package.into_iter()
+        .filter(|package| package.source.as_deref().is_some_and(|source| source.starts_with("registry+")))
+        .map(|package| Dependency::new(Ecosystem::Cargo, &package.name, &package.version, None))
+        .collect())
+}
+
+/// Packages in `package-lock.json`, with the licenses it records, leaving out development dependencies.
+/// Only the `packages` of lockfile version 2 and later are read.
+fn parse_package_lock(contents: &str) -> Result<Vec<Dependency>, String> {
+    #[derive(Deserialize)]
+    struct Lockfile {
+        #[serde(default)]
+        packages: BTreeMap<String, Package>,
+    }
+    #[derive(Deserialize)]
+    struct Package {
+        version: Option<String>,
+        license: Option<String>,
+        #[serde(default)]
+        dev: bool,
+        #[serde(default)]
+        link: bool,
+    }
+
+    let lockfile: Lockfile = serde_json::from_str(contents).map_err(|err| err.to_string())?;
+    Ok(lockfile.packages.into_iter()
+        .filter(|(_, package)| !package.dev && !package.link)
+        .filter_map(|(path, package)| {
+            // The project itself is the package at "", and dependencies are at `node_modules/<name>`.
+            let (_, name) = path.rsplit_once("node_modules/")?;
+            Some(Dependency::new(Ecosystem::Npm, name, package.version.as_deref()?, package.license))
+        })
+        .collect())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    pub fn read_lockfiles() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(matches!(Scan::read(dir.path()), Err(Error::NoLockfile(_))));
+
+        std::fs::write(dir.path().join("go.sum"), "\
+github.com/spf13/cobra v1.8.1 h1:e5/vxKd/rZsfSJMUX1agtjeTDf+qv1/JdBF8gg5k9ZM=
+github.com/spf13/cobra v1.8.1/go.mod h1:wHxEcudfqmLYa8iTfL+OuZPbBZkmvliBWKIezN3kD9Y=
+github.com/spf13/pflag v1.0.5/go.mod h1:McXfInJRrz4CZXVZOBLb0bTZqETkiAhM9Iw0y3An2Bg=
+").unwrap();
+        std::fs::write(dir.path().join("package-lock.json"), r#"{
+  "lockfileVersion": 3,
+  "packages": {
+    "": { "name": "frontend", "version": "1.0.0" },
+    "node_modules/react": { "version": "18.3.1", "license": "MIT" },
+    "node_modules/@babel/core": { "version": "7.25.2", "license": "MIT", "dev": true },
+    "node_modules/react/node_modules/loose-envify": { "version": "1.4.0" }
+  }
+}"#).unwrap();
+        let scan = Scan::read(dir.path()).unwrap();
+        assert_eq!(scan.lockfiles, ["go.sum", "package-lock.json"]);
+        assert_eq!(scan.dependencies, [
+            Dependency::new(Ecosystem::Go, "github.com/spf13/cobra", "v1.8.1", None),
+            Dependency::new(Ecosystem::Npm, "react", "18.3.1", Some("MIT".to_string())),
+            Dependency::new(Ecosystem::Npm, "loose-envify", "1.4.0", None),
+        ]);
+    }
+
+    #[test]
+    pub fn parse_gradle_and_cargo_lockfiles() {
+        let gradle = "\
+# This is a Gradle generated file for dependency locking.
+ch.qos.logback:logback-classic:1.5.8=runtimeClasspath
+org.junit.jupiter:junit-jupiter-api:5.11.0=testCompileClasspath,testRuntimeClasspath
+org.slf4j:slf4j-api:2.0.16=compileClasspath,runtimeClasspath,testRuntimeClasspath
+empty=annotationProcessor
+";
+        let names: Vec<_> = parse_gradle_lockfile(gradle).into_iter().map(|dependency| format!("{}@{}", dependency.name, dependency.version)).collect();
+        assert_eq!(names, ["ch.qos.logback:logback-classic@1.5.8", "org.slf4j:slf4j-api@2.0.16"]);
+
+        let cargo = r#"
+version = 3
+
+[[package]]
+name = "myapp"
+version = "0.1.0"
+
+[[package]]
+name = "serde"
+version = "1.0.210"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+"#;
+        assert_eq!(parse_cargo_lock(cargo).unwrap(), [Dependency::new(Ecosystem::Cargo, "serde", "1.0.210", None)]);
+        assert!(parse_cargo_lock("[[package]]\nname = 1").is_err());
+    }
+
+    #[test]
+    pub fn denied_licenses() {
+        let deny = vec!["GPL-3.0-only".to_string(), "AGPL-3.0-only".to_string()];
+        assert!(is_denied("GPL-3.0-only", &deny));
+        assert!(!is_denied("MIT", &deny));
+        assert!(!is_denied("MIT OR GPL-3.0-only", &deny), "MIT can be chosen");
+        assert!(is_denied("(MIT AND GPL-3.0-only) OR AGPL-3.0-only", &deny));
+        assert!(!is_denied("GPL-3.0-only WITH Classpath-exception-2.0", &deny));
+        assert!(is_denied("(MIT OR Apache-2.0) AND (GPL-3.0-only)", &deny), "GPL-3.0-only applies either way");
+        assert!(!is_denied("(MIT) AND (Apache-2.0 OR GPL-3.0-only)", &deny));
+        assert!(is_denied("MIT AND GPL-3.0-only OR AGPL-3.0-only", &deny), "AND binds tighter than OR");
+
+        let mut scan = Scan {
+            lockfiles: vec!["go.sum".to_string()],
+            dependencies: vec![
+                Dependency::new(Ecosystem::Go, "example.com/gpl", "v1.0.0", Some("GPL-3.0-only".to_string())),
+                Dependency::new(Ecosystem::Go, "example.com/mit", "v1.0.0", Some("MIT".to_string())),
+                Dependency::new(Ecosystem::Go, "example.com/unknown", "v1.0.0", None),
+            ],
+        };
+        scan.check(&Licenses { deny: Some(deny), fail_on_unknown: false });
+        assert_eq!(scan.denied().map(|dependency| dependency.name.as_str()).collect::<Vec<_>>(), ["example.com/gpl"]);
+        assert_eq!(scan.unknown().count(), 1);
+        assert_eq!(scan.markdown_summary(), "### nb licenses\n\n3 dependencies in go.sum\n\n| License | Dependencies |\n|---|---|\n\
+            | GPL-3.0-only | 1 |\n| MIT | 1 |\n| unknown | 1 |\n\n#### Denied licenses\n\n| Dependency | Version | License |\n|---|---|---|\n\
+            | `example.com/gpl` | v1.0.0 | GPL-3.0-only |\n");
+    }
+}
@@ -0,0 +1,80 @@
+//! Limits on how many steps run at once on a machine, across nb processes, so that monorepo builds
+//! that fan out do not overwhelm the Docker daemon, the registry or the deploy server.
+//!
+//! A limit of `n` is `n` lock files in the temporary directory, and a step runs while it holds a lock
+//! on one of them. Locks are released when the step finishes, or when the process exits.
+
+use std::fs::{File, TryLockError};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use log::info;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+#[error("{path}: {err}")]
+pub struct Error {
+    path: String,
+    err: std::io::Error,
+}
+
+/// How often to check for a free slot while all are taken.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// At most `max` steps of the kind `name`, e.g. `build`, at once.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Limit {
+    pub name: &'static str,
+    pub max: u32,
+}
+
+/// A slot of a [Limit], held until it is dropped.
+pub struct Slot {
+    _file: File,
+}
+
+/// Directory of the lock files, shared by the nb processes of a user.
+pub fn directory() -> PathBuf {
+    std::env::temp_dir().join("nb-limits")
+}
+
+/// Wait for a free slot of `limit`, with its lock files in `directory`.
+pub async fn acquire(directory: &Path, limit: &Limit) -> Result<Slot, Error> {
+    std::fs::create_dir_all(directory).map_err(|err| Error { path: directory.to_string_lossy().to_string(), err })?;
+    let mut waiting = false;
+    loop {
+        for index in 0..limit.max {
+            let path = directory.join(format!("{}-{index}.lock", limit.name));
+            let err = |err| Error { path: path.to_string_lossy().to_string(), err };
+            let file = File::options().create(true).truncate(false).write(true).open(&path).map_err(err)?;
+            match file.try_lock() {
+                Ok(()) => return Ok(Slot { _file: file }),
+                Err(TryLockError::WouldBlock) => {}
+                Err(TryLockError::Error(e)) => return Err(err(e)),
+            }
+        }
+        if !waiting {
+            info!("Waiting for one of {} {} slot(s); other nb processes are using them", limit.max, limit.name);
+            waiting = true;
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[tokio::test]
+    pub async fn wait_for_slots() {
+        let directory = tempfile::tempdir().unwrap();
+        let limit = Limit { name: "build", max: 2 };
+        let first = acquire(directory.path(), &limit).await.unwrap();
+        let _second = acquire(directory.path(), &limit).await.unwrap();
+        let third = tokio::time::timeout(Duration::from_millis(100), acquire(directory.path(), &limit)).await;
+        assert!(third.is_err(), "both slots are taken");
+
+        drop(first);
+        let third = tokio::time::timeout(Duration::from_secs(5), acquire(directory.path(), &limit)).await;
+        assert!(third.is_ok_and(|slot| slot.is_ok()));
+    }
+}
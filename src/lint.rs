@@ -0,0 +1,161 @@
+//! Best-practice checks for NAIS workloads, run by `nb lint`.
+//!
+//! Each rule has a built-in severity, which can be changed in the `[lint]` section of nb.toml,
+//! e.g. `missing-resources = "error"` or `deprecated-field = "off"`.
+
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use serde_json::Value;
+use thiserror::Error;
+use crate::config::runtime::Severity;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("unknown lint rule '{rule}' in configuration; available rules: {available}")]
+    UnknownRule {
+        rule: String,
+        available: String,
+    },
+}
+
+/// All rules, with their default severity.
+const RULES: &[(&str, Severity)] = &[
+    ("missing-resources", Severity::Warning),
+    ("missing-readiness", Severity::Warning),
+    ("prod-min-replicas-zero", Severity::Error),
+    ("deprecated-field", Severity::Warning),
+];
+
+/// Fields that are deprecated in the NAIS Application spec, and what to do instead.
+const DEPRECATED_FIELDS: &[(&str, &str)] = &[
+    ("webproxy", "use `accessPolicy.outbound.external` instead"),
+    ("logformat", "log in JSON format instead"),
+    ("logtransform", "log in JSON format instead"),
+    ("vault", "use secrets from NAIS Console instead"),
+];
+
+/// A rule violation found in a manifest.
+#[derive(Debug, PartialEq)]
+pub struct Finding {
+    pub rule: &'static str,
+    pub severity: Severity,
+    /// Dotted path to the offending field, e.g. `spec.replicas.min`.
+    pub path: String,
+    pub message: String,
+}
+
+impl Display for Finding {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => console::style("error").red().bold(),
+            _ => console::style("warning").yellow().bold(),
+        };
+        write!(f, "{severity}: {}: {} [{}]", self.path, self.message, self.rule)
+    }
+}
+
+/// Effective severity of each rule.
+pub struct Rules(BTreeMap<&'static str, Severity>);
+
+impl Rules {
+    /// Apply configured severities on top of the built-in defaults.
+    pub fn new(configured: Option<&BTreeMap<String, Severity>>) -> Result<Self, Error> {
+        let mut rules: BTreeMap<&'static str, Severity> = RULES.iter().copied().collect();
+        for (rule, severity) in configured.into_iter().flatten() {
+            let Some((rule, _)) = RULES.iter().find(|(name, _)| name == rule) else {
+                return Err(Error::UnknownRule {
+                    rule: rule.clone(),
+                    available: RULES.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(", "),
+                });
+            };
+            rules.insert(rule, *severity);
+        }
+        Ok(Self(rules))
+    }
+
+    /// Check a single Kubernetes resource. Resources that are not NAIS workloads are ignored.
+    ///
+    /// Set `production` if the resource is deployed to a production environment.
+    pub fn check(&self, document: &Value, production: bool) -> Vec<Finding> {
+        let mut findings = Vec::new();
+        let mut finding = |rule: &'static str, path: &str, message: &str| {
+            let severity = self.0.get(rule).copied().unwrap_or(Severity::Off);
+            if severity != Severity::Off {
+                findings.push(Finding { rule, severity, path: path.to_string(), message: message.to_string() });
+            }
+        };
+
+        let kind = document["kind"].as_str().unwrap_or_default();
+        if !crate::nais_yaml::WORKLOAD_KINDS.contains(&kind) {
+            return findings;
+        }
+        let spec = &document["spec"];
+
+        for section in ["requests", "limits"] {
+            if spec["resources"][section].is_null() {
+                finding("missing-resources", &format!("spec.resources.{section}"), &format!("resource {section} are not set"));
+            }
+        }
+
+        if kind == "Application" {
+            if spec["readiness"].is_null() {
+                finding("missing-readiness", "spec.readiness", "no readiness probe; traffic may be sent to pods that are not ready");
+            }
+            if production && spec["replicas"]["min"] == 0 {
+                finding("prod-min-replicas-zero", "spec.replicas.min", "must be at least 1 in production");
+            }
+        }
+
+        for (field, advice) in DEPRECATED_FIELDS {
+            if !spec[field].is_null() {
+                finding("deprecated-field", &format!("spec.{field}"), &format!("deprecated; {advice}"));
+            }
+        }
+
+        findings
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    pub fn lint_application() {
+        let application = crate::schema::documents(r#"
+apiVersion: nais.io/v1alpha1
+kind: Application
+metadata:
+  name: myapp
+spec:
+  image: foo
+  replicas:
+    min: 0
+  resources:
+    requests:
+      cpu: 50m
+  webproxy: true
+"#).unwrap().remove(0);
+
+        let rules = Rules::new(None).unwrap();
+        let findings: Vec<String> = rules.check(&application, true).iter().map(Finding::to_string).collect();
+        assert_eq!(findings, vec![
+            "warning: spec.resources.limits: resource limits are not set [missing-resources]",
+            "warning: spec.readiness: no readiness probe; traffic may be sent to pods that are not ready [missing-readiness]",
+            "error: spec.replicas.min: must be at least 1 in production [prod-min-replicas-zero]",
+            "warning: spec.webproxy: deprecated; use `accessPolicy.outbound.external` instead [deprecated-field]",
+        ]);
+        assert_eq!(rules.check(&application, false).len(), 3);
+
+        let configured = BTreeMap::from([
+            ("missing-readiness".to_string(), Severity::Off),
+            ("deprecated-field".to_string(), Severity::Error),
+        ]);
+        let rules = Rules::new(Some(&configured)).unwrap();
+        let severities: Vec<_> = rules.check(&application, false).iter().map(|finding| (finding.rule, finding.severity)).collect();
+        assert_eq!(severities, vec![("missing-resources", Severity::Warning), ("deprecated-field", Severity::Error)]);
+
+        let configured = BTreeMap::from([("no-such-rule".to_string(), Severity::Off)]);
+        assert!(Rules::new(Some(&configured)).is_err());
+    }
+}
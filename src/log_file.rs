@@ -0,0 +1,180 @@
+//! A copy of the logs, and of the output of the child processes run by pipeline steps, written to the file
+//! given with `--log-file` or `[log] file`, e.g. to upload as a CI artifact when a build fails.
+//!
+//! Lines are timestamped and never colored. Log lines name their level and module, and output lines
+//! are prefixed with their step, like `build | #5 DONE 0.1s`. Debug logs from nb are always included.
+//! Until the configuration is read, lines are kept in memory, so that the file starts from the beginning.
+//!
+//! The output of each step can also be written to a file of its own, `<directory>/<app>/<step>.log`,
+//! with `[log] step_directory`.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use log::{Level, Log, Metadata, Record};
+
+enum Sink {
+    Buffering(Vec<String>),
+    File(File),
+    Disabled,
+}
+
+static SINK: Mutex<Sink> = Mutex::new(Sink::Buffering(Vec::new()));
+
+/// Files of the steps that have written output, by path, under the step directory if there is one.
+struct StepFiles {
+    directory: PathBuf,
+    files: HashMap<PathBuf, File>,
+}
+
+static STEP_FILES: Mutex<Option<StepFiles>> = Mutex::new(None);
+
+/// Start writing to `path`, beginning with the lines written so far.
+pub fn open(path: &Path) -> std::io::Result<()> {
+    if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut file = File::create(path)?;
+    let mut sink = SINK.lock().expect("log file lock");
+    if let Sink::Buffering(lines) = &*sink {
+        file.write_all(lines.concat().as_bytes())?;
+    }
+    *sink = Sink::File(file);
+    Ok(())
+}
+
+/// Stop keeping lines, when there is no log file.
+pub fn disable() {
+    *SINK.lock().expect("log file lock") = Sink::Disabled;
+}
+
+/// Whether lines are written, or kept until the log file is opened.
+pub fn enabled() -> bool {
+    !matches!(*SINK.lock().expect("log file lock"), Sink::Disabled)
+}
+
+/// Write the output of each step to a file of its own in `directory`.
+pub fn open_step_files(directory: &Path) {
+    *STEP_FILES.lock().expect("step files lock") = Some(StepFiles { directory: directory.to_path_buf(), files: HashMap::new() });
+}
+
+/// Whether the output of steps is written to files of their own.
+pub fn step_files_enabled() -> bool {
+    STEP_FILES.lock().expect("step files lock").is_some()
+}
+
+/// Path of the output of `step` of `app` in the step directory.
+fn step_file(directory: &Path, app: Option<&str>, step: &str) -> PathBuf {
+    match app {
+        Some(app) => directory.join(app).join(format!("{step}.log")),
+        None => directory.join(format!("{step}.log")),
+    }
+}
+
+fn write_step(app: Option<&str>, step: &str, line: &str) {
+    let mut step_files = STEP_FILES.lock().expect("step files lock");
+    let Some(StepFiles { directory, files }) = &mut *step_files else {
+        return;
+    };
+    let path = step_file(directory, app, step);
+    if !files.contains_key(&path) {
+        // Each run starts the files of its steps afresh, and later attempts append to them.
+        let file = path.parent().map_or(Ok(()), std::fs::create_dir_all).and_then(|()| File::create(&path));
+        match file {
+            Ok(file) => {
+                files.insert(path.clone(), file);
+            }
+            Err(err) => {
+                log::warn!("{} not written: {err}", path.display());
+                *step_files = None;
+                return;
+            }
+        }
+    }
+    if let Some(file) = files.get_mut(&path) {
+        let _ = writeln!(file, "{line}");
+    }
+}
+
+fn write(line: String) {
+    match &mut *SINK.lock().expect("log file lock") {
+        Sink::Buffering(lines) => lines.push(line),
+        Sink::File(file) => {
+            // Logging must not fail the command.
+            let _ = file.write_all(line.as_bytes());
+        }
+        Sink::Disabled => {}
+    }
+}
+
+fn timestamp() -> String {
+    chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+}
+
+/// Write a line of output from a child process run by `step` of `app`.
+pub fn output(step: &str, app: Option<&str>, line: &str) {
+    if enabled() {
+        write(format!("{} {step} | {line}\n", timestamp()));
+    }
+    write_step(app, step, line);
+}
+
+/// A logger that writes to `inner`, and copies records to the log file.
+pub struct Tee<L> {
+    inner: L,
+}
+
+impl<L: Log> Tee<L> {
+    pub fn new(inner: L) -> Self {
+        Tee { inner }
+    }
+}
+
+/// Whether a record goes to the log file, regardless of the console's level.
+fn in_file(metadata: &Metadata) -> bool {
+    metadata.level() <= Level::Info || (metadata.level() <= Level::Debug && metadata.target().starts_with("nb"))
+}
+
+impl<L: Log> Log for Tee<L> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata) || (in_file(metadata) && enabled())
+    }
+
+    fn log(&self, record: &Record) {
+        if self.inner.enabled(record.metadata()) {
+            self.inner.log(record);
+        }
+        if (self.inner.enabled(record.metadata()) || in_file(record.metadata())) && enabled() {
+            write(format!("{} {:<5} {}: {}\n", timestamp(), record.level(), record.target(), record.args()));
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+        if let Sink::File(file) = &mut *SINK.lock().expect("log file lock") {
+            let _ = file.flush();
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    pub fn step_file_paths() {
+        assert_eq!(step_file(Path::new("build/logs"), Some("myapp"), "build"), Path::new("build/logs/myapp/build.log"));
+        assert_eq!(step_file(Path::new("build/logs"), None, "lint"), Path::new("build/logs/lint.log"));
+    }
+
+    #[test]
+    pub fn records_in_file() {
+        let metadata = |level, target| Metadata::builder().level(level).target(target).build();
+        assert!(in_file(&metadata(Level::Debug, "nb::docker")));
+        assert!(!in_file(&metadata(Level::Debug, "hyper")));
+        assert!(in_file(&metadata(Level::Info, "hyper")));
+        assert!(!in_file(&metadata(Level::Trace, "nb")));
+    }
+}
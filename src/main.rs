@@ -1,22 +1,20 @@
-/// NAIS Build
+//! NAIS Build
+
 use crate::Error::*;
-use clap::{Parser, Subcommand};
+use std::collections::BTreeMap;
+use clap::{ArgAction, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
 use thiserror::Error;
-use log::{debug, error, info};
-use sdk::SDK;
-use crate::nais_yaml::NaisYaml;
-
-mod config;
-mod docker;
-mod nais_yaml;
-mod sdk;
-mod deploy;
-mod auth;
-mod git;
+use log::{debug, error, info, warn};
+use tracing::Instrument;
+use nb::{alias, artifacts, attest, auth, cache, checkpoint, ci, color, config, deploy, docker, events, gar, git, github, init, keychain, ledger, licenses, lint, log_file, man, nais_yaml, notify, pipeline, process, progress, prompt, release_notes, report, resources, schema, sdk, shutdown, telemetry, template, verify, vfs, version, watch, workspace};
+use nb::nais_yaml::NaisYaml;
+use nb::pipeline::Node;
+use nb::steps::{init_sdk, write_dockerfile_copy, DeployTarget, Steps};
+use nb::SDK;
 
 /// Naisly build, test, release and deploy your application.
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(version = version::CLIENT_VERSION, about, long_about = None)]
 struct Cli {
     /// Root of the source code tree.
     #[arg(default_value = ".")]
@@ -32,10 +30,139 @@ struct Cli {
     #[arg(long)]
     config: Option<String>,
 
+    /// Path to the nais.yaml file to use, instead of detecting it.
+    #[arg(long, global = true)]
+    resource: Option<String>,
+
+    /// Select environment-specific nais.yaml files, as configured in `[naisyaml.environments]`,
+    /// or all files in `.nais/<ENV>` if that directory exists.
+    #[arg(long, global = true)]
+    env: Option<String>,
+
+    /// Only build, release or deploy if the source directory or a configured dependency
+    /// has changed since the common ancestor of this Git revision and HEAD, e.g. `origin/main`.
+    #[arg(long, global = true, value_name = "REF")]
+    changed_since: Option<String>,
+
+    /// Template variable for nais.yaml, on the form KEY=VALUE. Can be specified multiple times.
+    #[arg(long, global = true)]
+    var: Vec<String>,
+
+    /// YAML or JSON file with template variables for nais.yaml. Can be specified multiple times.
+    #[arg(long, global = true)]
+    vars: Vec<String>,
+
+    /// Print the results as a JSON object on standard output: image, tag, digest, Dockerfile path,
+    /// deployment status and timings. Logs and the output of child processes go to standard error.
+    #[arg(long, global = true, value_enum, default_value_t = report::OutputFormat::Text)]
+    output: report::OutputFormat,
+
+    /// Log more details: `-v` for debug logs, `-vv` for trace logs, including those of libraries.
+    #[arg(short, long, global = true, action = ArgAction::Count)]
+    verbose: u8,
+
+    /// Only log warnings and errors, and don't show progress.
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Whether to color output. Colors are used on terminals, unless `NO_COLOR` is set or `--non-interactive` is in effect.
+    #[arg(long, global = true, value_enum, default_value_t = color::ColorChoice::Auto)]
+    color: color::ColorChoice,
+
+    /// Never prompt, so that missing settings are errors, and write plain logs instead of progress.
+    /// Enabled when the `CI` environment variable is set.
+    #[arg(long, global = true)]
+    non_interactive: bool,
+
+    /// Where to write `artifacts.json`, describing the image, digest, Dockerfile, commit and timings,
+    /// after a successful build, release or deploy. Defaults to `build/artifacts.json` in the source directory.
+    /// Each run is also recorded in `ledger.jsonl` in the same directory.
+    #[arg(long, global = true, value_name = "PATH")]
+    artifacts_file: Option<String>,
+
+    /// Stop the command when it runs longer than this, e.g. `90s`, `20m` or `1h`, terminating
+    /// `docker` and deploy processes and removing temporary files, and exit with code 124.
+    #[arg(long, global = true, value_name = "DURATION", value_parser = shutdown::parse_duration)]
+    timeout: Option<std::time::Duration>,
+
+    /// Copy logs, including debug logs and the output of Docker and the deploy client, to this file.
+    /// Overrides `[log] file`.
+    #[arg(long, global = true, value_name = "PATH")]
+    log_file: Option<String>,
+
+    /// Keep the Dockerfile, rendered manifests and summary of the run under `<app>/<image tag>` in this
+    /// directory, or in a Cloud Storage bucket as `gs://<bucket>[/<path>]`. Overrides `[artifacts] store`.
+    #[arg(long, global = true, value_name = "LOCATION")]
+    artifact_store: Option<String>,
+
+    /// Skip the steps that the last failed build, release or deploy of the same commit finished,
+    /// such as building and pushing the image before a failed deploy, and use the image they produced.
+    #[arg(long, global = true)]
+    resume: bool,
+
+    /// Print how long each step took, and each stage of the Docker build, when the command finishes.
+    /// The timings are also in the JSON report, `artifacts.json` and the stored summary.
+    #[arg(long, global = true)]
+    timings: bool,
+
+    /// Keep the work directory of the run, `.nb/work/<pid>` in the source directory, with the
+    /// rendered manifests, instead of removing it when the command finishes.
+    #[arg(long, global = true)]
+    keep_workdir: bool,
+
+    /// Also write the generated Dockerfile to this path, e.g. to commit it and review changes to it as diffs.
+    /// Used by `dockerfile`, `build` and the commands that build, also with `--dry-run`.
+    #[arg(long, global = true, value_name = "PATH")]
+    write_dockerfile: Option<String>,
+
     #[command(subcommand)]
     command: Commands,
 }
 
+impl Cli {
+    /// Logger for the level selected with `--verbose` or `--quiet`, overridden by `RUST_LOG` if set.
+    fn logger(&self) -> env_logger::Logger {
+        use log::LevelFilter;
+        let mut builder = env_logger::Builder::new();
+        match (self.quiet, self.verbose) {
+            (true, _) => builder.filter_level(LevelFilter::Warn),
+            (false, 0) => builder.filter_level(LevelFilter::Info),
+            (false, 1) => builder.filter_module("nb", LevelFilter::Debug).filter_level(LevelFilter::Info),
+            (false, _) => builder.filter_level(LevelFilter::Trace),
+        };
+        builder.write_style(match color::stderr() {
+            true => env_logger::WriteStyle::Always,
+            false => env_logger::WriteStyle::Never,
+        });
+        builder.parse_default_env().build()
+    }
+
+    fn artifacts_file(&self) -> String {
+        self.artifacts_file.clone().unwrap_or_else(|| format!("{}/build/artifacts.json", self.source_directory))
+    }
+
+    /// Directory of the artifacts file relative to the source directory, if it is inside of it.
+    fn output_directory(&self) -> Option<std::path::PathBuf> {
+        let artifacts_file = self.artifacts_file();
+        let directory = std::path::Path::new(&artifacts_file).parent()?;
+        directory.strip_prefix(&self.source_directory).ok().map(std::path::Path::to_path_buf)
+    }
+
+    /// Apply the flags that override settings, `--log-file` and `--artifact-store`, on top of the configuration.
+    fn override_config(&self, cfg: &mut config::file::File) {
+        if let (Some(file), Some(log)) = (&self.log_file, &mut cfg.log) {
+            log.file = file.clone();
+        }
+        if let Some(store) = &self.artifact_store {
+            cfg.artifacts.get_or_insert_with(Default::default).store = store.clone();
+        }
+    }
+
+    fn non_interactive(&self) -> bool {
+        self.non_interactive || prompt::in_ci()
+    }
+}
+
 #[derive(Debug, Subcommand)]
 enum Commands {
     /// Detect and print configuration.
@@ -43,16 +170,179 @@ enum Commands {
     /// Detect build parameters, generate a Dockerfile for your project, and print it to standard output.
     Dockerfile,
     /// Build your project, resulting in a Docker image. Implies the `dockerfile` command.
-    Build,
-    /// Release this project's verified Docker image onto GAR or GHCR.
-    Release,
+    Build {
+        /// Print the generated Dockerfile and the `docker build` command instead of building.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Run the tests of the detected SDK without building a runtime image,
+    /// exiting with the status of the tests.
+    Test {
+        /// Run the tests with the locally installed toolchain instead of in the SDK's builder image.
+        #[arg(long)]
+        native: bool,
+    },
+    /// Build the project, and build it again whenever source files change.
+    Watch {
+        /// Build changed targets with the locally installed toolchain instead of building the Docker image.
+        /// For Go, only the binaries in `cmd/` whose sources changed are rebuilt, into `build/`.
+        #[arg(long)]
+        native: bool,
+
+        /// Wait until no files have changed for this many milliseconds before building.
+        #[arg(long, default_value_t = 500)]
+        debounce_ms: u64,
+    },
+    /// Build the Docker image and run it locally, with the port, probes and environment from nais.yaml.
+    /// Using `--docker-image-name` runs an existing image instead of building.
+    Run,
+    /// Release this project's verified Docker image onto GAR, GHCR or ACR.
+    Release {
+        /// Create an annotated Git tag for the released commit, named by `[release] git_tag_format`,
+        /// and push it to `origin` if `[release] push_git_tag` is set.
+        #[arg(long)]
+        git_tag: bool,
+
+        /// Print the image name, registry and login identity that would be used, instead of building and pushing.
+        #[arg(long)]
+        dry_run: bool,
+    },
     /// Deploy `nais.yaml` and the newly built Docker image to a Nais cluster.
     Deploy {
+        /// Cluster to deploy to. If not given on an interactive terminal, it is chosen from the deploy profiles.
+        #[arg(long)]
+        cluster: Option<String>,
+
+        /// Print the rendered nais.yaml instead of building, releasing and deploying.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Print the fully rendered resources that `deploy` would submit, without building anything.
+    Render {
+        /// Write one file per resource to this directory, instead of printing to standard output.
+        #[arg(long)]
+        output_dir: Option<String>,
+    },
+    /// Validate all detected nais.yaml files against the NAIS Application schema.
+    Validate,
+    /// Check all detected nais.yaml files against best practices.
+    /// Rule severities can be changed in the `[lint]` section of `nb.toml`.
+    Lint,
+    /// Report the licenses of the dependencies in the project's lockfiles, and fail on those denied
+    /// by the `[licenses]` section of `nb.toml`. The report is written next to the artifacts file.
+    Licenses,
+    /// Verify the cosign signature, SLSA provenance and SBOM of an image in a registry
+    /// against the policy in the `[verify]` section of `nb.toml`, and print a report.
+    Verify {
+        /// Image to verify, e.g. `ghcr.io/navikt/myapp:1.0.0`, or pinned by digest.
+        image: String,
+    },
+    /// Print a Markdown changelog of the commits since the previous release, grouped by
+    /// conventional commit type.
+    Changelog {
+        /// List commits since this revision instead of the previous `v*` tag.
+        #[arg(long, value_name = "REV")]
+        since: Option<String>,
+    },
+    /// Create a starter `.nais/nais.yaml` and `nb.toml` for a new project.
+    Init {
+        /// Application name. Defaults to the name of the source directory.
+        #[arg(long)]
+        app: Option<String>,
+
+        /// Team that owns the application.
+        #[arg(long)]
+        team: Option<String>,
+
+        /// Overwrite existing files.
         #[arg(long)]
-        cluster: String
+        force: bool,
+    },
+    /// Inspect the Nais build configuration.
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommands,
+    },
+    /// Manage credentials for releasing from a developer machine.
+    Auth {
+        #[command(subcommand)]
+        command: AuthCommands,
+    },
+    /// Set up continuous integration that runs nb.
+    Ci {
+        #[command(subcommand)]
+        command: CiCommands,
+    },
+    /// Print the version of nb, with the Git commit, build date and enabled features it was built with.
+    Version,
+    /// Print the manual page of nb in roff format, or write pages for all commands to a directory.
+    Man {
+        /// Write `nb.1` and one page per subcommand, such as `nb-auth-login.1`, to this directory.
+        #[arg(long)]
+        output_dir: Option<String>,
+    },
+    /// Show the latest build, release and deploy to each cluster, as recorded in the ledger,
+    /// whether the images still exist, and whether the working tree has changed since.
+    Status,
+    /// Remove generated Dockerfiles, cache entries, untagged images built by nb,
+    /// and ledger entries superseded by later builds, releases or deploys.
+    Clean {
+        /// List what would be removed, without removing anything.
+        #[arg(long)]
+        dry_run: bool,
     },
 }
 
+#[derive(Debug, Subcommand)]
+enum CiCommands {
+    /// Print a workflow that builds every push and pull request, and releases and deploys
+    /// branches according to the `[branch]` rules and `[deploy.nais.profiles]` in `nb.toml`.
+    Generate {
+        #[arg(value_enum)]
+        provider: CiProvider,
+    },
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum CiProvider {
+    /// GitHub Actions, to be committed as e.g. `.github/workflows/nb.yaml`.
+    Github,
+}
+
+#[derive(Debug, Subcommand)]
+enum AuthCommands {
+    /// Log in to the registry of the configured release type, using `gcloud` for GAR
+    /// or `gh` for GHCR, so that `nb release` works without CI credentials.
+    Login {
+        /// Log in again even if credentials already exist.
+        #[arg(long)]
+        force: bool,
+    },
+    /// Store a team's NAIS deploy API key in the OS keychain, where `nb deploy` finds it
+    /// when `NAIS_DEPLOY_APIKEY` is not set. The key is read from standard input.
+    SetDeployKey {
+        /// Team that the key belongs to. Defaults to `team` in `nb.toml`.
+        #[arg(long)]
+        team: Option<String>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum ConfigCommands {
+    /// Print the effective configuration, after merging built-in defaults,
+    /// `nb.toml` and `NB_*` environment variables. Secrets are redacted.
+    Show {
+        #[arg(long, value_enum, default_value_t = ConfigFormat::Toml)]
+        format: ConfigFormat,
+    },
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum ConfigFormat {
+    Toml,
+    Json,
+}
+
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("no compatible SDKs for this source directory")]
@@ -73,207 +363,1623 @@ pub enum Error {
     #[error("deploy: {0}")]
     Deploy(#[from] deploy::Error),
 
+    #[error("{0}")]
+    Attest(#[from] attest::Error),
+
     #[error("docker tag could not be generated: {0}")]
     DockerTag(#[from] docker::tag::Error),
 
+    #[error("git: {0}")]
+    Git(#[from] git::Error),
+
     #[error("docker error: {0}")]
     Docker(#[from] docker::Error),
 
     #[error("detect nais.yaml: {0}")]
     DetectNaisYaml(#[from] nais_yaml::Error),
 
-    #[error("google: {0}")]
-    Google(#[from] auth::Error),
+    #[error("auth: {0}")]
+    Auth(#[from] auth::Error),
+
+    #[error("keychain: {0}")]
+    Keychain(#[from] keychain::Error),
+
+    #[error("GAR: {0}")]
+    Gar(#[from] gar::Error),
 
     #[error("build error: {0}")]
     SDKError(#[from] sdk::Error),
+
+    #[error("serialize: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("template: {0}")]
+    Template(#[from] template::Error),
+
+    #[error("schema: {0}")]
+    Schema(#[from] schema::Error),
+
+    #[error("validation failed with {0} error(s)")]
+    ValidationFailed(usize),
+
+    #[error("lint: {0}")]
+    Lint(#[from] lint::Error),
+
+    #[error("github: {0}")]
+    GitHub(#[from] github::Error),
+
+    #[error("lint failed with {0} error(s)")]
+    LintFailed(usize),
+
+    #[error("tests failed with {0}")]
+    TestsFailed(std::process::ExitStatus),
+
+    #[error("container exited with {0}")]
+    ContainerFailed(std::process::ExitStatus),
+
+    #[error("building {target} failed with {status}")]
+    NativeBuildFailed {
+        target: String,
+        status: std::process::ExitStatus,
+    },
+
+    #[error("watch: {0}")]
+    Watch(#[from] watch::Error),
+
+    #[error("CI: {0}")]
+    Ci(#[from] ci::Error),
+
+    #[error("release is signed with key {0}, which is not in `[release] allowed_signing_keys`")]
+    SigningKeyNotAllowed(String),
+
+    #[error("working tree has {0} uncommitted change(s); commit them, or set `[release] dirty_policy`")]
+    DirtyWorkingTree(usize),
+
+    #[error("resources: {0}")]
+    Resources(#[from] resources::Error),
+
+    #[error("init: {0}")]
+    Init(#[from] init::Error),
+
+    #[error("{0}")]
+    Ledger(#[from] ledger::Error),
+
+    #[error("no cluster to deploy to; use --cluster")]
+    ClusterNotGiven,
+
+    #[error("{0}")]
+    Cancelled(shutdown::Reason),
+
+    #[error("{0}")]
+    Checkpoint(#[from] checkpoint::Error),
+
+    #[error("artifact store: {0}")]
+    Artifacts(#[from] artifacts::Error),
+
+    #[error("{0}")]
+    StepTimedOut(#[from] pipeline::StepTimedOut),
+
+    #[error("{name} step failed with {status}")]
+    ShellStepFailed {
+        name: String,
+        status: std::process::ExitStatus,
+    },
+
+    #[error("directory `{directory}` of the {name} step is not a directory inside the source directory")]
+    ShellStepDirectory {
+        name: String,
+        directory: String,
+    },
+
+    #[error("verify: {0}")]
+    Verify(#[from] verify::Error),
+
+    #[error("verification failed with {0} required check(s) not passing")]
+    VerificationFailed(usize),
+
+    #[error("licenses: {0}")]
+    Licenses(#[from] licenses::Error),
+
+    #[error("{denied} dependencies with denied licenses, {unknown} with unknown licenses")]
+    LicensesNotAllowed {
+        denied: usize,
+        unknown: usize,
+    },
+
+    #[error("write Dockerfile to {path}: {err}")]
+    WriteDockerfile {
+        path: String,
+        err: std::io::Error,
+    },
+}
+
+/// Errors of the library's pipeline steps keep the variants, and exit codes, they had before the steps were exposed.
+impl From<pipeline::Error> for Error {
+    fn from(err: pipeline::Error) -> Self {
+        match err {
+            pipeline::Error::Docker(err) => Docker(err),
+            pipeline::Error::Auth(err) => Auth(err),
+            pipeline::Error::Gar(err) => Gar(err),
+            pipeline::Error::Git(err) => Git(err),
+            pipeline::Error::Deploy(err) => Deploy(err),
+            pipeline::Error::Attest(err) => Attest(err),
+            pipeline::Error::NaisYaml(err) => DetectNaisYaml(err),
+            pipeline::Error::IO(err) => FilesystemError(err),
+            pipeline::Error::DeployNotConfigured => ConfigIncomplete,
+            pipeline::Error::ShellFailed { name, status } => ShellStepFailed { name, status },
+            pipeline::Error::ShellDirectory { name, directory } => ShellStepDirectory { name, directory },
+            pipeline::Error::SdkNotDetected => SDKNotDetected,
+            pipeline::Error::Sdk(err) => SDKError(err),
+            pipeline::Error::Json(err) => Json(err),
+            pipeline::Error::WriteDockerfile { path, err } => WriteDockerfile { path, err },
+            pipeline::Error::TimedOut(err) => StepTimedOut(err),
+            // Outside of step timeouts, only the command itself is cancelled.
+            pipeline::Error::Cancelled => Cancelled(shutdown::reason().unwrap_or(shutdown::Reason::Interrupted)),
+        }
+    }
+}
+
+/// Exit codes for each class of failure, so that CI scripts can tell them apart.
+/// Keep in sync with the table in README.md. Invalid command line usage exits with 2.
+mod exit_code {
+    pub const FAILURE: i32 = 1;
+    pub const USAGE: i32 = 2;
+    pub const CONFIG: i32 = 3;
+    pub const SDK_NOT_DETECTED: i32 = 4;
+    pub const BUILD: i32 = 5;
+    pub const PUSH: i32 = 6;
+    pub const DEPLOY: i32 = 7;
+    pub const AUTH: i32 = 8;
+    pub const CHECK: i32 = 9;
+}
+
+impl Error {
+    fn exit_code(&self) -> i32 {
+        match self {
+            ConfigIncomplete | ConfigParse(_) | Config(_) | DetectNaisYaml(_) | Template(_) | Resources(_) | Ci(_) | Artifacts(_) | ShellStepDirectory { .. } => exit_code::CONFIG,
+            SDKNotDetected => exit_code::SDK_NOT_DETECTED,
+            ClusterNotGiven => exit_code::USAGE,
+            SDKError(_) | NativeBuildFailed { .. } => exit_code::BUILD,
+            Docker(err) => match err {
+                docker::Error::Build(_) | docker::Error::Generate(_) | docker::Error::Git(_) | docker::Error::Pattern(_) => exit_code::BUILD,
+                docker::Error::Push(_) => exit_code::PUSH,
+                docker::Error::Login(_) => exit_code::AUTH,
+                _ => exit_code::FAILURE,
+            },
+            Gar(_) | GitHub(_) => exit_code::PUSH,
+            Attest(attest::Error::Sign(_) | attest::Error::Execute { program: "cosign", .. }) => exit_code::PUSH,
+            Attest(_) => exit_code::BUILD,
+            Deploy(_) => exit_code::DEPLOY,
+            Auth(_) | Keychain(_) => exit_code::AUTH,
+            ValidationFailed(_) | LintFailed(_) | SigningKeyNotAllowed(_) | DirtyWorkingTree(_) | ShellStepFailed { .. } | VerificationFailed(_) | LicensesNotAllowed { .. } => exit_code::CHECK,
+            // `nb test` exits with the status of the tests themselves.
+            TestsFailed(status) => status.code().unwrap_or(exit_code::CHECK),
+            Cancelled(reason) => reason.exit_code(),
+            StepTimedOut(err) => match err.step.node() {
+                Some(Node::Login) => exit_code::AUTH,
+                Some(Node::Dockerfile | Node::Build | Node::Sbom) => exit_code::BUILD,
+                Some(Node::Release | Node::Sign) => exit_code::PUSH,
+                Some(Node::Deploy) => exit_code::DEPLOY,
+                None => exit_code::CHECK,
+            },
+            _ => exit_code::FAILURE,
+        }
+    }
+
+    /// Stable code of the kind of failure, for CI dashboards to classify failures by.
+    /// Codes are never reused for another kind of failure. Keep in sync with the table in README.md.
+    fn code(&self) -> &'static str {
+        match self {
+            ConfigIncomplete => "NB-CONFIG-001",
+            ConfigParse(_) => "NB-CONFIG-002",
+            Config(_) => "NB-CONFIG-003",
+            DockerTag(_) => "NB-CONFIG-004",
+            DetectNaisYaml(_) => "NB-CONFIG-005",
+            Template(_) => "NB-CONFIG-006",
+            Schema(_) => "NB-CONFIG-007",
+            Ci(_) => "NB-CONFIG-008",
+            Resources(_) => "NB-CONFIG-009",
+            Artifacts(_) => "NB-CONFIG-010",
+            ShellStepDirectory { .. } => "NB-CONFIG-011",
+            SDKNotDetected => "NB-SDK-001",
+            SDKError(_) => "NB-SDK-002",
+            Docker(err) => match err {
+                docker::Error::Build(_) => "NB-BUILD-001",
+                docker::Error::Generate(_) => "NB-BUILD-002",
+                docker::Error::Git(_) | docker::Error::Pattern(_) => "NB-BUILD-003",
+                docker::Error::Push(_) => "NB-PUSH-001",
+                docker::Error::Login(_) => "NB-AUTH-001",
+                _ => "NB-DOCKER-001",
+            },
+            NativeBuildFailed { .. } => "NB-BUILD-004",
+            Gar(_) => "NB-PUSH-002",
+            GitHub(_) => "NB-PUSH-003",
+            Attest(attest::Error::Sign(_) | attest::Error::Execute { program: "cosign", .. }) => "NB-PUSH-004",
+            Attest(_) => "NB-BUILD-005",
+            Deploy(_) => "NB-DEPLOY-001",
+            ClusterNotGiven => "NB-USAGE-001",
+            Auth(_) => "NB-AUTH-002",
+            Keychain(_) => "NB-AUTH-003",
+            ValidationFailed(_) => "NB-CHECK-001",
+            Lint(_) => "NB-CHECK-002",
+            LintFailed(_) => "NB-CHECK-003",
+            TestsFailed(_) => "NB-CHECK-004",
+            SigningKeyNotAllowed(_) => "NB-CHECK-005",
+            DirtyWorkingTree(_) => "NB-CHECK-006",
+            ShellStepFailed { .. } => "NB-CHECK-007",
+            Verify(_) => "NB-CHECK-008",
+            VerificationFailed(_) => "NB-CHECK-009",
+            Licenses(_) => "NB-CHECK-010",
+            LicensesNotAllowed { .. } => "NB-CHECK-011",
+            ContainerFailed(_) => "NB-RUN-001",
+            Git(_) => "NB-GIT-001",
+            FilesystemError(_) => "NB-IO-001",
+            Ledger(_) => "NB-IO-002",
+            Checkpoint(_) => "NB-IO-003",
+            Json(_) => "NB-IO-004",
+            WriteDockerfile { .. } => "NB-IO-005",
+            Watch(_) => "NB-WATCH-001",
+            Init(_) => "NB-INIT-001",
+            Cancelled(shutdown::Reason::TimedOut(_)) => "NB-CANCEL-001",
+            Cancelled(_) => "NB-CANCEL-002",
+            StepTimedOut(_) => "NB-TIMEOUT-001",
+        }
+    }
+
+    /// What to do about the error, when there is more to say than the message.
+    fn hint(&self) -> Option<&'static str> {
+        match self {
+            ConfigIncomplete | Config(_) => Some("`nb config show` prints the effective configuration, after merging nb.toml and NB_* environment variables"),
+            ConfigParse(_) => Some("check the syntax of the file; settings and their defaults are documented in default.toml"),
+            DockerTag(_) => Some("check the placeholders of `[build.docker] tag_format`"),
+            DetectNaisYaml(_) => Some("put nais.yaml in the `.nais` directory, or give its path with --resource"),
+            Template(_) => Some("give the template variables of nais.yaml with --var KEY=VALUE or --vars FILE"),
+            SDKNotDetected => Some("run nb in the directory of the application, or set `[build] sdk` in nb.toml"),
+            Docker(docker::Error::Build(_)) => Some("the output of docker build is above; `nb build --dry-run` prints the Dockerfile and the build command"),
+            Docker(docker::Error::Git(_) | docker::Error::Pattern(_)) => Some("check `[build.docker] context_include`, or build with `context = \"directory\"`"),
+            Docker(docker::Error::Push(_) | docker::Error::Login(_)) | Auth(_) => Some("log in to the registry with `nb auth login`"),
+            Docker(_) => Some("check that Docker is installed and running with `docker info`"),
+            Gar(_) => Some("check that the GAR repository exists and that you may push to it, or set `[release] create_repository`"),
+            Attest(attest::Error::Execute { program: "syft", .. }) => Some("install syft, see https://github.com/anchore/syft#installation, or unset `[release] sbom`"),
+            Attest(attest::Error::Execute { .. }) => Some("install cosign, see https://docs.sigstore.dev/cosign/system_config/installation/, or unset `[release] sign`"),
+            Attest(attest::Error::Sign(_)) => Some("keyless signing needs the OIDC token of the CI job, e.g. the `id-token: write` permission in GitHub Actions; or set `[release] signing_key`"),
+            Deploy(_) => Some("the output of the deploy client is above; `nb deploy --dry-run` renders the resources without deploying them"),
+            ClusterNotGiven => Some("deploy profiles in nb.toml give the clusters to deploy to"),
+            Keychain(_) => Some("set NAIS_DEPLOY_APIKEY instead of storing the key in the keychain"),
+            ValidationFailed(_) => Some("`nb validate` lists the schema violations of each nais.yaml"),
+            LintFailed(_) => Some("`nb lint` lists the findings; rule severities can be changed in `[lint]`"),
+            Verify(verify::Error::Cosign(_)) => Some("install cosign, see https://docs.sigstore.dev/cosign/system_config/installation/"),
+            VerificationFailed(_) => Some("the report lists each check; the checks an image must pass are set in `[verify]`"),
+            Licenses(licenses::Error::NoLockfile(_)) => Some("commit the lockfile of the project; Gradle writes one with `./gradlew dependencies --write-locks`"),
+            LicensesNotAllowed { .. } => Some("the report lists the dependencies; denied licenses are set in `[licenses]`"),
+            Git(_) => Some("run nb in a Git checkout with at least one commit"),
+            Cancelled(shutdown::Reason::TimedOut(_)) => Some("give the command more time with --timeout"),
+            StepTimedOut(_) => Some("give the step more time with `timeout_seconds` in `[steps.<step>]`"),
+            _ => None,
+        }
+    }
+
+    /// The error as reported in `--output json`.
+    fn report(&self) -> report::ErrorReport {
+        report::ErrorReport {
+            code: self.code(),
+            message: self.to_string(),
+            hint: self.hint(),
+            exit_code: self.exit_code(),
+            file: self.file().map(str::to_string),
+        }
+    }
+
+    /// The file that caused the error, if known.
+    fn file(&self) -> Option<&str> {
+        use config::file::Error::{ParseConfig, ReadConfig};
+        match self {
+            ConfigParse(ReadConfig { filename, .. } | ParseConfig { filename, .. }) => Some(filename),
+            WriteDockerfile { path, .. } => Some(path),
+            Licenses(licenses::Error::Read { path, .. } | licenses::Error::Parse { path, .. }) => Some(path),
+            _ => None,
+        }
+    }
+}
+
+/// Aliases configured for the source directory. Configuration errors are reported later, when it is read again.
+fn aliases(source_directory: &str, config_file: Option<&str>) -> BTreeMap<String, String> {
+    let config_files = match config_file {
+        None => workspace_config_files(source_directory),
+        Some(config_file) => vec![config_file.to_string()],
+    };
+    config::file::File::default_with_user_config_files(&config_files)
+        .and_then(|cfg| cfg.with_env_overrides(std::env::vars()))
+        .map(|cfg| cfg.alias)
+        .unwrap_or_default()
 }
 
-/// Read configuration file from disk and merge it with the
+/// Read configuration files from disk and merge them with the
 /// `default.toml` [built-in config](../default.toml).
 ///
 /// If a configuration file name is not set explicitly, this function will
-/// detect whether a config file with the default file name exists on disk.
-/// If it does, it is used implicitly. If not, we ignore any read errors.
+/// detect whether config files with the default file name exist in the source
+/// directory or any of its parents, up to the root of the Git repository.
+/// This allows monorepos to keep shared settings in a root `nb.toml`, and
+/// per-application overrides in e.g. `apps/foo/nb.toml`. Files closer to the
+/// source directory take precedence.
+///
+/// Any `NB_*` environment variables are applied on top of the merged configuration,
+/// and the flags that override settings, such as `--log-file`, on top of those.
 fn read_config(args: &Cli) -> Result<config::file::File, Error> {
+    let config_files = match &args.config {
+        None => workspace_config_files(&args.source_directory),
+        Some(c) => vec![c.clone()],
+    };
+
+    for config_file in &config_files {
+        debug!("Using configuration file {config_file}");
+    }
+
+    let cfg = config::file::File::default_with_user_config_files(&config_files)?;
+
+    let mut cfg = cfg.with_env_overrides(std::env::vars())?;
+    args.override_config(&mut cfg);
+    Ok(cfg)
+}
+
+/// Find all `nb.toml` files from the repository root down to the source directory.
+/// If the source directory is not inside a Git repository, only the source directory is searched.
+fn workspace_config_files(source_directory: &str) -> Vec<String> {
     const DEFAULT_CONFIG_FILE: &str = "nb.toml";
 
-    // Typically found in project root, e.g. ./nb.toml
-    let config_path = format!("{}/{}", args.source_directory, DEFAULT_CONFIG_FILE);
-
-    let config_file = match &args.config {
-        None => {
-            if std::fs::metadata(&config_path)
-                .and_then(|metadata| Ok(metadata.is_file()))
-                .unwrap_or(false)
-            {
-                Some(config_path.into())
-            } else {
-                None
+    let is_file = |path: &std::path::Path| {
+        std::fs::metadata(path)
+            .map(|metadata| metadata.is_file())
+            .unwrap_or(false)
+    };
+
+    let source_directory = std::path::Path::new(source_directory);
+    let canonical = std::fs::canonicalize(source_directory).unwrap_or(source_directory.to_path_buf());
+    // Ask Git for the root, as `.git` may be a file in worktrees, or live elsewhere entirely.
+    let root = git::root(&source_directory.to_string_lossy())
+        .ok()
+        .and_then(|root| std::fs::canonicalize(root).ok());
+
+    let mut config_files = Vec::new();
+    for dir in canonical.ancestors() {
+        let config_path = dir.join(DEFAULT_CONFIG_FILE);
+        if is_file(&config_path) {
+            config_files.push(config_path.to_string_lossy().to_string());
+        }
+        if root.as_deref().is_none_or(|root| dir == root) {
+            break;
+        }
+    }
+
+    config_files.reverse();
+    config_files
+}
+
+/// Collect template variables for nais.yaml from configuration and the command line.
+/// Command line variables take precedence over variable files, which take precedence over configuration.
+fn template_vars(args: &Cli, cfg: &config::file::File) -> Result<template::Vars, Error> {
+    let mut vars = template::Vars::default();
+    if let Some(naisyaml) = &cfg.naisyaml {
+        vars.extend_from_toml(&naisyaml.vars)?;
+    }
+    for path in &args.vars {
+        vars.extend_from_file(path)?;
+    }
+    for var in &args.var {
+        vars.set_from_str(var)?;
+    }
+    Ok(vars)
+}
+
+/// Return where to look for nais.yaml files, as configured in `[naisyaml]` and selected with `--env`.
+///
+/// If the environment has its own directory, e.g. `.nais/dev`, and no paths are configured,
+/// all files in that directory are searched instead of matching file names.
+fn nais_yaml_search(args: &Cli, cfg: &config::file::File) -> Result<nais_yaml::Search, Error> {
+    let paths = cfg.naisyaml.as_ref().and_then(|settings| settings.paths.clone());
+    let exclude = cfg.naisyaml.as_ref().and_then(|settings| settings.base.clone()).into_iter().collect();
+    let Some(env) = &args.env else {
+        return Ok(nais_yaml::Search { paths, patterns: None, exclude });
+    };
+    let settings = cfg.naisyaml.as_ref().ok_or(ConfigIncomplete)?;
+
+    let env_directory = format!(".nais/{env}");
+    if paths.is_none() && std::path::Path::new(&args.source_directory).join(&env_directory).is_dir() {
+        debug!("Using environment directory {env_directory}");
+        return Ok(nais_yaml::Search {
+            paths: Some(vec![format!("{env_directory}/**/*.yaml"), format!("{env_directory}/**/*.yml")]),
+            patterns: None,
+            exclude,
+        });
+    }
+
+    Ok(nais_yaml::Search { paths, patterns: Some(settings.patterns_for(env)?), exclude })
+}
+
+/// Add the provenance annotations that are known before building to the deploy bundle,
+/// as used for output that is not deployed directly.
+fn render_bundle(args: &Cli, deploy_bundle: &str) -> Result<String, Error> {
+    let git_meta = git::metadata(&args.source_directory).ok();
+    let annotations = deploy::provenance_annotations(git_meta.as_ref(), None, release_notes::since_previous_release(&args.source_directory));
+    Ok(nais_yaml::annotate_workloads(deploy_bundle, &annotations)?)
+}
+
+/// Return the external source of resources configured in `[naisyaml] resources`, if any.
+fn resource_source(cfg: &config::file::File) -> Result<Option<resources::Source>, Error> {
+    Ok(cfg.naisyaml.as_ref()
+        .and_then(|settings| settings.resources.as_deref())
+        .map(str::parse)
+        .transpose()?)
+}
+
+/// Render a nais.yaml template, and apply it to the base manifest configured in `[naisyaml] base`, if any.
+fn render_nais_yaml(args: &Cli, cfg: &config::file::File, template: &str, vars: &template::Vars) -> Result<String, Error> {
+    let rendered = template::render(template, vars)?;
+    let Some(base) = cfg.naisyaml.as_ref().and_then(|settings| settings.base.as_ref()) else {
+        return Ok(rendered);
+    };
+    let base_path = std::path::Path::new(&args.source_directory).join(base);
+    let base = template::render(&nais_yaml::read_file(&base_path.to_string_lossy())?, vars)?;
+    Ok(nais_yaml::apply_patches(&base, &rendered)?)
+}
+
+/// Whether any file in the source directory, or in one of the configured build dependencies,
+/// has changed since the specified Git revision.
+fn changed_since(args: &Cli, cfg: &config::file::File, since: &str) -> Result<bool, Error> {
+    let dependencies = cfg.build.as_ref().map(|build| build.dependencies.as_slice()).unwrap_or_default();
+    Ok(git::changed_since(&args.source_directory, dependencies, since)?)
+}
+
+/// Read the nais.yaml templates selected by `--resource`, `[naisyaml] resources` or detection,
+/// returning pairs of path and template. Detected files are returned best candidate first.
+fn nais_yaml_templates(args: &Cli, cfg: &config::file::File) -> Result<Vec<(String, String)>, Error> {
+    Ok(match (&args.resource, resource_source(cfg)?) {
+        (Some(path), _) => vec![(path.clone(), nais_yaml::read_file(path)?)],
+        (None, Some(source)) => vec![(source.to_string(), source.render(&args.source_directory)?)],
+        (None, None) => {
+            let mut templates = Vec::new();
+            for path in nais_yaml::detect_nais_yaml_candidates(&vfs::Os, &args.source_directory, &nais_yaml_search(args, cfg)?)? {
+                let template = nais_yaml::read_file(&path)?;
+                templates.push((path, template));
+            }
+            templates
+        }
+    })
+}
+
+/// Check all detected nais.yaml files against best practices, printing any findings.
+fn lint(args: &Cli, cfg: &config::file::File, report: &mut report::Report) -> Result<(), Error> {
+    let rules = lint::Rules::new(cfg.lint.as_ref())?;
+    let vars = template_vars(args, cfg)?;
+    let templates = nais_yaml_templates(args, cfg)?;
+    if templates.is_empty() {
+        return Err(DetectNaisYaml(nais_yaml::Error::NaisYamlNotFound));
+    }
+
+    let mut errors = 0;
+    for (path, template) in templates {
+        let file_name = std::path::Path::new(&path).file_name().unwrap_or_default().to_string_lossy();
+        let production = args.env.as_deref().unwrap_or(&file_name).starts_with("prod");
+        let rendered = render_nais_yaml(args, cfg, &template, &vars)?;
+        for document in schema::documents(&rendered)? {
+            for finding in rules.check(&document, production) {
+                report.println(&format!("{path}: {finding}"));
+                let level = match finding.severity {
+                    config::runtime::Severity::Error => github::AnnotationLevel::Error,
+                    _ => github::AnnotationLevel::Warning,
+                };
+                let title = format!("lint: {}", finding.rule);
+                let message = format!("{}: {}", finding.path, finding.message);
+                github::Annotation { file: Some(&path), title: Some(&title), message: &message }.emit(level);
+                if finding.severity == config::runtime::Severity::Error {
+                    errors += 1;
+                }
+            }
+        }
+    }
+
+    match errors {
+        0 => Ok(()),
+        n => Err(LintFailed(n)),
+    }
+}
+
+/// Report the licenses of the dependencies of the project, and check them against the `[licenses]` policy.
+async fn licenses(args: &Cli, cfg: &config::file::File, report: &mut report::Report) -> Result<(), Error> {
+    let policy = cfg.licenses.clone().unwrap_or_default();
+    let source_directory = std::path::Path::new(&args.source_directory);
+    // Lockfiles are read regardless of the SDK, but a missing lockfile of the SDK leaves most dependencies out.
+    if let Ok(sdk) = init_sdk(&args.source_directory, cfg) {
+        match licenses::lockfile_of(sdk.name()) {
+            Some(lockfile) if !source_directory.join(lockfile).exists() => warn!("No {lockfile}; dependencies of the {} project are not scanned", sdk.name()),
+            None => warn!("The {} SDK has no lockfile; its dependencies are not scanned", sdk.name()),
+            Some(_) => {}
+        }
+    }
+
+    let mut scan = licenses::Scan::read(source_directory)?;
+    scan.resolve().await;
+    scan.check(&policy);
+    report.print(&scan.to_string());
+    for dependency in scan.denied() {
+        let message = format!("{} {} is licensed under {}", dependency.name, dependency.version, dependency.license.as_deref().unwrap_or_default());
+        github::Annotation { file: None, title: Some("denied license"), message: &message }.emit(github::AnnotationLevel::Error);
+    }
+
+    let path = licenses::path(&args.artifacts_file());
+    scan.write(&path)?;
+    info!("License report written to {}", path.display());
+    if let Err(err) = github::append_step_summary(&scan.markdown_summary()) {
+        warn!("GitHub Actions job summary not written: {err}");
+    }
+
+    let denied = scan.denied().count();
+    let unknown = scan.unknown().count();
+    report.licenses = Some(scan);
+    match (denied, unknown) {
+        (0, 0) => Ok(()),
+        (0, _) if !policy.fail_on_unknown => Ok(()),
+        (denied, unknown) => Err(LicensesNotAllowed { denied, unknown }),
+    }
+}
+
+/// Verify the signature and attestations of `image` against the `[verify]` policy, printing a report.
+async fn verify(cfg: &config::file::File, image: &str, report: &mut report::Report) -> Result<(), Error> {
+    let policy = cfg.verify.clone().unwrap_or_default();
+    let verification = verify::verify(image, &policy, &shutdown::token()).await?;
+    report.print(&verification.to_string());
+    for finding in verification.findings.iter().filter(|finding| finding.required && !finding.passed) {
+        let message = format!("{image}: {} failed: {}", finding.check, finding.detail);
+        github::Annotation { file: None, title: Some("verify"), message: &message }.emit(github::AnnotationLevel::Error);
+    }
+    report.image = Some(image.to_string());
+    report.digest = verification.digest.clone();
+    let failures = verification.failures();
+    report.verification = Some(verification);
+    match failures {
+        0 => Ok(()),
+        n => Err(VerificationFailed(n)),
+    }
+}
+
+/// Validate all detected nais.yaml files against the NAIS Application schema,
+/// printing any errors found.
+async fn validate(args: &Cli, cfg: &config::file::File, report: &mut report::Report) -> Result<(), Error> {
+    let schema_url = cfg.naisyaml.as_ref()
+        .and_then(|settings| settings.schema_url.as_ref())
+        .ok_or(ConfigIncomplete)?;
+    let schema_document = schema::fetch(schema_url).await?;
+    let vars = template_vars(args, cfg)?;
+
+    let templates = nais_yaml_templates(args, cfg)?;
+    if templates.is_empty() {
+        return Err(DetectNaisYaml(nais_yaml::Error::NaisYamlNotFound));
+    }
+
+    let mut violations = 0;
+    for (path, template) in templates {
+        let rendered = render_nais_yaml(args, cfg, &template, &vars)?;
+        for document in schema::documents(&rendered)? {
+            let kind = document["kind"].as_str().unwrap_or_default();
+            let api_version = document["apiVersion"].as_str().unwrap_or_default();
+            let Some(schema) = schema::schema_for(&schema_document, kind, api_version) else {
+                debug!("{path}: no schema for {api_version} {kind}, skipping");
+                continue;
+            };
+            for violation in schema::validate(&document, &schema) {
+                report.println(&format!("{path}: {violation}"));
+                let message = violation.to_string();
+                github::Annotation { file: Some(&path), title: Some("schema validation"), message: &message }.emit(github::AnnotationLevel::Error);
+                violations += 1;
             }
         }
-        Some(c) => Some(c.clone()),
+        info!("{path} validated");
+    }
+
+    match violations {
+        0 => Ok(()),
+        n => Err(ValidationFailed(n)),
+    }
+}
+
+/// Scaffold configuration files for a new project.
+/// Values not given on the command line are detected, and confirmed by the user if running in a terminal.
+fn init(args: &Cli, cfg: &config::file::File, app: Option<String>, team: Option<String>, force: bool) -> Result<(), Error> {
+    let sdk = init_sdk(&args.source_directory, cfg).ok().map(|sdk| sdk.name());
+    match sdk {
+        Some(sdk) => info!("SDK detected: {sdk}"),
+        None => info!("No SDK detected"),
+    }
+
+    let detected_app = std::fs::canonicalize(&args.source_directory)?
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string());
+    let detected_team = cfg.team.clone().or_else(auth::team_from_environment);
+    let interactive = prompt::is_interactive();
+
+    let ask = |value: Option<String>, question: &str, detected: Option<String>| -> Result<String, Error> {
+        match (value, interactive) {
+            (Some(value), _) => Ok(value),
+            (None, true) => Ok(prompt::input(question, detected.as_deref())?),
+            (None, false) => detected.ok_or(ConfigIncomplete),
+        }
+    };
+    let params = init::Params {
+        app: ask(app, "Application name", detected_app)?,
+        team: ask(team, "Team", detected_team)?,
+        sdk,
     };
 
-    Ok(if let Some(config_file) = config_file {
-        config::file::File::default_with_user_config_file(&config_file)?
-    } else {
-        config::file::File::default()
+    init::scaffold(&args.source_directory, &params, force)?;
+    Ok(())
+}
+
+/// Render the effective configuration in the requested format.
+fn show_config(cfg: &config::file::File, format: ConfigFormat) -> Result<String, Error> {
+    let value = cfg.redacted()?;
+    Ok(match format {
+        ConfigFormat::Toml => toml::to_string_pretty(&value).map_err(config::file::Error::from)?,
+        ConfigFormat::Json => serde_json::to_string_pretty(&value)?,
     })
 }
 
 #[tokio::main]
 async fn main() {
-    match run().await {
+    let args = alias::expand(&Cli::command(), std::env::args().collect(), aliases).unwrap_or_else(|err| {
+        Cli::command().error(clap::error::ErrorKind::InvalidValue, err).exit()
+    });
+    let matches = Cli::command().get_matches_from(args);
+    let args = Cli::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    let command = matches.subcommand_name().unwrap_or_default();
+    let mut report = report::Report::new(command, args.output);
+    color::init(args.color, args.non_interactive());
+    progress::init_logger(args.logger(), !args.quiet && !args.non_interactive()).expect("logger is initialized once");
+    if args.non_interactive() {
+        prompt::disable();
+    }
+    let artifacts_file = args.artifacts_file();
+    // Dry runs of build and release produce nothing; a dry run of deploy is recorded as such.
+    let produces_artifacts = !matches!(args.command, Commands::Build { dry_run: true } | Commands::Release { dry_run: true, .. });
+    let source_directory = args.source_directory.clone();
+    let print_timings = args.timings;
+    shutdown::install(args.timeout);
+    workspace::keep_workdir(args.keep_workdir);
+    let result = {
+        let run = run(args, &mut report);
+        tokio::pin!(run);
+        tokio::select! {
+            result = &mut run => result,
+            reason = shutdown::cancelled() => {
+                // Give the steps time to stop their processes, which they are told to do by the same cancellation.
+                let _ = tokio::time::timeout(shutdown::GRACE_PERIOD, run).await;
+                Err(Cancelled(reason))
+            }
+        }
+    };
+    // Steps fail when their processes are terminated, but the command failed because it was cancelled.
+    let result = match (result, shutdown::reason()) {
+        (Err(_), Some(reason)) => {
+            shutdown::remove_temporary_files();
+            Err(Cancelled(reason))
+        }
+        (result, _) => result,
+    };
+    if let Err(err) = &result {
+        let title = format!("nb {command} failed ({})", err.code());
+        let message = match err.hint() {
+            Some(hint) => format!("{err}\nhint: {hint}"),
+            None => err.to_string(),
+        };
+        github::Annotation { file: err.file(), title: Some(&title), message: &message }.emit(github::AnnotationLevel::Error);
+    }
+    if result.is_ok() && produces_artifacts && matches!(command, "build" | "release" | "deploy") {
+        if let Err(err) = github_outputs(&report) {
+            warn!("GitHub Actions outputs not written: {err}");
+        }
+        if let Err(err) = write_artifacts(&source_directory, &artifacts_file, &report) {
+            warn!("{artifacts_file} not written: {err}");
+        }
+    }
+    if matches!(result, Err(Cancelled(_))) && produces_artifacts && matches!(command, "build" | "release" | "deploy") {
+        // The steps record what they finished when they stop, unless they did not stop within the grace period.
+        report.record_events();
+        if let Err(err) = record_cancelled(&source_directory, &artifacts_file, &report) {
+            warn!("{} not written: {err}", ledger::path(&artifacts_file).display());
+        }
+    }
+    if print_timings {
+        eprint!("{}", report.timings_table());
+    }
+    report.finish(result.as_ref().err().map(Error::report));
+    workspace::cleanup();
+
+    match result {
         Ok(_) => std::process::exit(0),
         Err(err) => {
-            error!("fatal: {}", err.to_string());
-            std::process::exit(1)
+            error!("fatal: [{}] {err}", err.code());
+            if let Some(hint) = err.hint() {
+                error!("hint: {hint}");
+            }
+            std::process::exit(err.exit_code())
         }
     }
 }
 
-async fn release(registry: &str, docker_image_name: &str) -> Result<(), Error> {
-    // TODO: auth to ghcr
-    // FIXME: determine if the correct user is authed (@nais.io vs @tenant)
+/// Generate a CI workflow for the source directory.
+fn generate_ci(source_directory: &str, cfg: &config::file::File, provider: CiProvider, report: &mut report::Report) -> Result<(), Error> {
+    let release = cfg.release.as_ref().ok_or(ConfigIncomplete)?;
+    let profiles = cfg.deploy.as_ref()
+        .and_then(|deploy| deploy.nais.as_ref())
+        .map(|nais| nais.profiles.clone())
+        .unwrap_or_default();
+    // Workflows run from the repository root.
+    let relative_directory = git::root(source_directory).ok()
+        .and_then(|root| std::fs::canonicalize(root).ok())
+        .zip(std::fs::canonicalize(source_directory).ok())
+        .and_then(|(root, directory)| directory.strip_prefix(root).ok().map(|relative| relative.to_string_lossy().to_string()))
+        .unwrap_or(source_directory.to_string());
+    let params = ci::Params {
+        source_directory: &relative_directory,
+        release_type: release.typ.clone(),
+        branches: &cfg.branch,
+        profiles: &profiles,
+    };
+    let workflow = match provider {
+        CiProvider::Github => ci::github_workflow(&params)?,
+    };
+    report.print(&workflow);
+    Ok(())
+}
 
-    let token = auth::token().await?;
+/// Expose the built image to later steps of a GitHub Actions workflow, as step outputs and a job summary.
+fn github_outputs(report: &report::Report) -> std::io::Result<()> {
+    let artifacts = report.artifacts();
+    if artifacts.is_empty() {
+        return Ok(());
+    }
+    let outputs: Vec<(&str, &str)> = artifacts.iter().map(|(name, value)| (*name, value.as_str())).collect();
+    github::set_outputs(&outputs)?;
+    github::append_step_summary(&report.markdown_summary())
+}
 
-    // Sessions are automatically logged out when they go out of scope
-    let session = docker::Session::new(registry, &token)?;
-    session.push(docker_image_name)?;
+/// Write the artifacts of a successful command to `path`, replacing those of the previous run,
+/// and record them in the ledger.
+fn write_artifacts(source_directory: &str, path: &str, report: &report::Report) -> Result<(), Error> {
+    let mut manifest = report.manifest(git::sha(source_directory).ok());
+    manifest.licenses = Some(licenses::path(path)).filter(|licenses| licenses.exists()).map(|licenses| licenses.display().to_string());
+    if let Some(parent) = std::path::Path::new(path).parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, serde_json::to_string_pretty(&manifest)? + "\n")?;
+    ledger::append(&ledger::path(path), &manifest)?;
+    debug!("Artifacts written to {path}");
+    Ok(())
+}
 
+/// Record the steps that a cancelled run finished in the ledger, such as the push of an image, if any.
+fn record_cancelled(source_directory: &str, path: &str, report: &report::Report) -> Result<(), Error> {
+    if !report.events.iter().any(|event| matches!(event, events::Event::StepFinished { .. })) {
+        return Ok(());
+    }
+    let manifest = report::ArtifactManifest { cancelled: true, ..report.manifest(git::sha(source_directory).ok()) };
+    if let Some(parent) = std::path::Path::new(path).parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    ledger::append(&ledger::path(path), &manifest)?;
+    info!("Recorded the steps that finished before cancelling in {}", ledger::path(path).display());
     Ok(())
 }
 
-async fn run() -> Result<(), Error> {
-    env_logger::init();
+/// Print the latest state of each kind recorded in the ledger.
+///
+/// Nais deploy does not report which image is running, so deployments are shown as recorded.
+/// Built images are looked up in the local Docker daemon, and released images in their registry.
+async fn status(args: &Cli, report: &mut report::Report) -> Result<(), Error> {
+    let cancel = shutdown::token();
+    let ledger_path = ledger::path(&args.artifacts_file());
+    let entries = ledger::read(&ledger_path)?;
+    let latest = ledger::latest(&entries);
+    if latest.is_empty() {
+        info!("Nothing recorded in {}; run nb build, release or deploy first", ledger_path.display());
+        return Ok(());
+    }
+
+    let head = git::sha(&args.source_directory).ok();
+    let dirty = git::dirty_files(&args.source_directory).map(|files| files.len()).unwrap_or_default();
+    for entry in latest {
+        let image = entry.image.as_deref().unwrap_or("-");
+        let availability = match (entry.image.as_deref(), entry.command.as_str()) {
+            (None, _) => "",
+            (Some(image), "build") => match docker::image_digest(image, &cancel).await {
+                Ok(_) => "present locally",
+                Err(_) => "no longer present locally",
+            },
+            (Some(image), _) => match docker::exists_in_registry(image, &cancel).await {
+                Ok(true) => "in registry",
+                Ok(false) => "not found in registry, or not logged in",
+                Err(_) => "registry not checked",
+            },
+        };
+        let drift = match (&entry.git_sha, &head) {
+            (Some(built), Some(head)) if built == head => "at HEAD".to_string(),
+            (Some(built), Some(_)) => match git::commits_since(&args.source_directory, built) {
+                Ok(commits) => format!("{} commit(s) behind HEAD", commits.len()),
+                Err(_) => format!("built from {}", &built[..built.len().min(7)]),
+            },
+            _ => "commit unknown".to_string(),
+        };
+        report.println(&format!("{:<20} {image}", ledger::kind(entry)));
+        let details: Vec<String> = [availability.to_string(), drift, format!("recorded {}", entry.created)]
+            .into_iter()
+            .filter(|detail| !detail.is_empty())
+            .collect();
+        report.println(&format!("{:<20} {}", "", details.join(", ")));
+        if let Some(digest) = &entry.digest {
+            report.println(&format!("{:<20} {digest}", ""));
+        }
+    }
+    if dirty > 0 {
+        report.println(&format!("Working tree has {dirty} uncommitted change(s)"));
+    }
+    Ok(())
+}
+
+/// Remove files and images that nb has left behind, listing each of them.
+async fn clean(args: &Cli, dry_run: bool, report: &mut report::Report) -> Result<(), Error> {
+    let action = if dry_run { "Would remove" } else { "Removed" };
+    let mut removed = 0;
+
+    let workspace = workspace::Workspace::new(&args.source_directory);
+    let mut paths = workspace.generated_dockerfiles()?;
+    paths.extend(workspace.stale_workdirs()?);
+    paths.extend(cache::entries()?);
+    for path in paths {
+        if !dry_run {
+            match path.is_dir() {
+                true => std::fs::remove_dir_all(&path)?,
+                false => std::fs::remove_file(&path)?,
+            }
+        }
+        report.println(&format!("{action} {}", path.display()));
+        removed += 1;
+    }
+
+    let cancel = shutdown::token();
+    match docker::dangling_images(&cancel).await {
+        Ok(images) if !images.is_empty() => {
+            if !dry_run {
+                docker::remove_images(&images, &cancel).await?;
+            }
+            for image in &images {
+                report.println(&format!("{action} image {image}"));
+            }
+            removed += images.len();
+        }
+        Ok(_) => {}
+        // Cleaning files is still useful without Docker.
+        Err(err) => warn!("Untagged images not listed: {err}"),
+    }
+
+    let ledger_path = ledger::path(&args.artifacts_file());
+    let entries = ledger::read(&ledger_path)?;
+    let stale = ledger::stale(&entries);
+    if !stale.is_empty() {
+        for &index in &stale {
+            let entry = &entries[index];
+            report.println(&format!("{action} ledger entry {} {} from {}", ledger::kind(entry), entry.image.as_deref().unwrap_or("without image"), entry.created));
+        }
+        removed += stale.len();
+        if !dry_run {
+            let current: Vec<_> = entries.into_iter().enumerate()
+                .filter(|(index, _)| !stale.contains(index))
+                .map(|(_, entry)| entry)
+                .collect();
+            ledger::write(&ledger_path, &current)?;
+        }
+    }
+
+    match (removed, dry_run) {
+        (0, _) => info!("Nothing to clean"),
+        (n, true) => info!("{n} item(s) would be removed"),
+        (n, false) => info!("{n} item(s) removed"),
+    }
+    Ok(())
+}
+
+/// Apply the dirty policy before releasing or deploying from the source directory.
+fn check_dirty_policy(source_directory: &str, policy: config::runtime::DirtyPolicy) -> Result<(), Error> {
+    use config::runtime::DirtyPolicy;
+    if policy == DirtyPolicy::Allow {
+        return Ok(());
+    }
+    let dirty_files = git::dirty_files(source_directory)?;
+    if dirty_files.is_empty() {
+        return Ok(());
+    }
+    let level = match policy {
+        DirtyPolicy::Fail => log::Level::Error,
+        _ => log::Level::Warn,
+    };
+    for path in &dirty_files {
+        log::log!(level, "Uncommitted change: {path}");
+    }
+    match policy {
+        DirtyPolicy::Fail => Err(DirtyWorkingTree(dirty_files.len())),
+        _ => Ok(()),
+    }
+}
+
+fn changelog(source_directory: &str, since: Option<&str>, report: &mut report::Report) -> Result<(), Error> {
+    let commits = match since {
+        Some(since) => git::commits_since(source_directory, since)?,
+        None => git::commits_since_release(source_directory)?,
+    };
+    let version = git::version(source_directory)?.map(|version| format!("v{version}"));
+    report.print(&release_notes::changelog(version.as_deref(), &commits));
+    Ok(())
+}
+
+/// Require a valid signature by an allowed key on HEAD, or on the version tag pointing at HEAD.
+fn check_signature(source_directory: &str, allowed_keys: &[String]) -> Result<(), Error> {
+    let key = match git::verify_signature(source_directory, "HEAD", false) {
+        Ok(key) => key,
+        Err(err) => match git::version(source_directory)? {
+            Some(version) if version.is_release() => {
+                debug!("{err}; verifying tag instead");
+                git::verify_signature(source_directory, &format!("v{version}"), true)?
+            }
+            _ => return Err(err.into()),
+        },
+    };
+    if !allowed_keys.is_empty() && !allowed_keys.iter().any(|allowed| allowed.eq_ignore_ascii_case(&key)) {
+        return Err(SigningKeyNotAllowed(key));
+    }
+    info!("Release is signed with key {key}");
+    Ok(())
+}
+
+/// Create a GitHub release if HEAD is tagged with a version.
+async fn github_release(source_directory: &str) -> Result<(), Error> {
+    match git::version(source_directory)? {
+        Some(version) if version.is_release() => {
+            let git_meta = git::metadata(source_directory)?;
+            let notes = release_notes::since_previous_release(source_directory).unwrap_or_default();
+            github::create_release(&git_meta.owner, &git_meta.name, &format!("v{version}"), &notes).await?;
+        }
+        Some(version) => info!("Version {version} is not tagged; skipping GitHub release"),
+        None => warn!("No version tag found; skipping GitHub release"),
+    }
+    Ok(())
+}
+
+/// Run the tests of the detected SDK, in its builder image or with the local toolchain.
+async fn test(source_directory: &str, cfg: &config::file::File, native: bool) -> Result<(), Error> {
+    let sdk = init_sdk(source_directory, cfg)?;
+    info!("SDK detected: {}", sdk.name());
+    let cancel = shutdown::token();
+    let status = if native {
+        let command = sdk.test_command();
+        process::status(tokio::process::Command::new(&command[0])
+            .args(&command[1..])
+            .current_dir(sdk.filesystem_path())
+            .stdout(report::child_stdout()), &cancel).await?
+    } else {
+        docker::test(sdk.as_ref(), std::path::Path::new(&sdk.filesystem_path()), &cancel).await?
+    };
+    if !status.success() {
+        return Err(TestsFailed(status));
+    }
+    info!("Tests passed");
+    Ok(())
+}
+
+/// Build targets with the locally installed toolchain.
+async fn native_build(sdk: &dyn SDK, targets: &[String]) -> Result<(), Error> {
+    let cancel = shutdown::token();
+    for target in targets {
+        let command = sdk.native_build_command(target);
+        info!("Running `{}`", command.join(" "));
+        let status = process::status(tokio::process::Command::new(&command[0])
+            .args(&command[1..])
+            .current_dir(sdk.filesystem_path())
+            .stdout(report::child_stdout()), &cancel).await?;
+        if !status.success() {
+            return Err(NativeBuildFailed { target: target.clone(), status });
+        }
+    }
+    Ok(())
+}
+
+/// Run the image like NAIS would, and report when its probes first succeed.
+/// Returns when the container exits, e.g. when interrupted with Ctrl-C.
+async fn run_image(image_name: &str, nais_yaml: &NaisYaml, team: &str) -> Result<(), Error> {
+    const PROBE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+    const PROBE_ATTEMPTS: u32 = 60;
+
+    let mut args = vec![
+        "--env".to_string(), format!("NAIS_APP_NAME={}", nais_yaml.app),
+        "--env".to_string(), format!("NAIS_NAMESPACE={team}"),
+        "--env".to_string(), format!("NAIS_APP_IMAGE={image_name}"),
+    ];
+    args.extend(nais_yaml.docker_run_args());
+    let mut container = docker::run(image_name, &args)?;
+    let cancel = shutdown::token();
+
+    let client = reqwest::Client::new();
+    let mut pending = nais_yaml.probes();
+    let mut attempts = 0;
+    while !pending.is_empty() && attempts < PROBE_ATTEMPTS && !cancel.is_cancelled() {
+        if container.try_wait()?.is_some() {
+            break;
+        }
+        tokio::time::sleep(PROBE_INTERVAL).await;
+        attempts += 1;
+        let mut failing = Vec::new();
+        for (name, port, path) in pending {
+            let url = format!("http://localhost:{port}{path}");
+            match client.get(&url).timeout(PROBE_INTERVAL).send().await {
+                Ok(resp) if resp.status().is_success() => info!("{name} probe succeeded: {url}"),
+                Ok(resp) => {
+                    debug!("{name} probe returned {}: {url}", resp.status());
+                    failing.push((name, port, path));
+                }
+                Err(err) => {
+                    debug!("{name} probe failed: {err}");
+                    failing.push((name, port, path));
+                }
+            }
+        }
+        pending = failing;
+    }
+    for (name, port, path) in &pending {
+        warn!("{name} probe has not succeeded: http://localhost:{port}{path}");
+    }
+
+    let status = shutdown::wait(&mut container, &cancel).await?;
+    if !status.success() {
+        return Err(ContainerFailed(status));
+    }
+    Ok(())
+}
+
+/// Let the user choose a cluster from the deploy profiles, when none was given on the command line.
+fn ask_cluster(cfg: &config::file::File) -> Result<String, Error> {
+    if !prompt::is_interactive() {
+        return Err(ClusterNotGiven);
+    }
+    let mut clusters: Vec<String> = Vec::new();
+    let profiles = cfg.deploy.iter().flat_map(|deploy| &deploy.nais).flat_map(|nais| nais.profiles.values());
+    for cluster in profiles.flat_map(|profile| &profile.clusters) {
+        if !clusters.contains(cluster) {
+            clusters.push(cluster.clone());
+        }
+    }
+    match clusters.is_empty() {
+        true => Ok(prompt::input("Cluster", None)?),
+        false => Ok(prompt::select("Cluster", &clusters, 0)?),
+    }
+}
+
+/// Ask for a setting that could not be detected, and add the answer to the configuration.
+/// Returns false if `err` is not about a missing setting.
+fn ask_missing_setting(args: &Cli, cfg: &mut config::file::File, err: &config::runtime::Error) -> Result<bool, Error> {
+    match err {
+        config::runtime::Error::TeamNotDetected => {
+            let team = progress::suspend(|| prompt::input_validated("Team", None, validate_team))?;
+            info!("Set `team = \"{team}\"` in nb.toml to skip this question");
+            cfg.team = Some(team);
+        }
+        config::runtime::Error::InvalidRegistry { registry, .. } if registry.is_empty() => {
+            let Some(release) = cfg.release.as_mut() else {
+                return Ok(false);
+            };
+            let typ = release.typ.clone();
+            let suggested = suggested_registry(&typ, &args.source_directory);
+            let question = format!("Registry, as {}", typ.registry_format());
+            release.params_for_type_mut().registry = progress::suspend(|| {
+                prompt::input_validated(&question, suggested.as_deref(), |registry| typ.validate_registry(registry))
+            })?;
+        }
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+fn validate_team(team: &str) -> Result<(), String> {
+    match !team.is_empty() && team.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-') {
+        true => Ok(()),
+        false => Err("a team name has only lowercase letters, digits and dashes".to_string()),
+    }
+}
+
+/// A registry for the release type: the organization of the `origin` remote for GHCR,
+/// or the built-in default for the others.
+fn suggested_registry(typ: &config::runtime::ReleaseType, source_directory: &str) -> Option<String> {
+    if *typ == config::runtime::ReleaseType::GHCR {
+        if let Ok(git_meta) = git::metadata(source_directory) {
+            return Some(format!("ghcr.io/{}", git_meta.owner.to_lowercase()));
+        }
+    }
+    let mut release = config::file::File::default().release?;
+    release.typ = typ.clone();
+    Some(release.params_for_type().registry).filter(|registry| !registry.is_empty())
+}
+
+fn set_deploy_key(team: Option<String>) -> Result<(), Error> {
+    let team = team
+        .filter(|team| !team.is_empty())
+        .or_else(auth::team_from_environment)
+        .ok_or(config::runtime::Error::TeamNotDetected)?;
+    let apikey = if prompt::is_interactive() {
+        prompt::input(&format!("Deploy API key for team {team}"), None)?
+    } else {
+        let mut apikey = String::new();
+        std::io::stdin().read_line(&mut apikey)?;
+        apikey.trim().to_string()
+    };
+    if apikey.is_empty() {
+        return Err(Error::ConfigIncomplete);
+    }
+    keychain::store(&deploy::keychain_account(&team), &apikey)?;
+    info!("Deploy API key for team {team} stored in the keychain");
+    Ok(())
+}
+
+/// Who nb would log in to the release registry as, without acquiring credentials.
+fn login_identity(release: &config::runtime::Release) -> String {
+    match docker::helper_credentials(&release.params.registry) {
+        Some(credentials) => format!("{} from the Docker credential helper", credentials.username),
+        None => auth::Provider::for_release(release).to_string(),
+    }
+}
+
+async fn run(args: Cli, report: &mut report::Report) -> Result<(), Error> {
+    if let Commands::Version = &args.command {
+        report.println(&version::long());
+        return Ok(());
+    }
+
+    if let Commands::Man { output_dir } = &args.command {
+        match output_dir {
+            None => report.print(&man::render(Cli::command())?),
+            Some(directory) => {
+                for path in man::write_all(Cli::command(), std::path::Path::new(directory))? {
+                    info!("Wrote {}", path.display());
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    if let Commands::Clean { dry_run } = &args.command {
+        return clean(&args, *dry_run, report).await;
+    }
+
+    if let Commands::Status = &args.command {
+        return status(&args, report).await;
+    }
 
-    let args = Cli::parse();
     let cfg_file = read_config(&args)?;
+    let log_file = cfg_file.log.as_ref()
+        .map(|log| log.file.as_str())
+        .filter(|path| !path.is_empty());
+    match log_file {
+        Some(path) => log_file::open(std::path::Path::new(path)).unwrap_or_else(|err| {
+            warn!("{path} not written: {err}");
+            log_file::disable();
+        }),
+        None => log_file::disable(),
+    }
+    if let Some(log) = &cfg_file.log {
+        progress::prefix_output(log.prefix_output);
+        if !log.step_directory.is_empty() {
+            log_file::open_step_files(std::path::Path::new(&log.step_directory));
+        }
+    }
+    let endpoint = cfg_file.telemetry.as_ref().map(|telemetry| telemetry.endpoint.as_str());
+    let telemetry = telemetry::init(endpoint, &report.command).unwrap_or_else(|err| {
+        warn!("Telemetry disabled: {err}");
+        telemetry::Telemetry::disabled()
+    });
+    let mut bus = events::Bus::new();
+    report.subscribe(&bus);
+    if telemetry.enabled() {
+        let span = telemetry.span();
+        bus.spawn(|receiver| telemetry::record_events(span, receiver));
+    }
+    if let Some(webhook) = cfg_file.notify.as_ref().map(|notify| notify.slack_webhook.clone()).filter(|webhook| !webhook.is_empty()) {
+        bus.spawn(|receiver| notify::slack(webhook, receiver));
+    }
+    let result = run_with_config(args, cfg_file, report, &bus).instrument(telemetry.span()).await;
+    bus.close(std::time::Duration::from_secs(10)).await;
+    report.record_events();
+    telemetry.finish(&result);
+    result
+}
+
+async fn run_with_config(mut args: Cli, mut cfg_file: config::file::File, report: &mut report::Report, bus: &events::Bus) -> Result<(), Error> {
+    if let Commands::Config { command: ConfigCommands::Show { format } } = &args.command {
+        report.println(&show_config(&cfg_file, *format)?);
+        return Ok(());
+    }
+
+    info!("NAIS build {}", version::CLIENT_VERSION);
+
+    if let Commands::Init { app, team, force } = &args.command {
+        return init(&args, &cfg_file, app.clone(), team.clone(), *force);
+    }
+
+    if let Commands::Validate = &args.command {
+        return validate(&args, &cfg_file, report).await;
+    }
+
+    if let Commands::Licenses = &args.command {
+        return licenses(&args, &cfg_file, report).await;
+    }
+
+    if let Commands::Verify { image } = &args.command {
+        return verify(&cfg_file, image, report).await;
+    }
+
+    if let Commands::Lint = &args.command {
+        return lint(&args, &cfg_file, report);
+    }
 
-    info!("NAIS build 1.0.0");
+    if let Commands::Changelog { since } = &args.command {
+        return changelog(&args.source_directory, since.as_deref(), report);
+    }
+
+    if let Commands::Auth { command: AuthCommands::Login { force } } = &args.command {
+        let release = cfg_file.release.as_ref().ok_or(Error::ConfigIncomplete)?;
+        return Ok(auth::login(&release.typ, *force)?);
+    }
+
+    if let Commands::Ci { command: CiCommands::Generate { provider } } = &args.command {
+        return generate_ci(&args.source_directory, &cfg_file, *provider, report);
+    }
+
+    if let Commands::Auth { command: AuthCommands::SetDeployKey { team } } = &args.command {
+        return set_deploy_key(team.clone().or(cfg_file.team.clone()));
+    }
 
-    let nais_yaml_path = nais_yaml::detect_nais_yaml(&args.source_directory)?;
-    info!("nais.yaml detected at {nais_yaml_path}");
+    if let (Some(since), Commands::Build { .. } | Commands::Test { .. } | Commands::Release { .. } | Commands::Deploy { .. }) = (&args.changed_since, &args.command) {
+        if !changed_since(&args, &cfg_file, since)? {
+            info!("No changes since {since}; skipping");
+            return Ok(());
+        }
+    }
 
-    let nais_yaml_data = NaisYaml::parse_file(&nais_yaml_path)?;
+    // Tests need neither nais.yaml nor a release configuration.
+    if let Commands::Test { native } = &args.command {
+        return test(&args.source_directory, &cfg_file, *native).await;
+    }
 
-    let cfg = config::runtime::Config::new(&cfg_file, nais_yaml_data).map_err(Config)?;
+    // Ask for a missing cluster before building and releasing, not after.
+    if let Commands::Deploy { cluster: cluster @ None, .. } = &mut args.command {
+        *cluster = Some(ask_cluster(&cfg_file)?);
+    }
+
+    let detect = progress::step("detect");
+    let nais_yaml_search = nais_yaml_search(&args, &cfg_file)?;
+    let resource_source = resource_source(&cfg_file)?;
+    let (nais_yaml_path, nais_yaml_template) = match (&args.resource, &resource_source) {
+        (Some(path), _) => (path.clone(), nais_yaml::read_file(path)?),
+        (None, Some(source)) => {
+            info!("Rendering resources from {source}");
+            (source.to_string(), source.render(&args.source_directory)?)
+        }
+        (None, None) => {
+            let path = nais_yaml::detect_nais_yaml(&vfs::Os, &args.source_directory, &nais_yaml_search)?;
+            info!("nais.yaml detected at {path}");
+            let template = nais_yaml::read_file(&path)?;
+            (path, template)
+        }
+    };
+
+    // The image name depends on the application name, so it is not available yet.
+    let mut template_vars = template_vars(&args, &cfg_file)?;
+    let nais_yaml_data = NaisYaml::parse(&render_nais_yaml(&args, &cfg_file, &nais_yaml_template, &template_vars)?)?;
+
+    let cfg = loop {
+        match config::runtime::Config::new(&cfg_file, nais_yaml_data.clone()) {
+            Err(err) if prompt::is_interactive() && ask_missing_setting(&args, &mut cfg_file, &err)? => continue,
+            result => break result.map_err(Config)?,
+        }
+    };
 
     info!("Application name detected: {}", &cfg.app);
-    // FIXME: cfg.team might be an empty string
     info!("Team detected: {}", &cfg.team);
+    telemetry::record_workload(&cfg.team, &cfg.app);
+    progress::set_app(&cfg.app);
+    report.timings.insert("detect".to_string(), detect.elapsed().as_secs_f64());
+    detect.finish();
 
-    let sdk = init_sdk(&args.source_directory, &cfg_file)?;
+    // Only commands that build the project need an SDK.
+    let sdk = || init_sdk(&args.source_directory, &cfg_file);
 
     let mut docker_name_config = docker::name::Config {
         registry: cfg.release.params.registry.clone(),
-        tag: docker::tag::generate(&args.source_directory)?,
+        tag: docker::tag::generate(&args.source_directory, &cfg.tag_format)?,
         team: cfg.team.clone(),
         app: cfg.app.clone(),
     };
+    let checkpoint_path = checkpoint::path(&args.artifacts_file());
+    let commit = git::sha(&args.source_directory).ok();
+    let resumed = match args.resume {
+        true => checkpoint::read(&checkpoint_path)?.filter(|checkpoint| checkpoint.resumes(&cfg.app, commit.as_deref())),
+        false => None,
+    };
     if let Some(user_provided_tag) = &args.docker_image_name {
         docker_name_config.tag = user_provided_tag.clone();
         debug!("Docker tag overridden");
+    } else if let Some(resumed) = &resumed {
+        docker_name_config.tag = resumed.tag.clone();
     }
+    let image_tag = docker_name_config.tag.clone();
     let docker_image_name = cfg.release.docker_name_builder(docker_name_config).to_string();
+    let checkpoint = match resumed {
+        Some(resumed) if resumed.image == docker_image_name => {
+            let completed = resumed.completed.iter().map(|node| node.name()).collect::<Vec<_>>();
+            info!("Resuming with {docker_image_name}; skipping the finished steps: {}", completed.join(", "));
+            report.digest = resumed.digest.clone();
+            resumed
+        }
+        resumed => {
+            if args.resume {
+                info!("Nothing to resume for this commit{}; running all steps", if resumed.is_some() { " and image" } else { "" });
+            }
+            checkpoint::Checkpoint::new(&cfg.app, commit, &image_tag, &docker_image_name)
+        }
+    };
+    report.image = Some(docker_image_name.clone());
+    report.tag = Some(image_tag.clone());
+
+    template_vars.set("image", &docker_image_name);
+    let nais_yaml_rendered = render_nais_yaml(&args, &cfg_file, &nais_yaml_template, &template_vars)?;
+    debug!("Rendered {nais_yaml_path}:\n{nais_yaml_rendered}");
+
+    let nais_yaml = NaisYaml::parse(&nais_yaml_rendered)?;
+    let image_verified = nais_yaml.verify_image(&docker_image_name);
+    let allow_pinned_image = cfg_file.naisyaml.as_ref().is_some_and(|settings| settings.allow_pinned_image);
+    let pinned_image = match &image_verified {
+        Err(nais_yaml::Error::ImageNotTemplated(image)) if allow_pinned_image => Some(image.clone()),
+        _ => None,
+    };
 
-    match args.command {
+    // Supporting resources such as topics and alerts are deployed in the same bundle as the workload.
+    let mut deploy_bundle = nais_yaml_rendered;
+    // A resource source already renders every resource that should be deployed.
+    let additional_resources = match resource_source {
+        Some(_) => Vec::new(),
+        None => nais_yaml::detect_additional_resources(
+            &vfs::Os,
+            &args.source_directory,
+            &nais_yaml_search,
+            &nais_yaml_path,
+            |template| template::render(template, &template_vars).map_err(Error::from),
+        )?,
+    };
+    for (path, rendered) in additional_resources {
+        info!("Additional resources detected at {path}");
+        deploy_bundle.push_str(&format!("\n---\n{}", rendered.trim_start_matches("---").trim_start()));
+    }
+
+    let artifact_store = cfg_file.artifacts.as_ref()
+        .map(|artifacts| artifacts.store.as_str())
+        .filter(|location| !location.is_empty())
+        .map(|location| artifacts::Store::open(location, &format!("{}/{image_tag}", cfg.app), cfg.release.http.clone()))
+        .transpose()?;
+
+    let mut steps = Steps::new(&args.source_directory, &cfg_file, &cfg, &docker_image_name, report, bus)
+        .checkpointed(checkpoint, checkpoint_path);
+    steps.write_dockerfile = args.write_dockerfile.as_deref();
+    steps.artifact_store = artifact_store;
+    steps.output_directory = args.output_directory();
+    steps.sbom_file = Some(attest::sbom_path(&args.artifacts_file()));
+    // An image given on the command line, or pinned in nais.yaml, is used as is.
+    let image_given = args.docker_image_name.is_some() || pinned_image.is_some();
+    let git_tag_name = || cfg.release.git_tag_format.replace("{app}", &cfg.app).replace("{tag}", &image_tag);
+
+    match &args.command {
         Commands::Preflight => {
-            info!("Preflight starting; attempting to acquire Google token...");
-            auth::token().await?;
+            sdk()?;
+            info!("Preflight starting; attempting to acquire registry credentials...");
+            let registry = &cfg.release.params.registry;
+            if docker::helper_credentials(registry).is_none() {
+                auth::Provider::for_release(&cfg.release).credentials(&cfg.release.http, &shutdown::token()).await?;
+            }
             info!("Preflight completed successfully.");
         }
         Commands::Dockerfile => {
-            println!("{}\n", sdk.dockerfile()?);
+            let sdk = sdk()?;
+            steps.report.dockerfile = Some(docker::write_dockerfile(sdk.as_ref(), &cfg.app)?.to_string_lossy().to_string());
+            let dockerfile = sdk.dockerfile()?;
+            write_dockerfile_copy(steps.write_dockerfile, &dockerfile)?;
+            steps.report.println(&format!("{dockerfile}\n"));
             info!("Docker image tag: {}", docker_image_name);
         }
-        Commands::Build => {
-            docker::build(&sdk, &docker_image_name)?;
+        Commands::Build { dry_run: false } => {
+            steps.execute(&[Node::Build], &[]).await?;
+        }
+        Commands::Build { dry_run: true } => {
+            let sdk = sdk()?;
+            let source_directory = sdk.filesystem_path();
+            let mut builder = pipeline::Builder::new(sdk.as_ref(), &cfg);
+            if let Some(directory) = args.output_directory() {
+                builder = builder.excluding(&directory);
+            }
+            let files = builder.context_files()?;
+            info!("The build context would be {} files in {source_directory}, and the Dockerfile as {}", files.len(), docker::context::DOCKERFILE);
+            let dockerfile = workspace::Workspace::new(&source_directory).dockerfile(&cfg.app);
+            let builder = cfg.remote_builder.as_ref().map(|builder| builder.name());
+            let command = docker::build_command(&docker_image_name, &docker::source_labels(&args.source_directory), builder.as_deref());
+            let generated = sdk.dockerfile()?;
+            write_dockerfile_copy(steps.write_dockerfile, &generated)?;
+            steps.report.println(&generated);
+            steps.report.println(&process::command_line(&command));
+            info!("Dry run: would write {} and build {docker_image_name}", dockerfile.display());
         }
-        Commands::Release => {
-            // Release implies build, unless docker tag is supplied
-            if args.docker_image_name.is_none() {
-                docker::build(&sdk, &docker_image_name)?;
+        Commands::Watch { native, debounce_ms } => {
+            let sdk = sdk()?;
+            let mut watcher = watch::Watcher::new(&sdk.filesystem_path())?;
+            let debounce = std::time::Duration::from_millis(*debounce_ms);
+            let mut targets = sdk.detect_build_targets().map_err(sdk::Error::from)?;
+            loop {
+                let result = match native {
+                    true => native_build(sdk.as_ref(), &targets).await,
+                    false => steps.execute(&[Node::Build], &[]).await.map_err(Error::from),
+                };
+                // A failed build is reported, and retried after the next change.
+                match result {
+                    Ok(()) => info!("Build completed; watching for changes"),
+                    Err(err) => error!("Build failed: {err}; watching for changes"),
+                }
+                loop {
+                    // Wait on the runtime, so that Ctrl-C and --timeout stop an idle watch like a build.
+                    let changed = watcher.changes(debounce, &shutdown::token()).await?;
+                    info!("{} file(s) changed", changed.len());
+                    targets = sdk.affected_build_targets(&changed).map_err(sdk::Error::from)?;
+                    if !*native || !targets.is_empty() {
+                        break;
+                    }
+                }
             }
-            release(&cfg.release.params.registry, &docker_image_name).await?;
         }
-        Commands::Deploy { cluster } => {
-            let short_sha = git::short_sha(&args.source_directory)?;
-            let git_meta = git::metadata(&args.source_directory)?;
+        Commands::Run => {
+            let image_name = pinned_image.as_deref().unwrap_or(&docker_image_name);
+            let provided: &[Node] = if image_given { &[Node::Build] } else { &[] };
+            steps.execute(&[Node::Build], provided).await?;
+            run_image(image_name, &nais_yaml, &cfg.team).await?;
+        }
+        Commands::Release { git_tag, dry_run: true } => {
+            steps.report.println(&format!("image: {docker_image_name}"));
+            steps.report.println(&format!("registry: {}", cfg.release.params.registry));
+            steps.report.println(&format!("login: {}", login_identity(&cfg.release)));
+            if cfg.release.sbom {
+                steps.report.println(&format!("sbom: {}", attest::sbom_path(&args.artifacts_file()).display()));
+            }
+            if cfg.release.sign {
+                steps.report.println(&format!("sign: {}", cfg.release.signing_key.as_deref().unwrap_or("keyless")));
+            }
+            if *git_tag {
+                steps.report.println(&format!("git tag: {}", git_tag_name()));
+            }
+            info!("Dry run: would {}push {docker_image_name}", if args.docker_image_name.is_none() { "build and " } else { "" });
+        }
+        Commands::Release { git_tag, dry_run: false } => {
+            check_dirty_policy(&args.source_directory, cfg.release.dirty_policy)?;
+            if cfg.release.require_signed {
+                check_signature(&args.source_directory, &cfg.release.allowed_signing_keys)?;
+            }
+            // Release implies build, unless docker tag is supplied; an image that is not built here has no SBOM
+            let provided: &[Node] = if args.docker_image_name.is_some() { &[Node::Build, Node::Sbom] } else { &[] };
+            steps.execute(&[Node::Release, Node::Sign], provided).await?;
+            if cfg.release.github_release {
+                github_release(&args.source_directory).await?;
+            }
+            if *git_tag {
+                let name = git_tag_name();
+                git::create_tag(&args.source_directory, &name, &format!("Release {docker_image_name}"))?;
+                info!("Created Git tag {name}");
+                if cfg.release.push_git_tag {
+                    git::push_tag(&args.source_directory, &name)?;
+                    info!("Pushed Git tag {name} to origin");
+                }
+            }
+        }
+        Commands::Render { output_dir } => {
+            if let (Err(err), None) = (&image_verified, &pinned_image) {
+                warn!("{nais_yaml_path}: {err}");
+            }
+            let bundle = render_bundle(&args, &deploy_bundle)?;
+            let Some(output_dir) = output_dir else {
+                steps.report.print(&bundle);
+                return Ok(());
+            };
+            std::fs::create_dir_all(output_dir)?;
+            for (file_name, contents) in nais_yaml::split_resources(&bundle)? {
+                let path = std::path::Path::new(output_dir).join(file_name);
+                std::fs::write(&path, contents)?;
+                info!("Wrote {}", path.display());
+            }
+        }
+        Commands::Deploy { cluster, dry_run } => {
+            let cluster = cluster.as_deref().expect("cluster is chosen before detection");
+            let deployed_image = pinned_image.as_deref().unwrap_or(&docker_image_name);
+            if let Some(pinned_image) = &pinned_image {
+                info!("spec.image is pinned to {pinned_image}; skipping build and release");
+            }
 
-            // Deploy implies build and release, unless docker tag is supplied
-            if args.docker_image_name.is_none() {
-                docker::build(&sdk, &docker_image_name)?;
-                release(&cfg.release.params.registry, &docker_image_name).await?;
+            if *dry_run {
+                if let (Err(err), None) = (&image_verified, &pinned_image) {
+                    warn!("{nais_yaml_path}: {err}");
+                }
+                steps.report.println(&render_bundle(&args, &deploy_bundle)?);
+                steps.report.image = Some(deployed_image.to_string());
+                steps.report.deployment = Some(report::Deployment { cluster: cluster.to_string(), status: report::DeploymentStatus::DryRun });
+                info!("Dry run: would deploy {deployed_image} to {cluster}");
+                return Ok(());
             }
 
-            // FIXME: this should probably be a builder of some sort to validate the actual config
-            let mut cfg= deploy::Config::try_new_from_env().ok_or(ConfigIncomplete)?;
-            cfg.cluster = cluster;
-            cfg.owner = git_meta.owner;
-            cfg.git_ref = short_sha.to_string();
-            cfg.repository = git_meta.name;
-            cfg.resource = vec![nais_yaml_path.to_string()];
-            cfg.var = vec![format!("image={docker_image_name}")];
+            check_dirty_policy(&args.source_directory, cfg.release.dirty_policy)?;
 
-            deploy::deploy(cfg)?;
+            if pinned_image.is_none() {
+                image_verified.map_err(DetectNaisYaml)?;
+            }
+
+            steps.target = Some(DeployTarget {
+                image: deployed_image.to_string(),
+                bundle: deploy_bundle,
+                deployer: pipeline::Deployer::new(&cfg, cluster, &args.source_directory)?,
+            });
+            // Deploy implies build and release, unless docker tag is supplied or the image is pinned
+            let provided: &[Node] = if image_given { &[Node::Build, Node::Sbom, Node::Release, Node::Sign] } else { &[] };
+            steps.execute(&[Node::Deploy], provided).await?;
         }
+        Commands::Config { .. } | Commands::Validate | Commands::Lint | Commands::Verify { .. } | Commands::Licenses | Commands::Changelog { .. } | Commands::Init { .. } | Commands::Auth { .. } | Commands::Ci { .. } | Commands::Test { .. } | Commands::Version | Commands::Man { .. } | Commands::Clean { .. } | Commands::Status => unreachable!("handled before detection"),
     }
 
     Ok(())
 }
 
-fn init_sdk(
-    filesystem_path: &str,
-    cfg: &config::file::File,
-) -> Result<Box<dyn SDK>, Error> {
-    let sdk = cfg.sdk.clone().unwrap();
-
-    match sdk::golang::new(sdk::golang::Config {
-        filesystem_path: filesystem_path.to_string(),
-        docker_builder_image: sdk.go.build_docker_image.clone(),
-        docker_runtime_image: sdk.go.runtime_docker_image.clone(),
-        start_hook: None,
-        end_hook: None,
-    }) {
-        Ok(Some(sdk)) => {
-            return Ok(Box::new(sdk));
-        }
-        Ok(None) => {}
-        Err(err) => return Err(Error::from(err)),
-    }
-
-    match sdk::gradle::new(sdk::gradle::Config {
-        filesystem_path: filesystem_path.to_string(),
-        docker_builder_image: sdk.gradle.build_docker_image.clone(),
-        docker_runtime_image: sdk.gradle.runtime_docker_image.clone(),
-        settings_file: sdk.gradle.settings_file.clone(),
-        start_hook: None,
-        end_hook: None,
-    }) {
-        Ok(Some(sdk)) => {
-            return Ok(Box::new(sdk));
-        }
-        Ok(None) => {}
-        Err(err) => return Err(Error::from(err)),
-    }
-
-    match sdk::maven::new(sdk::maven::Config {
-        filesystem_path: filesystem_path.to_string(),
-        docker_builder_image: sdk.maven.build_docker_image.clone(),
-        docker_runtime_image: sdk.maven.runtime_docker_image.clone(),
-        start_hook: None,
-        end_hook: None,
-    }) {
-        Ok(Some(sdk)) => {
-            return Ok(Box::new(sdk));
-        }
-        Ok(None) => {}
-        Err(err) => return Err(Error::from(err)),
-    }
-    // try next sdk
-
-    Err(SDKNotDetected)
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::os::unix::process::ExitStatusExt;
+
+    #[test]
+    pub fn exit_codes() {
+        let status = |code: i32| std::process::ExitStatus::from_raw(code << 8);
+        let timed_out = |node| StepTimedOut(pipeline::StepTimedOut { step: pipeline::Step::Builtin(node), timeout: std::time::Duration::from_secs(1) });
+        let cases = [
+            (ConfigIncomplete, exit_code::CONFIG),
+            (SDKNotDetected, exit_code::SDK_NOT_DETECTED),
+            (ClusterNotGiven, exit_code::USAGE),
+            (Docker(docker::Error::Build(status(1))), exit_code::BUILD),
+            (Docker(docker::Error::Push(status(1))), exit_code::PUSH),
+            (Docker(docker::Error::Login(status(1))), exit_code::AUTH),
+            (Deploy(deploy::Error::Deploy(status(1))), exit_code::DEPLOY),
+            (DirtyWorkingTree(1), exit_code::CHECK),
+            (ShellStepFailed { name: "contract-test".into(), status: status(1) }, exit_code::CHECK),
+            (TestsFailed(status(3)), 3),
+            (TestsFailed(std::process::ExitStatus::from_raw(9)), exit_code::CHECK),
+            (Cancelled(shutdown::Reason::Interrupted), 130),
+            (Cancelled(shutdown::Reason::TimedOut(std::time::Duration::from_secs(1))), 124),
+            (timed_out(Node::Login), exit_code::AUTH),
+            (timed_out(Node::Build), exit_code::BUILD),
+            (timed_out(Node::Release), exit_code::PUSH),
+            (timed_out(Node::Sign), exit_code::PUSH),
+            (timed_out(Node::Deploy), exit_code::DEPLOY),
+            (Attest(attest::Error::Sbom(status(1))), exit_code::BUILD),
+            (Attest(attest::Error::Sign(status(1))), exit_code::PUSH),
+            (LintFailed(1), exit_code::CHECK),
+            (ValidationFailed(1), exit_code::CHECK),
+            (ContainerFailed(status(1)), exit_code::FAILURE),
+        ];
+        for (err, expected) in cases {
+            assert_eq!(err.exit_code(), expected, "{err}");
+        }
+    }
 }
@@ -0,0 +1,52 @@
+//! Manual pages in roff format, generated from the command line definitions for packaging.
+
+use std::path::{Path, PathBuf};
+
+/// Render the manual page of a command.
+pub fn render(command: clap::Command) -> std::io::Result<String> {
+    let mut page = Vec::new();
+    clap_mangen::Man::new(command).render(&mut page)?;
+    Ok(String::from_utf8_lossy(&page).to_string())
+}
+
+/// Write a manual page for the command and each of its subcommands to `directory`, named like
+/// `nb.1` and `nb-auth-login.1`, and return their paths.
+pub fn write_all(command: clap::Command, directory: &Path) -> std::io::Result<Vec<PathBuf>> {
+    std::fs::create_dir_all(directory)?;
+    let mut command = command;
+    // Subcommands are named after their parents, e.g. `nb-auth`, once the command is built.
+    command.build();
+    let mut paths = Vec::new();
+    write_recursively(&command, directory, &mut paths)?;
+    Ok(paths)
+}
+
+fn write_recursively(command: &clap::Command, directory: &Path, paths: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    let name = command.get_display_name().unwrap_or_else(|| command.get_name());
+    let path = directory.join(format!("{name}.1"));
+    std::fs::write(&path, render(command.clone())?)?;
+    paths.push(path);
+    for subcommand in command.get_subcommands().filter(|subcommand| !subcommand.is_hide_set() && subcommand.get_name() != "help") {
+        write_recursively(subcommand, directory, paths)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    pub fn write_manual_pages() {
+        let command = clap::Command::new("nb")
+            .about("NAIS build")
+            .subcommand(clap::Command::new("auth").subcommand(clap::Command::new("login").about("Log in")));
+        assert!(render(command.clone()).unwrap().contains(".TH nb 1"));
+
+        let directory = tempfile::tempdir().unwrap();
+        let paths = write_all(command, directory.path()).unwrap();
+        let names: Vec<_> = paths.iter().map(|path| path.file_name().unwrap().to_string_lossy().to_string()).collect();
+        assert_eq!(names, ["nb.1", "nb-auth.1", "nb-auth-login.1"]);
+        assert!(std::fs::read_to_string(&paths[2]).unwrap().contains("Log in"));
+    }
+}
@@ -1,7 +1,9 @@
-use std::fs::DirEntry;
-use log::{debug};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use log::{debug, info};
 use thiserror::Error;
 use Error::*;
+use crate::vfs::Filesystem;
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -19,104 +21,867 @@ pub enum Error {
 
     #[error("deserialize: {0}")]
     Deserialize(#[from] serde_yaml::Error),
+
+    #[error("several files are equally likely to be nais.yaml: {0}; select one with --resource")]
+    Ambiguous(String),
+
+    #[error("spec.image is not set; use `image: \"{{{{ image }}}}\"` to deploy the built image")]
+    ImageMissing,
+
+    #[error("spec.image is hardcoded to '{0}'; use `image: \"{{{{ image }}}}\"` to deploy the built image")]
+    ImageNotTemplated(String),
+
+    #[error("invalid file name pattern: {0}")]
+    Pattern(#[from] glob::PatternError),
 }
 
-fn walk_dir(filesystem_path: &str) -> Result<Vec<DirEntry>, std::io::Error> {
-    Ok(std::fs::read_dir(filesystem_path)?
-        .filter(|e| e.is_ok())
-        .map(|e| e.unwrap())
-        .collect())
+fn walk_dir(filesystem: &dyn Filesystem, filesystem_path: &str) -> Result<Vec<PathBuf>, std::io::Error> {
+    filesystem.read_dir(Path::new(filesystem_path))
 }
 
-// regex
-// \.?nais(erator)\.ya?ml
-// ^\.nais/.+\.ya?ml
-// ^\.nais/(dev|prod)(-(fss|gcp))?\.ya?ml
-/// Returns the path of the first and best detected nais.yaml
-pub fn detect_nais_yaml(filesystem_path: &str) -> Result<String, Error> {
-    // TODO: should be a well-known structure for resources and optionally variables
-    // - .nais
-    //    - <cluster>
-    //      - resource.(y(a?)ml)          # multi-document yaml
-    //      - vars.(y(a?)ml) | vars.json  # single-document yaml or json
+/// Where to look for nais.yaml files.
+#[derive(Debug, Default, Clone)]
+pub struct Search {
+    /// Glob patterns relative to the source directory, e.g. `.nais/**/*.y*ml`.
+    /// If unset, well-known file names in the source directory and `.nais` are searched.
+    pub paths: Option<Vec<String>>,
+
+    /// If set, only files with names matching any of these glob patterns are considered,
+    /// e.g. `dev*.yaml` for environment-specific files.
+    pub patterns: Option<Vec<String>>,
+
+    /// Paths relative to the source directory that are never considered, such as a base manifest.
+    pub exclude: Vec<String>,
+}
+
+impl Search {
+    fn is_excluded(&self, filesystem_path: &str, path: &str) -> bool {
+        let path = std::path::Path::new(path);
+        self.exclude.iter().any(|exclude| path == std::path::Path::new(filesystem_path).join(exclude))
+    }
+}
 
-    let root_dir_files = walk_dir(filesystem_path)?;
-    let nais_files = walk_dir(&format!("{}/.nais", filesystem_path)).unwrap_or_default();
-    let candidates = vec![
-        ".nais.yaml",
-        ".nais.yml",
-        ".naiserator.yaml",
-        ".naiserator.yml",
+/// Well-known nais.yaml file names, searched for in the source directory and `.nais`,
+/// grouped from highest to lowest priority.
+const CANDIDATES: &[&[&str]] = &[
+    &["nais.yaml", "nais.yml"],
+    &[".nais.yaml", ".nais.yml"],
+    &["naiserator.yaml", "naiserator.yml", ".naiserator.yaml", ".naiserator.yml"],
+    &[
         "dev-fss.yaml",
         "dev-fss.yml",
         "dev-gcp.yaml",
         "dev-gcp.yml",
         "dev.yml",
         "dev.yaml",
-        "nais.yaml",
-        "nais.yml",
-        "naiserator.yaml",
-        "naiserator.yml",
         "prod-fss.yaml",
         "prod-fss.yml",
         "prod-gcp.yaml",
         "prod-gcp.yml",
         "prod.yml",
         "prod.yaml",
-    ];
+    ],
+];
+
+/// Priority of a candidate; lower is better.
+/// Candidates with equal priority cannot be chosen between automatically.
+type Priority = (usize, usize);
+
+// regex
+// \.?nais(erator)\.ya?ml
+// ^\.nais/.+\.ya?ml
+// ^\.nais/(dev|prod)(-(fss|gcp))?\.ya?ml
+/// Returns the path of the best detected nais.yaml.
+///
+/// Files without workloads, such as supporting resources or template variables, are skipped.
+/// If several files are equally good candidates, an error listing them is returned.
+pub fn detect_nais_yaml(filesystem: &dyn Filesystem, filesystem_path: &str, search: &Search) -> Result<String, Error> {
+    let candidates = ranked_candidates(filesystem, filesystem_path, search)?;
+
+    if candidates.len() > 1 {
+        info!("{} nais.yaml candidates found: {}", candidates.len(), candidates.iter().map(|(_, path)| path.as_str()).collect::<Vec<_>>().join(", "));
+    }
+
+    // Candidates are read one priority at a time, and only until one with workloads is found.
+    for group in candidates.chunk_by(|(a, _), (b, _)| a == b) {
+        let mut best: Vec<String> = group
+            .iter()
+            .filter(|(_, path)| !contains_no_workload(filesystem, path))
+            .map(|(_, path)| path.clone())
+            .collect();
+        match best.len() {
+            0 => continue,
+            1 => return Ok(best.remove(0)),
+            _ => return Err(Ambiguous(best.join(", "))),
+        }
+    }
+    Err(NaisYamlNotFound)
+}
+
+/// Returns the paths of all detected nais.yaml files, best candidate first.
+/// See [detect_nais_yaml].
+pub fn detect_nais_yaml_candidates(filesystem: &dyn Filesystem, filesystem_path: &str, search: &Search) -> Result<Vec<String>, Error> {
+    Ok(ranked_candidates(filesystem, filesystem_path, search)?
+        .into_iter()
+        .map(|(_, path)| path)
+        .collect())
+}
+
+/// Returns all candidates sorted by priority, then by path.
+///
+/// With glob `paths`, files matching an earlier pattern are preferred. With file name `patterns`,
+/// files matching an earlier pattern are preferred. Otherwise, well-known file names are ranked
+/// as listed in [CANDIDATES], regardless of whether they are found in the source directory or `.nais`.
+fn ranked_candidates(filesystem: &dyn Filesystem, filesystem_path: &str, search: &Search) -> Result<Vec<(Priority, String)>, Error> {
+    // TODO: should be a well-known structure for resources and optionally variables
+    // - .nais
+    //    - <cluster>
+    //      - resource.(y(a?)ml)          # multi-document yaml
+    //      - vars.(y(a?)ml) | vars.json  # single-document yaml or json
+
+    let patterns = search.patterns.as_ref()
+        .map(|patterns| {
+            patterns
+                .iter()
+                .map(|pattern| glob::Pattern::new(pattern))
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .transpose()?;
+    let name_priority = |file_name: &str| match &patterns {
+        Some(patterns) => patterns.iter().position(|pattern| pattern.matches(file_name)),
+        None if search.paths.is_some() => Some(0),
+        None => CANDIDATES.iter().position(|names| names.contains(&file_name)),
+    };
+
+    let mut candidates: Vec<(Priority, String)> = manifest_files(filesystem, filesystem_path, search.paths.as_deref(), true)?
+        .into_iter()
+        .filter(|(_, path)| !search.is_excluded(filesystem_path, path))
+        .filter_map(|(path_priority, path)| {
+            name_priority(&file_name(&path)).map(|name_priority| ((path_priority, name_priority), path))
+        })
+        .collect();
+    candidates.sort();
+
+    for (_, path) in &candidates {
+        debug!("Possible nais.yaml candidate: {path}");
+    }
+    Ok(candidates)
+}
+
+/// Returns true if the file is valid YAML without any workloads, such as a file with
+/// supporting resources or template variables. Empty files and files that cannot be parsed,
+/// e.g. because they contain template expressions, are not excluded.
+fn contains_no_workload(filesystem: &dyn Filesystem, path: &str) -> bool {
+    let Ok(documents) = read(filesystem, path).and_then(|contents| documents(&contents)) else {
+        return false;
+    };
+    !documents.is_empty() && !documents.iter().any(|document| {
+        document["kind"].as_str().is_some_and(|kind| WORKLOAD_KINDS.contains(&kind))
+    })
+}
+
+/// List the files that may contain Kubernetes manifests, together with the index
+/// of the glob pattern they were found with.
+///
+/// If glob patterns are given, all files matching them are returned, in the order of the patterns.
+/// Otherwise, the files in the `.nais` directory are returned, preceded by those in the
+/// source directory if `include_root` is set.
+fn manifest_files(filesystem: &dyn Filesystem, filesystem_path: &str, paths: Option<&[String]>, include_root: bool) -> Result<Vec<(usize, String)>, Error> {
+    let Some(paths) = paths else {
+        let root_dir_files = match include_root {
+            true => walk_dir(filesystem, filesystem_path)?,
+            false => Vec::new(),
+        };
+        let nais_files = walk_dir(filesystem, &format!("{}/.nais", filesystem_path)).unwrap_or_default();
+
+        debug!("{} files found in project root", root_dir_files.len());
+        debug!("{} files found in .nais directory", nais_files.len());
+
+        return Ok([root_dir_files, nais_files]
+            .iter()
+            .flatten()
+            .filter(|path| filesystem.is_file(path))
+            .map(|path| (0, path.to_string_lossy().to_string()))
+            .collect());
+    };
+
+    // The source directory is walked once for all patterns, and only as deep as any of them can match.
+    let patterns: Vec<PathPattern> = paths.iter()
+        .map(|path| PathPattern::new(filesystem_path, path))
+        .collect::<Result<_, _>>()?;
+    let mut files: Vec<(usize, PathBuf)> = filesystem
+        .files(Path::new(filesystem_path), &|directory| patterns.iter().any(|pattern| pattern.may_contain(directory)))
+        .into_iter()
+        .filter_map(|path| Some((patterns.iter().position(|pattern| pattern.matches(&path))?, path)))
+        .collect();
+    files.sort();
+    for (index, path) in paths.iter().enumerate() {
+        debug!("{} files found matching {path}", files.iter().filter(|(file_index, _)| *file_index == index).count());
+    }
+    Ok(files.into_iter().map(|(index, path)| (index, path.to_string_lossy().to_string())).collect())
+}
+
+/// A glob pattern for files below the source directory, like `.nais/**/*.yaml`.
+struct PathPattern {
+    pattern: glob::Pattern,
+    /// The directory below which the pattern has no wildcards, like `.nais`.
+    base: PathBuf,
+    /// How many components the directories that matching files are in have at most, unless the pattern has `**`.
+    depth: Option<usize>,
+}
+
+impl PathPattern {
+    fn new(filesystem_path: &str, path: &str) -> Result<Self, glob::PatternError> {
+        let root = Path::new(filesystem_path);
+        let path = path.trim_start_matches("./");
+        let pattern = glob::Pattern::new(&format!("{}/{path}", glob::Pattern::escape(filesystem_path.trim_end_matches('/'))))?;
+        let mut components: Vec<&str> = path.split('/').filter(|component| !component.is_empty()).collect();
+        components.pop();
+        let base = components.iter()
+            .take_while(|component| !component.contains(['*', '?', '[']))
+            .fold(root.to_path_buf(), |base, component| base.join(component));
+        let depth = match components.contains(&"**") {
+            true => None,
+            false => Some(root.components().count() + components.len()),
+        };
+        Ok(PathPattern { pattern, base, depth })
+    }
+
+    /// Whether files matching the pattern can be in `directory` or below it.
+    fn may_contain(&self, directory: &Path) -> bool {
+        self.base.starts_with(directory) || directory.starts_with(&self.base)
+            && self.depth.is_none_or(|depth| directory.components().count() <= depth)
+    }
+
+    /// Like `glob::glob`, wildcards do not match `/`, but do match names starting with `.`.
+    fn matches(&self, path: &Path) -> bool {
+        let options = glob::MatchOptions { require_literal_separator: true, ..Default::default() };
+        self.pattern.matches_path_with(path, options)
+    }
+}
+
+fn file_name(path: &str) -> String {
+    std::path::Path::new(path)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+/// Read a nais.yaml file, without parsing it.
+pub fn read_file(path: &str) -> Result<String, Error> {
+    read(&crate::vfs::Os, path)
+}
+
+fn read(filesystem: &dyn Filesystem, path: &str) -> Result<String, Error> {
+    filesystem.read_to_string(Path::new(path)).map_err(|err| ReadFile {
+        err,
+        path: path.to_string(),
+    })
+}
+
+/// Kinds of workloads that can be built and deployed by NAIS build.
+pub const WORKLOAD_KINDS: &[&str] = &["Application", "Naisjob"];
+
+/// Kinds of supporting resources that are deployed together with the workload,
+/// such as Kafka topics, Cloud SQL instances and alerts.
+pub const ADDITIONAL_KINDS: &[&str] = &[
+    "Topic",
+    "Stream",
+    "Alert",
+    "PrometheusRule",
+    "SQLInstance",
+    "SQLDatabase",
+    "SQLUser",
+    "BigQueryDataset",
+];
+
+/// A Kubernetes resource found in a manifest file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Resource {
+    pub kind: String,
+    pub api_version: String,
+    pub name: String,
+    pub namespace: Option<String>,
+    /// The unparsed `spec` field; its contents depend on the kind of resource.
+    pub spec: serde_yaml::Value,
+}
+
+impl Resource {
+    pub fn is_workload(&self) -> bool {
+        WORKLOAD_KINDS.contains(&self.kind.as_str())
+    }
+
+    pub fn is_additional(&self) -> bool {
+        ADDITIONAL_KINDS.contains(&self.kind.as_str())
+    }
+}
 
-    debug!("{} files found in project root", root_dir_files.len());
-    debug!("{} files found in .nais directory", nais_files.len());
+/// Parse all documents in a multi-document YAML string, skipping empty documents.
+/// Anchors, aliases and `<<` merge keys are resolved.
+pub fn documents(yaml_string: &str) -> Result<Vec<serde_yaml::Value>, Error> {
+    use serde::Deserialize;
+    let mut documents = Vec::new();
+    for document in serde_yaml::Deserializer::from_str(yaml_string) {
+        let mut document = serde_yaml::Value::deserialize(document)?;
+        if !document.is_null() {
+            document.apply_merge()?;
+            documents.push(document);
+        }
+    }
+    Ok(documents)
+}
 
-    [root_dir_files, nais_files]
+/// Serialize documents into a multi-document YAML string.
+fn to_string(documents: &[serde_yaml::Value]) -> Result<String, Error> {
+    Ok(documents
         .iter()
-        .flatten()
-        .filter(|e| candidates.contains(&e.file_name().to_str().unwrap()))
-        .inspect(|nais_yaml_path| {
-            let path = nais_yaml_path.path().to_str().unwrap_or_default().to_string();
-            debug!("Possible nais.yaml candidate: {path}")
+        .map(serde_yaml::to_string)
+        .collect::<Result<Vec<_>, _>>()?
+        .join("---\n"))
+}
+
+/// Parse all resources in a multi-document YAML string.
+pub fn parse_resources(yaml_string: &str) -> Result<Vec<Resource>, Error> {
+    documents(yaml_string)?
+        .into_iter()
+        .map(|document| {
+            let parsed: yaml::KubernetesResource = serde_yaml::from_value(document)?;
+            Ok(Resource {
+                kind: parsed.kind,
+                api_version: parsed.api_version,
+                name: parsed.metadata.name,
+                namespace: parsed.metadata.namespace,
+                spec: parsed.spec,
+            })
         })
-        .collect::<Vec<_>>()
-        .iter().next() // Needed in order for inspect() to gather all values
-        .ok_or(NaisYamlNotFound)
-        .map(|e| e.path().to_str().unwrap().to_string())
+        .collect()
+}
+
+/// Add annotations to the metadata of all workloads in a multi-document YAML string.
+/// Existing annotations with the same names are replaced. Other resources are left untouched,
+/// but all documents are re-serialized, so comments are not preserved.
+pub fn annotate_workloads(yaml_string: &str, annotations: &BTreeMap<String, String>) -> Result<String, Error> {
+    let mut documents = documents(yaml_string)?;
+    for document in &mut documents {
+        let is_workload = document["kind"].as_str().is_some_and(|kind| WORKLOAD_KINDS.contains(&kind));
+        if let Some(metadata) = document.get_mut("metadata").filter(|_| is_workload && !annotations.is_empty()) {
+            if metadata.get("annotations").is_none_or(serde_yaml::Value::is_null) {
+                metadata["annotations"] = serde_yaml::Mapping::new().into();
+            }
+            for (key, value) in annotations {
+                metadata["annotations"][key.as_str()] = value.clone().into();
+            }
+        }
+    }
+    to_string(&documents)
+}
+
+/// Split a multi-document YAML string into one file per resource,
+/// returning pairs of file name, e.g. `application-myapp.yaml`, and contents.
+pub fn split_resources(yaml_string: &str) -> Result<Vec<(String, String)>, Error> {
+    let mut files: Vec<(String, String)> = Vec::new();
+    for document in documents(yaml_string)? {
+        let kind = document["kind"].as_str().unwrap_or("resource").to_lowercase();
+        let name = document["metadata"]["name"].as_str().unwrap_or("unnamed").to_string();
+        let mut file_name = format!("{kind}-{name}.yaml");
+        let mut index = 1;
+        while files.iter().any(|(existing, _)| *existing == file_name) {
+            index += 1;
+            file_name = format!("{kind}-{name}-{index}.yaml");
+        }
+        files.push((file_name, serde_yaml::to_string(&document)?));
+    }
+    Ok(files)
+}
+
+/// Apply per-environment patches to a base manifest, both multi-document YAML strings.
+///
+/// Each patch document is applied as a JSON merge patch (RFC 7386) to the base document with the
+/// same `kind` and `metadata.name`, or the same `kind` if the patch has no name. Maps are merged
+/// recursively, `null` removes a field, and all other values, including lists, are replaced.
+/// Patch documents without a matching base document are added as they are.
+pub fn apply_patches(base: &str, patches: &str) -> Result<String, Error> {
+    let mut merged = documents(base)?;
+    for patch in documents(patches)? {
+        let matches = |document: &serde_yaml::Value| {
+            document["kind"] == patch["kind"]
+                && (patch["metadata"]["name"].is_null() || document["metadata"]["name"] == patch["metadata"]["name"])
+        };
+        match merged.iter_mut().find(|document| matches(document)) {
+            Some(document) => merge_patch(document, &patch),
+            None => merged.push(patch),
+        }
+    }
+    to_string(&merged)
 }
 
+fn merge_patch(target: &mut serde_yaml::Value, patch: &serde_yaml::Value) {
+    let serde_yaml::Value::Mapping(patch) = patch else {
+        *target = patch.clone();
+        return;
+    };
+    if !target.is_mapping() {
+        *target = serde_yaml::Mapping::new().into();
+    }
+    let serde_yaml::Value::Mapping(target) = target else {
+        unreachable!("target was replaced by a mapping")
+    };
+    for (key, value) in patch {
+        if value.is_null() {
+            target.remove(key);
+        } else {
+            merge_patch(target.entry(key.clone()).or_insert(serde_yaml::Value::Null), value);
+        }
+    }
+}
+
+/// Returns the paths and rendered contents of manifest files that contain only
+/// supporting resources, i.e. that should be deployed together with the workload.
+/// Files are searched for in the `.nais` directory, or in `search.paths` if set.
+///
+/// `render` is used to expand templates before the files are classified.
+pub fn detect_additional_resources<E>(
+    filesystem: &dyn Filesystem,
+    filesystem_path: &str,
+    search: &Search,
+    exclude: &str,
+    render: impl Fn(&str) -> Result<String, E>,
+) -> Result<Vec<(String, String)>, E>
+where
+    E: From<Error>,
+{
+    let mut files: Vec<_> = manifest_files(filesystem, filesystem_path, search.paths.as_deref(), false)?
+        .into_iter()
+        .map(|(_, path)| path)
+        .filter(|path| path.ends_with(".yaml") || path.ends_with(".yml"))
+        .filter(|path| path != exclude && !search.is_excluded(filesystem_path, path))
+        .collect();
+    if search.paths.is_none() {
+        files.sort();
+    }
+
+    let mut additional = Vec::new();
+    for path in files {
+        let rendered = render(&read(filesystem, &path)?)?;
+        let resources = match parse_resources(&rendered) {
+            Ok(resources) => resources,
+            Err(err) => {
+                debug!("{path}: not a Kubernetes manifest: {err}");
+                continue;
+            }
+        };
+        if resources.is_empty() || !resources.iter().all(Resource::is_additional) {
+            continue;
+        }
+        for resource in &resources {
+            debug!("{path}: found {} {}", resource.kind, resource.name);
+        }
+        additional.push((path, rendered));
+    }
+    Ok(additional)
+}
+
+#[derive(Clone)]
 pub struct NaisYaml {
-    pub team: String,
+    /// Team name, taken from `metadata.namespace`, if present.
+    pub team: Option<String>,
     pub app: String,
+    /// Image, port, probes and environment of the workload.
+    pub spec: yaml::Spec,
+    /// All resources in the file, including the workload.
+    pub resources: Vec<Resource>,
 }
 
 impl NaisYaml {
+    /// Parse a nais.yaml file, which may contain multiple documents.
+    /// Name and team are taken from the workload, or the first resource if there is no workload.
     pub fn parse(yaml_string: &str) -> Result<Self, Error> {
-        let parsed = serde_yaml::from_str::<yaml::KubernetesResource>(yaml_string)?;
+        let resources = parse_resources(yaml_string)?;
+        let workload = resources
+            .iter()
+            .find(|resource| resource.is_workload())
+            .or(resources.first())
+            .ok_or(NaisYamlNotFound)?;
+        let spec = match workload.is_workload() && !workload.spec.is_null() {
+            true => serde_yaml::from_value(workload.spec.clone())?,
+            false => yaml::Spec::default(),
+        };
         Ok(Self {
-            team: parsed.metadata.namespace,
-            app: parsed.metadata.name,
+            team: workload.namespace.clone(),
+            app: workload.name.clone(),
+            spec,
+            resources,
         })
     }
 
     pub fn parse_file(path: &str) -> Result<Self, Error> {
-        Self::parse(
-            &std::fs::read_to_string(path).map_err(|err| ReadFile {
-                err,
-                path: path.to_string(),
-            })?
-        )
+        Self::parse(&read_file(path)?)
+    }
+
+    /// Check that the workload will run the expected image.
+    /// This fails if `spec.image` is hardcoded rather than set to `{{ image }}`,
+    /// since the freshly built image would then not be the one deployed.
+    pub fn verify_image(&self, expected: &str) -> Result<(), Error> {
+        match &self.spec.image {
+            None => Err(ImageMissing),
+            Some(image) if image != expected => Err(ImageNotTemplated(image.clone())),
+            Some(_) => Ok(()),
+        }
+    }
+
+    /// Arguments to `docker run` that make a container behave like it would on NAIS:
+    /// the application and probe ports are published on the same ports locally, and plain environment
+    /// variables are set. Variables referencing secrets or config maps are omitted.
+    pub fn docker_run_args(&self) -> Vec<String> {
+        let mut ports: Vec<u16> = self.spec.port.into_iter().collect();
+        for (_, port, _) in self.probes() {
+            if !ports.contains(&port) {
+                ports.push(port);
+            }
+        }
+        let mut args = Vec::new();
+        for port in ports {
+            args.extend(["--publish".to_string(), format!("{port}:{port}")]);
+        }
+        for var in &self.spec.env {
+            if let Some(value) = &var.value {
+                args.extend(["--env".to_string(), format!("{}={value}", var.name)]);
+            }
+        }
+        args
+    }
+
+    /// Liveness and readiness probes as name, port and path.
+    /// Probes without a port are served on the application port, and are skipped if that is not set either.
+    pub fn probes(&self) -> Vec<(&'static str, u16, String)> {
+        [("liveness", &self.spec.liveness), ("readiness", &self.spec.readiness)]
+            .into_iter()
+            .filter_map(|(name, probe)| {
+                let probe = probe.as_ref()?;
+                let port = probe.port.or(self.spec.port)?;
+                Some((name, port, format!("/{}", probe.path.trim_start_matches('/'))))
+            })
+            .collect()
     }
 }
 
-mod yaml {
+pub mod yaml {
     use serde::Deserialize;
 
     #[derive(Deserialize)]
     pub struct Metadata {
         pub name: String,
-        pub namespace: String,
+        pub namespace: Option<String>,
+    }
+
+    /// The parts of a NAIS workload's `spec` that are used by NAIS build.
+    #[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+    pub struct Spec {
+        pub image: Option<String>,
+        pub port: Option<u16>,
+        pub liveness: Option<Probe>,
+        pub readiness: Option<Probe>,
+        #[serde(default)]
+        pub env: Vec<EnvVar>,
+    }
+
+    #[derive(Deserialize, Debug, Clone, PartialEq)]
+    pub struct Probe {
+        pub path: String,
+        /// Defaults to the application port.
+        pub port: Option<u16>,
+    }
+
+    #[derive(Deserialize, Debug, Clone, PartialEq)]
+    pub struct EnvVar {
+        pub name: String,
+        /// Unset if the value comes from a secret or config map.
+        pub value: Option<String>,
     }
 
     #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
     pub struct KubernetesResource {
+        #[serde(default)]
+        pub kind: String,
+        #[serde(default)]
+        pub api_version: String,
         pub metadata: Metadata,
+        #[serde(default)]
+        pub spec: serde_yaml::Value,
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::vfs::{Memory, Os};
+
+    #[test]
+    pub fn detect_environment_specific_nais_yaml() {
+        let filesystem = Memory::new()
+            .with_file("/src/.nais/dev-gcp.yml", "")
+            .with_file("/src/.nais/prod-gcp.yml", "");
+
+        let patterns = vec!["prod*.yml".to_string()];
+        let search = Search { patterns: Some(patterns), ..Default::default() };
+        let detected = detect_nais_yaml(&filesystem, "/src", &search).unwrap();
+        assert_eq!(detected, "/src/.nais/prod-gcp.yml");
+
+        let patterns = vec!["staging*.yml".to_string()];
+        let search = Search { patterns: Some(patterns), ..Default::default() };
+        assert!(matches!(detect_nais_yaml(&filesystem, "/src", &search), Err(NaisYamlNotFound)));
+    }
+
+    #[test]
+    pub fn detect_nais_yaml_with_glob_paths() {
+        let filesystem = Memory::new()
+            .with_file("/src/.nais/dev/app.yaml", "")
+            .with_file("/src/deploy/app.yaml", "")
+            .with_file("/src/deploy/old/app.yaml", "")
+            .with_file("/src/deploy/README.md", "");
+
+        assert!(matches!(detect_nais_yaml(&filesystem, "/src", &Search::default()), Err(NaisYamlNotFound)));
+
+        let paths = vec!["deploy/*.yaml".to_string(), ".nais/**/*.y*ml".to_string()];
+        let search = Search { paths: Some(paths), ..Default::default() };
+        let detected = detect_nais_yaml_candidates(&filesystem, "/src", &search).unwrap();
+        assert_eq!(detected, vec!["/src/deploy/app.yaml", "/src/.nais/dev/app.yaml"]);
+    }
+
+    #[test]
+    pub fn walk_only_directories_patterns_can_match() {
+        let pattern = PathPattern::new("/src", "./deploy/*/app.yaml").unwrap();
+        assert!(pattern.may_contain(Path::new("/src/deploy")));
+        assert!(pattern.may_contain(Path::new("/src/deploy/dev")));
+        assert!(!pattern.may_contain(Path::new("/src/deploy/dev/old")));
+        assert!(!pattern.may_contain(Path::new("/src/node_modules")));
+        assert!(pattern.matches(Path::new("/src/deploy/dev/app.yaml")));
+        assert!(!pattern.matches(Path::new("/src/deploy/dev/old/app.yaml")));
+
+        let pattern = PathPattern::new("/src", ".nais/**/*.yaml").unwrap();
+        assert!(pattern.may_contain(Path::new("/src/.nais/dev/old")));
+        assert!(!pattern.may_contain(Path::new("/src/deploy")));
+    }
+
+    #[test]
+    pub fn detect_nais_yaml_with_glob_paths_on_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join(".nais/dev")).unwrap();
+        std::fs::create_dir_all(dir.path().join("deploy")).unwrap();
+        std::fs::write(dir.path().join(".nais/dev/app.yaml"), "").unwrap();
+        std::fs::write(dir.path().join("deploy/app.yaml"), "").unwrap();
+        std::fs::write(dir.path().join("deploy/README.md"), "").unwrap();
+        let root = dir.path().to_str().unwrap();
+
+        let paths = vec!["deploy/*.yaml".to_string(), ".nais/**/*.y*ml".to_string()];
+        let search = Search { paths: Some(paths), ..Default::default() };
+        let detected = detect_nais_yaml_candidates(&Os, root, &search).unwrap();
+        assert_eq!(detected, vec![format!("{root}/deploy/app.yaml"), format!("{root}/.nais/dev/app.yaml")]);
+    }
+
+    #[test]
+    pub fn detect_additional_resource_kinds() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join(".nais")).unwrap();
+        let application = r#"
+apiVersion: nais.io/v1alpha1
+kind: Application
+metadata:
+  name: myapp
+  namespace: myteam
+"#;
+        let topic = r#"
+apiVersion: kafka.nais.io/v1
+kind: Topic
+metadata:
+  name: mytopic
+"#;
+        std::fs::write(dir.path().join(".nais/nais.yaml"), format!("{topic}---{application}")).unwrap();
+        std::fs::write(dir.path().join(".nais/topic.yaml"), topic).unwrap();
+        std::fs::write(dir.path().join(".nais/prod.yaml"), application).unwrap();
+        std::fs::write(dir.path().join(".nais/vars.yaml"), "ingress: https://foo.example\n").unwrap();
+        let root = dir.path().to_str().unwrap();
+
+        let nais_yaml = NaisYaml::parse_file(&format!("{root}/.nais/nais.yaml")).unwrap();
+        assert_eq!(nais_yaml.app, "myapp");
+        assert_eq!(nais_yaml.team, Some("myteam".into()));
+        assert_eq!(nais_yaml.resources.len(), 2);
+
+        let additional = detect_additional_resources(
+            &Os,
+            root,
+            &Search::default(),
+            &format!("{root}/.nais/nais.yaml"),
+            |template| Ok::<_, Error>(template.to_string()),
+        ).unwrap();
+        let paths: Vec<_> = additional.iter().map(|(path, _)| path.as_str()).collect();
+        assert_eq!(paths, vec![format!("{root}/.nais/topic.yaml")]);
+    }
+
+    #[test]
+    pub fn detect_nais_yaml_priority() {
+        let filesystem = Memory::new()
+            .with_file("/src/.nais/dev.yaml", "")
+            .with_file("/src/.nais/prod.yaml", "");
+        assert!(matches!(detect_nais_yaml(&filesystem, "/src", &Search::default()), Err(Ambiguous(_))));
+
+        let filesystem = filesystem.with_file("/src/.nais/naiserator.yaml", "");
+        assert_eq!(detect_nais_yaml(&filesystem, "/src", &Search::default()).unwrap(), "/src/.nais/naiserator.yaml");
+
+        // Files with only supporting resources are never chosen.
+        let filesystem = filesystem.with_file("/src/.nais/nais.yaml", "apiVersion: kafka.nais.io/v1\nkind: Topic\nmetadata:\n  name: t\n");
+        assert_eq!(detect_nais_yaml(&filesystem, "/src", &Search::default()).unwrap(), "/src/.nais/naiserator.yaml");
+
+        let filesystem = filesystem.with_file("/src/nais.yaml", "");
+        assert_eq!(detect_nais_yaml(&filesystem, "/src", &Search::default()).unwrap(), "/src/nais.yaml");
+    }
+
+    #[test]
+    pub fn verify_templated_image() {
+        let template = "kind: Application\nmetadata:\n  name: myapp\nspec:\n  image: {{ image }}\n";
+        let mut vars = crate::template::Vars::default();
+        vars.set("image", "ghcr.io/navikt/myapp:1");
+        let nais_yaml = NaisYaml::parse(&crate::template::render(template, &vars).unwrap()).unwrap();
+        assert!(nais_yaml.verify_image("ghcr.io/navikt/myapp:1").is_ok());
+
+        let nais_yaml = NaisYaml::parse("kind: Application\nmetadata:\n  name: myapp\nspec:\n  image: ghcr.io/navikt/myapp:0\n").unwrap();
+        assert!(matches!(nais_yaml.verify_image("ghcr.io/navikt/myapp:1"), Err(ImageNotTemplated(_))));
+
+        let nais_yaml = NaisYaml::parse("kind: Application\nmetadata:\n  name: myapp\n").unwrap();
+        assert!(matches!(nais_yaml.verify_image("ghcr.io/navikt/myapp:1"), Err(ImageMissing)));
+    }
+
+    #[test]
+    pub fn parse_workload_spec() {
+        let nais_yaml = NaisYaml::parse(r#"
+apiVersion: nais.io/v1alpha1
+kind: Application
+metadata:
+  name: myapp
+spec:
+  image: ghcr.io/navikt/myapp:1
+  port: 8080
+  liveness:
+    path: /isalive
+  readiness:
+    path: /isready
+    port: 8081
+  env:
+    - name: LOG_LEVEL
+      value: debug
+    - name: PASSWORD
+      valueFrom:
+        secretKeyRef:
+          name: mysecret
+          key: password
+"#).unwrap();
+        assert_eq!(nais_yaml.spec.port, Some(8080));
+        assert_eq!(nais_yaml.spec.liveness, Some(yaml::Probe { path: "/isalive".into(), port: None }));
+        assert_eq!(nais_yaml.spec.readiness, Some(yaml::Probe { path: "/isready".into(), port: Some(8081) }));
+        assert_eq!(nais_yaml.docker_run_args(), vec!["--publish", "8080:8080", "--publish", "8081:8081", "--env", "LOG_LEVEL=debug"]);
+        assert_eq!(nais_yaml.probes(), vec![("liveness", 8080, "/isalive".to_string()), ("readiness", 8081, "/isready".to_string())]);
+    }
+
+    #[test]
+    pub fn annotate_workload_metadata() {
+        let annotations = BTreeMap::from([("nais.io/build-id".to_string(), "42".to_string())]);
+        let annotated = annotate_workloads(r#"
+apiVersion: nais.io/v1alpha1
+kind: Application
+metadata:
+  name: myapp
+  annotations:
+    foo: bar
+---
+apiVersion: kafka.nais.io/v1
+kind: Topic
+metadata:
+  name: mytopic
+"#, &annotations).unwrap();
+        assert_eq!(annotated, r#"apiVersion: nais.io/v1alpha1
+kind: Application
+metadata:
+  name: myapp
+  annotations:
+    foo: bar
+    nais.io/build-id: '42'
+---
+apiVersion: kafka.nais.io/v1
+kind: Topic
+metadata:
+  name: mytopic
+"#);
+    }
+
+    #[test]
+    pub fn detect_nais_yaml_in_environment_directory() {
+        let filesystem = Memory::new()
+            .with_file("/src/.nais/dev/app.yaml", "kind: Application\nmetadata:\n  name: myapp\nspec:\n  image: {{ image }}\n")
+            .with_file("/src/.nais/dev/topic.yaml", "kind: Topic\nmetadata:\n  name: mytopic\n")
+            .with_file("/src/.nais/dev/vars.yaml", "ingress: https://myapp.example\n");
+
+        let search = Search { paths: Some(vec![".nais/dev/**/*.yaml".into()]), ..Default::default() };
+        let detected = detect_nais_yaml(&filesystem, "/src", &search).unwrap();
+        assert_eq!(detected, "/src/.nais/dev/app.yaml");
+
+        let additional = detect_additional_resources(&filesystem, "/src", &search, &detected, |template| Ok::<_, Error>(template.to_string())).unwrap();
+        let paths: Vec<_> = additional.iter().map(|(path, _)| path.as_str()).collect();
+        assert_eq!(paths, vec!["/src/.nais/dev/topic.yaml"]);
+    }
+
+    #[test]
+    pub fn apply_environment_patch() {
+        let base = r#"
+defaults: &defaults
+  cpu: 50m
+apiVersion: nais.io/v1alpha1
+kind: Application
+metadata:
+  name: myapp
+spec:
+  image: "{{ image }}"
+  replicas:
+    min: 2
+    max: 4
+  resources:
+    requests:
+      <<: *defaults
+      memory: 256Mi
+  ingresses:
+    - https://myapp.example
+"#;
+        let patch = r#"
+kind: Application
+metadata:
+  name: myapp
+spec:
+  replicas:
+    min: 1
+    max: null
+  ingresses:
+    - https://myapp.dev.example
+---
+kind: Topic
+metadata:
+  name: mytopic
+"#;
+        let patched = documents(&apply_patches(base, patch).unwrap()).unwrap();
+        assert_eq!(patched.len(), 2);
+        let spec = &patched[0]["spec"];
+        assert_eq!(spec["image"], "{{ image }}");
+        assert_eq!(spec["replicas"]["min"], 1);
+        assert!(spec["replicas"].get("max").is_none());
+        assert_eq!(spec["resources"]["requests"]["cpu"], "50m");
+        assert_eq!(spec["ingresses"][0], "https://myapp.dev.example");
+        assert_eq!(patched[1]["kind"], "Topic");
+    }
+
+    #[test]
+    pub fn split_bundle_into_files() {
+        let files = split_resources("kind: Application\nmetadata:\n  name: myapp\n---\nkind: Topic\nmetadata:\n  name: t\n---\nkind: Topic\nmetadata:\n  name: t\n").unwrap();
+        let names: Vec<_> = files.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec!["application-myapp.yaml", "topic-t.yaml", "topic-t-2.yaml"]);
+        assert_eq!(files[1].1, "kind: Topic\nmetadata:\n  name: t\n");
     }
 }
@@ -0,0 +1,69 @@
+//! Notifications of the outcome of a pipeline, posted to a Slack incoming webhook configured with
+//! `[notify] slack_webhook`. Pipelines that fail, and deploys that succeed, are posted.
+
+use std::time::Duration;
+use log::{debug, warn};
+use crate::events::{Event, Receiver};
+
+/// The message to post for `event`, if any, about the pipeline described by the last [Event::Pipeline].
+fn message(pipeline: Option<&Event>, event: &Event) -> Option<String> {
+    let Some(Event::Pipeline { team, app, image, .. }) = pipeline else {
+        return None;
+    };
+    match event {
+        Event::StepFailed { step, error, retrying: false } => Some(format!(":x: {team}/{app}: {step} failed for `{image}`: {error}")),
+        Event::StepFinished { step, .. } if step == "deploy" => Some(format!(":white_check_mark: {team}/{app}: deployed `{image}`")),
+        _ => None,
+    }
+}
+
+/// Post messages about the events of `receiver` to `webhook`, until the bus is closed.
+pub async fn slack(webhook: String, mut receiver: Receiver) {
+    let client = match reqwest::Client::builder().timeout(Duration::from_secs(10)).build() {
+        Ok(client) => client,
+        Err(err) => {
+            warn!("Slack notifications disabled: {err}");
+            return;
+        }
+    };
+    let mut pipeline = None;
+    while let Some(event) = receiver.recv().await {
+        if let Event::Pipeline { .. } = event {
+            pipeline = Some(event);
+            continue;
+        }
+        let Some(text) = message(pipeline.as_ref(), &event) else {
+            continue;
+        };
+        let result = client.post(&webhook).json(&serde_json::json!({ "text": text })).send().await
+            .and_then(|resp| resp.error_for_status());
+        match result {
+            Ok(_) => debug!("Posted to Slack: {text}"),
+            // Notifications must not fail the command.
+            Err(err) => warn!("Slack notification not posted: {err}"),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    pub fn slack_messages() {
+        let pipeline = Event::Pipeline {
+            team: "myteam".into(),
+            app: "myapp".into(),
+            image: "ghcr.io/navikt/myapp:1".into(),
+            steps: vec!["release".into(), "deploy".into()],
+        };
+        let failed = |retrying| Event::StepFailed { step: "release".into(), error: "denied".into(), retrying };
+        assert_eq!(message(Some(&pipeline), &failed(false)).unwrap(), ":x: myteam/myapp: release failed for `ghcr.io/navikt/myapp:1`: denied");
+        assert_eq!(message(Some(&pipeline), &failed(true)), None);
+        assert_eq!(message(None, &failed(false)), None);
+
+        let finished = |step: &str| Event::StepFinished { step: step.into(), elapsed: Duration::from_secs(1) };
+        assert_eq!(message(Some(&pipeline), &finished("deploy")).unwrap(), ":white_check_mark: myteam/myapp: deployed `ghcr.io/navikt/myapp:1`");
+        assert_eq!(message(Some(&pipeline), &finished("release")), None);
+    }
+}
@@ -1,80 +1,950 @@
-/// Docker image name and tag combination.
-type ImageID = String;
+//! The build pipeline as a graph of steps: artifacts are built (dockerfile, build, sbom), published (release, sign),
+//! and deployed (deploy), each step after the ones whose results it needs.
+//!
+//! Commands select a subgraph by the steps they end with, e.g. `release` ends with [Node::Release],
+//! leaving out steps whose results are given another way, such as an image from `--docker-image-name`.
+//! Teams can add shell steps between the built-in steps with a user-defined pipeline, see [arrange].
+//! [execute] runs the selected steps in order, with state shared through the [Executor],
+//! and publishes their lifecycle events on the executor's [events::Bus].
+//!
+//! The work of the steps is done by a [Builder], [Releaser] and [Deployer], which are also
+//! the API for embedding nb in other tools.
 
-/// Templatable Kubernetes YAML file
-struct ResourceYaml;
+use std::fmt::Display;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio_util::sync::CancellationToken;
+use crate::config::runtime::{BuildContext, Config, Release, ReleaseType};
+use crate::sdk::SDK;
+use crate::events::{self, Event};
+use crate::{attest, auth, deploy, docker, gar, git, inputs, limits, nais_yaml, release_notes, verify};
 
-struct File;
+/// Built-in steps, in dependency order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Node {
+    /// Acquire credentials for the release registry, before building so that problems are found early.
+    Login,
+    /// Generate the Dockerfile of the detected SDK.
+    Dockerfile,
+    /// Build the Docker image.
+    Build,
+    /// Generate the SBOM of the built image, if enabled with `[release] sbom`.
+    Sbom,
+    /// Push the image to the release registry.
+    Release,
+    /// Sign the pushed image and attest its SBOM, if enabled with `[release] sign`.
+    Sign,
+    /// Deploy the image and the rendered resources to a cluster.
+    Deploy,
+}
 
-struct Directory;
+impl Node {
+    pub const ALL: [Node; 7] = [Node::Login, Node::Dockerfile, Node::Build, Node::Sbom, Node::Release, Node::Sign, Node::Deploy];
 
-struct NaisDeployMetadata {
-    /// build.nais.io/client-version
-    nais_build_version: String,
+    /// The steps whose results this step needs, in the order they are run.
+    pub fn dependencies(self) -> &'static [Node] {
+        match self {
+            Node::Login | Node::Dockerfile => &[],
+            Node::Build => &[Node::Dockerfile],
+            Node::Sbom => &[Node::Build],
+            Node::Release => &[Node::Login, Node::Build, Node::Sbom],
+            Node::Sign => &[Node::Release],
+            Node::Deploy => &[Node::Release, Node::Sign],
+        }
+    }
 
-    repository: String,
+    /// The built-in step named `name`.
+    pub fn from_name(name: &str) -> Option<Node> {
+        Node::ALL.into_iter().find(|node| node.name() == name)
+    }
 
-    // auto-detected by nais deploy, maybe enough to keep them in the templating step
-    team: String,
+    pub fn name(self) -> &'static str {
+        match self {
+            Node::Login => "login",
+            Node::Dockerfile => "dockerfile",
+            Node::Build => "build",
+            Node::Sbom => "sbom",
+            Node::Release => "release",
+            Node::Sign => "sign",
+            Node::Deploy => "deploy",
+        }
+    }
+}
+
+/// The steps needed to reach `targets`, in dependency order. Steps in `provided` are left out,
+/// along with the steps only they depend on.
+pub fn plan(targets: &[Node], provided: &[Node]) -> Vec<Node> {
+    fn visit(node: Node, provided: &[Node], plan: &mut Vec<Node>) {
+        if provided.contains(&node) || plan.contains(&node) {
+            return;
+        }
+        for dependency in node.dependencies() {
+            visit(*dependency, provided, plan);
+        }
+        plan.push(node);
+    }
+
+    let mut plan = Vec::new();
+    for target in targets {
+        visit(*target, provided, &mut plan);
+    }
+    plan
+}
+
+/// A step of a pipeline: a built-in step, or a command of a user-defined pipeline.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Step {
+    Builtin(Node),
+    Shell(Shell),
+}
+
+impl Step {
+    pub fn name(&self) -> &str {
+        match self {
+            Step::Builtin(node) => node.name(),
+            Step::Shell(shell) => &shell.name,
+        }
+    }
+
+    /// The built-in step, if this is one.
+    pub fn node(&self) -> Option<Node> {
+        match self {
+            Step::Builtin(node) => Some(*node),
+            Step::Shell(_) => None,
+        }
+    }
+}
+
+/// A command run as a step of a user-defined pipeline, from `[pipeline.shell.<name>]` in `nb.toml`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Shell {
+    pub name: String,
+    /// Command line, run with `sh -c` in the source directory.
+    pub run: String,
+    /// Directory to run in instead, relative to the source directory.
+    pub directory: Option<String>,
+}
+
+impl Shell {
+    /// Run the command in `source_directory` in `sandbox`, with `env` added to its environment, e.g. the image that was built.
+    pub async fn run(&self, source_directory: &str, sandbox: &Sandbox, env: &[(&str, String)], cancel: &CancellationToken) -> Result<(), Error> {
+        let source_directory = Path::new(source_directory).canonicalize()?;
+        let working_directory = self.working_directory(&source_directory)?;
+        let environment = sandbox.environment(std::env::vars(), env);
+        let mut command = sandbox.command(self, &source_directory, &working_directory, &environment);
+        let step = crate::progress::step(self.name.as_str()).redacting(secrets(std::env::vars()));
+        let status = crate::process::run(&step, &mut command, cancel).await?;
+        if !status.success() {
+            return Err(Error::ShellFailed { name: self.name.clone(), status });
+        }
+        step.finish();
+        Ok(())
+    }
+
+    /// The source directory, or [Shell::directory] if it is inside of it, also after following symlinks.
+    fn working_directory(&self, source_directory: &Path) -> Result<PathBuf, Error> {
+        let Some(directory) = &self.directory else {
+            return Ok(source_directory.to_path_buf());
+        };
+        match source_directory.join(directory).canonicalize() {
+            Ok(resolved) if resolved.starts_with(source_directory) && resolved.is_dir() => Ok(resolved),
+            _ => Err(Error::ShellDirectory { name: self.name.clone(), directory: directory.clone() }),
+        }
+    }
+}
+
+/// The constrained environment that shell steps run in. Only the allowed variables of nb's environment are passed
+/// to them, and they run in the source directory, on the host or in a container with nothing else mounted.
+/// Values of nb's variables that look like secrets are redacted from their output, see [secrets].
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Sandbox {
+    /// Names of the variables passed through. A name ending in `*` passes every variable with the prefix.
+    pub env: Vec<String>,
+    /// Image to run the steps in, instead of on the host.
+    pub image: Option<String>,
+}
+
+/// Where the source directory is mounted in the container of a [Sandbox] with an image.
+const SANDBOX_SOURCE_DIRECTORY: &str = "/src";
+
+impl Sandbox {
+    fn allows(&self, name: &str) -> bool {
+        // The host's PATH would hide the programs of the image.
+        if self.image.is_some() && name == "PATH" {
+            return false;
+        }
+        self.env.iter().any(|allowed| match allowed.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix),
+            None => name == allowed,
+        })
+    }
+
+    /// The allowed variables of `vars`, and the variables of `env` set by nb.
+    fn environment(&self, vars: impl Iterator<Item = (String, String)>, env: &[(&str, String)]) -> Vec<(String, String)> {
+        let mut environment: Vec<(String, String)> = vars
+            .filter(|(name, _)| self.allows(name) && !env.iter().any(|(set, _)| set == name))
+            .collect();
+        environment.extend(env.iter().map(|(name, value)| (name.to_string(), value.clone())));
+        environment
+    }
+
+    /// Command that runs `shell` in `working_directory` inside `source_directory`, with only `environment`.
+    fn command(&self, shell: &Shell, source_directory: &Path, working_directory: &Path, environment: &[(String, String)]) -> tokio::process::Command {
+        let Some(image) = &self.image else {
+            let mut command = tokio::process::Command::new("sh");
+            command.arg("-c").arg(&shell.run)
+                .current_dir(working_directory)
+                .env_clear()
+                .envs(environment.iter().map(|(name, value)| (name, value)));
+            return command;
+        };
+        let relative = working_directory.strip_prefix(source_directory).expect("working directory is in the source directory");
+        let mut command = tokio::process::Command::new("docker");
+        command.args(["run", "--rm", "--volume"])
+            .arg(format!("{}:{SANDBOX_SOURCE_DIRECTORY}", source_directory.display()))
+            .arg("--workdir")
+            .arg(Path::new(SANDBOX_SOURCE_DIRECTORY).join(relative));
+        // Values are passed through the environment of docker, rather than on its command line.
+        for (name, value) in environment {
+            command.arg("--env").arg(name).env(name, value);
+        }
+        command.arg(image).arg("sh").arg("-c").arg(&shell.run);
+        command
+    }
+}
+
+/// Values shorter than this are not redacted, since they would hide unrelated output.
+const MIN_SECRET_LENGTH: usize = 6;
+
+/// Values of the variables of `vars` that look like secrets by their names, e.g. `NAIS_DEPLOY_APIKEY` or `GITHUB_TOKEN`.
+pub fn secrets(vars: impl Iterator<Item = (String, String)>) -> Vec<String> {
+    vars.filter(|(name, value)| crate::config::redact::is_secret(name) && value.len() >= MIN_SECRET_LENGTH)
+        .map(|(_, value)| value)
+        .collect()
+}
+
+/// The steps of `plan` in the order of `sequence`, a user-defined pipeline. Without one, the steps of the plan.
+///
+/// Built-in steps left out of the sequence run right after the built-in step before them in it. A shell step runs
+/// when the built-in step after it runs, or, at the end of the sequence, when the last built-in step runs.
+/// The built-in steps of the sequence are expected in dependency order.
+pub fn arrange(plan: &[Node], sequence: &[Step]) -> Vec<Step> {
+    let mut complete = sequence.to_vec();
+    for node in Node::ALL {
+        if complete.contains(&Step::Builtin(node)) {
+            continue;
+        }
+        let before = |step: &Step| step.node().is_some_and(|other| other < node);
+        let index = complete.iter().rposition(before).map(|index| index + 1).unwrap_or(0);
+        complete.insert(index, Step::Builtin(node));
+    }
+
+    complete.iter().enumerate()
+        .filter(|(index, step)| {
+            let anchor = match step {
+                Step::Builtin(node) => *node,
+                Step::Shell(_) => complete[*index..].iter().find_map(Step::node)
+                    .or_else(|| complete[..*index].iter().rev().find_map(Step::node))
+                    .expect("the sequence has built-in steps"),
+            };
+            plan.contains(&anchor)
+        })
+        .map(|(_, step)| step.clone())
+        .collect()
+}
+
+/// How a step is retried when it fails, and how long each attempt may run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Policy {
+    pub retries: u32,
+    /// Delay before the first retry, doubled for each subsequent retry.
+    pub retry_delay: Duration,
+    pub timeout: Option<Duration>,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy { retries: 0, retry_delay: Duration::from_secs(1), timeout: None }
+    }
+}
+
+impl Policy {
+    /// Delay before retry number `attempt`, starting at 1.
+    fn backoff(&self, attempt: u32) -> Duration {
+        self.retry_delay.saturating_mul(2u32.saturating_pow(attempt - 1))
+    }
+}
+
+/// A step that ran longer than the timeout of its [Policy].
+#[derive(Debug, Clone, PartialEq, Error)]
+#[error("{} step timed out after {}s", step.name(), timeout.as_secs())]
+pub struct StepTimedOut {
+    pub step: Step,
+    pub timeout: Duration,
+}
+
+/// Runs the steps of a pipeline, keeping the results that later steps need.
+pub trait Executor {
+    type Error: Display + From<StepTimedOut>;
+
+    /// Run `step`, stopping its processes and requests promptly if `cancel` is cancelled: when the step times out,
+    /// or the pipeline is cancelled.
+    fn run(&mut self, step: &Step, cancel: &CancellationToken) -> impl Future<Output = Result<(), Self::Error>>;
+
+    /// Called after each step that succeeded, before the next step runs.
+    fn finished(&mut self, _result: &StepResult) {}
+
+    /// Retries and timeout of a step. By default, steps run once, for as long as they take.
+    fn policy(&self, _step: &Step) -> Policy {
+        Policy::default()
+    }
+
+    /// Whether a step that failed with `err` may succeed if it is run again.
+    fn retryable(&self, _err: &Self::Error) -> bool {
+        true
+    }
+
+    /// How many steps like `step` may run at once on the machine. By default, any number.
+    fn limits(&self, _step: &Step) -> Vec<limits::Limit> {
+        Vec::new()
+    }
+
+    /// Where the lifecycle events of the steps are published, if anywhere.
+    fn events(&self) -> Option<&events::Bus> {
+        None
+    }
+}
+
+/// Result of a step that succeeded.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StepResult {
+    pub step: Step,
+    pub elapsed: Duration,
+}
+
+/// Run `steps` in order, retrying them according to their [Policy], and stopping at the first that fails.
+/// Once `cancel` is cancelled, the running step is stopped, and failed steps are not retried.
+pub async fn execute<E: Executor>(steps: &[Step], executor: &mut E, cancel: &CancellationToken) -> Result<Vec<StepResult>, E::Error> {
+    debug!("Pipeline: {}", steps.iter().map(Step::name).collect::<Vec<_>>().join(" → "));
+    let mut results = Vec::new();
+    for step in steps {
+        let started = Instant::now();
+        let policy = executor.policy(step);
+        let name = step.name().to_string();
+        let publish = |executor: &E, event| {
+            if let Some(bus) = executor.events() {
+                bus.publish(event);
+            }
+        };
+        let mut attempt = 0;
+        loop {
+            let slots = cancel.run_until_cancelled(acquire_slots(&executor.limits(step))).await.unwrap_or_default();
+            publish(executor, Event::StepStarted { step: name.clone(), attempt: attempt + 1 });
+            let result = run_attempt(executor, step, policy.timeout, cancel).await;
+            drop(slots);
+            match result {
+                Ok(()) => break,
+                Err(err) if attempt < policy.retries && executor.retryable(&err) && !cancel.is_cancelled() => {
+                    publish(executor, Event::StepFailed { step: name.clone(), error: err.to_string(), retrying: true });
+                    attempt += 1;
+                    let delay = policy.backoff(attempt);
+                    warn!("{name} step failed: {err}; retrying in {}s ({attempt} of {})", delay.as_secs_f64(), policy.retries);
+                    cancel.run_until_cancelled(tokio::time::sleep(delay)).await;
+                }
+                Err(err) => {
+                    publish(executor, Event::StepFailed { step: name, error: err.to_string(), retrying: false });
+                    return Err(err);
+                }
+            }
+        }
+        let result = StepResult { step: step.clone(), elapsed: started.elapsed() };
+        publish(executor, Event::StepFinished { step: name, elapsed: result.elapsed });
+        executor.finished(&result);
+        results.push(result);
+    }
+    Ok(results)
+}
+
+/// Slots of every limit of a step, acquired in order so that processes waiting for them cannot deadlock.
+/// A limit that cannot be enforced is skipped.
+async fn acquire_slots(step_limits: &[limits::Limit]) -> Vec<limits::Slot> {
+    let mut slots = Vec::new();
+    for limit in step_limits {
+        match limits::acquire(&limits::directory(), limit).await {
+            Ok(slot) => slots.push(slot),
+            Err(err) => warn!("Not limiting parallel {} steps: {err}", limit.name),
+        }
+    }
+    slots
+}
+
+/// Run an attempt of `step` with a token of its own, which is cancelled when the attempt times out.
+async fn run_attempt<E: Executor>(executor: &mut E, step: &Step, timeout: Option<Duration>, cancel: &CancellationToken) -> Result<(), E::Error> {
+    let attempt = cancel.child_token();
+    let Some(timeout) = timeout else {
+        return executor.run(step, &attempt).await;
+    };
+    let run = executor.run(step, &attempt);
+    tokio::pin!(run);
+    if let Ok(result) = tokio::time::timeout(timeout, &mut run).await {
+        return result;
+    }
+    // Let the step stop its processes gracefully. If it does not, dropping it kills them.
+    attempt.cancel();
+    if tokio::time::timeout(crate::shutdown::GRACE_PERIOD, run).await.is_err() {
+        warn!("{} step did not stop {}s after timing out", step.name(), crate::shutdown::GRACE_PERIOD.as_secs());
+    }
+    Err(StepTimedOut { step: step.clone(), timeout }.into())
+}
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("docker error: {0}")]
+    Docker(#[from] docker::Error),
+
+    #[error("auth: {0}")]
+    Auth(#[from] auth::Error),
+
+    #[error("GAR: {0}")]
+    Gar(#[from] gar::Error),
+
+    #[error("git: {0}")]
+    Git(#[from] git::Error),
+
+    #[error("deploy: {0}")]
+    Deploy(#[from] deploy::Error),
 
-    // these parameters are already loaded by nais deploy using environment variables
-    // available in the Github environment.
-    // is it necessary to include them here?
-    git_sha: String,
-    correlation_id: String,
-    actor: String,
+    #[error("{0}")]
+    Attest(#[from] attest::Error),
+
+    #[error("nais.yaml: {0}")]
+    NaisYaml(#[from] nais_yaml::Error),
+
+    #[error("filesystem error: {0}")]
+    IO(#[from] std::io::Error),
+
+    #[error("no compatible SDKs for this source directory")]
+    SdkNotDetected,
+
+    #[error("build error: {0}")]
+    Sdk(#[from] crate::sdk::Error),
+
+    #[error("serialize: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("write Dockerfile to {path}: {err}")]
+    WriteDockerfile {
+        path: String,
+        err: std::io::Error,
+    },
+
+    #[error("{0}")]
+    TimedOut(#[from] StepTimedOut),
+
+    #[error("directory `{directory}` of the {name} step is not a directory inside the source directory")]
+    ShellDirectory {
+        name: String,
+        directory: String,
+    },
+
+    #[error("{name} step failed with {status}")]
+    ShellFailed {
+        name: String,
+        status: std::process::ExitStatus,
+    },
+
+    #[error("deploying is not configured; set NAIS_DEPLOY_SERVER, and NAIS_DEPLOY_APIKEY or a deploy key in the keychain")]
+    DeployNotConfigured,
+
+    #[error("cancelled")]
+    Cancelled,
 }
 
-struct NaisDeployUnit {
-    resources: Vec<ResourceYaml>,
-    metadata: NaisDeployMetadata,
-    // destination is cluster+tenant, but is not a part of the "artifact" itself
+/// Builds the Docker image of an application, with the Dockerfile generated by its SDK
+/// ([Node::Dockerfile] and [Node::Build]).
+pub struct Builder<'a> {
+    sdk: &'a dyn SDK,
+    app: String,
+    context: BuildContext,
+    context_include: Vec<String>,
+    skip_unchanged: bool,
+    remote_builder: Option<docker::remote::Builder>,
+    /// Directories of the source directory left out of the build context, relative to it.
+    excluded: Vec<PathBuf>,
 }
 
-/// Various things that can be built.
-/// Builds produce artifacts, which can then be published or deployed.
-enum Artifact {
-    /// SLSA signatures and attestation for a Docker image.
-    SLSA(ImageID),
+/// Result of [Builder::build].
+#[derive(Debug, Default)]
+pub struct Built {
+    /// The image tagged with the hash of its inputs, to push along with the image so that later builds can be skipped.
+    pub inputs_image: Option<String>,
+    /// Whether the build was skipped, because an image built from the same inputs was found.
+    pub cached: bool,
+    /// Seconds spent in each stage of the Dockerfile, if BuildKit reported them.
+    pub stages: std::collections::BTreeMap<String, f64>,
+}
+
+impl<'a> Builder<'a> {
+    pub fn new(sdk: &'a dyn SDK, cfg: &Config) -> Self {
+        Builder {
+            sdk,
+            app: cfg.app.clone(),
+            context: cfg.context,
+            context_include: cfg.context_include.clone(),
+            skip_unchanged: cfg.skip_unchanged,
+            remote_builder: cfg.remote_builder.clone(),
+            excluded: Vec::new(),
+        }
+    }
+
+    /// Leave `directory`, relative to the source directory, out of the build context and its hash,
+    /// e.g. the directory that nb writes `artifacts.json` to.
+    pub fn excluding(mut self, directory: &Path) -> Self {
+        if !directory.as_os_str().is_empty() {
+            self.excluded.push(directory.to_path_buf());
+        }
+        self
+    }
+
+    /// The files of the build context, relative to the source directory, see [docker::context::files].
+    pub fn context_files(&self) -> Result<Vec<PathBuf>, Error> {
+        let mut files = docker::context::files(&self.sdk.filesystem_path(), self.context, &self.context_include, self.sdk.build_outputs())?;
+        files.retain(|file| !self.excluded.iter().any(|directory| file.starts_with(directory)));
+        Ok(files)
+    }
 
-    /// Docker image produced on the local machine.
-    DockerImage(ImageID),
+    /// Write the generated Dockerfile to the workspace, and return its path.
+    pub fn write_dockerfile(&self) -> Result<PathBuf, Error> {
+        Ok(docker::write_dockerfile(self.sdk, &self.app)?)
+    }
 
-    /// A nais deploy artifact is the collection of data and parameters
-    /// required to deploy an image as a container on the NAIS platform.
-    NaisDeploy(NaisDeployUnit),
-    Binary(File),
-    Directory(Directory),
+    /// Build `image` from `dockerfile`, labelled with the Git metadata of the source directory.
+    ///
+    /// If an image built from the same inputs is in the registry, it is pulled and labelled as `image` instead.
+    pub async fn build(&self, dockerfile: &Path, image: &str, cancel: &CancellationToken) -> Result<Built, Error> {
+        let source_directory = self.sdk.filesystem_path();
+        // Submodules are inputs too, so they are checked out before the inputs are hashed.
+        if let Err(err) = git::ensure_submodules(&source_directory) {
+            match err {
+                git::Error::Submodules(_) => return Err(err.into()),
+                // Building outside a Git repository is fine.
+                err => debug!("Submodules not checked: {err}"),
+            }
+        }
+        let labels = docker::source_labels(&source_directory);
+        let inputs_image = match self.skip_unchanged {
+            true => self.inputs_hash(dockerfile, cancel).await
+                .unwrap_or_else(|err| {
+                    warn!("Build inputs not hashed, so the build is not skipped: {err}");
+                    None
+                })
+                .map(|hash| inputs::tag(image, &hash)),
+            false => None,
+        };
+        if let Some(inputs_image) = &inputs_image {
+            if docker::manifest_digest(inputs_image, cancel).await?.is_some() {
+                info!("Cache hit: {inputs_image} was built from the same inputs; skipping the build");
+                // The image has the labels of the commit it was built from, which are replaced by those of this one.
+                let pulled = match docker::pull(inputs_image, cancel).await {
+                    Ok(()) => docker::relabel(inputs_image, image, &labels, cancel).await,
+                    Err(err) => Err(err),
+                };
+                match pulled {
+                    Ok(()) => return Ok(Built { inputs_image: Some(inputs_image.clone()), cached: true, ..Built::default() }),
+                    Err(err) => warn!("Building, since {inputs_image} could not be used: {err}"),
+                }
+            }
+        }
+
+        let files = self.context_files()?;
+        let builder = match &self.remote_builder {
+            Some(builder) => Some(docker::remote::ensure(builder, cancel).await?),
+            None => None,
+        };
+        let stages = docker::build(dockerfile, image, &labels, Path::new(&source_directory), &files, builder.as_deref(), cancel).await?;
+        if let Some(inputs_image) = &inputs_image {
+            docker::tag(image, inputs_image, cancel).await?;
+        }
+        Ok(Built { inputs_image, cached: false, stages })
+    }
+
+    /// Hash of the inputs of the build, see [inputs]. `None` if the digests of the base images are not available,
+    /// since their tags may have moved to images that the build must use.
+    async fn inputs_hash(&self, dockerfile: &Path, cancel: &CancellationToken) -> Result<Option<String>, Error> {
+        let mut base_image_digests = Vec::new();
+        for base_image in [self.sdk.builder_docker_image(), self.sdk.runtime_docker_image()] {
+            match docker::manifest_digest(&base_image, cancel).await? {
+                Some(digest) => base_image_digests.push(digest),
+                None => {
+                    debug!("Digest of {base_image} not available; not checking for an unchanged build");
+                    return Ok(None);
+                }
+            }
+        }
+        let files = self.context_files()?;
+        let inputs = inputs::Inputs {
+            dockerfile: &std::fs::read_to_string(dockerfile)?,
+            base_image_digests: &base_image_digests,
+            context: self.context,
+            context_include: &self.context_include,
+        };
+        let hash = inputs::hash(&inputs, Path::new(&self.sdk.filesystem_path()), &files)?;
+        debug!("Build inputs hash: {hash}");
+        Ok(Some(hash))
+    }
 }
 
-/// Various ways to publish an artifact.
-/// Publishing is defined as storing the artifact's data and metadata at a well-known location.
-enum Publish {
-    /// Upload a Docker image and its associated SLSA data to Google Artifact Registry.
-    ArtifactRegistry,
+/// Pushes images to the release registry, and signs them ([Node::Login], [Node::Release] and [Node::Sign]).
+pub struct Releaser {
+    release: Release,
+    team: String,
+    credentials: Option<auth::RefreshingCredentials>,
+}
+
+impl Releaser {
+    pub fn new(cfg: &Config) -> Self {
+        Releaser { release: cfg.release.clone(), team: cfg.team.clone(), credentials: None }
+    }
+
+    /// Acquire credentials for the release registry, unless Docker already has them from a credential helper.
+    /// For GAR, also check that the target repository exists and may be pushed to.
+    ///
+    /// Done before building, so that authentication problems are found without waiting for the build.
+    pub async fn login(&mut self, cancel: &CancellationToken) -> Result<(), Error> {
+        let registry = &self.release.params.registry;
+        if docker::helper_credentials(registry).is_some() {
+            info!("Using credentials from the Docker credential helper for {registry}");
+            return Ok(());
+        }
+        let mut credentials = auth::RefreshingCredentials::acquire(auth::Provider::for_release(&self.release), self.release.http.clone(), cancel.clone()).await?;
+        if self.release.typ == ReleaseType::GAR {
+            let repository = gar::Repository::from_registry(registry, &self.team)?;
+            let password = &credentials.get().await?.password;
+            cancel.run_until_cancelled(gar::ensure_repository(&repository, password, self.release.create_repository)).await.ok_or(Error::Cancelled)??;
+        }
+        self.credentials = Some(credentials);
+        Ok(())
+    }
+
+    /// Push `image`, and then the `additional_images` such as [Built::inputs_image], with the credentials
+    /// from [Releaser::login] if any. Returns the registry digest of `image` if known.
+    pub async fn push(&mut self, image: &str, additional_images: &[String], cancel: &CancellationToken) -> Result<Option<String>, Error> {
+        // FIXME: determine if the correct user is authed (@nais.io vs @tenant)
+        let session = self.session(cancel).await?;
+        session.push(image, cancel).await?;
+        for additional_image in additional_images {
+            session.push(additional_image, cancel).await?;
+        }
+        Ok(image_digest(image, cancel).await)
+    }
 
-    /// Create a GitHub release and add the files to it.
-    /// https://docs.github.com/en/rest/releases/releases?apiVersion=2022-11-28#create-a-release
-    GitHubRelease(Vec<File>),
+    /// Sign the pushed `image` by its `digest`, and attest the SBOM at `sbom` if any, with the credentials
+    /// from [Releaser::login] if any. Returns the references of the attestations.
+    pub async fn sign(&mut self, image: &str, digest: Option<&str>, sbom: Option<&Path>, cancel: &CancellationToken) -> Result<Vec<String>, Error> {
+        // Kept until cosign is done, since it pushes signatures with the credentials of Docker.
+        let _session = self.session(cancel).await?;
+        let digest = match digest {
+            Some(digest) => Some(digest.to_string()),
+            None => docker::manifest_digest(image, cancel).await?,
+        };
+        let repository = verify::image_name(image);
+        let reference = match &digest {
+            Some(digest) => format!("{repository}@{digest}"),
+            None => image.to_string(),
+        };
+        let key = self.release.signing_key.as_deref();
+        attest::sign(&reference, key, cancel).await?;
+        info!("Signed {reference}");
+        let Some(sbom) = sbom else {
+            return Ok(Vec::new());
+        };
+        attest::attest_sbom(&reference, sbom, key, cancel).await?;
+        info!("Attested the SBOM of {reference}");
+        Ok(digest.iter().map(|digest| attest::attestation_reference(repository, digest)).collect())
+    }
+
+    /// Log in to the release registry with the credentials from [Releaser::login], or use those Docker has.
+    /// Sessions are automatically logged out when they go out of scope.
+    async fn session(&mut self, cancel: &CancellationToken) -> Result<docker::Session, Error> {
+        let registry = &self.release.params.registry;
+        Ok(match self.credentials.as_mut() {
+            Some(credentials) => docker::Session::new(registry, credentials.get().await?, cancel).await?,
+            None => docker::Session::existing(registry),
+        })
+    }
+}
+
+/// Deploys rendered resources to a cluster with the deploy client ([Node::Deploy]).
+pub struct Deployer {
+    team: String,
+    cluster: String,
+    source_directory: String,
+    git_meta: git::RepositoryMetadata,
+    short_sha: String,
 }
 
-/// Various ways to deploy an artifact.
-/// Deploying things means that the runtime environment is affected.
-/// Thus, a deploy is visible to end users.
-enum Deploy {
-    /// Deploy a containerized app.
-    /// These deploys need a combination of a Docker image and an Application spec.
-    /// - detect image name
-    /// - detect customer (NAV)
-    /// - detect environment (prod-gcp)
-    /// - detect namespace (team)
-    /// - branched deployments for PR's
-    NaisDeploy(NaisDeployUnit),
+impl Deployer {
+    /// Read the Git metadata of `source_directory` that deployments are annotated with.
+    pub fn new(cfg: &Config, cluster: &str, source_directory: &str) -> Result<Self, Error> {
+        Ok(Deployer {
+            team: cfg.team.clone(),
+            cluster: cluster.to_string(),
+            source_directory: source_directory.to_string(),
+            git_meta: git::metadata(source_directory)?,
+            short_sha: git::short_sha(source_directory)?,
+        })
+    }
+
+    pub fn cluster(&self) -> &str {
+        &self.cluster
+    }
+
+    /// Deploy `bundle` with `image`, annotating its workloads with their provenance,
+    /// and return the registry digest of the image if known.
+    pub async fn deploy(&self, image: &str, bundle: &str, cancel: &CancellationToken) -> Result<Option<String>, Error> {
+        let digest = image_digest(image, cancel).await;
+        let annotations = deploy::provenance_annotations(Some(&self.git_meta), digest.clone(), release_notes::since_previous_release(&self.source_directory));
+        let bundle = nais_yaml::annotate_workloads(bundle, &annotations)?;
+
+        // FIXME: this should probably be a builder of some sort to validate the actual config
+        let mut cfg = deploy::Config::try_new_from_env(&self.team).ok_or(Error::DeployNotConfigured)?;
+        cfg.cluster = self.cluster.clone();
+        cfg.owner = self.git_meta.owner.clone();
+        cfg.git_ref = self.short_sha.clone();
+        cfg.repository = self.git_meta.name.clone();
+        cfg.var = vec![format!("image={image}")];
 
-    /// Deploy a directory to the team's CDN bucket.
-    /// - detect source
-    /// - detect destination
-    /// - upload to bucket
-    CDNDeploy(Directory),
+        // Deploy exactly what was rendered locally.
+        let resource_file = crate::workspace::Workspace::new(&self.source_directory).workdir()?.join(format!("manifests-{}.yaml", self.cluster));
+        std::fs::write(&resource_file, bundle.as_bytes())?;
+        cfg.resource = vec![resource_file.to_string_lossy().to_string()];
+
+        deploy::deploy(cfg, cancel).await?;
+        Ok(digest)
+    }
+}
+
+async fn image_digest(image: &str, cancel: &CancellationToken) -> Option<String> {
+    docker::image_digest(image, cancel).await.unwrap_or_else(|err| {
+        warn!("Image digest not available: {err}");
+        None
+    })
 }
 
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::git::tests::git;
+    use Node::*;
+
+    #[derive(Debug, PartialEq, Error)]
+    enum Failed {
+        #[error("{0:?} failed")]
+        Step(Node),
+        #[error("{0}")]
+        TimedOut(#[from] StepTimedOut),
+    }
+
+    struct Recorder {
+        ran: Vec<Node>,
+        /// A step that fails, and how many times.
+        fail: Option<(Node, u32)>,
+        /// A step that runs for a second, unless it is cancelled.
+        slow: Option<Node>,
+        cancelled: Vec<Node>,
+        policy: Policy,
+    }
+
+    impl Recorder {
+        fn new(fail: Option<(Node, u32)>) -> Self {
+            Recorder { ran: Vec::new(), fail, slow: None, cancelled: Vec::new(), policy: Policy::default() }
+        }
+    }
+
+    impl Executor for Recorder {
+        type Error = Failed;
+
+        async fn run(&mut self, step: &Step, cancel: &CancellationToken) -> Result<(), Failed> {
+            let node = step.node().expect("only built-in steps are run");
+            if let Some((failing, times)) = &mut self.fail {
+                if *failing == node && *times > 0 {
+                    *times -= 1;
+                    return Err(Failed::Step(node));
+                }
+            }
+            if self.slow == Some(node) && cancel.run_until_cancelled(tokio::time::sleep(Duration::from_secs(1))).await.is_none() {
+                self.cancelled.push(node);
+                return Ok(());
+            }
+            self.ran.push(node);
+            Ok(())
+        }
+
+        fn policy(&self, _step: &Step) -> Policy {
+            self.policy.clone()
+        }
+    }
+
+    /// Configuration of `myapp` of `myteam`, with the built-in defaults.
+    pub fn config() -> Config {
+        let nais_yaml = nais_yaml::NaisYaml::parse("apiVersion: nais.io/v1alpha1\nkind: Application\nmetadata:\n  name: myapp\n  namespace: myteam\n").unwrap();
+        Config::new(&crate::config::file::File::default(), nais_yaml).unwrap()
+    }
+
+    #[tokio::test]
+    pub async fn skip_unchanged_build() {
+        let directory = tempfile::tempdir().unwrap();
+        let source_directory = directory.path().to_str().unwrap();
+        std::fs::write(directory.path().join("pom.xml"), "<project/>").unwrap();
+        git(directory.path(), &["init", "--quiet"]);
+        git(directory.path(), &["remote", "add", "origin", "git@github.com:navikt/myapp.git"]);
+        git(directory.path(), &["add", "pom.xml"]);
+        git(directory.path(), &["commit", "--quiet", "--message", "initial"]);
+        std::fs::create_dir_all(directory.path().join("build")).unwrap();
+        std::fs::write(directory.path().join("build/artifacts.json"), "{}").unwrap();
+
+        let mut cfg = config();
+        cfg.skip_unchanged = true;
+        cfg.context = BuildContext::Directory;
+        let sdk = crate::sdk::detect(source_directory, crate::config::file::File::default().sdk.as_ref().unwrap()).unwrap().unwrap();
+        let builder = Builder::new(sdk.as_ref(), &cfg).excluding(Path::new("build"));
+        assert_eq!(builder.context_files().unwrap(), [PathBuf::from("pom.xml")], "nb's outputs are not in the build context");
+
+        let mock = std::sync::Arc::new(crate::process::Mock::default().respond("docker buildx imagetools inspect", 0, "sha256:abc\n"));
+        let dockerfile = builder.write_dockerfile().unwrap();
+        let cancel = CancellationToken::new();
+        let hash = crate::process::scope(mock.clone(), builder.inputs_hash(&dockerfile, &cancel)).await.unwrap();
+        std::fs::write(directory.path().join("build/artifacts.json"), r#"{"digest": "sha256:abc"}"#).unwrap();
+        assert_eq!(crate::process::scope(mock.clone(), builder.inputs_hash(&dockerfile, &cancel)).await.unwrap(), hash);
+
+        let image = "europe-north1-docker.pkg.dev/nais-management-233d/myteam/myapp:1";
+        let built = crate::process::scope(mock.clone(), builder.build(&dockerfile, image, &cancel)).await.unwrap();
+        assert!(built.cached);
+        let inputs_image = inputs::tag(image, &hash.unwrap());
+        assert_eq!(built.inputs_image.as_deref(), Some(inputs_image.as_str()));
+
+        // The cached image is labelled with the commit it is used for.
+        let invocations = mock.invocations();
+        let relabel = invocations.iter().find(|invocation| invocation.command_line().starts_with("docker build ")).unwrap();
+        let revision = format!("org.opencontainers.image.revision={}", git::sha(source_directory).unwrap());
+        assert!(relabel.args.contains(&revision), "{}", relabel.command_line());
+        assert!(relabel.command_line().ends_with(&format!("--tag {image} -")));
+        assert_eq!(relabel.input.as_deref(), Some(format!("FROM {inputs_image}\n").as_bytes()));
+    }
+
+    #[test]
+    pub fn plan_subgraphs() {
+        assert_eq!(plan(&[Build], &[]), [Dockerfile, Build]);
+        assert_eq!(plan(&[Release, Sign], &[]), [Login, Dockerfile, Build, Sbom, Release, Sign]);
+        assert_eq!(plan(&[Deploy], &[]), [Login, Dockerfile, Build, Sbom, Release, Sign, Deploy]);
+        // Steps that are not enabled are left out like those whose results are given.
+        assert_eq!(plan(&[Deploy], &[Sbom, Sign]), [Login, Dockerfile, Build, Release, Deploy]);
+        // An image given on the command line is neither built nor pushed.
+        assert_eq!(plan(&[Deploy], &[Build, Release, Sign]), [Deploy]);
+        assert_eq!(plan(&[Release], &[Build]), [Login, Sbom, Release]);
+    }
+
+    #[test]
+    pub fn arrange_user_pipelines() {
+        let shell = |name: &str| Step::Shell(Shell { name: name.into(), run: format!("make {name}"), directory: None });
+        let names = |steps: Vec<Step>| steps.iter().map(|step| step.name().to_string()).collect::<Vec<_>>();
+        assert_eq!(names(arrange(&plan(&[Build], &[]), &[])), ["dockerfile", "build"]);
+
+        let sequence = [shell("lint"), Step::Builtin(Build), Step::Builtin(Release), shell("contract-test"), Step::Builtin(Deploy), shell("smoke-test")];
+        assert_eq!(
+            names(arrange(&plan(&[Deploy], &[Sbom, Sign]), &sequence)),
+            ["login", "dockerfile", "lint", "build", "release", "contract-test", "deploy", "smoke-test"],
+        );
+        // Built-in steps left out of the sequence run after the built-in step before them.
+        assert_eq!(
+            names(arrange(&plan(&[Deploy], &[]), &sequence)),
+            ["login", "dockerfile", "lint", "build", "sbom", "release", "sign", "contract-test", "deploy", "smoke-test"],
+        );
+        // Shell steps run with the built-in step after them.
+        assert_eq!(names(arrange(&plan(&[Release], &[Sbom]), &sequence)), ["login", "dockerfile", "lint", "build", "release"]);
+        assert_eq!(names(arrange(&plan(&[Deploy], &[Build, Release, Sign]), &sequence)), ["contract-test", "deploy", "smoke-test"]);
+    }
+
+    #[test]
+    pub fn sandbox_environment() {
+        let vars = || [("PATH", "/usr/bin"), ("GITHUB_SHA", "abc"), ("GITHUB_TOKEN", "ghs_secret"), ("NAIS_DEPLOY_APIKEY", "key"), ("NB_STEP_APP", "host")]
+            .into_iter().map(|(name, value)| (name.to_string(), value.to_string()));
+        let mut sandbox = Sandbox { env: vec!["PATH".into(), "GITHUB_*".into(), "NB_STEP_APP".into()], image: None };
+        let env = [("NB_STEP_APP", "myapp".to_string())];
+        let names = |environment: Vec<(String, String)>| environment.into_iter().map(|(name, value)| format!("{name}={value}")).collect::<Vec<_>>();
+        assert_eq!(names(sandbox.environment(vars(), &env)), ["PATH=/usr/bin", "GITHUB_SHA=abc", "GITHUB_TOKEN=ghs_secret", "NB_STEP_APP=myapp"]);
+        sandbox.image = Some("alpine".into());
+        assert_eq!(names(sandbox.environment(vars(), &env)), ["GITHUB_SHA=abc", "GITHUB_TOKEN=ghs_secret", "NB_STEP_APP=myapp"]);
+
+        assert_eq!(secrets(vars()), ["ghs_secret"], "the API key is too short to redact");
+    }
+
+    #[test]
+    pub fn confine_working_directory() {
+        let directory = tempfile::tempdir().unwrap();
+        let source_directory = directory.path().join("src");
+        std::fs::create_dir_all(source_directory.join("tests")).unwrap();
+        let source_directory = source_directory.canonicalize().unwrap();
+        let shell = |directory: Option<&str>| Shell { name: "test".into(), run: "true".into(), directory: directory.map(String::from) };
+        assert_eq!(shell(None).working_directory(&source_directory).unwrap(), source_directory);
+        assert_eq!(shell(Some("tests")).working_directory(&source_directory).unwrap(), source_directory.join("tests"));
+        assert!(shell(Some("..")).working_directory(&source_directory).is_err());
+        assert!(shell(Some("/tmp")).working_directory(&source_directory).is_err());
+        assert!(shell(Some("missing")).working_directory(&source_directory).is_err());
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(directory.path(), source_directory.join("escape")).unwrap();
+            assert!(shell(Some("escape")).working_directory(&source_directory).is_err());
+        }
+    }
+
+    #[tokio::test]
+    pub async fn execute_in_order() {
+        let mut recorder = Recorder::new(None);
+        let results = execute(&arrange(&plan(&[Release], &[]), &[]), &mut recorder, &CancellationToken::new()).await.unwrap();
+        assert_eq!(recorder.ran, [Login, Dockerfile, Build, Sbom, Release]);
+        assert_eq!(results.iter().map(|result| result.step.node().unwrap()).collect::<Vec<_>>(), recorder.ran);
+
+        let mut recorder = Recorder::new(Some((Build, 1)));
+        assert_eq!(execute(&arrange(&plan(&[Deploy], &[]), &[]), &mut recorder, &CancellationToken::new()).await, Err(Failed::Step(Build)));
+        assert_eq!(recorder.ran, [Login, Dockerfile]);
+    }
+
+    #[tokio::test]
+    pub async fn retry_and_time_out() {
+        let mut recorder = Recorder::new(Some((Release, 2)));
+        recorder.policy = Policy { retries: 2, retry_delay: Duration::ZERO, timeout: None };
+        execute(&[Step::Builtin(Release)], &mut recorder, &CancellationToken::new()).await.unwrap();
+        assert_eq!(recorder.ran, [Release]);
+
+        let mut recorder = Recorder::new(Some((Release, 3)));
+        recorder.policy = Policy { retries: 2, retry_delay: Duration::ZERO, timeout: None };
+        assert_eq!(execute(&[Step::Builtin(Release)], &mut recorder, &CancellationToken::new()).await, Err(Failed::Step(Release)));
+
+        let mut recorder = Recorder::new(None);
+        recorder.slow = Some(Deploy);
+        recorder.policy.timeout = Some(Duration::from_millis(10));
+        let timed_out = StepTimedOut { step: Step::Builtin(Deploy), timeout: Duration::from_millis(10) };
+        assert_eq!(execute(&[Step::Builtin(Deploy)], &mut recorder, &CancellationToken::new()).await, Err(Failed::TimedOut(timed_out)));
+        assert_eq!(recorder.cancelled, [Deploy], "the step is cancelled when it times out");
+    }
+
+    #[tokio::test]
+    pub async fn cancel_pipeline() {
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let mut recorder = Recorder::new(Some((Release, 1)));
+        recorder.policy = Policy { retries: 2, retry_delay: Duration::ZERO, timeout: None };
+        assert_eq!(execute(&[Step::Builtin(Release)], &mut recorder, &cancel).await, Err(Failed::Step(Release)), "not retried");
+
+        let mut recorder = Recorder::new(None);
+        recorder.slow = Some(Build);
+        execute(&[Step::Builtin(Build)], &mut recorder, &cancel).await.unwrap();
+        assert_eq!(recorder.cancelled, [Build]);
+    }
+}
@@ -0,0 +1,318 @@
+//! Running external programs: `docker`, `git`, the deploy client, credential helpers and the like.
+//!
+//! Every program nb runs goes through the [Runner] of the current task, with the functions of this module.
+//! That is the [System] runner, which logs each command line and stops processes when they are cancelled,
+//! unless code runs in a [scope] with another runner, such as the `Mock` of unit tests, which records
+//! the invocations instead and answers them without running anything.
+//!
+//! Tasks spawned with [tokio::spawn] or [tokio::task::spawn_blocking] use the [System] runner.
+
+use std::future::Future;
+use std::io::Write;
+use std::pin::Pin;
+use std::process::{ExitStatus, Output, Stdio};
+use std::sync::Arc;
+use log::debug;
+use tokio::io::AsyncWriteExt;
+use tokio::process::{Child, Command};
+use tokio_util::sync::CancellationToken;
+use crate::progress::Step;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Receives each line of the output of a command run with [run_observed].
+pub type Observer<'a> = Option<&'a mut (dyn FnMut(&str) + Send)>;
+
+/// Runs commands, in the ways nb needs to.
+pub trait Runner: Send + Sync {
+    /// Run `command` until it exits, writing `input` to its standard input if given.
+    /// The process is stopped if `cancel` is cancelled, see [crate::shutdown::wait].
+    fn status<'a>(&self, command: &'a mut Command, input: Option<&'a [u8]>, cancel: &'a CancellationToken) -> BoxFuture<'a, std::io::Result<ExitStatus>>;
+
+    /// Run `command` until it exits, and capture its standard output and error, unless redirected.
+    /// The process is killed if `cancel` is cancelled, see [crate::shutdown::output].
+    fn output<'a>(&self, command: &'a mut Command, cancel: &'a CancellationToken) -> BoxFuture<'a, std::io::Result<Output>>;
+
+    /// Run `command` as part of a step, passing each line of its output to `observe`, see [crate::progress::run_observed].
+    fn run<'a>(&self, step: &'a Step, command: &'a mut Command, observe: Observer<'a>, cancel: &'a CancellationToken) -> BoxFuture<'a, std::io::Result<ExitStatus>>;
+
+    /// Start `command` without waiting for it to exit.
+    fn spawn(&self, command: &mut Command) -> std::io::Result<Child>;
+
+    /// Run `command` until it exits, blocking the thread, for code that is not async.
+    fn status_blocking(&self, command: &mut std::process::Command) -> std::io::Result<ExitStatus>;
+
+    /// Run `command` until it exits, blocking the thread, writing `input` to its standard input if given,
+    /// and capture its standard output and error.
+    fn output_blocking(&self, command: &mut std::process::Command, input: Option<&[u8]>) -> std::io::Result<Output>;
+}
+
+/// Runs commands as processes of the operating system.
+pub struct System;
+
+impl Runner for System {
+    fn status<'a>(&self, command: &'a mut Command, input: Option<&'a [u8]>, cancel: &'a CancellationToken) -> BoxFuture<'a, std::io::Result<ExitStatus>> {
+        log(command.as_std());
+        Box::pin(async move {
+            let Some(input) = input else {
+                return crate::shutdown::status(command, cancel).await;
+            };
+            let mut child = command.stdin(Stdio::piped()).kill_on_drop(true).spawn()?;
+            let mut stdin = child.stdin.take().expect("stdin is piped");
+            stdin.write_all(input).await?;
+            drop(stdin);
+            crate::shutdown::wait(&mut child, cancel).await
+        })
+    }
+
+    fn output<'a>(&self, command: &'a mut Command, cancel: &'a CancellationToken) -> BoxFuture<'a, std::io::Result<Output>> {
+        log(command.as_std());
+        Box::pin(crate::shutdown::output(command, cancel))
+    }
+
+    fn run<'a>(&self, step: &'a Step, command: &'a mut Command, observe: Observer<'a>, cancel: &'a CancellationToken) -> BoxFuture<'a, std::io::Result<ExitStatus>> {
+        log(command.as_std());
+        Box::pin(crate::progress::run_observed(step, command, observe, cancel))
+    }
+
+    fn spawn(&self, command: &mut Command) -> std::io::Result<Child> {
+        log(command.as_std());
+        command.spawn()
+    }
+
+    fn status_blocking(&self, command: &mut std::process::Command) -> std::io::Result<ExitStatus> {
+        log(command);
+        command.status()
+    }
+
+    fn output_blocking(&self, command: &mut std::process::Command, input: Option<&[u8]>) -> std::io::Result<Output> {
+        log(command);
+        let Some(input) = input else {
+            return command.output();
+        };
+        let mut child = command.stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+        child.stdin.take().expect("stdin is piped").write_all(input)?;
+        child.wait_with_output()
+    }
+}
+
+fn log(command: &std::process::Command) {
+    match command.get_current_dir() {
+        Some(directory) => debug!("Running `{}` in {}", command_line(command), directory.display()),
+        None => debug!("Running `{}`", command_line(command)),
+    }
+}
+
+tokio::task_local! {
+    static RUNNER: Arc<dyn Runner>;
+}
+
+/// The runner of the current task: [System], unless the task runs in a [scope].
+pub fn runner() -> Arc<dyn Runner> {
+    RUNNER.try_with(Arc::clone).unwrap_or_else(|_| Arc::new(System))
+}
+
+/// Run the commands of `future` with `runner`.
+pub async fn scope<F: Future>(runner: Arc<dyn Runner>, future: F) -> F::Output {
+    RUNNER.scope(runner, future).await
+}
+
+/// Run the commands of `f` with `runner`, for code that is not async.
+pub fn sync_scope<R>(runner: Arc<dyn Runner>, f: impl FnOnce() -> R) -> R {
+    RUNNER.sync_scope(runner, f)
+}
+
+/// See [Runner::status].
+pub async fn status(command: &mut Command, cancel: &CancellationToken) -> std::io::Result<ExitStatus> {
+    runner().status(command, None, cancel).await
+}
+
+/// See [Runner::status].
+pub async fn status_with_input(command: &mut Command, input: &[u8], cancel: &CancellationToken) -> std::io::Result<ExitStatus> {
+    runner().status(command, Some(input), cancel).await
+}
+
+/// See [Runner::output].
+pub async fn output(command: &mut Command, cancel: &CancellationToken) -> std::io::Result<Output> {
+    runner().output(command, cancel).await
+}
+
+/// Run `command` as part of a step, see [crate::progress::run].
+pub async fn run(step: &Step, command: &mut Command, cancel: &CancellationToken) -> std::io::Result<ExitStatus> {
+    runner().run(step, command, None, cancel).await
+}
+
+/// See [Runner::run].
+pub async fn run_observed<'a>(step: &'a Step, command: &'a mut Command, observe: Observer<'a>, cancel: &'a CancellationToken) -> std::io::Result<ExitStatus> {
+    runner().run(step, command, observe, cancel).await
+}
+
+/// See [Runner::spawn].
+pub fn spawn(command: &mut Command) -> std::io::Result<Child> {
+    runner().spawn(command)
+}
+
+/// See [Runner::status_blocking].
+pub fn status_blocking(command: &mut std::process::Command) -> std::io::Result<ExitStatus> {
+    runner().status_blocking(command)
+}
+
+/// See [Runner::output_blocking].
+pub fn output_blocking(command: &mut std::process::Command, input: Option<&[u8]>) -> std::io::Result<Output> {
+    runner().output_blocking(command, input)
+}
+
+/// A command as it would be typed in a shell, as printed by `--dry-run` and logged when it runs.
+/// The values of options that look like they hold secrets, like `--apikey`, are redacted.
+pub fn command_line(command: &std::process::Command) -> String {
+    use crate::config::redact::{is_secret, REDACTED};
+    let mut words = vec![command.get_program().to_string_lossy().to_string()];
+    let mut secret_value = false;
+    for arg in command.get_args().map(|arg| arg.to_string_lossy()) {
+        let option = arg.strip_prefix("--");
+        words.push(match option.and_then(|option| option.split_once('=')) {
+            _ if secret_value => REDACTED.to_string(),
+            Some((name, _)) if is_secret(name) => format!("--{name}={REDACTED}"),
+            _ => arg.to_string(),
+        });
+        // Like `--apikey <key>`, but not `--password-stdin`.
+        secret_value = option.is_some_and(|option| !option.contains('=') && !option.ends_with("-stdin") && is_secret(option));
+    }
+    shell_words::join(words)
+}
+
+/// An invocation recorded by the `Mock` runner.
+#[cfg(test)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Invocation {
+    pub program: String,
+    pub args: Vec<String>,
+    /// Environment variables set for the command, and `None` for those removed.
+    pub env: Vec<(String, Option<String>)>,
+    pub current_dir: Option<std::path::PathBuf>,
+    /// What was written to the standard input of the command.
+    pub input: Option<Vec<u8>>,
+}
+
+#[cfg(test)]
+impl Invocation {
+    fn new(command: &std::process::Command, input: Option<&[u8]>) -> Self {
+        let lossy = |value: &std::ffi::OsStr| value.to_string_lossy().to_string();
+        Invocation {
+            program: lossy(command.get_program()),
+            args: command.get_args().map(lossy).collect(),
+            env: command.get_envs().map(|(name, value)| (lossy(name), value.map(lossy))).collect(),
+            current_dir: command.get_current_dir().map(std::path::Path::to_path_buf),
+            input: input.map(<[u8]>::to_vec),
+        }
+    }
+
+    /// The program and its arguments, separated by spaces.
+    pub fn command_line(&self) -> String {
+        std::iter::once(&self.program).chain(&self.args).cloned().collect::<Vec<_>>().join(" ")
+    }
+}
+
+/// A runner for unit tests, which records the commands it is given, and answers them without running anything:
+/// with the first response whose command line prefix matches, or else by succeeding without output.
+#[cfg(test)]
+#[derive(Default)]
+pub struct Mock {
+    responses: Vec<(String, i32, String, String)>,
+    invocations: std::sync::Mutex<Vec<Invocation>>,
+}
+
+#[cfg(test)]
+impl Mock {
+    /// Answer commands starting with `prefix`, like `docker push`, with an exit code and standard output.
+    pub fn respond(self, prefix: &str, code: i32, stdout: &str) -> Self {
+        self.respond_with_stderr(prefix, code, stdout, "")
+    }
+
+    pub fn respond_with_stderr(mut self, prefix: &str, code: i32, stdout: &str, stderr: &str) -> Self {
+        self.responses.push((prefix.to_string(), code, stdout.to_string(), stderr.to_string()));
+        self
+    }
+
+    /// The commands run so far, in order.
+    pub fn invocations(&self) -> Vec<Invocation> {
+        self.invocations.lock().unwrap().clone()
+    }
+
+    fn answer(&self, command: &std::process::Command, input: Option<&[u8]>) -> Output {
+        use std::os::unix::process::ExitStatusExt;
+        let invocation = Invocation::new(command, input);
+        let command_line = invocation.command_line();
+        self.invocations.lock().unwrap().push(invocation);
+        let (code, stdout, stderr) = self.responses.iter()
+            .find(|(prefix, ..)| command_line.starts_with(prefix.as_str()))
+            .map_or((0, "", ""), |(_, code, stdout, stderr)| (*code, stdout.as_str(), stderr.as_str()));
+        Output { status: ExitStatus::from_raw(code << 8), stdout: stdout.as_bytes().to_vec(), stderr: stderr.as_bytes().to_vec() }
+    }
+}
+
+#[cfg(test)]
+impl Runner for Mock {
+    fn status<'a>(&self, command: &'a mut Command, input: Option<&'a [u8]>, _cancel: &'a CancellationToken) -> BoxFuture<'a, std::io::Result<ExitStatus>> {
+        let output = self.answer(command.as_std(), input);
+        Box::pin(async move { Ok(output.status) })
+    }
+
+    fn output<'a>(&self, command: &'a mut Command, _cancel: &'a CancellationToken) -> BoxFuture<'a, std::io::Result<Output>> {
+        let output = self.answer(command.as_std(), None);
+        Box::pin(async move { Ok(output) })
+    }
+
+    fn run<'a>(&self, _step: &'a Step, command: &'a mut Command, observe: Observer<'a>, _cancel: &'a CancellationToken) -> BoxFuture<'a, std::io::Result<ExitStatus>> {
+        let output = self.answer(command.as_std(), None);
+        if let Some(observe) = observe {
+            String::from_utf8_lossy(&output.stdout).lines().for_each(observe);
+        }
+        Box::pin(async move { Ok(output.status) })
+    }
+
+    /// Starts a shell that exits like the command would.
+    fn spawn(&self, command: &mut Command) -> std::io::Result<Child> {
+        let output = self.answer(command.as_std(), None);
+        Command::new("sh").arg("-c").arg(format!("exit {}", output.status.code().unwrap_or(1))).spawn()
+    }
+
+    fn status_blocking(&self, command: &mut std::process::Command) -> std::io::Result<ExitStatus> {
+        Ok(self.answer(command, None).status)
+    }
+
+    fn output_blocking(&self, command: &mut std::process::Command, input: Option<&[u8]>) -> std::io::Result<Output> {
+        Ok(self.answer(command, input))
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    pub fn redact_command_line() {
+        let mut command = std::process::Command::new("deploy");
+        command.args(["--apikey", "0123456789abcdef", "--cluster", "dev gcp", "--github-token=ghp_123456", "--password-stdin"]);
+        assert_eq!(command_line(&command), "deploy --apikey '<redacted>' --cluster 'dev gcp' '--github-token=<redacted>' --password-stdin");
+    }
+
+    #[tokio::test]
+    pub async fn record_invocations() {
+        let mock = Arc::new(Mock::default().respond("docker image inspect", 0, "sha256:abc\n"));
+        let cancel = CancellationToken::new();
+        let (output, status) = scope(mock.clone(), async {
+            let output = output(Command::new("docker").args(["image", "inspect", "app"]).env("LC_ALL", "C"), &cancel).await.unwrap();
+            let status = status_with_input(Command::new("docker").arg("login"), b"hunter22", &cancel).await.unwrap();
+            (output, status)
+        }).await;
+        assert_eq!(output.stdout, b"sha256:abc\n");
+        assert!(status.success());
+
+        let invocations = mock.invocations();
+        assert_eq!(invocations.len(), 2);
+        assert_eq!(invocations[0].command_line(), "docker image inspect app");
+        assert_eq!(invocations[0].env, vec![("LC_ALL".to_string(), Some("C".to_string()))]);
+        assert_eq!(invocations[1].input.as_deref(), Some(b"hunter22".as_slice()));
+    }
+}
@@ -0,0 +1,224 @@
+//! Pipeline step progress on interactive terminals.
+//!
+//! When standard error is a terminal and `--non-interactive` is not in effect, each step (detect, dockerfile, build, push, deploy)
+//! is shown as a spinner line with its elapsed time, and the output of child processes is condensed
+//! into the latest line, printed in full only if the process fails. Otherwise, steps are plain logs,
+//! and the output of child processes is streamed line by line with the step and application as a prefix,
+//! like `[build:myapp] #5 DONE 0.1s`, so that the output of nb processes running side by side stays readable.
+
+use std::io::IsTerminal;
+use std::process::{ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use log::debug;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::Command;
+use tokio_util::sync::CancellationToken;
+use tracing::field::Empty;
+use crate::log_file::Tee;
+
+static PROGRESS: OnceLock<Option<MultiProgress>> = OnceLock::new();
+static PREFIX_OUTPUT: AtomicBool = AtomicBool::new(true);
+static APP: OnceLock<String> = OnceLock::new();
+
+/// Whether progress can be drawn, i.e. standard error is a terminal that supports it.
+fn enabled() -> bool {
+    std::io::stderr().is_terminal() && std::env::var("TERM").map_or(true, |term| term != "dumb")
+}
+
+fn multi_progress() -> Option<&'static MultiProgress> {
+    PROGRESS.get_or_init(|| enabled().then(MultiProgress::new)).as_ref()
+}
+
+/// Install `logger` as the global logger, and decide whether to draw progress.
+/// While progress is drawn, log lines are printed above it. Logs are also copied to the log file, if any.
+pub fn init_logger(logger: env_logger::Logger, show_progress: bool) -> Result<(), log::SetLoggerError> {
+    // The log file has debug logs even when the console does not.
+    let level = logger.filter().max(log::LevelFilter::Debug);
+    let multi = PROGRESS.get_or_init(|| (show_progress && enabled()).then(MultiProgress::new));
+    match multi {
+        Some(multi) => log::set_boxed_logger(Box::new(Tee::new(indicatif_log_bridge::LogWrapper::new(multi.clone(), logger))))?,
+        None => log::set_boxed_logger(Box::new(Tee::new(logger)))?,
+    }
+    log::set_max_level(level);
+    Ok(())
+}
+
+/// Whether the output of child processes is prefixed when progress is not drawn. Without a prefix,
+/// it is written directly to the console, unless it is copied to log files.
+pub fn prefix_output(enabled: bool) {
+    PREFIX_OUTPUT.store(enabled, Ordering::Relaxed);
+}
+
+/// Name the application in the prefix of child process output, once it is detected.
+pub fn set_app(app: &str) {
+    let _ = APP.set(app.to_string());
+}
+
+/// The application, if it has been detected.
+pub fn app() -> Option<&'static str> {
+    APP.get().map(String::as_str)
+}
+
+fn prefix(step: &str) -> String {
+    match app() {
+        Some(app) => format!("[{step}:{app}] "),
+        None => format!("[{step}] "),
+    }
+}
+
+/// Hide progress while running `f`, e.g. to ask the user a question.
+pub fn suspend<R>(f: impl FnOnce() -> R) -> R {
+    match multi_progress() {
+        Some(multi) => multi.suspend(f),
+        None => f(),
+    }
+}
+
+/// A pipeline step in progress. Dropping a step without finishing it marks it as failed.
+/// Each step is also a span in the exported traces, see [crate::telemetry].
+pub struct Step {
+    name: String,
+    started: Instant,
+    bar: Option<ProgressBar>,
+    span: tracing::Span,
+    finished: bool,
+    /// Values replaced with `<redacted>` in the output of the step's commands.
+    secrets: Vec<String>,
+}
+
+/// Start a pipeline step.
+pub fn step(name: impl Into<String>) -> Step {
+    let name = name.into();
+    let bar = multi_progress().map(|multi| {
+        let bar = multi.add(ProgressBar::new_spinner());
+        bar.set_style(ProgressStyle::with_template("{spinner:.cyan} {prefix:.bold} [{elapsed}] {wide_msg:.dim}").unwrap());
+        bar.set_prefix(name.clone());
+        bar.enable_steady_tick(Duration::from_millis(100));
+        bar
+    });
+    let span = tracing::info_span!("step", otel.name = name.as_str(), otel.status_code = Empty);
+    debug!("Step {name} started");
+    Step { name, started: Instant::now(), bar, span, finished: false, secrets: Vec::new() }
+}
+
+impl Step {
+    /// Hide `secrets` from the output of the step's commands, which is then always captured.
+    pub fn redacting(mut self, secrets: Vec<String>) -> Self {
+        self.secrets = secrets;
+        self
+    }
+
+    fn redact(&self, line: String) -> String {
+        self.secrets.iter().fold(line, |line, secret| line.replace(secret, crate::config::redact::REDACTED))
+    }
+
+    /// Show the latest output of the step next to the spinner.
+    pub fn message(&self, message: &str) {
+        if let Some(bar) = &self.bar {
+            bar.set_message(message.to_string());
+        }
+    }
+
+    /// Time since the step started.
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn finish(mut self) {
+        self.finished = true;
+        self.end("{prefix:.bold.green} [{elapsed}] done");
+    }
+
+    fn end(&self, template: &str) {
+        debug!("Step {} {} after {:.1}s", self.name, if self.finished { "finished" } else { "failed" }, self.started.elapsed().as_secs_f64());
+        self.span.record("otel.status_code", if self.finished { "OK" } else { "ERROR" });
+        if let Some(bar) = &self.bar {
+            bar.set_style(ProgressStyle::with_template(template).unwrap());
+            bar.finish();
+        }
+    }
+}
+
+impl Drop for Step {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.end("{prefix:.bold.red} [{elapsed}] failed");
+        }
+    }
+}
+
+/// Run a command as part of a step. While progress is drawn, its output is captured and shown
+/// one line at a time, and printed in full if the command fails. Its output is also copied to the log file.
+/// The command is stopped if `cancel` is cancelled, see [crate::shutdown::wait].
+pub async fn run(step: &Step, command: &mut Command, cancel: &CancellationToken) -> std::io::Result<ExitStatus> {
+    run_observed(step, command, None, cancel).await
+}
+
+/// Run a command like [run], passing each line of its output to `observe`.
+pub async fn run_observed(step: &Step, command: &mut Command, mut observe: Option<&mut (dyn FnMut(&str) + Send)>, cancel: &CancellationToken) -> std::io::Result<ExitStatus> {
+    let multi = multi_progress().filter(|_| step.bar.is_some());
+    let prefix = match PREFIX_OUTPUT.load(Ordering::Relaxed) {
+        true => prefix(&step.name),
+        false => String::new(),
+    };
+    let captured = multi.is_some() || !prefix.is_empty() || observe.is_some() || crate::log_file::enabled() || crate::log_file::step_files_enabled() || !step.secrets.is_empty();
+    if !captured {
+        return crate::shutdown::status(command.stdout(crate::report::child_stdout()).stderr(Stdio::inherit()), cancel).await;
+    }
+
+    let mut child = command.stdout(Stdio::piped()).stderr(Stdio::piped()).kill_on_drop(true).spawn()?;
+    let mut stdout = BufReader::new(child.stdout.take().expect("stdout is piped")).lines();
+    let mut stderr = BufReader::new(child.stderr.take().expect("stderr is piped")).lines();
+    let (mut stdout_open, mut stderr_open) = (true, true);
+
+    let mut output = Vec::new();
+    let read = async {
+        while stdout_open || stderr_open {
+            let (stderr, line) = tokio::select! {
+                line = stdout.next_line(), if stdout_open => (false, line),
+                line = stderr.next_line(), if stderr_open => (true, line),
+            };
+            let Ok(Some(line)) = line else {
+                match stderr {
+                    true => stderr_open = false,
+                    false => stdout_open = false,
+                }
+                continue;
+            };
+            let line = step.redact(line);
+            crate::log_file::output(&step.name, app(), &line);
+            if let Some(observe) = &mut observe {
+                observe(&line);
+            }
+            match multi {
+                Some(_) => {
+                    step.message(line.trim());
+                    output.push(line);
+                }
+                None if stderr || crate::report::stdout_reserved() => eprintln!("{prefix}{line}"),
+                None => println!("{prefix}{line}"),
+            }
+        }
+    };
+    let status = {
+        let wait = crate::shutdown::wait(&mut child, cancel);
+        tokio::pin!(read, wait);
+        tokio::select! {
+            () = &mut read => wait.await,
+            status = &mut wait => {
+                // Processes left behind by a cancelled child may keep its output open.
+                if !cancel.is_cancelled() {
+                    read.await;
+                }
+                status
+            }
+        }
+    }?;
+    if let (Some(multi), false) = (multi, status.success()) {
+        multi.suspend(|| eprintln!("{}", output.join("\n")));
+    }
+    Ok(status)
+}
@@ -0,0 +1,59 @@
+//! Simple interactive prompts on the terminal.
+
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static NON_INTERACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Never prompt from now on, even on a terminal.
+pub fn disable() {
+    NON_INTERACTIVE.store(true, Ordering::Relaxed);
+}
+
+/// Whether the `CI` environment variable says that we are running in CI, as most CI systems set it.
+pub fn in_ci() -> bool {
+    std::env::var("CI").is_ok_and(|ci| !matches!(ci.as_str(), "" | "0" | "false"))
+}
+
+/// Returns true if prompts are allowed, and both standard input and standard error are attached
+/// to a terminal, so that the user is able to see and answer prompts.
+pub fn is_interactive() -> bool {
+    !NON_INTERACTIVE.load(Ordering::Relaxed) && std::io::stdin().is_terminal() && std::io::stderr().is_terminal()
+}
+
+/// Ask the user for a value on standard error, and read the answer from standard input.
+/// An empty answer selects the default value, if any.
+pub fn input(question: &str, default: Option<&str>) -> std::io::Result<String> {
+    input_validated(question, default, |_| Ok(()))
+}
+
+/// Like [input], but ask again with the reason until `validate` accepts the answer.
+pub fn input_validated<F>(question: &str, default: Option<&str>, mut validate: F) -> std::io::Result<String>
+where
+    F: FnMut(&str) -> Result<(), String>,
+{
+    let mut input = dialoguer::Input::<String>::new()
+        .with_prompt(question)
+        .validate_with(|answer: &String| validate(answer.trim()));
+    if let Some(default) = default {
+        input = input.default(default.to_string());
+    }
+    Ok(input.interact_text().map_err(io_error)?.trim().to_string())
+}
+
+/// Let the user choose one of `items` with the arrow keys, starting at the `default` index.
+pub fn select(question: &str, items: &[String], default: usize) -> std::io::Result<String> {
+    let index = dialoguer::Select::new()
+        .with_prompt(question)
+        .items(items)
+        .default(default)
+        .interact()
+        .map_err(io_error)?;
+    Ok(items[index].clone())
+}
+
+fn io_error(err: dialoguer::Error) -> std::io::Error {
+    match err {
+        dialoguer::Error::IO(err) => err,
+    }
+}
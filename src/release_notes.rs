@@ -0,0 +1,153 @@
+//! Release notes generated from commit messages following the Conventional Commits format,
+//! e.g. `feat(api): add endpoint` or `fix!: handle empty input`.
+
+/// Sections of the release notes, in order, with the commit types they contain.
+/// Commits of other types, or without a type, are listed under "Other changes".
+const SECTIONS: &[(&str, &[&str])] = &[
+    ("Features", &["feat"]),
+    ("Bug fixes", &["fix"]),
+    ("Chores", &["chore", "build", "ci", "deps"]),
+];
+
+const OTHER_SECTION: &str = "Other changes";
+
+/// A commit subject line parsed as a conventional commit.
+#[derive(Debug, PartialEq)]
+pub struct Commit<'a> {
+    /// Commit type such as `feat` or `fix`, or `None` if the subject is not a conventional commit.
+    pub typ: Option<&'a str>,
+    pub scope: Option<&'a str>,
+    pub breaking: bool,
+    pub description: &'a str,
+}
+
+impl<'a> Commit<'a> {
+    pub fn parse(subject: &'a str) -> Self {
+        let unconventional = Self { typ: None, scope: None, breaking: false, description: subject };
+        let Some((header, description)) = subject.split_once(": ") else {
+            return unconventional;
+        };
+        let (header, breaking) = match header.strip_suffix('!') {
+            Some(header) => (header, true),
+            None => (header, false),
+        };
+        let (typ, scope) = match header.split_once('(') {
+            Some((typ, scope)) => match scope.strip_suffix(')') {
+                Some(scope) => (typ, Some(scope)),
+                None => return unconventional,
+            },
+            None => (header, None),
+        };
+        if typ.is_empty() || !typ.chars().all(|c| c.is_ascii_alphabetic()) {
+            return unconventional;
+        }
+        Self { typ: Some(typ), scope, breaking, description: description.trim() }
+    }
+}
+
+/// Render Markdown release notes from commit subject lines, grouped by commit type.
+///
+/// Returns an empty string if there are no commits.
+pub fn generate<S: AsRef<str>>(subjects: &[S]) -> String {
+    let commits: Vec<Commit> = subjects.iter().map(|subject| Commit::parse(subject.as_ref())).collect();
+
+    let section_of = |commit: &Commit| {
+        SECTIONS
+            .iter()
+            .find(|(_, types)| commit.typ.is_some_and(|typ| types.contains(&typ.to_ascii_lowercase().as_str())))
+            .map(|(title, _)| *title)
+            .unwrap_or(OTHER_SECTION)
+    };
+
+    let mut notes = Vec::new();
+    let titles = SECTIONS.iter().map(|(title, _)| *title).chain(std::iter::once(OTHER_SECTION));
+    for title in titles {
+        let entries: Vec<String> = commits
+            .iter()
+            .filter(|commit| section_of(commit) == title)
+            .map(|commit| {
+                let breaking = if commit.breaking { "**BREAKING** " } else { "" };
+                match commit.scope {
+                    Some(scope) => format!("* {breaking}**{scope}:** {}", commit.description),
+                    None => format!("* {breaking}{}", commit.description),
+                }
+            })
+            .collect();
+        if !entries.is_empty() {
+            notes.push(format!("### {title}\n\n{}\n", entries.join("\n")));
+        }
+    }
+    notes.join("\n")
+}
+
+/// Generate release notes from the commits in `source_directory` since the previous version tag.
+///
+/// Returns `None` if there are no commits, the repository cannot be read,
+/// or the repository has no version tags, in which case the whole history would be included.
+pub fn since_previous_release(source_directory: &str) -> Option<String> {
+    crate::git::version(source_directory).ok().flatten()?;
+    let commits = crate::git::commits_since_release(source_directory)
+        .inspect_err(|err| log::warn!("Release notes not available: {err}"))
+        .ok()?;
+    Some(generate(&commits)).filter(|notes| !notes.is_empty())
+}
+
+/// Render a Markdown changelog entry with the version as heading, if known, followed by the release notes.
+pub fn changelog<S: AsRef<str>>(version: Option<&str>, subjects: &[S]) -> String {
+    let notes = match generate(subjects) {
+        notes if notes.is_empty() => "No changes.\n".to_string(),
+        notes => notes,
+    };
+    match version {
+        Some(version) => format!("## {version}\n\n{notes}"),
+        None => notes,
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    pub fn parse_conventional_commit() {
+        assert_eq!(Commit::parse("feat(api)!: drop v1"), Commit {
+            typ: Some("feat"),
+            scope: Some("api"),
+            breaking: true,
+            description: "drop v1",
+        });
+        assert_eq!(Commit::parse("Merge pull request #1: foo").typ, None);
+        assert_eq!(Commit::parse("update readme").typ, None);
+    }
+
+    #[test]
+    pub fn generate_release_notes() {
+        let notes = generate(&[
+            "fix: handle empty input",
+            "feat(api): add endpoint",
+            "chore(deps): bump serde",
+            "update readme",
+            "feat!: require Java 21",
+        ]);
+        assert_eq!(notes, "\
+### Features
+
+* **api:** add endpoint
+* **BREAKING** require Java 21
+
+### Bug fixes
+
+* handle empty input
+
+### Chores
+
+* **deps:** bump serde
+
+### Other changes
+
+* update readme
+");
+        assert_eq!(generate::<&str>(&[]), "");
+        assert_eq!(changelog::<&str>(Some("v1.0.0"), &[]), "## v1.0.0\n\nNo changes.\n");
+    }
+}
@@ -0,0 +1,369 @@
+//! Machine-readable results, printed as JSON on standard output with `--output json`.
+//!
+//! In JSON mode, standard output is reserved for the report: anything that would otherwise be
+//! printed is collected in the report, and the output of child processes such as `docker build`
+//! is redirected to standard error, together with the logs.
+
+use std::collections::BTreeMap;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+static JSON_OUTPUT: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, ValueEnum)]
+pub enum OutputFormat {
+    /// Human-readable output.
+    #[default]
+    Text,
+    /// A single JSON object with the results of the command.
+    Json,
+}
+
+/// Whether standard output is reserved for the JSON report.
+pub fn stdout_reserved() -> bool {
+    JSON_OUTPUT.load(Ordering::Relaxed)
+}
+
+/// Standard output for child processes: inherited, or standard error if standard output is reserved for the report.
+pub fn child_stdout() -> Stdio {
+    match stdout_reserved() {
+        true => std::io::stderr().into(),
+        false => Stdio::inherit(),
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Deployment {
+    pub cluster: String,
+    pub status: DeploymentStatus,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeploymentStatus {
+    Deployed,
+    DryRun,
+}
+
+/// The artifacts produced by a successful build, release or deploy, written to `artifacts.json`
+/// for rollback, promotion and other tooling.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct ArtifactManifest {
+    /// Name of the subcommand that produced the artifacts, e.g. `release`.
+    pub command: String,
+    /// Version of nb that produced the artifacts.
+    pub nb_version: String,
+    /// When the command finished, in RFC 3339 format.
+    #[serde(default)]
+    pub created: String,
+    pub image: Option<String>,
+    pub tag: Option<String>,
+    pub digest: Option<String>,
+    /// Path of the SBOM of the image, if one was generated.
+    pub sbom: Option<String>,
+    /// References to the attestations of the signed image, such as that of its SBOM.
+    pub attestations: Vec<String>,
+    /// Path of the license report of the dependencies, if `nb licenses` wrote one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub licenses: Option<String>,
+    pub dockerfile: Option<String>,
+    /// SHA-256 of the contents of the Dockerfile.
+    pub dockerfile_sha256: Option<String>,
+    /// Commit that the artifacts were built from.
+    pub git_sha: Option<String>,
+    pub deployment: Option<Deployment>,
+    /// Duration of each step in seconds, and of the whole command as `total`.
+    pub timings: BTreeMap<String, f64>,
+    /// Seconds spent in each stage of the Dockerfile, as reported by BuildKit.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub build_stages: BTreeMap<String, f64>,
+    /// Whether the command was cancelled, e.g. with Ctrl-C, after some of its steps finished.
+    /// The timings then only have the steps that finished, and only the ledger records the entry.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub cancelled: bool,
+}
+
+/// Why a command failed, in a form that CI dashboards can classify failures by.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ErrorReport {
+    /// Stable code of the kind of failure, e.g. `NB-CONFIG-002`.
+    pub code: &'static str,
+    pub message: String,
+    /// What to do about the error, if there is more to say than the message.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hint: Option<&'static str>,
+    pub exit_code: i32,
+    /// The file that caused the error, if known.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct Report {
+    #[serde(skip)]
+    format: OutputFormat,
+    #[serde(skip)]
+    started: Option<Instant>,
+    #[serde(skip)]
+    receiver: Option<crate::events::Receiver>,
+
+    /// Name of the subcommand, e.g. `release`.
+    pub command: String,
+    pub success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+    /// Registry digest of the released image.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+    /// Path of the generated Dockerfile.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dockerfile: Option<String>,
+    /// Path of the SBOM of the image, if one was generated.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sbom: Option<String>,
+    /// References to the attestations of the signed image, such as that of its SBOM.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub attestations: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deployment: Option<Deployment>,
+    /// Dependencies and their licenses, as reported by `nb licenses`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub licenses: Option<crate::licenses::Scan>,
+    /// Signatures and attestations of the image, as checked by `nb verify`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub verification: Option<crate::verify::Verification>,
+    /// Text that the command prints on standard output in text mode, such as a Dockerfile or rendered resources.
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub output: String,
+    /// Duration of each step in seconds, and of the whole command as `total`.
+    pub timings: BTreeMap<String, f64>,
+    /// Seconds spent in each stage of the Dockerfile, as reported by BuildKit.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub build_stages: BTreeMap<String, f64>,
+    /// Lifecycle events of the pipeline steps, in the order they happened.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub events: Vec<crate::events::Event>,
+}
+
+impl Report {
+    pub fn new(command: &str, format: OutputFormat) -> Self {
+        JSON_OUTPUT.store(format == OutputFormat::Json, Ordering::Relaxed);
+        Self {
+            format,
+            started: Some(Instant::now()),
+            command: command.to_string(),
+            ..Self::default()
+        }
+    }
+
+    /// Print to standard output, or add to the report in JSON mode.
+    pub fn print(&mut self, text: &str) {
+        match self.format {
+            OutputFormat::Text => print!("{text}"),
+            OutputFormat::Json => self.output.push_str(text),
+        }
+    }
+
+    pub fn println(&mut self, text: &str) {
+        self.print(&format!("{text}\n"));
+    }
+
+    /// Image, tag, digest and deployment as name and value, for those that are known.
+    pub fn artifacts(&self) -> Vec<(&'static str, String)> {
+        let deployment = self.deployment.as_ref()
+            .filter(|deployment| deployment.status == DeploymentStatus::Deployed)
+            .map(|deployment| deployment.cluster.clone());
+        [("image", self.image.clone()), ("tag", self.tag.clone()), ("digest", self.digest.clone()), ("cluster", deployment)]
+            .into_iter()
+            .filter_map(|(name, value)| Some((name, value?)))
+            .collect()
+    }
+
+    /// A Markdown summary of the artifacts, for the GitHub Actions job summary.
+    pub fn markdown_summary(&self) -> String {
+        let mut summary = format!("### nb {}\n\n| | |\n|---|---|\n", self.command);
+        for (name, value) in self.artifacts() {
+            summary.push_str(&format!("| {name} | `{value}` |\n"));
+        }
+        summary
+    }
+
+    /// The artifacts produced so far, built from the commit `git_sha`.
+    pub fn manifest(&self, git_sha: Option<String>) -> ArtifactManifest {
+        let mut timings = self.timings.clone();
+        if let Some(started) = self.started {
+            timings.insert("total".to_string(), started.elapsed().as_secs_f64());
+        }
+        ArtifactManifest {
+            command: self.command.clone(),
+            nb_version: crate::version::CLIENT_VERSION.to_string(),
+            created: chrono::Utc::now().to_rfc3339(),
+            image: self.image.clone(),
+            tag: self.tag.clone(),
+            digest: self.digest.clone(),
+            sbom: self.sbom.clone(),
+            attestations: self.attestations.clone(),
+            licenses: None,
+            dockerfile_sha256: self.dockerfile.as_ref()
+                .and_then(|path| std::fs::read(path).ok())
+                .map(sha256::digest),
+            dockerfile: self.dockerfile.clone(),
+            git_sha,
+            deployment: self.deployment.clone(),
+            timings,
+            build_stages: self.build_stages.clone(),
+            cancelled: false,
+        }
+    }
+
+    /// Record the events published on `bus` from now on, see [Report::record_events].
+    pub fn subscribe(&mut self, bus: &crate::events::Bus) {
+        self.receiver = Some(bus.subscribe());
+    }
+
+    /// Record the events published so far, with the timings of the steps that finished.
+    pub fn record_events(&mut self) {
+        let Some(receiver) = &mut self.receiver else {
+            return;
+        };
+        for event in receiver.drain() {
+            if let crate::events::Event::StepFinished { step, elapsed } = &event {
+                self.timings.insert(step.clone(), elapsed.as_secs_f64());
+            }
+            self.events.push(event);
+        }
+    }
+
+    /// Record the duration of a step that began at `started`.
+    pub fn timing(&mut self, step: &str, started: Instant) {
+        self.timings.insert(step.to_string(), started.elapsed().as_secs_f64());
+    }
+
+    /// The timings as a table, with the stages of the build under it, for `--timings`.
+    pub fn timings_table(&self) -> String {
+        let mut rows: Vec<(String, f64)> = Vec::new();
+        // Steps in the order they run, then any others.
+        let order = ["detect", "login", "dockerfile", "build", "sbom", "release", "sign", "deploy"];
+        let mut steps: Vec<(&String, &f64)> = self.timings.iter().filter(|(step, _)| *step != "total").collect();
+        steps.sort_by_key(|(step, _)| order.iter().position(|name| name == step).unwrap_or(order.len()));
+        for (step, seconds) in steps {
+            rows.push((step.clone(), *seconds));
+            if step == "build" {
+                rows.extend(self.build_stages.iter().map(|(stage, seconds)| (format!("  {stage}"), *seconds)));
+            }
+        }
+        if let Some(started) = self.started {
+            rows.push(("total".to_string(), started.elapsed().as_secs_f64()));
+        }
+        let width = rows.iter().map(|(name, _)| name.len()).max().unwrap_or(0).max("step".len());
+        let mut table = format!("{:<width$}  {:>8}\n", "step", "seconds");
+        for (name, seconds) in rows {
+            table.push_str(&format!("{name:<width$}  {seconds:>8.1}\n"));
+        }
+        table
+    }
+
+    /// Record the result of the command, and print the report in JSON mode.
+    pub fn finish(mut self, error: Option<ErrorReport>) {
+        if let Some(started) = self.started {
+            self.timing("total", started);
+        }
+        self.success = error.is_none();
+        self.error = error;
+        if self.format == OutputFormat::Json {
+            println!("{}", serde_json::to_string(&self).expect("report is serializable"));
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    pub fn serialize_report() {
+        let mut report = Report { format: OutputFormat::Json, command: "deploy".into(), ..Report::default() };
+        report.image = Some("ghcr.io/navikt/myapp:1".into());
+        report.deployment = Some(Deployment { cluster: "dev-gcp".into(), status: DeploymentStatus::DryRun });
+        report.print("rendered");
+        report.success = true;
+        let json: serde_json::Value = serde_json::to_value(&report).unwrap();
+        assert_eq!(json, serde_json::json!({
+            "command": "deploy",
+            "success": true,
+            "image": "ghcr.io/navikt/myapp:1",
+            "deployment": { "cluster": "dev-gcp", "status": "dry_run" },
+            "output": "rendered",
+            "timings": {},
+        }));
+    }
+
+    #[test]
+    pub fn serialize_error() {
+        let mut report = Report { format: OutputFormat::Json, command: "build".into(), ..Report::default() };
+        report.error = Some(ErrorReport {
+            code: "NB-CONFIG-002",
+            message: "configuration file: nb.toml: expected `=`".into(),
+            hint: Some("check the syntax of the file"),
+            exit_code: 3,
+            file: Some("nb.toml".into()),
+        });
+        let json: serde_json::Value = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["success"], false);
+        assert_eq!(json["error"], serde_json::json!({
+            "code": "NB-CONFIG-002",
+            "message": "configuration file: nb.toml: expected `=`",
+            "hint": "check the syntax of the file",
+            "exit_code": 3,
+            "file": "nb.toml",
+        }));
+    }
+
+    #[test]
+    pub fn timings_table() {
+        let mut report = Report::default();
+        report.timings.insert("release".into(), 3.0);
+        report.timings.insert("build".into(), 12.34);
+        report.timings.insert("detect".into(), 0.1);
+        report.build_stages.insert("builder".into(), 10.0);
+        assert_eq!(report.timings_table(), "\
+step        seconds
+detect          0.1
+build          12.3
+  builder      10.0
+release         3.0
+");
+    }
+
+    #[test]
+    pub fn artifact_manifest() {
+        let dockerfile = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(dockerfile.path(), "FROM scratch\n").unwrap();
+        let mut report = Report { command: "release".into(), ..Report::default() };
+        report.image = Some("ghcr.io/navikt/myapp:1".into());
+        report.dockerfile = Some(dockerfile.path().to_string_lossy().to_string());
+        report.timings.insert("build".into(), 1.5);
+        let manifest = report.manifest(Some("abc123".into()));
+        assert_eq!(manifest.dockerfile_sha256.as_deref(), Some(sha256::digest("FROM scratch\n").as_str()));
+        assert_eq!(manifest.git_sha.as_deref(), Some("abc123"));
+        assert_eq!(manifest.timings["build"], 1.5);
+        let json = serde_json::to_string(&manifest).unwrap();
+        assert_eq!(serde_json::from_str::<ArtifactManifest>(&json).unwrap(), manifest);
+    }
+
+    #[test]
+    pub fn markdown_summary() {
+        let mut report = Report { command: "deploy".into(), ..Report::default() };
+        report.image = Some("ghcr.io/navikt/myapp:1".into());
+        report.tag = Some("1".into());
+        report.deployment = Some(Deployment { cluster: "dev-gcp".into(), status: DeploymentStatus::Deployed });
+        assert_eq!(report.markdown_summary(), "### nb deploy\n\n| | |\n|---|---|\n| image | `ghcr.io/navikt/myapp:1` |\n| tag | `1` |\n| cluster | `dev-gcp` |\n");
+    }
+}
@@ -0,0 +1,138 @@
+//! External sources of Kubernetes resources, used instead of nais.yaml detection.
+//!
+//! A source is configured as `[naisyaml] resources = "<type>:<arguments>"`, e.g.
+//! `kustomize:.nais/overlays/dev` or `helm:charts/myapp --values values-dev.yaml`.
+//! The source is rendered locally, and the output is treated exactly like a nais.yaml file:
+//! it is rendered as a template, and deployed through the normal deploy path.
+
+use std::fmt::{Display, Formatter};
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use log::debug;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("unknown resource source '{0}'; expected e.g. `kustomize:<path>` or `helm:<chart> [args]`")]
+    UnknownSource(String),
+
+    #[error("execute {program}: {err}")]
+    Execute {
+        err: std::io::Error,
+        program: String,
+    },
+
+    #[error("{program} exited with {status}: {stderr}")]
+    Failed {
+        program: String,
+        status: std::process::ExitStatus,
+        stderr: String,
+    },
+
+    #[error("{program} produced invalid UTF-8: {err}")]
+    Utf8 {
+        err: std::string::FromUtf8Error,
+        program: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    /// Directory containing a `kustomization.yaml`, relative to the source directory.
+    Kustomize(String),
+
+    /// Helm chart, relative to the source directory, and extra arguments to `helm template`
+    /// such as `--values values-dev.yaml`.
+    Helm {
+        chart: String,
+        args: Vec<String>,
+    },
+}
+
+impl FromStr for Source {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("kustomize", path)) if !path.is_empty() => Ok(Self::Kustomize(path.to_string())),
+            Some(("helm", args)) => {
+                let mut args = args.split_whitespace().map(str::to_string);
+                let chart = args.next().ok_or_else(|| Error::UnknownSource(s.to_string()))?;
+                Ok(Self::Helm { chart, args: args.collect() })
+            }
+            _ => Err(Error::UnknownSource(s.to_string())),
+        }
+    }
+}
+
+impl Display for Source {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Kustomize(path) => write!(f, "kustomize:{path}"),
+            Self::Helm { chart, args } if args.is_empty() => write!(f, "helm:{chart}"),
+            Self::Helm { chart, args } => write!(f, "helm:{chart} {}", args.join(" ")),
+        }
+    }
+}
+
+impl Source {
+    /// Render the source into a multi-document YAML string.
+    pub fn render(&self, source_directory: &str) -> Result<String, Error> {
+        match self {
+            Self::Kustomize(path) => {
+                // Prefer the standalone binary, but fall back to the version bundled with kubectl.
+                match output(source_directory, "kustomize", &["build", path]) {
+                    Err(Error::Execute { err, .. }) if err.kind() == std::io::ErrorKind::NotFound => {
+                        debug!("kustomize not found, using kubectl kustomize");
+                        output(source_directory, "kubectl", &["kustomize", path])
+                    }
+                    result => result,
+                }
+            }
+            Self::Helm { chart, args } => {
+                let mut helm_args = vec!["template", chart.as_str()];
+                helm_args.extend(args.iter().map(String::as_str));
+                output(source_directory, "helm", &helm_args)
+            }
+        }
+    }
+}
+
+/// Run a program in the source directory, and return its standard output.
+fn output(source_directory: &str, program: &str, args: &[&str]) -> Result<String, Error> {
+    let output = crate::process::output_blocking(Command::new(program)
+        .args(args)
+        .current_dir(source_directory)
+        .stdin(Stdio::null()), None)
+        .map_err(|err| Error::Execute { err, program: program.to_string() })?;
+    if !output.status.success() {
+        return Err(Error::Failed {
+            program: program.to_string(),
+            status: output.status,
+            stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        });
+    }
+    String::from_utf8(output.stdout).map_err(|err| Error::Utf8 { err, program: program.to_string() })
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    pub fn parse_source() {
+        let source: Source = "kustomize:.nais/overlays/dev".parse().unwrap();
+        assert_eq!(source, Source::Kustomize(".nais/overlays/dev".into()));
+        assert_eq!(source.to_string(), "kustomize:.nais/overlays/dev");
+        assert!("kustomize:".parse::<Source>().is_err());
+
+        let source: Source = "helm:charts/myapp --values values-dev.yaml".parse().unwrap();
+        assert_eq!(source, Source::Helm {
+            chart: "charts/myapp".into(),
+            args: vec!["--values".into(), "values-dev.yaml".into()],
+        });
+        assert_eq!(source.to_string(), "helm:charts/myapp --values values-dev.yaml");
+        assert!("helm: ".parse::<Source>().is_err());
+        assert!(".nais/nais.yaml".parse::<Source>().is_err());
+    }
+}
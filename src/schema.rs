@@ -0,0 +1,331 @@
+//! Validate Kubernetes resources against the schemas of their custom resource definitions.
+//!
+//! Naiserator publishes the NAIS Application schema as a CustomResourceDefinition,
+//! whose OpenAPI v3 "structural schema" is a subset of JSON schema. This module
+//! implements the parts of that subset needed to report useful errors with field paths.
+
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+use log::{debug, warn};
+use serde::Deserialize;
+use serde_json::Value;
+use thiserror::Error;
+use crate::cache;
+
+/// How long a downloaded schema is used before it is fetched again.
+const CACHE_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("fetch schema: {0}")]
+    Fetch(#[from] reqwest::Error),
+
+    #[error("fetch schema from {url}: HTTP status {status}")]
+    HttpStatus {
+        url: String,
+        status: u16,
+    },
+
+    #[error("parse YAML: {0}")]
+    Parse(#[from] serde_yaml::Error),
+}
+
+/// A single validation error.
+#[derive(Debug, PartialEq)]
+pub struct Violation {
+    /// Dotted path to the offending field, e.g. `spec.replicas.min`.
+    pub path: String,
+    pub message: String,
+}
+
+impl Display for Violation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.path.is_empty() {
+            true => write!(f, "{}", self.message),
+            false => write!(f, "{}: {}", self.path, self.message),
+        }
+    }
+}
+
+/// Download a schema document, or use a cached copy if it is recent enough.
+/// If downloading fails, a stale cached copy is used if one exists.
+pub async fn fetch(url: &str) -> Result<Value, Error> {
+    let cache_name = format!("schema-{}.yaml", &sha256::digest(url)[..16]);
+
+    let body = match cache::read(&cache_name, Some(CACHE_MAX_AGE)) {
+        Some(body) => {
+            debug!("Using cached schema for {url}");
+            body
+        }
+        None => match download(url).await {
+            Ok(body) => {
+                if let Err(err) = cache::write(&cache_name, &body) {
+                    warn!("Schema could not be cached: {err}");
+                }
+                body
+            }
+            Err(err) => {
+                let body = cache::read(&cache_name, None).ok_or(err)?;
+                warn!("Using stale cached schema for {url}");
+                body
+            }
+        },
+    };
+
+    Ok(serde_yaml::from_str(&body)?)
+}
+
+async fn download(url: &str) -> Result<String, Error> {
+    debug!("Downloading schema from {url}");
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()?;
+    let resp = client.get(url).send().await?;
+    if !resp.status().is_success() {
+        return Err(Error::HttpStatus {
+            url: url.to_string(),
+            status: resp.status().as_u16(),
+        });
+    }
+    Ok(resp.text().await?)
+}
+
+/// Parse all documents in a multi-document YAML string, resolving `<<` merge keys.
+pub fn documents(yaml: &str) -> Result<Vec<Value>, Error> {
+    let mut documents = Vec::new();
+    for document in serde_yaml::Deserializer::from_str(yaml) {
+        let mut document = serde_yaml::Value::deserialize(document)?;
+        if !document.is_null() {
+            document.apply_merge()?;
+            documents.push(serde_yaml::from_value(document)?);
+        }
+    }
+    Ok(documents)
+}
+
+/// Find the schema for a resource of the given kind and API version.
+///
+/// If `schema_document` is a CustomResourceDefinition, the schema of the matching version is returned.
+/// Otherwise, the document is assumed to be a plain schema for NAIS Applications.
+pub fn schema_for(schema_document: &Value, kind: &str, api_version: &str) -> Option<Value> {
+    if schema_document["kind"] != "CustomResourceDefinition" {
+        return (kind == "Application").then(|| schema_document.clone());
+    }
+
+    let spec = &schema_document["spec"];
+    let (group, version) = api_version.split_once('/')?;
+    if spec["names"]["kind"] != kind || spec["group"] != group {
+        return None;
+    }
+    spec["versions"]
+        .as_array()?
+        .iter()
+        .find(|v| v["name"] == version)
+        .map(|v| v["schema"]["openAPIV3Schema"].clone())
+}
+
+/// Validate a resource against a schema, returning all violations found.
+pub fn validate(value: &Value, schema: &Value) -> Vec<Violation> {
+    let mut violations = Vec::new();
+    validate_node(value, schema, "", &mut violations);
+    violations
+}
+
+fn validate_node(value: &Value, schema: &Value, path: &str, violations: &mut Vec<Violation>) {
+    let mut violation = |message: String| violations.push(Violation { path: path.to_string(), message });
+
+    if value.is_null() && schema["nullable"] == true {
+        return;
+    }
+
+    if schema["x-kubernetes-int-or-string"] == true {
+        if !(value.is_i64() || value.is_u64() || value.is_string()) {
+            violation(format!("expected integer or string, found {}", type_name(value)));
+        }
+        return;
+    }
+
+    if let Some(expected) = schema["type"].as_str() {
+        let matches = match expected {
+            "object" => value.is_object(),
+            "array" => value.is_array(),
+            "string" => value.is_string(),
+            "integer" => value.is_i64() || value.is_u64(),
+            "number" => value.is_number(),
+            "boolean" => value.is_boolean(),
+            _ => true,
+        };
+        if !matches {
+            violation(format!("expected {expected}, found {}", type_name(value)));
+            return;
+        }
+    }
+
+    if let Some(allowed) = schema["enum"].as_array() {
+        if !allowed.contains(value) {
+            let allowed: Vec<String> = allowed.iter().map(Value::to_string).collect();
+            violation(format!("must be one of {}, found {value}", allowed.join(", ")));
+        }
+    }
+
+    match value {
+        Value::Object(map) => {
+            for required in schema["required"].as_array().into_iter().flatten().filter_map(Value::as_str) {
+                if !map.contains_key(required) {
+                    violation(format!("missing required field `{required}`"));
+                }
+            }
+
+            let properties = schema["properties"].as_object();
+            let additional = &schema["additionalProperties"];
+            let preserve_unknown = schema["x-kubernetes-preserve-unknown-fields"] == true;
+            for (key, child) in map {
+                let child_path = match path.is_empty() {
+                    true => key.clone(),
+                    false => format!("{path}.{key}"),
+                };
+                if let Some(child_schema) = properties.and_then(|properties| properties.get(key)) {
+                    validate_node(child, child_schema, &child_path, violations);
+                } else if additional.is_object() {
+                    validate_node(child, additional, &child_path, violations);
+                } else if properties.is_some() && !preserve_unknown && *additional != true {
+                    violations.push(Violation { path: child_path, message: "unknown field".to_string() });
+                }
+            }
+        }
+        Value::Array(items) => {
+            if let Some(min_items) = schema["minItems"].as_u64() {
+                if (items.len() as u64) < min_items {
+                    violation(format!("must contain at least {min_items} items"));
+                }
+            }
+            if let Some(max_items) = schema["maxItems"].as_u64() {
+                if items.len() as u64 > max_items {
+                    violation(format!("must contain at most {max_items} items"));
+                }
+            }
+            for (index, item) in items.iter().enumerate() {
+                validate_node(item, &schema["items"], &format!("{path}[{index}]"), violations);
+            }
+        }
+        Value::String(string) => {
+            let length = string.chars().count() as u64;
+            if let Some(min_length) = schema["minLength"].as_u64() {
+                if length < min_length {
+                    violation(format!("must be at least {min_length} characters long"));
+                }
+            }
+            if let Some(max_length) = schema["maxLength"].as_u64() {
+                if length > max_length {
+                    violation(format!("must be at most {max_length} characters long"));
+                }
+            }
+        }
+        Value::Number(number) => {
+            let number = number.as_f64().unwrap_or_default();
+            if let Some(minimum) = schema["minimum"].as_f64() {
+                if number < minimum {
+                    violation(format!("must be at least {minimum}"));
+                }
+            }
+            if let Some(maximum) = schema["maximum"].as_f64() {
+                if number > maximum {
+                    violation(format!("must be at most {maximum}"));
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    const CRD: &str = r#"
+apiVersion: apiextensions.k8s.io/v1
+kind: CustomResourceDefinition
+spec:
+  group: nais.io
+  names:
+    kind: Application
+  versions:
+    - name: v1alpha1
+      schema:
+        openAPIV3Schema:
+          type: object
+          required: [spec]
+          properties:
+            apiVersion:
+              type: string
+            kind:
+              type: string
+            metadata:
+              type: object
+            spec:
+              type: object
+              required: [image]
+              properties:
+                image:
+                  type: string
+                port:
+                  x-kubernetes-int-or-string: true
+                replicas:
+                  type: object
+                  properties:
+                    min:
+                      type: integer
+                      minimum: 0
+                ingresses:
+                  type: array
+                  items:
+                    type: string
+"#;
+
+    #[test]
+    pub fn validate_application() {
+        let crd = documents(CRD).unwrap().remove(0);
+        assert!(schema_for(&crd, "Topic", "kafka.nais.io/v1").is_none());
+        let schema = schema_for(&crd, "Application", "nais.io/v1alpha1").unwrap();
+
+        let application = documents(r#"
+apiVersion: nais.io/v1alpha1
+kind: Application
+metadata:
+  name: myapp
+spec:
+  image: foo
+  port: http
+  ingresses: [https://foo.example]
+"#).unwrap().remove(0);
+        assert_eq!(validate(&application, &schema), vec![]);
+
+        let application = documents(r#"
+apiVersion: nais.io/v1alpha1
+kind: Application
+spec:
+  replicas:
+    min: -1
+  ingresses: [1]
+  replica: 2
+"#).unwrap().remove(0);
+        let violations: Vec<String> = validate(&application, &schema).iter().map(Violation::to_string).collect();
+        assert_eq!(violations, vec![
+            "spec: missing required field `image`",
+            "spec.ingresses[0]: expected string, found number",
+            "spec.replica: unknown field",
+            "spec.replicas.min: must be at least 0",
+        ]);
+    }
+}
@@ -1,4 +1,7 @@
+use std::path::PathBuf;
+use std::sync::Arc;
 use thiserror::Error;
+use crate::vfs::Filesystem;
 
 #[derive(Error, Debug)]
 pub enum DetectBuildTargetError {
@@ -20,11 +23,89 @@ pub enum Error {
 
 /// SDK is anything that can produce artifacts
 pub trait SDK {
+    /// Short name of the SDK, e.g. `go`, as used in the `[sdk]` configuration section.
+    fn name(&self) -> &'static str;
     fn builder_docker_image(&self) -> String;
     fn runtime_docker_image(&self) -> String;
     fn detect_build_targets(&self) -> Result<Vec<String>, DetectBuildTargetError>;
     fn dockerfile(&self) -> Result<String, Error>;
     fn filesystem_path(&self) -> String;
+    /// Command that runs the project's tests from the source directory, natively or in the builder image.
+    fn test_command(&self) -> Vec<String>;
+    /// Command that builds a single target with the locally installed toolchain, for fast rebuilds.
+    fn native_build_command(&self, target: &str) -> Vec<String>;
+
+    /// Directories in the source directory that native builds write to, which are not inputs of the image build.
+    fn build_outputs(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    /// Return the build targets that must be rebuilt after the files in `changed`,
+    /// relative to the source directory, have changed. Defaults to all targets.
+    fn affected_build_targets(&self, changed: &[PathBuf]) -> Result<Vec<String>, DetectBuildTargetError> {
+        let _ = changed;
+        self.detect_build_targets()
+    }
+}
+
+/// Detect the SDK of the project in `filesystem_path`, with the builder and runtime images from
+/// the `[sdk]` settings. Returns `None` if no SDK supports the project.
+pub fn detect(filesystem_path: &str, settings: &crate::config::runtime::Sdk) -> Result<Option<Box<dyn SDK>>, Error> {
+    detect_in(Arc::new(crate::vfs::Os), filesystem_path, settings)
+}
+
+/// Like [detect], for a project in `filesystem`, which the SDK also reads its build targets from.
+pub fn detect_in(filesystem: Arc<dyn Filesystem>, filesystem_path: &str, settings: &crate::config::runtime::Sdk) -> Result<Option<Box<dyn SDK>>, Error> {
+    if let Some(sdk) = golang::new(golang::Config {
+        filesystem: filesystem.clone(),
+        filesystem_path: filesystem_path.to_string(),
+        docker_builder_image: settings.go.build_docker_image.clone(),
+        docker_runtime_image: settings.go.runtime_docker_image.clone(),
+        start_hook: None,
+        end_hook: None,
+    })? {
+        return Ok(Some(Box::new(sdk)));
+    }
+
+    if let Some(sdk) = gradle::new(gradle::Config {
+        filesystem: filesystem.clone(),
+        filesystem_path: filesystem_path.to_string(),
+        docker_builder_image: settings.gradle.build_docker_image.clone(),
+        docker_runtime_image: settings.gradle.runtime_docker_image.clone(),
+        settings_file: settings.gradle.settings_file.clone(),
+        start_hook: None,
+        end_hook: None,
+    })? {
+        return Ok(Some(Box::new(sdk)));
+    }
+
+    if let Some(sdk) = maven::new(maven::Config {
+        filesystem,
+        filesystem_path: filesystem_path.to_string(),
+        docker_builder_image: settings.maven.build_docker_image.clone(),
+        docker_runtime_image: settings.maven.runtime_docker_image.clone(),
+        start_hook: None,
+        end_hook: None,
+    })? {
+        return Ok(Some(Box::new(sdk)));
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+#[test]
+fn detect_in_memory() {
+    use crate::vfs::Memory;
+    let settings = crate::config::file::File::default().sdk.unwrap();
+    let detect = |filesystem: Memory| detect_in(Arc::new(filesystem), "/src", &settings).unwrap().map(|sdk| sdk.name());
+    assert_eq!(detect(Memory::new().with_file("/src/go.mod", "module example.com/app")), Some("go"));
+    assert_eq!(detect(Memory::new().with_file("/src/gradlew", "#!/bin/sh")), Some("gradle"));
+    assert_eq!(detect(Memory::new().with_file("/src/pom.xml", "<project/>")), Some("maven"));
+    assert_eq!(detect(Memory::new().with_file("/src/go.mod/README", "")), None, "go.mod is a directory");
+
+    let sdk = detect_in(Arc::new(Memory::new().with_file("/src/pom.xml", "<project/>")), "/src/./", &settings).unwrap().unwrap();
+    assert_eq!(sdk.detect_build_targets().unwrap(), ["src"]);
 }
 
 /// Build Go projects.
@@ -32,11 +113,15 @@ pub mod golang {
     use super::DetectBuildTargetError;
     use super::SDK;
     use super::Error;
+    use crate::vfs::Filesystem;
     use log::debug;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
 
     pub struct Golang(Config);
 
     pub struct Config {
+        pub filesystem: Arc<dyn Filesystem>,
         pub filesystem_path: String,
         pub docker_builder_image: String,
         pub docker_runtime_image: String,
@@ -48,18 +133,19 @@ pub mod golang {
     }
 
     pub fn new(cfg: Config) -> Result<Option<Golang>, Error> {
-        let Ok(file_stat) = std::fs::metadata(cfg.filesystem_path.to_owned() + "/go.mod") else {
-            return Ok(None);
-        };
-        debug!("Detected `go.mod` in project root");
-        if !file_stat.is_file() {
+        if !cfg.filesystem.is_file(&Path::new(&cfg.filesystem_path).join("go.mod")) {
             return Ok(None);
         }
+        debug!("Detected `go.mod` in project root");
 
         Ok(Some(Golang(cfg)))
     }
 
     impl SDK for Golang {
+        fn name(&self) -> &'static str {
+            "go"
+        }
+
         fn builder_docker_image(&self) -> String {
             self.0.docker_builder_image.clone()
         }
@@ -71,11 +157,13 @@ pub mod golang {
         /// Return a list of binaries that can be built.
         fn detect_build_targets(&self) -> Result<Vec<String>, DetectBuildTargetError> {
             let targets_path = format!("{}/cmd", self.0.filesystem_path.to_owned());
-            std::fs::read_dir(&targets_path)
+            self.0.filesystem.read_dir(Path::new(&targets_path))
                 .map_err(|e| DetectBuildTargetError::FileError(e, targets_path.clone()))?
-                .map(|dir_entry| {
-                    Ok(dir_entry?
+                .into_iter()
+                .map(|entry| {
+                    Ok(entry
                         .file_name()
+                        .ok_or(DetectBuildTargetError::EmptyFilename)?
                         .to_str()
                         .ok_or(DetectBuildTargetError::EmptyFilename)?
                         .to_string())
@@ -167,6 +255,65 @@ WORKDIR /app
         fn filesystem_path(&self) -> String {
             self.0.filesystem_path.clone()
         }
+
+        fn test_command(&self) -> Vec<String> {
+            vec!["go".to_string(), "test".to_string(), "./...".to_string()]
+        }
+
+        fn native_build_command(&self, target: &str) -> Vec<String> {
+            ["go", "build", "-o", &format!("build/{target}"), &format!("./cmd/{target}")]
+                .map(str::to_string)
+                .to_vec()
+        }
+
+        fn build_outputs(&self) -> &'static [&'static str] {
+            &["build"]
+        }
+
+        /// Only targets with changes below `cmd/<target>/` are rebuilt, unless other
+        /// packages have changed, which may be imported by any target.
+        fn affected_build_targets(&self, changed: &[PathBuf]) -> Result<Vec<String>, DetectBuildTargetError> {
+            let targets = self.detect_build_targets()?;
+            let mut affected = Vec::new();
+            for path in changed {
+                let mut components = path.components().map(|component| component.as_os_str().to_string_lossy());
+                match (components.next(), components.next(), components.next()) {
+                    (Some(cmd), Some(target), Some(_)) if cmd == "cmd" => {
+                        if targets.iter().any(|t| *t == target) && !affected.iter().any(|t| *t == target) {
+                            affected.push(target.to_string());
+                        }
+                    }
+                    _ => return Ok(targets),
+                }
+            }
+            Ok(affected)
+        }
+    }
+
+    #[cfg(test)]
+    #[test]
+    fn affected_go_targets() {
+        let filesystem = crate::vfs::Memory::new()
+            .with_file("/src/cmd/api/main.go", "package main")
+            .with_file("/src/cmd/worker/main.go", "package main");
+        let golang = Golang(Config {
+            filesystem: Arc::new(filesystem),
+            filesystem_path: "/src".to_string(),
+            docker_builder_image: String::new(),
+            docker_runtime_image: String::new(),
+            start_hook: None,
+            end_hook: None,
+        });
+        let affected = |paths: &[&str]| {
+            let mut targets = golang.affected_build_targets(&paths.iter().map(PathBuf::from).collect::<Vec<_>>()).unwrap();
+            targets.sort();
+            targets
+        };
+        assert_eq!(affected(&["cmd/api/main.go"]), vec!["api"]);
+        assert_eq!(affected(&["cmd/api/main.go", "cmd/api/routes.go"]), vec!["api"]);
+        assert_eq!(affected(&["cmd/api/main.go", "internal/db/db.go"]), vec!["api", "worker"]);
+        assert_eq!(affected(&["go.mod"]), vec!["api", "worker"]);
+        assert!(affected(&["cmd/removed/main.go"]).is_empty());
     }
 }
 
@@ -175,11 +322,15 @@ pub mod gradle {
     use super::DetectBuildTargetError;
     use super::SDK;
     use super::Error;
+    use crate::vfs::Filesystem;
     use log::debug;
+    use std::path::Path;
+    use std::sync::Arc;
 
     pub struct Gradle(Config);
 
     pub struct Config {
+        pub filesystem: Arc<dyn Filesystem>,
         pub filesystem_path: String,
         pub docker_builder_image: String,
         pub docker_runtime_image: String,
@@ -192,18 +343,19 @@ pub mod gradle {
     }
 
     pub fn new(cfg: Config) -> Result<Option<Gradle>, Error> {
-        let Ok(file_stat) = std::fs::metadata(cfg.filesystem_path.to_owned() + "/gradlew") else {
-            return Ok(None);
-        };
-        debug!("Detected `gradlew` in project root");
-        if !file_stat.is_file() {
+        if !cfg.filesystem.is_file(&Path::new(&cfg.filesystem_path).join("gradlew")) {
             return Ok(None);
         }
+        debug!("Detected `gradlew` in project root");
 
         Ok(Some(Gradle(cfg)))
     }
 
     impl SDK for Gradle {
+        fn name(&self) -> &'static str {
+            "gradle"
+        }
+
         fn builder_docker_image(&self) -> String {
             self.0.docker_builder_image.clone()
         }
@@ -283,6 +435,38 @@ CMD ["java", "-jar", "/app/app.jar"]
         fn filesystem_path(&self) -> String {
             self.0.filesystem_path.clone()
         }
+
+        fn test_command(&self) -> Vec<String> {
+            self.native_build_command("test")
+        }
+
+        fn native_build_command(&self, target: &str) -> Vec<String> {
+            let mut command = vec!["./gradlew".to_string()];
+            if let Some(settings_file) = &self.0.settings_file {
+                command.extend(["-settings-file".to_string(), settings_file.clone()]);
+            }
+            command.push(target.to_string());
+            command
+        }
+
+        fn build_outputs(&self) -> &'static [&'static str] {
+            &["build", ".gradle"]
+        }
+    }
+
+    #[cfg(test)]
+    #[test]
+    fn gradle_test_command() {
+        let gradle = Gradle(Config {
+            filesystem: Arc::new(crate::vfs::Os),
+            filesystem_path: ".".to_string(),
+            docker_builder_image: String::new(),
+            docker_runtime_image: String::new(),
+            settings_file: Some("settings-ci.gradle.kts".to_string()),
+            start_hook: None,
+            end_hook: None,
+        });
+        assert_eq!(gradle.test_command(), vec!["./gradlew", "-settings-file", "settings-ci.gradle.kts", "test"]);
     }
 }
 
@@ -291,12 +475,16 @@ pub mod maven {
     use super::DetectBuildTargetError;
     use super::SDK;
     use super::Error;
+    use crate::vfs::Filesystem;
     use log::debug;
+    use std::path::Path;
+    use std::sync::Arc;
     use sxd_xpath::Value;
 
     pub struct Maven(Config);
 
     pub struct Config {
+        pub filesystem: Arc<dyn Filesystem>,
         pub filesystem_path: String,
         pub docker_builder_image: String,
         pub docker_runtime_image: String,
@@ -308,15 +496,10 @@ pub mod maven {
     }
 
     pub fn new(cfg: Config) -> Result<Option<Maven>, Error> {
-        let Ok(file_stat) = std::fs::metadata(cfg.filesystem_path.to_owned() + "/pom.xml") else {
-            return Ok(None);
-        };
-
-        debug!("Detected `pom.xml` in project root");
-
-        if !file_stat.is_file() {
+        if !cfg.filesystem.is_file(&Path::new(&cfg.filesystem_path).join("pom.xml")) {
             return Ok(None);
         }
+        debug!("Detected `pom.xml` in project root");
 
         Ok(Some(Maven(cfg)))
     }
@@ -456,6 +639,10 @@ pub mod maven {
     }
 
     impl SDK for Maven {
+        fn name(&self) -> &'static str {
+            "maven"
+        }
+
         fn builder_docker_image(&self) -> String {
             self.0.docker_builder_image.clone()
         }
@@ -466,7 +653,7 @@ pub mod maven {
 
         /// Return a list of binaries that can be built.
         fn detect_build_targets(&self) -> Result<Vec<String>, DetectBuildTargetError> {
-            let canon = std::fs::canonicalize(self.0.filesystem_path.to_owned())?;
+            let canon = self.0.filesystem.canonicalize(Path::new(&self.0.filesystem_path))?;
 
             Ok(vec![canon
                 .file_name()
@@ -546,5 +733,19 @@ ENV JDK_JAVA_OPTS='-XX:MaxRAMPercentage={max_ram_percentage}'
         fn filesystem_path(&self) -> String {
             self.0.filesystem_path.clone()
         }
+
+        fn test_command(&self) -> Vec<String> {
+            vec!["mvn".to_string(), "test".to_string(), "--no-transfer-progress".to_string()]
+        }
+
+        fn native_build_command(&self, target: &str) -> Vec<String> {
+            ["mvn", "verify", "--no-transfer-progress", "--also-make-dependents", "--also-make", "--projects", &format!(":{target}")]
+                .map(str::to_string)
+                .to_vec()
+        }
+
+        fn build_outputs(&self) -> &'static [&'static str] {
+            &["target"]
+        }
     }
 }
@@ -0,0 +1,274 @@
+//! Graceful cancellation of a command that is interrupted with Ctrl-C, sent SIGTERM, or runs longer than `--timeout`.
+//!
+//! Cancellation is propagated with a [CancellationToken]: the command has a root token, and each subsystem
+//! that runs child processes or sends HTTP requests is handed a child of it, see [token]. Pipeline steps get their
+//! own child token, which is also cancelled when the step times out. A process waited for with a cancelled token
+//! is terminated, and killed if it has not exited shortly after; a request is dropped. Temporary files that would
+//! otherwise outlive the command are registered, and removed before nb exits with a distinct exit code.
+
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+use std::process::ExitStatus;
+use std::sync::{LazyLock, Mutex, OnceLock};
+use std::time::Duration;
+use log::{debug, warn};
+use tokio::process::{Child, Command};
+use tokio_util::sync::CancellationToken;
+
+/// How long a cancelled command has to stop, before nb exits regardless.
+pub const GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// How long a terminated child process has to exit, before it is killed.
+const TERMINATE_PERIOD: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reason {
+    Interrupted,
+    Terminated,
+    TimedOut(Duration),
+}
+
+impl Reason {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            // Like a shell reports a process killed by SIGINT or SIGTERM, and like timeout(1).
+            Reason::Interrupted => 130,
+            Reason::Terminated => 143,
+            Reason::TimedOut(_) => 124,
+        }
+    }
+}
+
+impl Display for Reason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Reason::Interrupted => write!(f, "interrupted"),
+            Reason::Terminated => write!(f, "terminated"),
+            Reason::TimedOut(timeout) => write!(f, "timed out after {}", format_duration(*timeout)),
+        }
+    }
+}
+
+static REASON: OnceLock<Reason> = OnceLock::new();
+static ROOT: LazyLock<CancellationToken> = LazyLock::new(CancellationToken::new);
+static TEMPORARY_FILES: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Cancel the command on SIGINT or SIGTERM, or once `timeout` has passed. Must be called from within the Tokio runtime.
+///
+/// If the command has not stopped by the end of the grace period, or another signal is received,
+/// nb exits without waiting for it.
+pub fn install(timeout: Option<Duration>) {
+    tokio::spawn(async move {
+        let reason = tokio::select! {
+            _ = interrupt() => Reason::Interrupted,
+            _ = terminate_signal() => Reason::Terminated,
+            _ = elapsed(timeout) => Reason::TimedOut(timeout.expect("only elapses with a timeout")),
+        };
+        cancel(reason);
+        tokio::select! {
+            _ = interrupt() => warn!("Interrupted again, exiting"),
+            _ = terminate_signal() => warn!("Terminated again, exiting"),
+            _ = tokio::time::sleep(GRACE_PERIOD) => warn!("Still running {}s after being cancelled, exiting", GRACE_PERIOD.as_secs()),
+        }
+        remove_temporary_files();
+        std::process::exit(reason.exit_code());
+    });
+}
+
+/// Wait for Ctrl-C. Once this is listening, SIGINT no longer kills nb.
+async fn interrupt() {
+    if let Err(err) = tokio::signal::ctrl_c().await {
+        warn!("Not handling Ctrl-C: {err}");
+        std::future::pending::<()>().await;
+    }
+}
+
+#[cfg(unix)]
+async fn terminate_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+    match signal(SignalKind::terminate()) {
+        Ok(mut terminate) => {
+            terminate.recv().await;
+        }
+        Err(err) => {
+            warn!("Not handling SIGTERM: {err}");
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn terminate_signal() {
+    std::future::pending::<()>().await;
+}
+
+async fn elapsed(timeout: Option<Duration>) {
+    match timeout {
+        Some(timeout) => tokio::time::sleep(timeout).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Cancel the root token, and with it every token handed out by [token]. Only the first reason is kept.
+fn cancel(reason: Reason) {
+    if REASON.set(reason).is_err() {
+        return;
+    }
+    warn!("Command {reason}, stopping");
+    ROOT.cancel();
+}
+
+/// A token that is cancelled when the command is. Cancelling it does not cancel the command.
+pub fn token() -> CancellationToken {
+    ROOT.child_token()
+}
+
+/// Why the command was cancelled, if it was.
+pub fn reason() -> Option<Reason> {
+    REASON.get().copied()
+}
+
+/// Wait until the command is cancelled.
+pub async fn cancelled() -> Reason {
+    ROOT.cancelled().await;
+    reason().expect("reason is set before cancelling")
+}
+
+/// Wait for `child` to exit. If `cancel` is cancelled first, the child is terminated,
+/// and killed if it has not exited [TERMINATE_PERIOD] later.
+pub async fn wait(child: &mut Child, cancel: &CancellationToken) -> std::io::Result<ExitStatus> {
+    tokio::select! {
+        status = child.wait() => return status,
+        _ = cancel.cancelled() => {}
+    }
+    // A child that has already been waited for has no ID, and nothing to terminate.
+    if let Some(pid) = child.id() {
+        terminate(pid);
+    }
+    match tokio::time::timeout(TERMINATE_PERIOD, child.wait()).await {
+        Ok(status) => status,
+        Err(_) => {
+            warn!("Process did not exit {}s after being terminated, killing it", TERMINATE_PERIOD.as_secs());
+            child.kill().await?;
+            child.wait().await
+        }
+    }
+}
+
+/// Like [Command::status], but the child is stopped as by [wait] if `cancel` is cancelled,
+/// and killed if the returned future is dropped before it exits.
+pub async fn status(command: &mut Command, cancel: &CancellationToken) -> std::io::Result<ExitStatus> {
+    let mut child = command.kill_on_drop(true).spawn()?;
+    wait(&mut child, cancel).await
+}
+
+/// Like [Command::output], but the child is killed if `cancel` is cancelled, or the returned future is dropped,
+/// before it exits. For short-lived commands, which are not worth terminating gracefully.
+pub async fn output(command: &mut Command, cancel: &CancellationToken) -> std::io::Result<std::process::Output> {
+    cancel.run_until_cancelled(command.kill_on_drop(true).output()).await
+        .unwrap_or_else(|| Err(std::io::Error::new(std::io::ErrorKind::Interrupted, "cancelled")))
+}
+
+#[cfg(unix)]
+fn terminate(pid: u32) {
+    debug!("Terminating process {pid}");
+    // SAFETY: kill(2) has no memory safety requirements, and the process is a child that has not been reaped.
+    unsafe {
+        libc::kill(pid as libc::pid_t, libc::SIGTERM);
+    }
+}
+
+#[cfg(not(unix))]
+fn terminate(pid: u32) {
+    debug!("Process {pid} is left to exit by itself");
+}
+
+/// Remove the file or directory at `path` if the command is cancelled. Needed even for files that are removed
+/// when dropped, such as a [tempfile::NamedTempFile], since destructors do not run when nb exits.
+pub fn remove_on_cancel(path: impl Into<PathBuf>) {
+    TEMPORARY_FILES.lock().expect("temporary files lock").push(path.into());
+}
+
+/// Remove the files registered with [remove_on_cancel] that still exist.
+pub fn remove_temporary_files() {
+    for path in std::mem::take(&mut *TEMPORARY_FILES.lock().expect("temporary files lock")) {
+        let removed = if path.is_dir() { std::fs::remove_dir_all(&path) } else { std::fs::remove_file(&path) };
+        match removed {
+            Ok(()) => debug!("Removed {}", path.display()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => warn!("{} not removed: {err}", path.display()),
+        }
+    }
+}
+
+/// Parse a duration like `90s`, `20m` or `1h`. A number without a unit is in seconds.
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let (number, seconds_per_unit) = match value.char_indices().last() {
+        Some((index, 's')) => (&value[..index], 1),
+        Some((index, 'm')) => (&value[..index], 60),
+        Some((index, 'h')) => (&value[..index], 60 * 60),
+        _ => (value, 1),
+    };
+    match number.parse::<u64>().ok().filter(|number| *number > 0).and_then(|number| number.checked_mul(seconds_per_unit)) {
+        Some(seconds) => Ok(Duration::from_secs(seconds)),
+        None => Err(format!("`{value}` is not a duration like `90s`, `20m` or `1h`")),
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    match duration.as_secs() {
+        seconds if seconds % 3600 == 0 => format!("{}h", seconds / 3600),
+        seconds if seconds % 60 == 0 => format!("{}m", seconds / 60),
+        seconds => format!("{seconds}s"),
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    pub fn durations() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("20m"), Ok(Duration::from_secs(20 * 60)));
+        assert_eq!(parse_duration("1h"), Ok(Duration::from_secs(3600)));
+        assert!(parse_duration("0").is_err());
+        assert!(parse_duration("1d").is_err());
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("99999999999999999999h").is_err());
+        assert!(parse_duration("9999999999999999999h").is_err());
+        assert_eq!(Reason::TimedOut(Duration::from_secs(90)).to_string(), "timed out after 90s");
+        assert_eq!(Reason::TimedOut(Duration::from_secs(1200)).to_string(), "timed out after 20m");
+    }
+
+    #[tokio::test]
+    pub async fn kill_dropped_children() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut command = Command::new("sh");
+        command.args(["-c", "sleep 0.5 && touch finished"]).current_dir(directory.path());
+        let status = tokio::time::timeout(Duration::from_millis(100), status(&mut command, &CancellationToken::new())).await;
+        assert!(status.is_err(), "the child is still running");
+
+        tokio::time::sleep(Duration::from_millis(800)).await;
+        assert!(!directory.path().join("finished").exists());
+    }
+
+    #[tokio::test]
+    pub async fn terminate_cancelled_children() {
+        let cancel = CancellationToken::new();
+        let started = std::time::Instant::now();
+        let mut command = Command::new("sh");
+        command.args(["-c", "exec sleep 5"]);
+        let (status, _) = tokio::join!(status(&mut command, &cancel), async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            cancel.cancel();
+        });
+        assert!(!status.unwrap().success());
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        let output = output(Command::new("sh").args(["-c", "sleep 5"]), &cancel).await;
+        assert_eq!(output.unwrap_err().kind(), std::io::ErrorKind::Interrupted);
+    }
+}
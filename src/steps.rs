@@ -0,0 +1,379 @@
+//! The steps of the pipeline of the `nb` command, for a detected application.
+//!
+//! [Steps] is the [pipeline::Executor] of the built-in steps and the shell steps of a user-defined pipeline.
+//! It does their work with a [Builder], [Releaser] and [Deployer], records their results in the [report::Report],
+//! keeps the checkpoint that a failed run is resumed from, and keeps artifacts in the artifact store, if any.
+
+use std::path::{Path, PathBuf};
+use log::{debug, info, warn};
+use tokio_util::sync::CancellationToken;
+use crate::config::runtime::Config;
+use crate::pipeline::{self, Builder, Deployer, Error, Node, Releaser};
+use crate::sdk::SDK;
+use crate::{artifacts, attest, checkpoint, config, events, git, limits, progress, report, sdk, shutdown, workspace};
+
+/// Detect the SDK of `filesystem_path`, with the SDK settings of `cfg`.
+pub fn init_sdk(filesystem_path: &str, cfg: &config::file::File) -> Result<Box<dyn SDK>, Error> {
+    let settings = cfg.sdk.as_ref().expect("SDK settings have built-in defaults");
+    sdk::detect(filesystem_path, settings)?.ok_or(Error::SdkNotDetected)
+}
+
+/// Write a copy of the generated Dockerfile to the path given with `--write-dockerfile`, if any.
+pub fn write_dockerfile_copy(path: Option<&str>, contents: impl AsRef<[u8]>) -> Result<(), Error> {
+    let Some(path) = path else {
+        return Ok(());
+    };
+    std::fs::write(path, contents).map_err(|err| Error::WriteDockerfile { path: path.to_string(), err })?;
+    info!("Dockerfile written to {path}");
+    Ok(())
+}
+
+/// Where and what to deploy, decided before building so that problems are found early.
+pub struct DeployTarget {
+    /// The image to deploy, which is the built image unless `spec.image` is pinned.
+    pub image: String,
+    pub bundle: String,
+    pub deployer: Deployer,
+}
+
+/// The steps of the pipeline for the detected application, and the results they share.
+pub struct Steps<'a> {
+    source_directory: &'a str,
+    cfg_file: &'a config::file::File,
+    cfg: &'a Config,
+    /// Name of the image to build and push.
+    image: &'a str,
+    pub report: &'a mut report::Report,
+    bus: &'a events::Bus,
+    /// Where to also write the generated Dockerfile, see `--write-dockerfile`.
+    pub write_dockerfile: Option<&'a str>,
+    pub artifact_store: Option<artifacts::Store>,
+    /// Where nb writes its reports, such as `artifacts.json`, relative to the source directory. Left out of the build.
+    pub output_directory: Option<PathBuf>,
+    /// Where to write the SBOM of the image, by default in the work directory of the run.
+    pub sbom_file: Option<PathBuf>,
+    pub target: Option<DeployTarget>,
+    /// Steps that finished, also those of the run that is resumed, and the image they produced,
+    /// with the path the checkpoint is written to.
+    checkpoint: Option<(checkpoint::Checkpoint, PathBuf)>,
+    /// Steps finished by the run that is resumed, which only the first pipeline skips.
+    resumed: Vec<Node>,
+    sdk: Option<Box<dyn SDK>>,
+    releaser: Releaser,
+    dockerfile: Option<PathBuf>,
+    /// The built image tagged with the hash of its inputs, pushed along with it.
+    inputs_image: Option<String>,
+}
+
+impl<'a> Steps<'a> {
+    pub fn new(source_directory: &'a str, cfg_file: &'a config::file::File, cfg: &'a Config, image: &'a str, report: &'a mut report::Report, bus: &'a events::Bus) -> Self {
+        Steps {
+            source_directory,
+            cfg_file,
+            cfg,
+            image,
+            report,
+            bus,
+            write_dockerfile: None,
+            artifact_store: None,
+            output_directory: None,
+            sbom_file: None,
+            target: None,
+            checkpoint: None,
+            resumed: Vec::new(),
+            sdk: None,
+            releaser: Releaser::new(cfg),
+            dockerfile: None,
+            inputs_image: None,
+        }
+    }
+
+    /// Keep the state of the pipeline in `path` while it runs, starting from `checkpoint`.
+    pub fn checkpointed(mut self, checkpoint: checkpoint::Checkpoint, path: PathBuf) -> Self {
+        self.resumed = checkpoint.completed.clone();
+        self.checkpoint = Some((checkpoint, path));
+        self
+    }
+
+    /// Run the steps needed to reach `targets`, see [pipeline::plan], in the order of the user-defined pipeline.
+    /// Steps that are not enabled in the configuration, such as signing, are left out.
+    /// The first pipeline also skips the steps finished by the run that is resumed; later ones, such as
+    /// the rebuilds of `nb watch`, run every step again.
+    pub async fn execute(&mut self, targets: &[Node], provided: &[Node]) -> Result<(), Error> {
+        let disabled = [(Node::Sbom, self.cfg.release.sbom), (Node::Sign, self.cfg.release.sign)]
+            .into_iter()
+            .filter_map(|(node, enabled)| (!enabled).then_some(node));
+        let provided: Vec<Node> = provided.iter().copied().chain(disabled).chain(std::mem::take(&mut self.resumed)).collect();
+        let steps = pipeline::arrange(&pipeline::plan(targets, &provided), &self.cfg.pipeline);
+        self.bus.publish(events::Event::Pipeline {
+            team: self.cfg.team.clone(),
+            app: self.cfg.app.clone(),
+            image: self.image.to_string(),
+            steps: steps.iter().map(|step| step.name().to_string()).collect(),
+        });
+        let result = pipeline::execute(&steps, self, &shutdown::token()).await;
+        self.report.record_events();
+        result?;
+        if let Some((checkpoint, path)) = &mut self.checkpoint {
+            checkpoint.completed.clear();
+            if let Err(err) = checkpoint::remove(path) {
+                warn!("{} not removed: {err}", path.display());
+            }
+        }
+        let summary = serde_json::to_vec_pretty(&self.report.manifest(git::sha(self.source_directory).ok()))?;
+        self.store(artifacts::Artifact::Summary, &summary).await;
+        Ok(())
+    }
+
+    /// Keep an artifact in the artifact store, if any. The run does not fail if it cannot be stored.
+    async fn store(&self, artifact: artifacts::Artifact, contents: &[u8]) {
+        use artifacts::ArtifactStore;
+        let Some(store) = &self.artifact_store else {
+            return;
+        };
+        match store.put(artifact, contents).await {
+            Ok(()) => debug!("Stored {} in {store}", artifact.file_name()),
+            Err(err) => warn!("{} not stored in {store}: {err}", artifact.file_name()),
+        }
+    }
+
+    /// Environment of shell steps: the application, and the image as far as it has come.
+    /// The names have the [config::env::STEP_PREFIX], so that an `nb` run by a shell step does not read them as overrides.
+    fn shell_env(&self) -> Vec<(&'static str, String)> {
+        let mut env = vec![
+            ("NB_STEP_APP", self.cfg.app.clone()),
+            ("NB_STEP_TEAM", self.cfg.team.clone()),
+            ("NB_STEP_IMAGE", self.image.to_string()),
+        ];
+        if let Some(digest) = &self.report.digest {
+            env.push(("NB_STEP_DIGEST", digest.clone()));
+        }
+        if let Some(target) = &self.target {
+            env.push(("NB_STEP_CLUSTER", target.deployer.cluster().to_string()));
+        }
+        env
+    }
+
+    fn builder(&mut self) -> Result<Builder<'_>, Error> {
+        if self.sdk.is_none() {
+            self.sdk = Some(init_sdk(self.source_directory, self.cfg_file)?);
+        }
+        let builder = Builder::new(self.sdk.as_deref().expect("SDK is initialized"), self.cfg);
+        Ok(match &self.output_directory {
+            Some(directory) => builder.excluding(directory),
+            None => builder,
+        })
+    }
+}
+
+impl pipeline::Executor for Steps<'_> {
+    type Error = Error;
+
+    async fn run(&mut self, step: &pipeline::Step, cancel: &CancellationToken) -> Result<(), Error> {
+        let cfg = self.cfg;
+        let node = match step {
+            pipeline::Step::Builtin(node) => *node,
+            pipeline::Step::Shell(shell) => {
+                shell.run(self.source_directory, &cfg.sandbox, &self.shell_env(), cancel).await?;
+                return Ok(());
+            }
+        };
+        match node {
+            Node::Login => {
+                self.releaser.login(cancel).await?;
+            }
+            Node::Dockerfile => {
+                let step = progress::step("dockerfile");
+                let dockerfile = self.builder()?.write_dockerfile()?;
+                step.finish();
+                let contents = std::fs::read(&dockerfile)?;
+                write_dockerfile_copy(self.write_dockerfile, &contents)?;
+                self.store(artifacts::Artifact::Dockerfile, &contents).await;
+                self.report.dockerfile = Some(dockerfile.to_string_lossy().to_string());
+                self.dockerfile = Some(dockerfile);
+            }
+            Node::Build => {
+                let dockerfile = self.dockerfile.clone().expect("Dockerfile is written before building");
+                let image = self.image;
+                let built = self.builder()?.build(&dockerfile, image, cancel).await?;
+                self.report.build_stages = built.stages;
+                self.inputs_image = built.inputs_image;
+            }
+            Node::Sbom => {
+                let path = match &self.sbom_file {
+                    Some(path) => path.clone(),
+                    None => workspace::Workspace::new(self.source_directory).workdir()?.join(attest::SBOM_FILE_NAME),
+                };
+                attest::sbom(self.image, &path, cancel).await?;
+                self.store(artifacts::Artifact::Sbom, &std::fs::read(&path)?).await;
+                self.report.sbom = Some(path.to_string_lossy().to_string());
+            }
+            Node::Release => {
+                let additional_images: Vec<String> = self.inputs_image.iter().cloned().collect();
+                self.report.digest = self.releaser.push(self.image, &additional_images, cancel).await?;
+            }
+            Node::Sign => {
+                let sbom = self.report.sbom.clone();
+                let digest = self.report.digest.clone();
+                self.report.attestations = self.releaser.sign(self.image, digest.as_deref(), sbom.as_deref().map(Path::new), cancel).await?;
+            }
+            Node::Deploy => {
+                let target = self.target.as_ref().expect("deploy target is set before deploying");
+                self.report.image = Some(target.image.clone());
+                self.report.digest = target.deployer.deploy(&target.image, &target.bundle, cancel).await?;
+                self.report.deployment = Some(report::Deployment { cluster: target.deployer.cluster().to_string(), status: report::DeploymentStatus::Deployed });
+                self.store(artifacts::Artifact::Manifests, target.bundle.as_bytes()).await;
+            }
+        }
+        Ok(())
+    }
+
+    fn policy(&self, step: &pipeline::Step) -> pipeline::Policy {
+        self.cfg.step_policies.iter().find(|(name, _)| name == step.name()).map(|(_, policy)| policy.clone()).unwrap_or_default()
+    }
+
+    /// Failures of the registry, the cluster, the network or shell steps may pass. Invalid configuration fails
+    /// the same way again.
+    fn retryable(&self, err: &Error) -> bool {
+        matches!(err, Error::Docker(_) | Error::Auth(_) | Error::Gar(_) | Error::Deploy(_) | Error::TimedOut(_) | Error::ShellFailed { .. } | Error::Attest(attest::Error::Sign(_)))
+    }
+
+    fn limits(&self, step: &pipeline::Step) -> Vec<limits::Limit> {
+        self.cfg.limits.of(step)
+    }
+
+    fn events(&self) -> Option<&events::Bus> {
+        Some(self.bus)
+    }
+
+    fn finished(&mut self, result: &pipeline::StepResult) {
+        let Some((checkpoint, path)) = &mut self.checkpoint else {
+            return;
+        };
+        if let Some(node @ (Node::Build | Node::Release | Node::Sign)) = result.step.node() {
+            checkpoint.complete(node);
+            checkpoint.digest = self.report.digest.clone();
+            if let Err(err) = checkpoint::write(path, checkpoint) {
+                warn!("{} not written, so this run cannot be resumed: {err}", path.display());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use crate::git::tests::git;
+    use crate::pipeline::tests::config;
+    use crate::process::Mock;
+
+    const IMAGE: &str = "europe-north1-docker.pkg.dev/nais-management-233d/myteam/myapp:1";
+
+    /// Number of times the build step was started.
+    fn builds(report: &report::Report) -> usize {
+        report.events.iter().filter(|event| matches!(event, events::Event::StepStarted { step, .. } if step == "build")).count()
+    }
+
+    #[tokio::test]
+    pub async fn build_every_time() {
+        let directory = tempfile::tempdir().unwrap();
+        let source_directory = directory.path().to_str().unwrap();
+        std::fs::write(directory.path().join("pom.xml"), "<project/>").unwrap();
+        git(directory.path(), &["init", "--quiet"]);
+        // Images are found in the registry, so that the build is skipped instead of run by Docker.
+        let mock = Arc::new(Mock::default().respond("docker buildx imagetools inspect", 0, "sha256:abc\n"));
+        let cfg_file = config::file::File::default();
+        let mut cfg = config();
+        cfg.skip_unchanged = true;
+        let checkpoint_path = directory.path().join("build/checkpoint.json");
+        let bus = events::Bus::new();
+
+        let mut report = report::Report::default();
+        report.subscribe(&bus);
+        let mut steps = Steps::new(source_directory, &cfg_file, &cfg, IMAGE, &mut report, &bus)
+            .checkpointed(checkpoint::Checkpoint::new("myapp", None, "1", IMAGE), checkpoint_path.clone());
+        crate::process::scope(mock.clone(), async {
+            steps.execute(&[Node::Build], &[]).await.unwrap();
+            steps.execute(&[Node::Build], &[]).await.unwrap();
+        }).await;
+        assert_eq!(builds(&report), 2);
+        assert!(!checkpoint_path.exists());
+
+        let mut report = report::Report::default();
+        report.subscribe(&bus);
+        let mut resumed = checkpoint::Checkpoint::new("myapp", None, "1", IMAGE);
+        resumed.complete(Node::Build);
+        let mut steps = Steps::new(source_directory, &cfg_file, &cfg, IMAGE, &mut report, &bus).checkpointed(resumed, checkpoint_path);
+        crate::process::scope(mock.clone(), async {
+            steps.execute(&[Node::Build], &[]).await.unwrap();
+            steps.execute(&[Node::Build], &[]).await.unwrap();
+        }).await;
+        assert_eq!(builds(&report), 1, "only the first pipeline skips the resumed build");
+    }
+
+    #[tokio::test]
+    pub async fn sbom_and_sign() {
+        let directory = tempfile::tempdir().unwrap();
+        let source_directory = directory.path().to_str().unwrap();
+        let sbom_file = directory.path().join("build").join(attest::SBOM_FILE_NAME);
+        // syft is not run, so the SBOM it would write is there already.
+        std::fs::create_dir_all(sbom_file.parent().unwrap()).unwrap();
+        std::fs::write(&sbom_file, "{}").unwrap();
+        let mock = Arc::new(Mock::default().respond("docker buildx imagetools inspect", 0, "sha256:abc\n"));
+        let cfg_file = config::file::File::default();
+        let mut cfg = config();
+        cfg.release.sbom = true;
+        cfg.release.sign = true;
+        let bus = events::Bus::new();
+        let mut report = report::Report::default();
+        let mut steps = Steps::new(source_directory, &cfg_file, &cfg, IMAGE, &mut report, &bus);
+        steps.sbom_file = Some(sbom_file.clone());
+        steps.artifact_store = Some(artifacts::Store::open(directory.path().join("store").to_str().unwrap(), "myapp", cfg.release.http.clone()).unwrap());
+        crate::process::scope(mock.clone(), async {
+            steps.execute(&[Node::Sbom, Node::Sign], &[Node::Build, Node::Release]).await.unwrap();
+        }).await;
+
+        let commands: Vec<String> = mock.invocations().iter().map(|invocation| invocation.command_line()).collect();
+        let sbom = sbom_file.to_string_lossy();
+        let reference = "europe-north1-docker.pkg.dev/nais-management-233d/myteam/myapp@sha256:abc";
+        assert!(commands.contains(&format!("syft scan docker:{IMAGE} --output spdx-json={sbom}")), "{commands:?}");
+        assert!(commands.contains(&format!("cosign sign --yes {reference}")), "{commands:?}");
+        assert!(commands.contains(&format!("cosign attest --type spdxjson --predicate {sbom} --yes {reference}")), "{commands:?}");
+        assert!(directory.path().join("store/myapp").join(artifacts::Artifact::Sbom.file_name()).exists());
+        let manifest = report.manifest(None);
+        assert_eq!(manifest.sbom.as_deref(), Some(sbom.as_ref()));
+        assert_eq!(manifest.attestations, ["europe-north1-docker.pkg.dev/nais-management-233d/myteam/myapp:sha256-abc.att"]);
+    }
+
+    #[tokio::test]
+    pub async fn sbom_and_sign_are_disabled_by_default() {
+        let mock = Arc::new(Mock::default());
+        let cfg_file = config::file::File::default();
+        let cfg = config();
+        let bus = events::Bus::new();
+        let mut report = report::Report::default();
+        let mut steps = Steps::new(".", &cfg_file, &cfg, IMAGE, &mut report, &bus);
+        crate::process::scope(mock.clone(), async {
+            steps.execute(&[Node::Sbom, Node::Sign], &[Node::Build, Node::Release]).await.unwrap();
+        }).await;
+        assert!(!mock.invocations().iter().any(|invocation| invocation.program == "syft" || invocation.program == "cosign"));
+        assert_eq!(report.sbom, None);
+        assert!(report.attestations.is_empty());
+    }
+
+    #[test]
+    pub fn shell_env_is_not_configuration() {
+        let cfg_file = config::file::File::default();
+        let cfg = config();
+        let bus = events::Bus::new();
+        let mut report = report::Report::default();
+        report.digest = Some("sha256:abc".into());
+        let steps = Steps::new(".", &cfg_file, &cfg, IMAGE, &mut report, &bus);
+        let env = steps.shell_env();
+        assert!(env.iter().any(|(name, value)| *name == "NB_STEP_TEAM" && value == "myteam"));
+        let vars = env.into_iter().map(|(name, value)| (name.to_string(), value));
+        assert!(config::env::overrides(vars.clone()).is_empty());
+        assert_eq!(cfg_file.with_env_overrides(vars).unwrap().team, None);
+    }
+}
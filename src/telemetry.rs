@@ -0,0 +1,124 @@
+//! Opt-in OpenTelemetry traces of pipeline steps, exported with OTLP over HTTP.
+//!
+//! When a collector is configured with `[telemetry] endpoint` or `OTEL_EXPORTER_OTLP_ENDPOINT`,
+//! each command is exported as a trace, with a span for each pipeline step shown by [crate::progress].
+//! Otherwise, spans are not recorded at all.
+
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::{Protocol, WithExportConfig};
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use thiserror::Error;
+use tracing::field::Empty;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("OTLP exporter: {0}")]
+    Exporter(#[from] opentelemetry_otlp::ExporterBuildError),
+
+    #[error("install tracing subscriber: {0}")]
+    Subscriber(#[from] tracing_subscriber::util::TryInitError),
+}
+
+/// Environment variables that configure the collector, as specified by OpenTelemetry.
+const ENDPOINT_VARIABLES: &[&str] = &["OTEL_EXPORTER_OTLP_TRACES_ENDPOINT", "OTEL_EXPORTER_OTLP_ENDPOINT"];
+
+/// Traces of one command. Spans are only exported if a collector is configured.
+pub struct Telemetry {
+    provider: Option<SdkTracerProvider>,
+    root: tracing::Span,
+}
+
+/// Start exporting traces to the collector at `endpoint`, the base URL of an OTLP/HTTP receiver,
+/// or to the collector configured in the environment. Nothing is exported if neither is set.
+pub fn init(endpoint: Option<&str>, command: &str) -> Result<Telemetry, Error> {
+    let endpoint = endpoint.filter(|endpoint| !endpoint.is_empty());
+    let configured_in_environment = ENDPOINT_VARIABLES.iter().any(|name| std::env::var(name).is_ok_and(|value| !value.is_empty()));
+    if endpoint.is_none() && !configured_in_environment {
+        return Ok(Telemetry::disabled());
+    }
+
+    let mut exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_protocol(Protocol::HttpJson);
+    if let Some(endpoint) = endpoint {
+        exporter = exporter.with_endpoint(format!("{}/v1/traces", endpoint.trim_end_matches('/')));
+    }
+    let resource = Resource::builder()
+        .with_service_name("nb")
+        .with_attribute(KeyValue::new("service.version", crate::version::CLIENT_VERSION))
+        .build();
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter.build()?)
+        .with_resource(resource)
+        .build();
+    tracing_subscriber::registry()
+        .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("nb")))
+        .try_init()?;
+    log::debug!("Exporting traces to {}", endpoint.unwrap_or("the collector in OTEL_EXPORTER_OTLP_ENDPOINT"));
+
+    let root = tracing::info_span!(
+        "nb",
+        otel.name = format!("nb {command}"),
+        otel.status_code = Empty,
+        otel.status_description = Empty,
+        nb.command = command,
+        nb.team = Empty,
+        nb.app = Empty,
+    );
+    Ok(Telemetry { provider: Some(provider), root })
+}
+
+impl Telemetry {
+    pub fn disabled() -> Self {
+        Telemetry { provider: None, root: tracing::Span::none() }
+    }
+
+    /// Whether spans are exported.
+    pub fn enabled(&self) -> bool {
+        self.provider.is_some()
+    }
+
+    /// Span of the whole command, to instrument it with. Pipeline steps become its children.
+    pub fn span(&self) -> tracing::Span {
+        self.root.clone()
+    }
+
+    /// Record the result of the command, and wait for its spans to be exported.
+    pub fn finish<E: std::fmt::Display>(self, result: &Result<(), E>) {
+        match result {
+            Ok(()) => self.root.record("otel.status_code", "OK"),
+            Err(err) => self.root.record("otel.status_code", "ERROR").record("otel.status_description", err.to_string()),
+        };
+        drop(self.root);
+        if let Some(provider) = self.provider {
+            if let Err(err) = provider.shutdown() {
+                log::warn!("Traces not exported: {err}");
+            }
+        }
+    }
+}
+
+/// Record the events of the pipeline on `span`, until the bus is closed.
+pub async fn record_events(span: tracing::Span, mut receiver: crate::events::Receiver) {
+    use crate::events::Event;
+    while let Some(event) = receiver.recv().await {
+        match event {
+            Event::Pipeline { steps, .. } => {
+                let steps = steps.join(",");
+                tracing::info!(parent: &span, nb.steps = steps, "pipeline");
+            }
+            Event::StepStarted { step, attempt } => tracing::info!(parent: &span, nb.step = step, nb.attempt = attempt, "step started"),
+            Event::StepFinished { step, elapsed } => tracing::info!(parent: &span, nb.step = step, nb.elapsed = elapsed.as_secs_f64(), "step finished"),
+            Event::StepFailed { step, error, retrying } => tracing::warn!(parent: &span, nb.step = step, nb.retrying = retrying, error, "step failed"),
+        }
+    }
+}
+
+/// Add the team and application to the span of the command.
+pub fn record_workload(team: &str, app: &str) {
+    tracing::Span::current().record("nb.team", team).record("nb.app", app);
+}
@@ -0,0 +1,129 @@
+//! Render Kubernetes resource templates, such as nais.yaml.
+//!
+//! Templates use the same Handlebars syntax as nais deploy, e.g. `{{ image }}`
+//! or `{{#each ingresses}}`, so that a manifest renders identically with both tools.
+//! Variables missing from the context render as empty strings.
+
+use log::debug;
+use serde_json::{Map, Value};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("render template: {0}")]
+    Render(#[from] handlebars::RenderError),
+
+    #[error("read {path}: {err}")]
+    ReadVars {
+        err: std::io::Error,
+        path: String,
+    },
+
+    #[error("parse {path}: {err}")]
+    ParseVars {
+        err: serde_yaml::Error,
+        path: String,
+    },
+
+    #[error("{path} must contain a map of variables")]
+    VarsNotMap {
+        path: String,
+    },
+
+    #[error("variable '{0}' must be on the form KEY=VALUE")]
+    InvalidVar(String),
+
+    #[error("convert variables: {0}")]
+    Convert(#[from] serde_json::Error),
+}
+
+/// Template variables.
+///
+/// Variables are added in increasing order of precedence;
+/// a variable that is set again overwrites the previous value.
+#[derive(Debug, Default, Clone)]
+pub struct Vars(Map<String, Value>);
+
+impl Vars {
+    /// Add variables from the configuration file.
+    pub fn extend_from_toml(&mut self, table: &toml::Table) -> Result<(), Error> {
+        if let Value::Object(map) = serde_json::to_value(table)? {
+            self.0.extend(map);
+        }
+        Ok(())
+    }
+
+    /// Add variables from a YAML or JSON file, as accepted by `deploy --vars`.
+    pub fn extend_from_file(&mut self, path: &str) -> Result<(), Error> {
+        let contents = std::fs::read_to_string(path).map_err(|err| Error::ReadVars {
+            err,
+            path: path.to_string(),
+        })?;
+        let value: Value = serde_yaml::from_str(&contents).map_err(|err| Error::ParseVars {
+            err,
+            path: path.to_string(),
+        })?;
+        match value {
+            Value::Object(map) => self.0.extend(map),
+            Value::Null => {}
+            _ => return Err(Error::VarsNotMap { path: path.to_string() }),
+        }
+        Ok(())
+    }
+
+    /// Add a variable on the form `KEY=VALUE`, as accepted by `deploy --var`.
+    pub fn set_from_str(&mut self, var: &str) -> Result<(), Error> {
+        let (key, value) = var
+            .split_once('=')
+            .filter(|(key, _)| !key.is_empty())
+            .ok_or(Error::InvalidVar(var.to_string()))?;
+        self.set(key, value);
+        Ok(())
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) {
+        self.0.insert(key.to_string(), Value::String(value.to_string()));
+    }
+}
+
+/// Render a template using the provided variables.
+pub fn render(template: &str, vars: &Vars) -> Result<String, Error> {
+    let handlebars = handlebars::Handlebars::new();
+    debug!("Rendering template with {} variables", vars.0.len());
+    Ok(handlebars.render_template(template, &vars.0)?)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    pub fn render_nais_yaml() {
+        let template = r#"spec:
+  image: {{ image }}
+  ingresses:
+  {{#each ingresses}}
+    - {{ this }}
+  {{/each}}
+  replicas: {{ replicas.min }}
+  missing: "{{ missing }}"
+"#;
+        let mut config = toml::Table::new();
+        config.insert("ingresses".into(), toml::Value::Array(vec!["https://foo.example".into()]));
+        config.insert("image".into(), "overridden".into());
+        config.insert("replicas".into(), toml::Value::Table(toml::toml! { min = 2 }));
+
+        let mut vars = Vars::default();
+        vars.extend_from_toml(&config).unwrap();
+        vars.set_from_str("image=ghcr.io/navikt/app:1").unwrap();
+        assert!(vars.set_from_str("=foo").is_err());
+
+        assert_eq!(render(template, &vars).unwrap(), r#"spec:
+  image: ghcr.io/navikt/app:1
+  ingresses:
+    - https://foo.example
+  replicas: 2
+  missing: ""
+"#);
+    }
+}
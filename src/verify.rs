@@ -0,0 +1,251 @@
+//! Verification of the signatures and attestations of an image in a registry, run by `nb verify`.
+//!
+//! Each check is done with `cosign`, against the policy in the `[verify]` section of nb.toml:
+//! a signature, a SLSA provenance attestation and an SBOM attestation. Checks that the policy
+//! does not require are still done, so that the report shows what the image has.
+
+use std::fmt::{Display, Formatter};
+use std::process::Stdio;
+use serde::Serialize;
+use thiserror::Error;
+use tokio::process::Command;
+use tokio_util::sync::CancellationToken;
+use crate::config::runtime::Verify;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("run cosign: {0}")]
+    Cosign(#[from] std::io::Error),
+
+    #[error("resolve digest: {0}")]
+    Docker(#[from] crate::docker::Error),
+
+    #[error("`[verify] key` or `certificate_identity_regexp` and `certificate_oidc_issuer` must be set")]
+    NoIdentity,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Check {
+    Signature,
+    Provenance,
+    Sbom,
+}
+
+impl Check {
+    /// Predicate types of the attestations that pass the check, as named by `cosign verify-attestation --type`.
+    /// None for the signature itself.
+    fn attestation_types(&self) -> Option<&'static [&'static str]> {
+        match self {
+            Check::Signature => None,
+            Check::Provenance => Some(&["slsaprovenance1", "slsaprovenance"]),
+            Check::Sbom => Some(&["spdxjson", "cyclonedx"]),
+        }
+    }
+}
+
+impl Display for Check {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Check::Signature => write!(f, "signature"),
+            Check::Provenance => write!(f, "SLSA provenance"),
+            Check::Sbom => write!(f, "SBOM"),
+        }
+    }
+}
+
+/// Outcome of one check.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Finding {
+    pub check: Check,
+    /// Whether the policy requires the check to pass.
+    pub required: bool,
+    pub passed: bool,
+    /// The attestation type that was verified, or why the check failed, as reported by cosign.
+    pub detail: String,
+}
+
+impl Display for Finding {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let outcome = match (self.passed, self.required) {
+            (true, _) => console::style("passed").green().bold(),
+            (false, true) => console::style("failed").red().bold(),
+            (false, false) => console::style("missing").yellow().bold(),
+        };
+        write!(f, "{outcome}: {}", self.check)?;
+        match self.detail.is_empty() {
+            true => Ok(()),
+            false => write!(f, " ({})", self.detail),
+        }
+    }
+}
+
+/// The verification report of an image.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Verification {
+    /// The image as given.
+    pub image: String,
+    /// Digest of the manifest that was verified, if it could be resolved.
+    pub digest: Option<String>,
+    pub findings: Vec<Finding>,
+}
+
+impl Verification {
+    /// Number of checks that the policy requires, but that did not pass.
+    pub fn failures(&self) -> usize {
+        self.findings.iter().filter(|finding| finding.required && !finding.passed).count()
+    }
+}
+
+impl Display for Verification {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.digest {
+            Some(digest) => writeln!(f, "{} ({digest})", self.image)?,
+            None => writeln!(f, "{}", self.image)?,
+        }
+        for finding in &self.findings {
+            writeln!(f, "  {finding}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Verify `image` against `policy`. The image is resolved to a digest first, if it is not given by one,
+/// so that every check is of the same manifest even if the tag is moved meanwhile.
+pub async fn verify(image: &str, policy: &Verify, cancel: &CancellationToken) -> Result<Verification, Error> {
+    let identity = identity_args(policy)?;
+    let digest = crate::docker::manifest_digest(image, cancel).await?;
+    let reference = match &digest {
+        Some(digest) if !image.contains('@') => format!("{}@{digest}", image_name(image)),
+        _ => image.to_string(),
+    };
+
+    let mut findings = Vec::new();
+    for (check, required) in [(Check::Signature, policy.signature), (Check::Provenance, policy.provenance), (Check::Sbom, policy.sbom)] {
+        let (passed, detail) = match check.attestation_types() {
+            None => cosign(&["verify"], &identity, &reference, cancel).await?,
+            Some(types) => {
+                let mut result = (false, String::new());
+                for typ in types {
+                    result = match cosign(&["verify-attestation", "--type", typ], &identity, &reference, cancel).await? {
+                        (true, _) => (true, typ.to_string()),
+                        failed => failed,
+                    };
+                    if result.0 {
+                        break;
+                    }
+                }
+                result
+            }
+        };
+        findings.push(Finding { check, required, passed, detail });
+    }
+    Ok(Verification { image: image.to_string(), digest, findings })
+}
+
+/// How cosign is to verify signatures: with a key, or keyless with the identity of the signing certificate.
+fn identity_args(policy: &Verify) -> Result<Vec<String>, Error> {
+    if !policy.key.is_empty() {
+        return Ok(vec!["--key".to_string(), policy.key.clone()]);
+    }
+    if policy.certificate_identity_regexp.is_empty() || policy.certificate_oidc_issuer.is_empty() {
+        return Err(Error::NoIdentity);
+    }
+    Ok(vec![
+        "--certificate-identity-regexp".to_string(),
+        policy.certificate_identity_regexp.clone(),
+        "--certificate-oidc-issuer".to_string(),
+        policy.certificate_oidc_issuer.clone(),
+    ])
+}
+
+/// The image without its tag or digest, e.g. `ghcr.io/navikt/app` for `ghcr.io/navikt/app:1.0.0`.
+pub fn image_name(image: &str) -> &str {
+    let image = image.split_once('@').map_or(image, |(name, _)| name);
+    match image.rsplit_once(':') {
+        // A colon before the last slash separates a registry port, not a tag.
+        Some((name, tag)) if !tag.contains('/') => name,
+        _ => image,
+    }
+}
+
+/// Run a cosign verification, returning whether it passed, and the error it reported if not.
+async fn cosign(subcommand: &[&str], identity: &[String], reference: &str, cancel: &CancellationToken) -> Result<(bool, String), Error> {
+    let output = crate::process::output(Command::new("cosign")
+        .args(subcommand)
+        .args(identity)
+        .arg(reference)
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped()), cancel).await?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let detail = match output.status.success() {
+        true => String::new(),
+        false => stderr.lines()
+            .find_map(|line| line.strip_prefix("Error: "))
+            .or_else(|| stderr.lines().rfind(|line| !line.trim().is_empty()))
+            .map(|line| line.trim().to_string())
+            .unwrap_or_else(|| format!("cosign exited with {}", output.status)),
+    };
+    Ok((output.status.success(), detail))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use crate::process::Mock;
+
+    const IMAGE: &str = "europe-north1-docker.pkg.dev/nais-management-233d/myteam/myapp:1.0.0";
+    const DIGEST: &str = "sha256:0123456789abcdef";
+
+    #[test]
+    pub fn image_names() {
+        assert_eq!(image_name("ghcr.io/navikt/app:1.0.0"), "ghcr.io/navikt/app");
+        assert_eq!(image_name("localhost:5000/app"), "localhost:5000/app");
+        assert_eq!(image_name("localhost:5000/app:1.0.0@sha256:abc"), "localhost:5000/app");
+    }
+
+    #[tokio::test]
+    pub async fn verify_with_key() {
+        let mock = Arc::new(Mock::default()
+            .respond("docker buildx imagetools inspect", 0, &format!("{DIGEST}\n"))
+            .respond_with_stderr("cosign verify-attestation --type slsaprovenance1", 1, "", "Error: none of the attestations matched the predicate type: https://slsa.dev/provenance/v1\n")
+            .respond_with_stderr("cosign verify-attestation --type spdxjson", 1, "", "Error: no matching attestations\n")
+            .respond_with_stderr("cosign verify-attestation --type cyclonedx", 1, "", "Error: no matching attestations\n"));
+        let policy = Verify { key: "cosign.pub".into(), ..Default::default() };
+        let verification = crate::process::scope(mock.clone(), verify(IMAGE, &policy, &CancellationToken::new())).await.unwrap();
+
+        let findings: Vec<_> = verification.findings.iter().map(|finding| (finding.check, finding.passed, finding.detail.as_str())).collect();
+        assert_eq!(findings, [
+            (Check::Signature, true, ""),
+            (Check::Provenance, true, "slsaprovenance"),
+            (Check::Sbom, false, "no matching attestations"),
+        ]);
+        assert_eq!(verification.failures(), 0, "an SBOM is not required by default");
+
+        let pinned = format!("europe-north1-docker.pkg.dev/nais-management-233d/myteam/myapp@{DIGEST}");
+        assert_eq!(mock.invocations()[1].command_line(), format!("cosign verify --key cosign.pub {pinned}"));
+    }
+
+    #[tokio::test]
+    pub async fn fail_required_checks() {
+        let mock = Arc::new(Mock::default()
+            .respond("docker", 1, "")
+            .respond_with_stderr("cosign verify ", 10, "", "Error: no signatures found\nmain.go:74: error during command execution: no signatures found\n"));
+        let policy = Verify {
+            sbom: true,
+            certificate_identity_regexp: "^https://github.com/navikt/".into(),
+            certificate_oidc_issuer: "https://token.actions.githubusercontent.com".into(),
+            ..Default::default()
+        };
+        let verification = crate::process::scope(mock.clone(), verify(IMAGE, &policy, &CancellationToken::new())).await.unwrap();
+        assert_eq!(verification.digest, None);
+        assert_eq!(verification.failures(), 1);
+        assert_eq!(verification.findings[0].detail, "no signatures found");
+        assert_eq!(mock.invocations()[1].command_line(), format!("cosign verify --certificate-identity-regexp ^https://github.com/navikt/ \
+            --certificate-oidc-issuer https://token.actions.githubusercontent.com {IMAGE}"));
+
+        let policy = Verify::default();
+        assert!(matches!(verify(IMAGE, &policy, &CancellationToken::new()).await, Err(Error::NoIdentity)));
+    }
+}
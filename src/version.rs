@@ -0,0 +1,31 @@
+//! Version and build information of nb itself, embedded by the build script.
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const GIT_COMMIT: &str = env!("NB_GIT_COMMIT");
+pub const BUILD_DATE: &str = env!("NB_BUILD_DATE");
+/// Enabled Cargo features, separated by commas.
+pub const FEATURES: &str = env!("NB_FEATURES");
+
+/// Version with the Git commit as build metadata, e.g. `0.1.0+abc1234`.
+/// Used in logs and the `build.nais.io/client-version` deploy annotation.
+pub const CLIENT_VERSION: &str = concat!(env!("CARGO_PKG_VERSION"), "+", env!("NB_GIT_COMMIT"));
+
+/// Version and build information, one item per line.
+pub fn long() -> String {
+    let features = match FEATURES {
+        "" => "none",
+        features => features,
+    };
+    format!("nb {VERSION}\ncommit:     {GIT_COMMIT}\nbuild date: {BUILD_DATE}\nfeatures:   {features}")
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    pub fn client_version() {
+        assert_eq!(CLIENT_VERSION, format!("{VERSION}+{GIT_COMMIT}"));
+        assert!(long().starts_with(&format!("nb {VERSION}\n")));
+    }
+}
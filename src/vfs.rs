@@ -0,0 +1,189 @@
+//! Read-only view of a source directory, as used to detect its SDK and manifests.
+//!
+//! Detection reads files through a [Filesystem]: the [Os] filesystem when nb runs in a checkout,
+//! or a [Memory] tree, for unit tests and for sources that are not checked out on disk.
+
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind};
+use std::path::{Component, Path, PathBuf};
+
+pub trait Filesystem: Send + Sync {
+    /// Whether `path` is a regular file.
+    fn is_file(&self, path: &Path) -> bool;
+
+    /// Paths of the entries of the directory `path`, in no particular order.
+    fn read_dir(&self, path: &Path) -> std::io::Result<Vec<PathBuf>>;
+
+    fn read_to_string(&self, path: &Path) -> std::io::Result<String>;
+
+    /// Regular files in `directory` and its subdirectories, in no particular order. Only the subdirectories
+    /// that `descend` returns true for are read, so that a search can skip those it has no use for.
+    fn files(&self, directory: &Path, descend: &dyn Fn(&Path) -> bool) -> Vec<PathBuf> {
+        let mut files = Vec::new();
+        let mut directories = vec![directory.to_path_buf()];
+        while let Some(directory) = directories.pop() {
+            for path in self.read_dir(&directory).unwrap_or_default() {
+                if self.is_file(&path) {
+                    files.push(path);
+                } else if descend(&path) {
+                    directories.push(path);
+                }
+            }
+        }
+        files
+    }
+
+    /// The absolute form of `path`, with `.` and `..` resolved.
+    fn canonicalize(&self, path: &Path) -> std::io::Result<PathBuf>;
+}
+
+/// The filesystem of the operating system.
+pub struct Os;
+
+impl Filesystem for Os {
+    fn is_file(&self, path: &Path) -> bool {
+        path.is_file()
+    }
+
+    fn read_dir(&self, path: &Path) -> std::io::Result<Vec<PathBuf>> {
+        std::fs::read_dir(path)?.map(|entry| Ok(entry?.path())).collect()
+    }
+
+    fn read_to_string(&self, path: &Path) -> std::io::Result<String> {
+        std::fs::read_to_string(path)
+    }
+
+    /// Like the default implementation, but with the file types of the directory entries,
+    /// instead of asking for the type of each entry.
+    fn files(&self, directory: &Path, descend: &dyn Fn(&Path) -> bool) -> Vec<PathBuf> {
+        let mut files = Vec::new();
+        let mut directories = vec![directory.to_path_buf()];
+        while let Some(directory) = directories.pop() {
+            let Ok(entries) = std::fs::read_dir(&directory) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                // Links are followed, like `glob::glob` does.
+                let Ok(file_type) = entry.file_type().and_then(|file_type| match file_type.is_symlink() {
+                    true => std::fs::metadata(entry.path()).map(|metadata| metadata.file_type()),
+                    false => Ok(file_type),
+                }) else {
+                    continue;
+                };
+                if file_type.is_file() {
+                    files.push(entry.path());
+                } else if file_type.is_dir() && descend(&entry.path()) {
+                    directories.push(entry.path());
+                }
+            }
+        }
+        files
+    }
+
+    fn canonicalize(&self, path: &Path) -> std::io::Result<PathBuf> {
+        std::fs::canonicalize(path)
+    }
+}
+
+/// Files held in memory, in directories that exist as long as they contain a file.
+/// Relative paths are relative to the root directory.
+#[derive(Debug, Default, Clone)]
+pub struct Memory {
+    files: BTreeMap<PathBuf, String>,
+}
+
+impl Memory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a file, replacing any file at the same path.
+    pub fn with_file(mut self, path: impl AsRef<Path>, contents: &str) -> Self {
+        self.files.insert(normalize(path.as_ref()), contents.to_string());
+        self
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.files.keys().any(|file| file.starts_with(path) && file != path)
+    }
+}
+
+impl Filesystem for Memory {
+    fn is_file(&self, path: &Path) -> bool {
+        self.files.contains_key(&normalize(path))
+    }
+
+    fn read_dir(&self, path: &Path) -> std::io::Result<Vec<PathBuf>> {
+        let path = normalize(path);
+        if !self.is_dir(&path) {
+            return Err(not_found(&path));
+        }
+        let mut entries: Vec<PathBuf> = self.files.keys()
+            .filter_map(|file| file.strip_prefix(&path).ok()?.components().next())
+            .map(|entry| path.join(entry))
+            .collect();
+        entries.dedup();
+        Ok(entries)
+    }
+
+    fn read_to_string(&self, path: &Path) -> std::io::Result<String> {
+        let path = normalize(path);
+        self.files.get(&path).cloned().ok_or_else(|| not_found(&path))
+    }
+
+    fn canonicalize(&self, path: &Path) -> std::io::Result<PathBuf> {
+        let path = normalize(path);
+        match self.files.contains_key(&path) || self.is_dir(&path) {
+            true => Ok(path),
+            false => Err(not_found(&path)),
+        }
+    }
+}
+
+/// Make `path` absolute, resolving `.` and `..` without following links, which a [Memory] filesystem has none of.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::from("/");
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => normalized.push(name),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+        }
+    }
+    normalized
+}
+
+fn not_found(path: &Path) -> Error {
+    Error::new(ErrorKind::NotFound, format!("{} not found", path.display()))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    pub fn memory_filesystem() {
+        let filesystem = Memory::new()
+            .with_file("/src/go.mod", "module example.com/app")
+            .with_file("/src/cmd/api/main.go", "package main")
+            .with_file("src/cmd/worker/main.go", "package main")
+            .with_file("/src/.nais/dev/app.yaml", "kind: Application");
+
+        assert!(filesystem.is_file(Path::new("/src/./go.mod")));
+        assert!(!filesystem.is_file(Path::new("/src/cmd")));
+        assert_eq!(filesystem.read_to_string(Path::new("/src/cmd/../go.mod")).unwrap(), "module example.com/app");
+        assert_eq!(filesystem.read_to_string(Path::new("/src/nais.yaml")).unwrap_err().kind(), ErrorKind::NotFound);
+
+        let mut entries = filesystem.read_dir(Path::new("/src/cmd")).unwrap();
+        entries.sort();
+        assert_eq!(entries, [PathBuf::from("/src/cmd/api"), PathBuf::from("/src/cmd/worker")]);
+        assert!(filesystem.read_dir(Path::new("/src/go.mod")).is_err());
+
+        let mut files = filesystem.files(Path::new("/src"), &|directory| !directory.ends_with("cmd"));
+        files.sort();
+        assert_eq!(files, [PathBuf::from("/src/.nais/dev/app.yaml"), PathBuf::from("/src/go.mod")]);
+        assert_eq!(filesystem.canonicalize(Path::new("/src/cmd/api/..")).unwrap(), PathBuf::from("/src/cmd"));
+    }
+}
@@ -0,0 +1,124 @@
+//! Watch the source directory for changes, so that `nb watch` can rebuild on save.
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use log::debug;
+use notify::Watcher as _;
+use thiserror::Error;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+use tokio_util::sync::CancellationToken;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("watch: {0}")]
+    Notify(#[from] notify::Error),
+
+    #[error("file watcher stopped unexpectedly")]
+    Disconnected,
+
+    #[error("watch cancelled")]
+    Cancelled,
+
+    #[error(transparent)]
+    IOError(#[from] std::io::Error),
+}
+
+/// Directories that are never watched: build output, which would otherwise trigger
+/// a rebuild after every build, and hidden directories such as `.git` and `.idea`.
+const IGNORED_DIRECTORIES: &[&str] = &["build", "target", "node_modules"];
+
+pub struct Watcher {
+    root: PathBuf,
+    events: UnboundedReceiver<notify::Result<notify::Event>>,
+    // Dropping the watcher stops the events.
+    _watcher: notify::RecommendedWatcher,
+}
+
+impl Watcher {
+    /// Watch all files below `source_directory` recursively.
+    pub fn new(source_directory: &str) -> Result<Self, Error> {
+        let root = std::fs::canonicalize(source_directory)?;
+        let (sender, events) = unbounded_channel();
+        // Events are sent from the thread of the watcher, and received by the async command.
+        let mut watcher = notify::recommended_watcher(move |event| {
+            let _ = sender.send(event);
+        })?;
+        watcher.watch(&root, notify::RecursiveMode::Recursive)?;
+        debug!("Watching {} for changes", root.display());
+        Ok(Self { root, events, _watcher: watcher })
+    }
+
+    /// Wait until files change, and return the changed paths relative to the source directory
+    /// once no further changes have been seen for `debounce`. Ignored paths are never returned.
+    /// Fails with [Error::Cancelled] as soon as `cancel` is cancelled.
+    pub async fn changes(&mut self, debounce: Duration, cancel: &CancellationToken) -> Result<Vec<PathBuf>, Error> {
+        let mut changed = Vec::new();
+        while changed.is_empty() {
+            let event = cancel.run_until_cancelled(self.events.recv()).await.ok_or(Error::Cancelled)?;
+            self.collect(event.ok_or(Error::Disconnected)??, &mut changed);
+        }
+        loop {
+            let received = cancel.run_until_cancelled(tokio::time::timeout(debounce, self.events.recv())).await.ok_or(Error::Cancelled)?;
+            match received {
+                Ok(Some(event)) => self.collect(event?, &mut changed),
+                Ok(None) => return Err(Error::Disconnected),
+                Err(_) => break,
+            }
+        }
+        changed.sort();
+        Ok(changed)
+    }
+
+    fn collect(&self, event: notify::Event, changed: &mut Vec<PathBuf>) {
+        if event.kind.is_access() {
+            return;
+        }
+        for path in event.paths {
+            let Ok(relative) = path.strip_prefix(&self.root) else {
+                continue;
+            };
+            if !ignored(relative) && !changed.iter().any(|seen| seen == relative) {
+                changed.push(relative.to_path_buf());
+            }
+        }
+    }
+}
+
+/// Whether a path relative to the source directory is in a hidden or build output directory.
+fn ignored(relative: &Path) -> bool {
+    relative.parent().into_iter().flat_map(Path::components).any(|component| {
+        let name = component.as_os_str().to_string_lossy();
+        name.starts_with('.') || IGNORED_DIRECTORIES.contains(&name.as_ref())
+    })
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    pub fn ignored_paths() {
+        assert!(ignored(Path::new(".git/index")));
+        assert!(ignored(Path::new("build/myapp")));
+        assert!(ignored(Path::new("app/target/classes/Main.class")));
+        assert!(!ignored(Path::new("cmd/myapp/main.go")));
+        assert!(!ignored(Path::new(".env")));
+        assert!(!ignored(Path::new("build.gradle.kts")));
+    }
+
+    #[tokio::test]
+    pub async fn changes_until_cancelled() {
+        let directory = tempfile::tempdir().unwrap();
+        std::fs::create_dir(directory.path().join("build")).unwrap();
+        let mut watcher = Watcher::new(directory.path().to_str().unwrap()).unwrap();
+        let cancel = CancellationToken::new();
+        std::fs::write(directory.path().join("main.go"), "package main\n").unwrap();
+        std::fs::write(directory.path().join("build/myapp"), "").unwrap();
+        let changed = watcher.changes(Duration::from_millis(100), &cancel).await.unwrap();
+        assert_eq!(changed, [PathBuf::from("main.go")]);
+
+        cancel.cancel();
+        let result = tokio::time::timeout(Duration::from_secs(1), watcher.changes(Duration::from_millis(100), &cancel)).await;
+        assert!(matches!(result, Ok(Err(Error::Cancelled))), "{result:?}");
+    }
+}
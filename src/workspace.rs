@@ -0,0 +1,170 @@
+//! Working files of nb, kept under `.nb/` in the source directory:
+//!
+//! ```text
+//! .nb/
+//!   .gitignore                      ignores the workspace, so that it is not committed or in a `git` build context
+//!   dockerfiles/<app>.Dockerfile    generated Dockerfiles, kept for inspection until `nb clean`
+//!   work/<pid>/                     files of one run, removed when it finishes unless `--keep-workdir` is given
+//!     manifests-<cluster>.yaml      rendered resources, as given to the deploy client
+//! ```
+//!
+//! Work directories of runs that were killed are removed by `nb clean`.
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use log::{debug, info, warn};
+
+/// Name of the workspace directory in the source directory.
+pub const DIRECTORY: &str = ".nb";
+
+const DOCKERFILE_SUFFIX: &str = ".Dockerfile";
+
+static KEEP_WORKDIR: AtomicBool = AtomicBool::new(false);
+
+/// Work directories created by this process.
+static WORKDIRS: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Keep the work directories of this run when it finishes, e.g. to inspect a build context.
+pub fn keep_workdir(keep: bool) {
+    KEEP_WORKDIR.store(keep, Ordering::Relaxed);
+}
+
+/// The workspace of a source directory.
+pub struct Workspace {
+    root: PathBuf,
+}
+
+impl Workspace {
+    pub fn new(source_directory: impl AsRef<Path>) -> Self {
+        Workspace { root: source_directory.as_ref().join(DIRECTORY) }
+    }
+
+    /// Create `directory` in the workspace, and the workspace with its `.gitignore` if needed.
+    fn create(&self, directory: &Path) -> std::io::Result<()> {
+        std::fs::create_dir_all(directory)?;
+        let gitignore = self.root.join(".gitignore");
+        if !gitignore.exists() {
+            std::fs::write(gitignore, "*\n")?;
+        }
+        Ok(())
+    }
+
+    /// Path of the generated Dockerfile of `app`.
+    pub fn dockerfile(&self, app: &str) -> PathBuf {
+        self.root.join("dockerfiles").join(format!("{app}{DOCKERFILE_SUFFIX}"))
+    }
+
+    /// Create the directory of the generated Dockerfiles, and return the path of the one of `app`.
+    pub fn create_dockerfile(&self, app: &str) -> std::io::Result<PathBuf> {
+        let path = self.dockerfile(app);
+        self.create(path.parent().expect("Dockerfiles are in a directory"))?;
+        Ok(path)
+    }
+
+    /// The generated Dockerfiles of every application.
+    pub fn generated_dockerfiles(&self) -> std::io::Result<Vec<PathBuf>> {
+        let mut paths = Vec::new();
+        for path in entries(&self.root.join("dockerfiles"))? {
+            if path.file_name().unwrap_or_default().to_string_lossy().ends_with(DOCKERFILE_SUFFIX) {
+                paths.push(path);
+            }
+        }
+        Ok(paths)
+    }
+
+    /// The work directory of this run, created on first use, and removed by [cleanup].
+    pub fn workdir(&self) -> std::io::Result<PathBuf> {
+        let path = self.root.join("work").join(std::process::id().to_string());
+        let mut workdirs = WORKDIRS.lock().expect("workdirs lock");
+        if !workdirs.contains(&path) {
+            self.create(&path)?;
+            debug!("Work directory is {}", path.display());
+            if !KEEP_WORKDIR.load(Ordering::Relaxed) {
+                crate::shutdown::remove_on_cancel(&path);
+            }
+            workdirs.push(path.clone());
+        }
+        Ok(path)
+    }
+
+    /// Work directories of runs that are no longer running, e.g. because they were killed.
+    pub fn stale_workdirs(&self) -> std::io::Result<Vec<PathBuf>> {
+        Ok(entries(&self.root.join("work"))?
+            .into_iter()
+            .filter(|path| {
+                let pid = path.file_name().and_then(|name| name.to_str()).and_then(|name| name.parse::<u32>().ok());
+                pid.is_none_or(|pid| pid != std::process::id() && !running(pid))
+            })
+            .collect())
+    }
+}
+
+/// Entries of `directory`, sorted, or none if it does not exist.
+fn entries(directory: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    match std::fs::read_dir(directory) {
+        Ok(entries) => {
+            for entry in entries {
+                paths.push(entry?.path());
+            }
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err),
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Whether the process `pid` is running.
+#[cfg(unix)]
+fn running(pid: u32) -> bool {
+    // Signal 0 checks whether the process exists, without signalling it.
+    // SAFETY: kill(2) has no memory safety requirements.
+    let result = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    result == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Whether the process `pid` is running. Without a way to tell, it is assumed to be.
+#[cfg(not(unix))]
+fn running(_pid: u32) -> bool {
+    true
+}
+
+/// Remove the work directories of this run, unless they are kept with `--keep-workdir`.
+pub fn cleanup() {
+    let workdirs = std::mem::take(&mut *WORKDIRS.lock().expect("workdirs lock"));
+    for path in workdirs {
+        if KEEP_WORKDIR.load(Ordering::Relaxed) {
+            info!("Kept work directory {}", path.display());
+        } else if let Err(err) = std::fs::remove_dir_all(&path) {
+            warn!("{} not removed: {err}", path.display());
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    pub fn workspace_layout() {
+        let directory = tempfile::tempdir().unwrap();
+        let workspace = Workspace::new(directory.path());
+        assert_eq!(workspace.generated_dockerfiles().unwrap(), Vec::<PathBuf>::new());
+
+        let dockerfile = workspace.create_dockerfile("myapp").unwrap();
+        assert_eq!(dockerfile, directory.path().join(".nb/dockerfiles/myapp.Dockerfile"));
+        std::fs::write(&dockerfile, "FROM scratch\n").unwrap();
+        assert_eq!(workspace.generated_dockerfiles().unwrap(), [dockerfile]);
+        assert_eq!(std::fs::read_to_string(directory.path().join(".nb/.gitignore")).unwrap(), "*\n");
+
+        let workdir = workspace.workdir().unwrap();
+        assert!(workdir.is_dir());
+        // Removed by cleanup, which is not called here since it also removes the work directories of other tests.
+        assert!(WORKDIRS.lock().unwrap().contains(&workdir));
+        let stale = directory.path().join(".nb/work/not-a-pid");
+        std::fs::create_dir_all(&stale).unwrap();
+        assert_eq!(workspace.stale_workdirs().unwrap(), [stale]);
+    }
+}
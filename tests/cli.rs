@@ -0,0 +1,176 @@
+//! End-to-end tests of nb commands in the fixture project of each SDK, with stubs of the programs it runs.
+
+mod support;
+
+use support::Project;
+
+const SDKS: [&str; 3] = ["go", "gradle", "maven"];
+
+/// Marker file of each fixture project, by which its SDK is detected.
+fn marker(sdk: &str) -> &'static str {
+    match sdk {
+        "go" => "go.mod",
+        "gradle" => "gradlew",
+        "maven" => "pom.xml",
+        _ => unreachable!("unknown SDK {sdk}"),
+    }
+}
+
+#[test]
+fn dockerfile() {
+    for sdk in SDKS {
+        let project = Project::new(sdk);
+        let output = project.nb(&["dockerfile"]);
+        output.assert_success();
+        assert!(output.stdout.contains("FROM "), "{sdk}: {}", output.stdout);
+        assert!(project.path().join(".nb/dockerfiles/myapp.Dockerfile").exists(), "{sdk}");
+        assert!(project.invocations("docker").is_empty(), "{sdk}");
+    }
+}
+
+#[test]
+fn write_dockerfile() {
+    let project = Project::new("go");
+    let output = project.nb(&["dockerfile", "--write-dockerfile", "Dockerfile.generated"]);
+    output.assert_success();
+    let written = std::fs::read_to_string(project.path().join("Dockerfile.generated")).unwrap();
+    assert!(output.stdout.starts_with(&written), "{}", output.stdout);
+
+    project.nb(&["build", "--write-dockerfile", "build.Dockerfile"]).assert_success();
+    assert_eq!(std::fs::read_to_string(project.path().join("build.Dockerfile")).unwrap(), written);
+}
+
+#[test]
+fn build() {
+    for sdk in SDKS {
+        let project = Project::new(sdk);
+        project.nb(&["build"]).assert_success();
+
+        let builds: Vec<_> = project.invocations("docker").into_iter().filter(|args| args[0] == "build").collect();
+        assert_eq!(builds.len(), 1, "{sdk}");
+        let build = &builds[0];
+        assert!(build.windows(2).any(|pair| pair == ["--file", ".nb/Dockerfile"]), "{sdk}: {build:?}");
+        assert_eq!(build.last().map(String::as_str), Some("-"), "{sdk}: the context is streamed");
+
+        let context = project.build_context();
+        assert!(context.contains(&".nb/Dockerfile".to_string()), "{sdk}: {context:?}");
+        assert!(context.contains(&marker(sdk).to_string()), "{sdk}: {context:?}");
+        assert!(!context.iter().any(|path| path.starts_with(".git/")), "{sdk}: {context:?}");
+        assert!(project.invocations("git").is_empty(), "{sdk}: the repository is read with libgit2");
+        assert!(project.path().join("build/artifacts.json").exists(), "{sdk}");
+    }
+}
+
+#[test]
+fn failed_build() {
+    let project = Project::new("go").fail("docker-build");
+    let output = project.nb(&["--output", "json", "build"]);
+    assert_eq!(output.code, Some(5), "{}", output.stderr);
+    let report: serde_json::Value = serde_json::from_str(&output.stdout).unwrap();
+    assert_eq!(report["error"]["code"], "NB-BUILD-001");
+    assert!(!project.path().join("build/artifacts.json").exists());
+}
+
+#[test]
+fn deploy() {
+    for sdk in SDKS {
+        let project = Project::new(sdk)
+            .env("NAIS_DEPLOY_SERVER", "deploy.example.com:443")
+            .env("NAIS_DEPLOY_APIKEY", "0123456789abcdef");
+        project.nb(&["--docker-image-name", "1.0.0", "deploy", "--cluster", "dev-gcp"]).assert_success();
+
+        let deploys = project.invocations("deploy");
+        assert_eq!(deploys.len(), 1, "{sdk}");
+        let deploy = &deploys[0];
+        for expected in [["--cluster", "dev-gcp"], ["--deploy-server", "deploy.example.com:443"], ["--owner", "navikt"], ["--repository", "myapp"]] {
+            assert!(deploy.windows(2).any(|pair| pair == expected), "{sdk}: {expected:?} in {deploy:?}");
+        }
+        let resource = project.deployed_resource("manifests-dev-gcp.yaml");
+        assert!(resource.contains("image: europe-north1-docker.pkg.dev/nais-management-233d/myteam/myapp:1.0.0"), "{sdk}: {resource}");
+        assert!(!project.invocations("docker").iter().any(|args| args[0] == "build" || args[0] == "push"), "{sdk}: a given image is neither built nor pushed");
+    }
+}
+
+#[test]
+fn deploy_not_configured() {
+    let project = Project::new("go");
+    let output = project.nb(&["--docker-image-name", "1.0.0", "deploy", "--cluster", "dev-gcp"]);
+    assert_eq!(output.code, Some(3), "{}", output.stderr);
+    assert!(output.stderr.contains("NB-CONFIG-001"), "{}", output.stderr);
+    assert!(project.invocations("deploy").is_empty());
+}
+
+#[test]
+fn cancelled_build() {
+    let project = Project::new("go").hang("docker-build");
+    let output = project.nb(&["--timeout", "2s", "build"]);
+    assert_eq!(output.code, Some(124), "{}", output.stderr);
+    let ledger = std::fs::read_to_string(project.path().join("build/ledger.jsonl")).unwrap();
+    assert!(ledger.contains("\"cancelled\":true"), "{ledger}");
+    assert!(ledger.contains("\"dockerfile\":"), "the dockerfile step finished: {ledger}");
+    assert!(!project.path().join("build/artifacts.json").exists());
+    let output = project.nb(&["status"]);
+    assert!(output.stdout.starts_with("cancelled build "), "{}", output.stdout);
+}
+
+#[test]
+fn watch_timeout() {
+    let project = Project::new("go");
+    let started = std::time::Instant::now();
+    let output = project.nb(&["--timeout", "2s", "watch"]);
+    assert_eq!(output.code, Some(124), "{}", output.stderr);
+    assert!(!output.stderr.contains("Still running"), "an idle watch stops at once: {}", output.stderr);
+    assert!(started.elapsed() < std::time::Duration::from_secs(8));
+}
+
+#[test]
+fn dirty_policy() {
+    let deploy = |policy: &str, dirty: bool| {
+        let project = Project::new("go")
+            .env("NAIS_DEPLOY_SERVER", "deploy.example.com:443")
+            .env("NAIS_DEPLOY_APIKEY", "0123456789abcdef")
+            .env("NB_RELEASE__DIRTY_POLICY", policy);
+        if dirty {
+            std::fs::write(project.path().join("go.mod"), "module example.com/myapp\n").unwrap();
+            std::fs::write(project.path().join("untracked.go"), "").unwrap();
+        }
+        let output = project.nb(&["--docker-image-name", "1.0.0", "deploy", "--cluster", "dev-gcp"]);
+        (output, project.invocations("deploy").len())
+    };
+    deploy("fail", false).0.assert_success();
+    let (output, deploys) = deploy("warn", true);
+    output.assert_success();
+    assert!(output.stderr.contains("Uncommitted change: untracked.go"), "{}", output.stderr);
+    assert_eq!(deploys, 1);
+    let (output, deploys) = deploy("fail", true);
+    assert_eq!(output.code, Some(9), "{}", output.stderr);
+    assert!(output.stderr.contains("working tree has 2 uncommitted change(s)"), "{}", output.stderr);
+    assert_eq!(deploys, 0);
+}
+
+#[test]
+fn config_show() {
+    let project = Project::new("go").env("NB_BUILD__DOCKER__SKIP_UNCHANGED", "true");
+    let output = project.nb(&["--artifact-store", "/tmp/artifacts", "config", "show"]);
+    output.assert_success();
+    assert!(output.stdout.contains("skip_unchanged = true"), "{}", output.stdout);
+    assert!(output.stdout.contains("store = \"/tmp/artifacts\""), "{}", output.stdout);
+}
+
+#[test]
+fn status() {
+    let project = Project::new("go");
+    let output = project.nb(&["status"]);
+    output.assert_success();
+    assert!(output.stdout.is_empty(), "nothing is recorded before building: {}", output.stdout);
+
+    project.nb(&["build"]).assert_success();
+    std::fs::write(project.path().join("main.go"), "package main\n").unwrap();
+    let output = project.nb(&["status"]);
+    output.assert_success();
+    assert!(output.stdout.starts_with("build "), "{}", output.stdout);
+    assert!(output.stdout.contains("europe-north1-docker.pkg.dev/nais-management-233d/myteam/myapp:"), "{}", output.stdout);
+    assert!(output.stdout.contains("present locally, at HEAD, recorded "), "{}", output.stdout);
+    assert!(output.stdout.contains("uncommitted change(s)"), "{}", output.stdout);
+    assert!(project.invocations("docker").iter().any(|args| args[..2] == ["image", "inspect"]));
+}
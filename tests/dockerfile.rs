@@ -0,0 +1,35 @@
+//! Golden-file tests of the Dockerfile generated for each fixture project, in `tests/snapshots`.
+//!
+//! A change to a Dockerfile template fails these tests until the snapshots are updated, so that the
+//! change is reviewed as a diff of them. Review and accept changes with `cargo insta review`, or run
+//! the tests with `INSTA_UPDATE=always` and inspect `git diff`.
+
+use std::path::Path;
+
+/// Dockerfile generated for the fixture project `name`, with the default builder and runtime images.
+fn dockerfile(name: &str) -> String {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name);
+    let settings = nb::config::file::File::default().sdk.expect("default SDK settings");
+    let sdk = nb::detect_sdk(path.to_str().unwrap(), &settings).unwrap().expect("an SDK is detected");
+    sdk.dockerfile().unwrap()
+}
+
+#[test]
+fn go() {
+    insta::assert_snapshot!(dockerfile("go"));
+}
+
+#[test]
+fn go_multiple_targets() {
+    insta::assert_snapshot!(dockerfile("go-multiple-targets"));
+}
+
+#[test]
+fn gradle() {
+    insta::assert_snapshot!(dockerfile("gradle"));
+}
+
+#[test]
+fn maven() {
+    insta::assert_snapshot!(dockerfile("maven"));
+}
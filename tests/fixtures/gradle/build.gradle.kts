@@ -0,0 +1,4 @@
+plugins {
+    kotlin("jvm") version "2.0.21"
+    id("com.gradleup.shadow") version "8.3.5"
+}
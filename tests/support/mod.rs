@@ -0,0 +1,192 @@
+//! Harness for end-to-end tests of the `nb` executable.
+//!
+//! A [Project] is a copy of one of the fixture projects in `tests/fixtures`, committed to a fresh Git
+//! repository. `nb` runs in it with a clean environment, where `docker`, `git` and `deploy` are stubs that
+//! record how they were invoked, and answer like the real programs would for a successful run.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Separates the program and its arguments in the log of invocations.
+const SEPARATOR: char = '\u{1f}';
+
+/// Records the invocation, and fails it if `NB_STUBS_FAIL` names it, e.g. `docker-build`,
+/// or never finishes it if `NB_STUBS_HANG` does.
+const PRELUDE: &str = r#"#!/bin/sh
+program=$(basename "$0")
+{ printf '%s' "$program"; for arg in "$@"; do printf '\037%s' "$arg"; done; printf '\n'; } >> "$NB_STUBS/invocations"
+case " $NB_STUBS_FAIL " in *" $program-$1 "*) echo "$program $1 failed" >&2; exit 1;; esac
+case " $NB_STUBS_HANG " in *" $program-$1 "*) exec sleep 60;; esac
+"#;
+
+const DOCKER: &str = r##"
+case "$1" in
+    build)
+        cat > "$NB_STUBS/context.tar"
+        echo "#1 [internal] load build definition from Dockerfile"
+        echo "#1 DONE 0.1s"
+        ;;
+    login) cat > /dev/null ;;
+    # Nothing is in a registry.
+    manifest | buildx) exit 1 ;;
+esac
+"##;
+
+const DEPLOY: &str = r#"
+mkdir -p "$NB_STUBS/resources"
+while [ $# -gt 0 ]; do
+    [ "$1" = --resource ] && cp "$2" "$NB_STUBS/resources/"
+    shift
+done
+"#;
+
+/// nb reads repositories with libgit2, and only runs `git` when that fails.
+const GIT: &str = r#"
+echo "git stub: not expected to run" >&2
+exit 1
+"#;
+
+pub struct Project {
+    directory: tempfile::TempDir,
+    path: PathBuf,
+    env: Vec<(String, String)>,
+}
+
+/// Result of running `nb`.
+pub struct Output {
+    pub code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl Output {
+    #[track_caller]
+    pub fn assert_success(&self) -> &Self {
+        assert_eq!(self.code, Some(0), "nb failed:\n{}", self.stderr);
+        self
+    }
+}
+
+impl Project {
+    /// Copy the fixture project `name`, and commit it to a new repository with a GitHub remote.
+    pub fn new(name: &str) -> Self {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join(name);
+        copy(&Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name), &path);
+        commit(&path);
+
+        let stubs = directory.path().join("stubs");
+        std::fs::create_dir_all(&stubs).unwrap();
+        for (program, body) in [("docker", DOCKER), ("deploy", DEPLOY), ("git", GIT)] {
+            let stub = stubs.join(program);
+            std::fs::write(&stub, format!("{PRELUDE}{body}")).unwrap();
+            make_executable(&stub);
+        }
+        let home = directory.path().join("home");
+        std::fs::create_dir_all(&home).unwrap();
+
+        let env = vec![
+            ("PATH".to_string(), format!("{}:/usr/bin:/bin", stubs.display())),
+            ("HOME".to_string(), home.display().to_string()),
+            ("NB_STUBS".to_string(), stubs.display().to_string()),
+        ];
+        Project { directory, path, env }
+    }
+
+    /// Set an environment variable for `nb`.
+    pub fn env(mut self, name: &str, value: &str) -> Self {
+        self.env.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Make the stubs fail `invocations`, like `docker-build`: a program and its first argument.
+    pub fn fail(self, invocations: &str) -> Self {
+        self.env("NB_STUBS_FAIL", invocations)
+    }
+
+    /// Make the stubs hang on `invocations`, like [Project::fail], until they are terminated.
+    pub fn hang(self, invocations: &str) -> Self {
+        self.env("NB_STUBS_HANG", invocations)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Run `nb` non-interactively in the project.
+    pub fn nb(&self, args: &[&str]) -> Output {
+        let output = Command::new(env!("CARGO_BIN_EXE_nb"))
+            .arg("--non-interactive")
+            .args(args)
+            .current_dir(&self.path)
+            .env_clear()
+            .envs(self.env.iter().map(|(name, value)| (name, value)))
+            .output()
+            .unwrap();
+        Output {
+            code: output.status.code(),
+            stdout: String::from_utf8_lossy(&output.stdout).to_string(),
+            stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+        }
+    }
+
+    fn stubs(&self) -> PathBuf {
+        self.directory.path().join("stubs")
+    }
+
+    /// Arguments of each invocation of the stub of `program`, in order.
+    pub fn invocations(&self, program: &str) -> Vec<Vec<String>> {
+        let log = std::fs::read_to_string(self.stubs().join("invocations")).unwrap_or_default();
+        log.lines()
+            .map(|line| line.split(SEPARATOR).map(str::to_string).collect::<Vec<_>>())
+            .filter(|invocation| invocation[0] == program)
+            .map(|invocation| invocation[1..].to_vec())
+            .collect()
+    }
+
+    /// Paths in the build context that was last streamed to `docker build`.
+    pub fn build_context(&self) -> Vec<String> {
+        let file = std::fs::File::open(self.stubs().join("context.tar")).expect("a build context was streamed");
+        let mut archive = tar::Archive::new(file);
+        let mut paths: Vec<String> = archive.entries().unwrap()
+            .map(|entry| entry.unwrap().path().unwrap().to_string_lossy().to_string())
+            .collect();
+        paths.sort();
+        paths
+    }
+
+    /// A resource file given to the deploy client.
+    pub fn deployed_resource(&self, file_name: &str) -> String {
+        std::fs::read_to_string(self.stubs().join("resources").join(file_name)).expect("the resource was deployed")
+    }
+}
+
+fn copy(from: &Path, to: &Path) {
+    std::fs::create_dir_all(to).unwrap();
+    for entry in std::fs::read_dir(from).unwrap() {
+        let entry = entry.unwrap();
+        let target = to.join(entry.file_name());
+        if entry.file_type().unwrap().is_dir() {
+            copy(&entry.path(), &target);
+        } else {
+            std::fs::copy(entry.path(), &target).unwrap();
+        }
+    }
+}
+
+fn commit(path: &Path) {
+    let repository = git2::Repository::init(path).unwrap();
+    repository.remote("origin", "https://github.com/navikt/myapp.git").unwrap();
+    let mut index = repository.index().unwrap();
+    index.add_all(["*"], git2::IndexAddOption::DEFAULT, None).unwrap();
+    index.write().unwrap();
+    let tree = repository.find_tree(index.write_tree().unwrap()).unwrap();
+    let signature = git2::Signature::now("nb tests", "nb@example.com").unwrap();
+    repository.commit(Some("HEAD"), &signature, &signature, "Initial commit", &tree, &[]).unwrap();
+}
+
+#[cfg(unix)]
+fn make_executable(path: &Path) {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755)).unwrap();
+}